@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::fs::read_to_string;
+use std::fs::{read_to_string, write};
 use std::path::Path;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,4 +17,11 @@ impl ContractConfig {
         // https://github.com/serde-rs/json/issues/160#issuecomment-253446892
         Ok(serde_json::from_str(&read_to_string(path)?)?)
     }
+
+    pub fn save_to_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(write(path, serde_json::to_string_pretty(self)?)?)
+    }
 }
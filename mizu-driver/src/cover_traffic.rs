@@ -0,0 +1,130 @@
+//! Cover traffic hides when and how many messages a user sends (threat items E2 and E3).
+//!
+//! An identity with cover traffic enabled posts only at randomized slots (see
+//! `Driver::run_cover_slot`). Each slot carries the oldest held message, or a dummy ciphertext
+//! if there is none and the daily budget allows.
+//!
+//! A dummy is a `Message::Regular` from our identity, like the messages of established
+//! conversations, encrypting a padded envelope in a Double Ratchet session nobody else knows.
+//! Recipients fail to decrypt it just like messages addressed to others, and discard it without
+//! touching their ratchets.
+//! TODO: ratchet headers are not encrypted, and a dummy always starts a new sending chain, as
+//! the first message after a reply does.
+
+use crate::envelope::{Content, Envelope};
+use bincode::serialize;
+use chrono::Duration;
+use mizu_crypto::double_ratchet::DoubleRatchetClient;
+use mizu_crypto::keys::{IdentityPublicKey, PrekeyKeyPair};
+use mizu_crypto::x3dh::{X3DHSecretKey, X3DHAD};
+use mizu_crypto::Message;
+use rand::distributions::Alphanumeric;
+use rand::{CryptoRng, Rng, RngCore};
+
+/// Dummies carry a text of a length up to this, which covers most chat messages.
+pub const MAX_DUMMY_LENGTH: usize = 140;
+
+/// What a cover traffic slot was used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// The next slot has not come yet.
+    NotDue,
+    /// A held message was posted.
+    Real,
+    /// A dummy was posted.
+    Dummy,
+    /// Nothing was posted since the daily budget is spent.
+    OverBudget,
+    /// Cover traffic is disabled, and held messages were posted, except to contacts with a
+    /// pending key change.
+    Disabled,
+}
+
+/// Returns the time until the next slot, which is uniformly distributed between 1/2 and 3/2 of
+/// `interval_secs`.
+pub fn next_slot_delay<R: Rng>(rng: &mut R, interval_secs: i32) -> Duration {
+    let interval_secs = i64::from(interval_secs.max(0));
+    Duration::seconds(rng.gen_range(interval_secs / 2, interval_secs * 3 / 2 + 1))
+}
+
+/// Creates a serialized `mizu_crypto::Message` from `our_identity_key` which nobody can
+/// decrypt.
+pub fn create_dummy<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    our_identity_key: &IdentityPublicKey,
+) -> Vec<u8> {
+    // Encoded like real messages, so that it is padded to the same sizes.
+    let length = rng.gen_range(1, MAX_DUMMY_LENGTH + 1);
+    let text = rng.sample_iter(Alphanumeric).take(length).collect();
+    let payload = Envelope::new(rng, Content::Text(text)).encode();
+
+    let mut secret_key = [0; 32];
+    rng.fill_bytes(&mut secret_key);
+    let prekey = PrekeyKeyPair::new(rng).public_key;
+    let mut double_ratchet =
+        DoubleRatchetClient::initiate(rng, &X3DHSecretKey(secret_key), &prekey);
+    // Associated data is not sent, so any will do. Encrypting with an initiated session
+    // doesn't fail.
+    let message = double_ratchet
+        .encrypt_message(&payload, &X3DHAD(vec![]))
+        .unwrap();
+    serialize(&Message::Regular(our_identity_key.clone(), message)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mizu_crypto::x3dh::X3DHClient;
+    use mizu_crypto::Client;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn next_slot_delay_is_around_interval() {
+        for _ in 0..100 {
+            let delay = next_slot_delay(&mut OsRng, 60);
+            assert!(delay >= Duration::seconds(30));
+            assert!(delay <= Duration::seconds(90));
+        }
+        assert_eq!(next_slot_delay(&mut OsRng, 0), Duration::seconds(0));
+    }
+    /// Returns the variant of a serialized `Message`, which bincode writes first.
+    fn variant(message: &[u8]) -> &[u8] {
+        &message[..4]
+    }
+
+    #[test]
+    fn dummies_are_shaped_like_regular_messages() {
+        let (alice_x3dh, bob_x3dh) = (X3DHClient::new(&mut OsRng), X3DHClient::new(&mut OsRng));
+        let alice_keys = (
+            alice_x3dh.identity_key.public_key.clone(),
+            alice_x3dh.prekey.public_key.clone(),
+        );
+        let bob_keys = (
+            bob_x3dh.identity_key.public_key.clone(),
+            bob_x3dh.prekey.public_key.clone(),
+        );
+        let mut alice = Client::with_x3dh_client(alice_x3dh, b"alice", b"bob");
+        let mut bob = Client::with_x3dh_client(bob_x3dh, b"bob", b"alice");
+        // The first message of a conversation is an X3DH one, and replies are regular ones.
+        let hello = alice
+            .create_message(&mut OsRng, &bob_keys.0, &bob_keys.1, b"hello")
+            .unwrap();
+        bob.attempt_message_decryption(&mut OsRng, hello).unwrap();
+
+        let mut real_shapes = std::collections::HashSet::new();
+        for length in 1..=MAX_DUMMY_LENGTH {
+            let payload = Envelope::new(&mut OsRng, Content::Text("a".repeat(length))).encode();
+            let message = bob
+                .create_message(&mut OsRng, &alice_keys.0, &alice_keys.1, &payload)
+                .unwrap();
+            assert!(matches!(message, Message::Regular(..)));
+            let message = serialize(&message).unwrap();
+            real_shapes.insert((variant(&message).to_vec(), message.len()));
+        }
+
+        for _ in 0..100 {
+            let dummy = create_dummy(&mut OsRng, &bob_keys.0);
+            assert!(real_shapes.contains(&(variant(&dummy).to_vec(), dummy.len())));
+        }
+    }
+}
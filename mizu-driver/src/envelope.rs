@@ -0,0 +1,208 @@
+//! The application-level format of the plaintext inside ratchet messages.
+//!
+//! A payload is a version byte followed by a bincode-serialized `Envelope`, padded with zeros
+//! to a multiple of `PADDING_BLOCK` bytes.
+//! Messages sent before envelopes were introduced are raw UTF-8 text; they are decoded as
+//! `Content::Text` without a message ID.
+
+use bincode::{deserialize, serialize};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+/// The envelope version this driver writes.
+pub const VERSION: u8 = 1;
+
+/// Payloads starting with a byte below this are envelopes. UTF-8 text practically never
+/// starts with such a control character.
+const MAX_VERSION_BYTE: u8 = 0x08;
+
+/// Payloads are padded to a multiple of this many bytes, so that the size of a ciphertext only
+/// tells roughly how long the message is (threat item E3).
+pub const PADDING_BLOCK: usize = 64;
+
+/// A random ID chosen by the sender, which both peers use to refer to the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageId(pub [u8; 16]);
+
+impl MessageId {
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut id = [0; 16];
+        rng.fill_bytes(&mut id);
+        MessageId(id)
+    }
+
+    /// Returns `None` if `bytes` is not 16 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        let mut id = [0; 16];
+        if bytes.len() != id.len() {
+            return None;
+        }
+        id.copy_from_slice(bytes);
+        Some(MessageId(id))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Content {
+    Text(String),
+    /// A text message replying to another message of the conversation.
+    Reply {
+        to: MessageId,
+        text: String,
+    },
+    /// Replaces the text of a message sent by the same peer.
+    Edit {
+        target: MessageId,
+        text: String,
+    },
+    /// Deletes a message sent by the same peer for everyone.
+    Delete {
+        target: MessageId,
+    },
+    /// Reacts to a message of the conversation. An empty reaction removes the previous one.
+    Reaction {
+        target: MessageId,
+        reaction: String,
+    },
+    /// Tells that the peer has read our messages.
+    ReadReceipt {
+        targets: Vec<MessageId>,
+    },
+}
+
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Content::Text(text) => write!(f, "{}", text),
+            Content::Reply { text, .. } => write!(f, "(reply) {}", text),
+            Content::Edit { text, .. } => write!(f, "(edit) {}", text),
+            Content::Delete { .. } => write!(f, "(deleted a message)"),
+            Content::Reaction { reaction, .. } if reaction.is_empty() => {
+                write!(f, "(removed a reaction)")
+            }
+            Content::Reaction { reaction, .. } => write!(f, "(reaction) {}", reaction),
+            Content::ReadReceipt { targets } => write!(f, "(read {} messages)", targets.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: MessageId,
+    pub content: Content,
+}
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("unsupported envelope version {0}")]
+    UnsupportedVersion(u8),
+    #[error("invalid envelope: {0}")]
+    Invalid(bincode::Error),
+}
+
+impl Envelope {
+    pub fn new<R: RngCore + CryptoRng>(rng: &mut R, content: Content) -> Self {
+        Envelope {
+            id: MessageId::generate(rng),
+            content,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![VERSION];
+        // Serializing plain data into a Vec doesn't fail.
+        payload.extend(serialize(self).unwrap());
+        // bincode ignores trailing bytes, so the padding needs no length.
+        payload.resize(padded_length(payload.len()), 0);
+        payload
+    }
+}
+
+/// Rounds `length` up to a multiple of `PADDING_BLOCK`.
+pub fn padded_length(length: usize) -> usize {
+    match length % PADDING_BLOCK {
+        0 => length,
+        rest => length + PADDING_BLOCK - rest,
+    }
+}
+
+/// Decodes a payload into the message ID (`None` for unversioned messages) and its content.
+pub fn decode(payload: &[u8]) -> Result<(Option<MessageId>, Content), EnvelopeError> {
+    match payload.first() {
+        Some(&VERSION) => {
+            let envelope: Envelope = deserialize(&payload[1..]).map_err(EnvelopeError::Invalid)?;
+            Ok((Some(envelope.id), envelope.content))
+        }
+        Some(&version) if version <= MAX_VERSION_BYTE => {
+            Err(EnvelopeError::UnsupportedVersion(version))
+        }
+        _ => Ok((
+            None,
+            Content::Text(String::from_utf8_lossy(payload).into_owned()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn envelopes_round_trip() {
+        let target = MessageId::generate(&mut OsRng);
+        for content in &[
+            Content::Text("hello".to_string()),
+            Content::Reply {
+                to: target,
+                text: "hi".to_string(),
+            },
+            Content::Edit {
+                target,
+                text: "hello!".to_string(),
+            },
+            Content::Delete { target },
+            Content::Reaction {
+                target,
+                reaction: "👍".to_string(),
+            },
+            Content::ReadReceipt {
+                targets: vec![target],
+            },
+        ] {
+            let envelope = Envelope::new(&mut OsRng, content.clone());
+            assert_eq!(
+                decode(&envelope.encode()).unwrap(),
+                (Some(envelope.id), content.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn payloads_are_padded() {
+        for length in &[0, 1, 30, 100, 1000] {
+            let text = "a".repeat(*length);
+            let payload = Envelope::new(&mut OsRng, Content::Text(text.clone())).encode();
+            assert_eq!(payload.len() % PADDING_BLOCK, 0);
+            assert_eq!(decode(&payload).unwrap().1, Content::Text(text));
+        }
+        assert_eq!(padded_length(1), PADDING_BLOCK);
+        assert_eq!(padded_length(PADDING_BLOCK), PADDING_BLOCK);
+        assert_eq!(padded_length(PADDING_BLOCK + 1), 2 * PADDING_BLOCK);
+    }
+
+    #[test]
+    fn unversioned_payloads_are_text() {
+        assert_eq!(
+            decode("こんにちは".as_bytes()).unwrap(),
+            (None, Content::Text("こんにちは".to_string()))
+        );
+        assert_eq!(decode(b"").unwrap(), (None, Content::Text(String::new())));
+        assert!(matches!(
+            decode(&[2, 0, 0]),
+            Err(EnvelopeError::UnsupportedVersion(2))
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+//! Events published by `Driver` as it talks to Tezos, so that callers can react to changes
+//! instead of re-querying the whole world.
+//!
+//! Subscribe with `Driver::subscribe` to receive events through a channel, or with
+//! `Driver::on_event` to have a callback called for each event.
+
+use crate::envelope::Content;
+use chrono::naive::NaiveDateTime;
+use mizu_sqlite::message::DeliveryStatus;
+use mizu_tezos_rpc::lock;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A message from a contact was decrypted and saved, or applied to the message it refers to.
+    MessageReceived {
+        identity_id: i32,
+        contact_id: i32,
+        content: Content,
+        timestamp: NaiveDateTime,
+    },
+    /// A message is held until the next cover traffic slot.
+    MessageQueued {
+        identity_id: i32,
+        contact_id: i32,
+        content: Content,
+    },
+    /// A message was posted to Tezos.
+    MessageSent {
+        identity_id: i32,
+        contact_id: i32,
+        content: Content,
+    },
+    /// An operation posting our message got further on its way to the chain, or failed.
+    DeliveryStatusChanged {
+        identity_id: i32,
+        contact_id: i32,
+        message_id: i32,
+        status: DeliveryStatus,
+        included_level: Option<i64>,
+    },
+    /// A poke not seen before by this driver was found in our postal box.
+    ContactRequestReceived { poke: Vec<u8> },
+    /// The identity key a contact published on Tezos differs from the pinned one.
+    /// Sending to them is blocked until the new key is accepted.
+    KeyChanged {
+        contact_id: i32,
+        old_identity_key: Vec<u8>,
+        new_identity_key: Vec<u8>,
+    },
+    /// A contact with a pending key change published the pinned identity key again, which
+    /// resolves the change and unblocks sending to them.
+    KeyRestored { contact_id: i32 },
+    /// Fetching new messages from a contact failed.
+    SyncFailed {
+        identity_id: i32,
+        contact_id: i32,
+        error: String,
+    },
+    /// Posting a message held for cover traffic failed. It stays held and is tried again later.
+    SendFailed {
+        identity_id: i32,
+        contact_id: i32,
+        error: String,
+    },
+    /// Updating the delivery status of our messages failed. It is tried again on the next sync.
+    TrackingFailed { identity_id: i32, error: String },
+}
+
+/// A callback, which is dropped once it returns `false`. Each one has its own lock, so that
+/// publishing doesn't hold the list of subscribers while calling them.
+type Subscriber = Arc<Mutex<Option<Box<dyn FnMut(&Event) -> bool + Send>>>>;
+
+/// Delivers events to subscribers.
+#[derive(Default)]
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    /// Registers `callback`, which is unregistered once it returns `false`.
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(&Event) -> bool + Send + 'static,
+    {
+        self.lock()
+            .push(Arc::new(Mutex::new(Some(Box::new(callback)))));
+    }
+
+    /// Returns a receiver of all events published from now on.
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.on_event(move |event| sender.send(event.clone()).is_ok());
+        receiver
+    }
+
+    /// Calls the subscribers with `event`. They may subscribe others meanwhile, which are called
+    /// from the next event on.
+    pub fn publish(&self, event: Event) {
+        let subscribers = self.lock().clone();
+        let mut done = vec![];
+        for subscriber in subscribers {
+            let mut callback = lock(&subscriber);
+            if let Some(f) = callback.as_mut() {
+                if !f(&event) {
+                    *callback = None;
+                    done.push(Arc::clone(&subscriber));
+                }
+            }
+        }
+
+        if !done.is_empty() {
+            self.lock()
+                .retain(|subscriber| !done.iter().any(|done| Arc::ptr_eq(done, subscriber)));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        lock(&self.subscribers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_failed(contact_id: i32) -> Event {
+        Event::SyncFailed {
+            identity_id: 1,
+            contact_id,
+            error: "error".to_string(),
+        }
+    }
+
+    #[test]
+    fn subscribers_receive_events_after_subscribing() {
+        let bus = EventBus::default();
+        bus.publish(sync_failed(1));
+
+        let first = bus.subscribe();
+        bus.publish(sync_failed(2));
+        let second = bus.subscribe();
+        bus.publish(sync_failed(3));
+
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            [sync_failed(2), sync_failed(3)]
+        );
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), [sync_failed(3)]);
+    }
+
+    #[test]
+    fn dropped_receivers_are_unsubscribed() {
+        let bus = EventBus::default();
+        drop(bus.subscribe());
+        bus.publish(sync_failed(1));
+
+        assert!(bus.lock().is_empty());
+    }
+
+    #[test]
+    fn subscribers_can_subscribe_others() {
+        let bus = Arc::new(EventBus::default());
+        let (sender, receiver) = channel();
+        bus.on_event({
+            let bus = Arc::clone(&bus);
+            move |_| {
+                sender.send(bus.subscribe()).unwrap();
+                false
+            }
+        });
+
+        bus.publish(sync_failed(1));
+        let subscribed = receiver.try_recv().unwrap();
+        bus.publish(sync_failed(2));
+
+        assert_eq!(subscribed.try_iter().collect::<Vec<_>>(), [sync_failed(2)]);
+        assert_eq!(bus.lock().len(), 1);
+    }
+}
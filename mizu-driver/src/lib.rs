@@ -1,23 +1,43 @@
 use bincode::{deserialize, serialize};
 use chrono::{naive::NaiveDateTime, Utc};
+use cover_traffic::Slot;
+use envelope::{Content, Envelope, EnvelopeError, MessageId};
+use events::{Event, EventBus};
 use mizu_crypto::keys::{IdentityPublicKey, PrekeyPublicKey};
 use mizu_crypto::x3dh::X3DHClient;
 use mizu_crypto::Client;
 use mizu_sqlite::MizuConnection;
-use mizu_sqlite::{contact::Contact, identity::Identity, message::Message};
-use mizu_tezos_interface::{BoxedTezos, Tezos};
+use mizu_sqlite::{
+    contact::Contact,
+    identity::Identity,
+    key_change::KeyChange,
+    message::{DeliveryStatus, Message, NewMessage},
+    outbox::OutboxMessage,
+    reaction::Reaction,
+    undecoded_payload::NewUndecodedPayload,
+};
+use mizu_tezos_interface::{BoxedTezos, OperationHandle, OperationStatus, Tezos, UserDataUpdate};
 use mizu_tezos_rpc::crypto;
-use mizu_tezos_rpc::TezosRpc;
+use mizu_tezos_rpc::michelson::{self, BigMap, Expr, ToMichelson};
+use mizu_tezos_rpc::monitor::{BlockUpdates, MonitorConfig};
+use mizu_tezos_rpc::signer::{self, InMemorySigner};
+use mizu_tezos_rpc::{TezosRpc, WaitConfig};
 use rand::{CryptoRng, RngCore};
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::ffi::OsStr;
 use std::fmt::{Debug, Display};
-use std::path::PathBuf;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use thiserror::Error;
 
 pub mod contract;
-
-type DieselError = diesel::result::Error;
+pub mod cover_traffic;
+pub mod envelope;
+pub mod events;
+pub mod poller;
+pub mod worker;
 
 #[derive(Debug, Error)]
 pub enum DriverError<RE: Debug + Display, WE: Debug + Display> {
@@ -26,7 +46,7 @@ pub enum DriverError<RE: Debug + Display, WE: Debug + Display> {
     #[error("something not found")]
     NotFound,
     #[error("persistency layer: {0}")]
-    UserData(DieselError),
+    UserData(mizu_sqlite::Error),
     #[error("Tezos read: {0}")]
     TezosRead(RE),
     #[error("Tezos write: {0}")]
@@ -39,15 +59,41 @@ pub enum DriverError<RE: Debug + Display, WE: Debug + Display> {
     InvalidKeyLength,
     #[error("Invalid message")]
     InvalidMessage(bincode::Error),
+    #[error("the identity key of contact {0} has changed and is not accepted yet")]
+    KeyChanged(i32),
+    #[error("Invalid envelope: {0}")]
+    InvalidEnvelope(EnvelopeError),
+    #[error("message {0} cannot be referred to")]
+    InvalidTarget(i32),
+    #[error("contact {0} sent a message with the ID of another message")]
+    DuplicateMessageId(i32),
+    #[error("secret keys must be encrypted or kept by a remote signer")]
+    PlainSecretKey,
 }
 
+/// Operations included this many blocks below the head are considered final.
+pub const CONFIRMATIONS: i64 = 5;
+
 pub type DriverResult<T, A> =
     Result<A, DriverError<<T as Tezos>::ReadError, <T as Tezos>::WriteError>>;
 
-// TODO: better name
-struct ClientAndTimestamp {
+/// The outcome of syncing the conversation with a contact.
+#[derive(Debug)]
+pub struct ContactSync<E> {
+    pub contact_id: i32,
+    /// Messages received from the contact in this sync, or the error which occurred.
+    pub new_messages: Result<Vec<Content>, E>,
+}
+
+pub type SyncSummary<T> =
+    Vec<ContactSync<DriverError<<T as Tezos>::ReadError, <T as Tezos>::WriteError>>>;
+
+/// A `Client` along with the sync cursor of the postal box it reads from.
+struct ClientAndCursor {
     client: Client,
     latest_message_timestamp: Option<NaiveDateTime>,
+    /// (block level, index within the block) of the latest message processed.
+    latest_message_cursor: Option<(i64, i32)>,
 }
 
 /// Data associated with each user in Tezos
@@ -59,35 +105,198 @@ struct TezosData {
 }
 
 // All states needed to run protocols are saved to a SQLite database and retrieved on demand.
+// `Driver<T>` is `Send + Sync` if `T` is, so it can be shared with worker threads
+// (see the `worker` module).
 pub struct Driver<T> {
-    conn: Rc<MizuConnection>,
+    conn: Arc<MizuConnection>,
     tezos: T,
+    events: EventBus,
 }
 
 impl<T> Driver<T>
 where
     T: Tezos,
 {
-    pub fn new(conn: Rc<MizuConnection>, tezos: T) -> Self {
-        Self { conn, tezos }
+    pub fn new(conn: Arc<MizuConnection>, tezos: T) -> Self {
+        Self {
+            conn,
+            tezos,
+            events: EventBus::default(),
+        }
     }
 
     pub fn boxed<'a>(self) -> Driver<BoxedTezos<'a>>
     where
-        T: 'a,
+        T: Send + Sync + 'a,
     {
         Driver {
             conn: self.conn,
             tezos: self.tezos.boxed(),
+            events: self.events,
+        }
+    }
+
+    /// Returns a receiver of all events published from now on.
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Calls `callback` for each event published from now on, until it returns `false`.
+    /// The callback is called on the thread running the driver operation. It may subscribe others,
+    /// but must not run driver operations publishing events itself, as they would call it again.
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(&Event) -> bool + Send + 'static,
+    {
+        self.events.on_event(callback)
+    }
+
+    /// Pins the identity key of a contact on first use, and records a key change if it differs
+    /// from the pinned one. Returns whether the key is the pinned one.
+    ///
+    /// A pending key change is resolved if the contact goes back to the pinned key, by recording
+    /// the change back to it as accepted.
+    fn check_identity_key(
+        &self,
+        contact: &Contact,
+        identity_key: &IdentityPublicKey,
+    ) -> DriverResult<T, bool> {
+        use DriverError::*;
+
+        let identity_key = identity_key.0.as_bytes();
+        match &contact.pinned_identity_key {
+            None => {
+                self.conn
+                    .pin_identity_key(contact.id, identity_key)
+                    .map_err(UserData)?;
+                Ok(true)
+            }
+            Some(pinned) if pinned[..] == identity_key[..] => {
+                if let Some(pending) = self
+                    .conn
+                    .find_pending_key_change(contact.id)
+                    .map_err(UserData)?
+                {
+                    let now = Utc::now().naive_utc();
+                    self.conn
+                        .create_key_change(contact.id, &pending.new_identity_key, pinned, now)
+                        .map_err(UserData)?;
+                    let reverted = self
+                        .conn
+                        .find_pending_key_change(contact.id)
+                        .map_err(UserData)?
+                        .ok_or(NotFound)?;
+                    self.conn
+                        .accept_key_change(&reverted, now)
+                        .map_err(UserData)?;
+                    self.events.publish(Event::KeyRestored {
+                        contact_id: contact.id,
+                    });
+                }
+                Ok(true)
+            }
+            Some(pinned) => {
+                // Changes are recorded (and published) once, not on every read.
+                let pending = self
+                    .conn
+                    .find_pending_key_change(contact.id)
+                    .map_err(UserData)?;
+                if pending.map_or(true, |change| {
+                    change.new_identity_key[..] != identity_key[..]
+                }) {
+                    self.conn
+                        .create_key_change(contact.id, pinned, identity_key, Utc::now().naive_utc())
+                        .map_err(UserData)?;
+                    self.events.publish(Event::KeyChanged {
+                        contact_id: contact.id,
+                        old_identity_key: pinned.clone(),
+                        new_identity_key: identity_key.to_vec(),
+                    });
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Lists the identity key changes of a contact, oldest first.
+    pub fn list_key_changes(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Vec<KeyChange>> {
+        let contact = self.find_contact(our_identity_id, their_contact_id)?;
+        self.conn
+            .list_key_changes(contact.id)
+            .map_err(DriverError::UserData)
+    }
+
+    /// Trusts the identity key a contact published last, which unblocks sending to them.
+    ///
+    /// The session with the old key is dropped, so that the next message starts a new one with
+    /// the new key. Messages before the sync cursor are still not received again.
+    pub fn accept_identity_key(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, ()> {
+        use DriverError::*;
+
+        let contact = self.find_contact(our_identity_id, their_contact_id)?;
+        let key_change = self
+            .conn
+            .find_pending_key_change(contact.id)
+            .map_err(UserData)?
+            .ok_or(NotFound)?;
+        self.conn
+            .accept_key_change(&key_change, Utc::now().naive_utc())
+            .map_err(UserData)?;
+
+        if let Some(ClientAndCursor {
+            latest_message_timestamp,
+            latest_message_cursor,
+            ..
+        }) = self.find_client(our_identity_id, their_contact_id)?
+        {
+            let our_identity = self.conn.find_identity(our_identity_id).map_err(UserData)?;
+            let client = self.new_client(&our_identity.x3dh_client, &contact.address);
+            self.conn
+                .upsert_client(
+                    our_identity_id,
+                    their_contact_id,
+                    &client,
+                    latest_message_timestamp.as_ref(),
+                    latest_message_cursor,
+                )
+                .map_err(UserData)?;
         }
+        Ok(())
     }
 
     pub fn list_identities(&self) -> DriverResult<T, Vec<Identity>> {
         self.conn.list_identities().map_err(DriverError::UserData)
     }
 
-    pub fn list_contacts(&self) -> DriverResult<T, Vec<Contact>> {
-        self.conn.list_contacts().map_err(DriverError::UserData)
+    pub fn list_contacts(&self, our_identity_id: i32) -> DriverResult<T, Vec<Contact>> {
+        self.conn
+            .list_contacts(our_identity_id)
+            .map_err(DriverError::UserData)
+    }
+
+    /// Finds a contact of our identity. Contacts of other identities are not found.
+    fn find_contact(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Contact> {
+        use DriverError::*;
+
+        let contact = self.conn.find_contact(their_contact_id).map_err(UserData)?;
+        if contact.identity_id == our_identity_id {
+            Ok(contact)
+        } else {
+            Err(NotFound)
+        }
     }
 
     pub fn list_messages(
@@ -100,14 +309,71 @@ where
             .map_err(DriverError::UserData)
     }
 
+    /// Lists the reactions to messages of a conversation.
+    pub fn list_reactions(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Vec<Reaction>> {
+        self.conn
+            .list_reactions(our_identity_id, their_contact_id)
+            .map_err(DriverError::UserData)
+    }
+
+    /// Finds the uid of a message of the conversation, which must be ours if `ours_only`.
+    fn find_target(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        message_id: i32,
+        ours_only: bool,
+    ) -> DriverResult<T, MessageId> {
+        use DriverError::*;
+
+        let message = self.conn.find_message(message_id).map_err(UserData)?;
+        if message.identity_id != our_identity_id
+            || message.contact_id != their_contact_id
+            || (ours_only && !message.my_message)
+        {
+            return Err(InvalidTarget(message_id));
+        }
+        // Messages sent before envelopes were introduced have no uid.
+        message
+            .uid
+            .as_deref()
+            .and_then(MessageId::from_slice)
+            .ok_or(InvalidTarget(message_id))
+    }
+
+    /// Generates an identity for our Tezos address.
+    ///
+    /// `signer` is recorded as is for the application to find how to sign for the address
+    /// later: either the URI of a remote signer or an encrypted secret key (see
+    /// `encrypt_secret_key`), or anything else for mock addresses which never sign. Plain secret
+    /// keys are refused so that they never hit the disk.
     pub fn generate_identity<R: RngCore + CryptoRng>(
         &self,
         rng: &mut R,
         name: &str,
+        signer: &str,
     ) -> DriverResult<T, ()> {
+        if signer::reveals_secret_key(signer) {
+            return Err(DriverError::PlainSecretKey);
+        }
         let x3dh = X3DHClient::new(rng);
         self.conn
-            .create_identity(name, self.tezos.address(), self.tezos.secret_key(), &x3dh)
+            .create_identity(name, self.tezos.address(), signer, &x3dh)
+            .map_err(DriverError::UserData)
+    }
+
+    /// Replaces how to sign for the identity, e.g. to encrypt a plain secret key recorded by
+    /// older versions. The same rules as in `generate_identity` apply to `signer`.
+    pub fn set_identity_signer(&self, identity_id: i32, signer: &str) -> DriverResult<T, ()> {
+        if signer::reveals_secret_key(signer) {
+            return Err(DriverError::PlainSecretKey);
+        }
+        self.conn
+            .update_identity_signer(identity_id, signer)
             .map_err(DriverError::UserData)
     }
 
@@ -122,21 +388,28 @@ where
                 Some(x3dh.identity_key.public_key.0.as_bytes()),
                 x3dh.prekey.public_key.0.as_bytes(),
             )
+            .map(|_| ())
             .map_err(TezosWrite)
     }
 
-    pub fn add_contact(&self, name: &str, address: &str) -> DriverResult<T, ()> {
+    pub fn add_contact(
+        &self,
+        our_identity_id: i32,
+        name: &str,
+        address: &str,
+    ) -> DriverResult<T, ()> {
         self.conn
-            .create_contact(name, address)
+            .create_contact(our_identity_id, name, address)
             .map_err(DriverError::UserData)
     }
 
     pub fn find_contact_by_address(
         &self,
+        our_identity_id: i32,
         address: &str,
     ) -> DriverResult<T, mizu_sqlite::contact::Contact> {
         self.conn
-            .find_contact_by_address(address)
+            .find_contact_by_address(our_identity_id, address)
             .map_err(DriverError::UserData)
     }
 
@@ -153,16 +426,17 @@ where
         &self,
         our_identity_id: i32,
         their_contact_id: i32,
-    ) -> DriverResult<T, Option<ClientAndTimestamp>> {
+    ) -> DriverResult<T, Option<ClientAndCursor>> {
         use DriverError::*;
 
         self.conn
             .find_client(our_identity_id, their_contact_id)
             .map_err(UserData)?
             .map(|client| {
-                Ok(ClientAndTimestamp {
+                Ok(ClientAndCursor {
                     client: deserialize(&client.client_data).map_err(InvalidClient)?,
                     latest_message_timestamp: client.latest_message_timestamp,
+                    latest_message_cursor: client.latest_message_cursor(),
                 })
             })
             .transpose()
@@ -174,77 +448,371 @@ where
         their_contact_id: i32,
         our_x3dh: &[u8],
         their_address: &str,
-    ) -> DriverResult<T, ClientAndTimestamp> {
+    ) -> DriverResult<T, ClientAndCursor> {
         Ok(self
             .find_client(our_identity_id, their_contact_id)?
-            .unwrap_or_else(|| {
-                // Construct a new Client from X3DHClient.
-
-                // This unwrap() trusts the local SQLite database.
-                let our_x3dh: X3DHClient = deserialize(our_x3dh).unwrap();
-                ClientAndTimestamp {
-                    client: Client::with_x3dh_client(
-                        our_x3dh,
-                        self.tezos.address().as_bytes(),
-                        their_address.as_bytes(),
-                    ),
-                    latest_message_timestamp: None,
-                }
+            .unwrap_or_else(|| ClientAndCursor {
+                client: self.new_client(our_x3dh, their_address),
+                latest_message_timestamp: None,
+                latest_message_cursor: None,
             }))
     }
 
-    fn retrieve_tezos_data(&self, address: &str) -> DriverResult<T, Option<TezosData>> {
+    /// Constructs a Client without a session from our X3DHClient.
+    fn new_client(&self, our_x3dh: &[u8], their_address: &str) -> Client {
+        // This unwrap() trusts the local SQLite database.
+        let our_x3dh: X3DHClient = deserialize(our_x3dh).unwrap();
+        Client::with_x3dh_client(
+            our_x3dh,
+            self.tezos.address().as_bytes(),
+            their_address.as_bytes(),
+        )
+    }
+
+    /// Messages posted before `since` are left out of the postal box.
+    fn retrieve_tezos_data(
+        &self,
+        address: &str,
+        since: Option<NaiveDateTime>,
+    ) -> DriverResult<T, Option<TezosData>> {
         use DriverError::*;
 
-        self.tezos
-            .retrieve_user_data(address)
-            .map_err(TezosRead)?
-            .map(|data| {
-                let identity_key: [u8; 32] = data
-                    .identity_key
-                    .as_slice()
-                    .try_into()
-                    .map_err(|_| InvalidKeyLength)?;
-                let identity_key = IdentityPublicKey(identity_key.into());
-                let prekey: [u8; 32] = data
-                    .prekey
-                    .as_slice()
-                    .try_into()
-                    .map_err(|_| InvalidKeyLength)?;
-                let prekey = PrekeyPublicKey(prekey.into());
-
-                Ok(TezosData {
-                    identity_key,
-                    prekey,
-                    postal_box: data.postal_box,
-                    pokes: data.pokes,
-                })
+        match since {
+            Some(since) => self.tezos.retrieve_user_data_since(address, since),
+            None => self.tezos.retrieve_user_data(address),
+        }
+        .map_err(TezosRead)?
+        .map(|data| {
+            let identity_key: [u8; 32] = data
+                .identity_key
+                .as_slice()
+                .try_into()
+                .map_err(|_| InvalidKeyLength)?;
+            let identity_key = IdentityPublicKey(identity_key.into());
+            let prekey: [u8; 32] = data
+                .prekey
+                .as_slice()
+                .try_into()
+                .map_err(|_| InvalidKeyLength)?;
+            let prekey = PrekeyPublicKey(prekey.into());
+
+            Ok(TezosData {
+                identity_key,
+                prekey,
+                postal_box: data.postal_box,
+                pokes: data.pokes,
             })
-            .transpose()
+        })
+        .transpose()
     }
 
-    // TODO: what if posting to Tezos succeeds but saving to SQLite fails?
     pub fn post_message<R: RngCore + CryptoRng>(
         &self,
         rng: &mut R,
         our_identity_id: i32,
         their_contact_id: i32,
         message: &str,
-    ) -> DriverResult<T, Vec<Vec<u8>>> {
+    ) -> DriverResult<T, Vec<Content>> {
+        self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::Text(message.to_string()),
+        )
+    }
+
+    /// Replies to a message of the conversation with `message_id`.
+    pub fn reply_to_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        message_id: i32,
+        message: &str,
+    ) -> DriverResult<T, Vec<Content>> {
+        let to = self.find_target(our_identity_id, their_contact_id, message_id, false)?;
+        self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::Reply {
+                to,
+                text: message.to_string(),
+            },
+        )
+    }
+
+    /// Replaces the text of a message we sent, for both of us.
+    pub fn edit_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        message_id: i32,
+        message: &str,
+    ) -> DriverResult<T, Vec<Content>> {
+        let target = self.find_target(our_identity_id, their_contact_id, message_id, true)?;
+        self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::Edit {
+                target,
+                text: message.to_string(),
+            },
+        )
+    }
+
+    /// Deletes a message we sent, for both of us.
+    pub fn delete_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        message_id: i32,
+    ) -> DriverResult<T, Vec<Content>> {
+        let target = self.find_target(our_identity_id, their_contact_id, message_id, true)?;
+        self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::Delete { target },
+        )
+    }
+
+    /// Reacts to a message of the conversation. An empty `reaction` removes our reaction.
+    pub fn react_to_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        message_id: i32,
+        reaction: &str,
+    ) -> DriverResult<T, Vec<Content>> {
+        let target = self.find_target(our_identity_id, their_contact_id, message_id, false)?;
+        self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::Reaction {
+                target,
+                reaction: reaction.to_string(),
+            },
+        )
+    }
+
+    /// Sends a read receipt for the messages received from a contact since the last receipt.
+    /// Nothing is sent if there are none.
+    pub fn send_read_receipt<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Vec<Content>> {
+        use DriverError::*;
+
+        let targets: Vec<_> = self
+            .conn
+            .list_unread_messages(our_identity_id, their_contact_id)
+            .map_err(UserData)?
+            .into_iter()
+            .filter_map(|message| message.uid.as_deref().and_then(MessageId::from_slice))
+            .collect();
+        if targets.is_empty() {
+            return Ok(vec![]);
+        }
+        let messages = self.post_content(
+            rng,
+            our_identity_id,
+            their_contact_id,
+            Content::ReadReceipt {
+                targets: targets.clone(),
+            },
+        )?;
+        // Mark them now rather than when the receipt is sent, so that a held receipt is not
+        // queued again.
+        self.conn
+            .mark_messages_read(
+                our_identity_id,
+                their_contact_id,
+                false,
+                &targets.iter().map(|id| id.0.to_vec()).collect::<Vec<_>>(),
+                Utc::now().naive_utc(),
+            )
+            .map_err(UserData)?;
+        Ok(messages)
+    }
+
+    /// Sends a message, or holds it until the next slot if cover traffic is enabled for our
+    /// identity (see `run_cover_slot`).
+    /// Returns the messages received before sending.
+    fn post_content<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        content: Content,
+    ) -> DriverResult<T, Vec<Content>> {
         use DriverError::*;
 
         // To mitigate inconsistency of message ordering, we checks new mesages before posting
         // TODO: TOCTOU. New messages can appear after checking but before posting.
         let messages = self.get_messages(rng, our_identity_id, their_contact_id)?;
 
+        // get_messages has checked the identity key they published.
+        if self
+            .conn
+            .find_pending_key_change(their_contact_id)
+            .map_err(UserData)?
+            .is_some()
+        {
+            return Err(KeyChanged(their_contact_id));
+        }
+
+        let payload = Envelope::new(rng, content.clone()).encode();
+        let cover_traffic = self
+            .conn
+            .find_cover_traffic(our_identity_id)
+            .map_err(UserData)?;
+        if cover_traffic.is_none() {
+            self.send_message(rng, our_identity_id, their_contact_id, &payload)?;
+            return Ok(messages);
+        }
+
+        // get_messages has checked that they have published their identity.
+        self.conn
+            .create_outbox_message(
+                our_identity_id,
+                their_contact_id,
+                &payload,
+                Utc::now().naive_utc(),
+            )
+            .map_err(UserData)?;
+        self.events.publish(Event::MessageQueued {
+            identity_id: our_identity_id,
+            contact_id: their_contact_id,
+            content,
+        });
+
+        Ok(messages)
+    }
+
+    /// Saves a message sent by either side, or applies it to the message it refers to.
+    /// Edits and deletions only apply to messages of the same side, and a message with the ID of
+    /// another one is refused.
+    #[allow(clippy::too_many_arguments)]
+    fn apply_content(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        my_message: bool,
+        uid: Option<&MessageId>,
+        content: &Content,
+        timestamp: NaiveDateTime,
+        position: Option<(i64, i32)>,
+    ) -> DriverResult<T, ()> {
+        use DriverError::*;
+
+        let find = |target: &MessageId| {
+            self.conn
+                .find_message_by_uid(our_identity_id, their_contact_id, &target.0)
+                .map_err(UserData)
+        };
+        let (text, reply_to) = match content {
+            Content::Text(text) => (text, None),
+            Content::Reply { to, text } => (text, Some(&to.0[..])),
+            Content::Edit { target, text } => {
+                if let Some(message) = find(target)?.filter(|m| m.my_message == my_message) {
+                    self.conn
+                        .edit_message(message.id, text.as_bytes(), timestamp)
+                        .map_err(UserData)?;
+                }
+                return Ok(());
+            }
+            Content::Delete { target } => {
+                if let Some(message) = find(target)?.filter(|m| m.my_message == my_message) {
+                    self.conn.delete_message(message.id).map_err(UserData)?;
+                }
+                return Ok(());
+            }
+            Content::Reaction { target, reaction } => {
+                if let Some(message) = find(target)? {
+                    if reaction.is_empty() {
+                        self.conn.delete_reaction(message.id, my_message)
+                    } else {
+                        self.conn
+                            .set_reaction(message.id, my_message, reaction, timestamp)
+                    }
+                    .map_err(UserData)?;
+                }
+                return Ok(());
+            }
+            Content::ReadReceipt { targets } => {
+                // A receipt marks the messages of the other side.
+                return self
+                    .conn
+                    .mark_messages_read(
+                        our_identity_id,
+                        their_contact_id,
+                        !my_message,
+                        &targets.iter().map(|id| id.0.to_vec()).collect::<Vec<_>>(),
+                        timestamp,
+                    )
+                    .map_err(UserData);
+            }
+        };
+
+        if let Some(existing) = uid.map(find).transpose()?.flatten() {
+            // A message saved by a previous run which failed to save the client is ignored, so
+            // messages are stored exactly once.
+            let saved_before = existing.my_message == my_message
+                && existing.level == position.map(|(level, _)| level)
+                && existing.entry_index == position.map(|(_, index)| index);
+            return if saved_before {
+                Ok(())
+            } else {
+                Err(DuplicateMessageId(their_contact_id))
+            };
+        }
+        self.conn
+            .create_message(&NewMessage {
+                identity_id: our_identity_id,
+                contact_id: their_contact_id,
+                content: text.as_bytes(),
+                my_message,
+                created_at: timestamp,
+                level: position.map(|(level, _)| level),
+                entry_index: position.map(|(_, index)| index),
+                uid: uid.map(|id| &id.0[..]),
+                reply_to,
+            })
+            .map_err(UserData)
+    }
+
+    // TODO: what if posting to Tezos succeeds but saving to SQLite fails?
+    fn send_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        payload: &[u8],
+    ) -> DriverResult<T, ()> {
+        use DriverError::*;
+
+        let (uid, content) = envelope::decode(payload).map_err(InvalidEnvelope)?;
         let our_identity = self.conn.find_identity(our_identity_id).map_err(UserData)?;
-        let their_contact = self.conn.find_contact(their_contact_id).map_err(UserData)?;
+        let their_contact = self.find_contact(our_identity_id, their_contact_id)?;
 
-        match self.retrieve_tezos_data(&their_contact.address)? {
+        // The postal box isn't read, so none of it is retrieved.
+        match self.retrieve_tezos_data(&their_contact.address, Some(Utc::now().naive_utc()))? {
             Some(data) => {
-                let ClientAndTimestamp {
+                if !self.check_identity_key(&their_contact, &data.identity_key)? {
+                    return Err(KeyChanged(their_contact_id));
+                }
+                let ClientAndCursor {
                     mut client,
                     latest_message_timestamp,
+                    latest_message_cursor,
                 } = self.find_or_create_client(
                     our_identity_id,
                     their_contact_id,
@@ -253,15 +821,23 @@ where
                 )?;
 
                 // Save the sending message (in plaintext).
-                self.conn
-                    .create_message(
-                        our_identity_id,
-                        their_contact_id,
-                        message.as_bytes(),
-                        true,
-                        Utc::now().naive_utc(),
-                    )
-                    .map_err(UserData)?;
+                self.apply_content(
+                    our_identity_id,
+                    their_contact_id,
+                    true,
+                    uid.as_ref(),
+                    &content,
+                    Utc::now().naive_utc(),
+                    None,
+                )?;
+                // Only texts and replies are saved as messages, whose delivery is tracked.
+                let saved = match &uid {
+                    Some(uid) => self
+                        .conn
+                        .find_message_by_uid(our_identity_id, their_contact_id, &uid.0)
+                        .map_err(UserData)?,
+                    None => None,
+                };
 
                 // Encrypt message and increment a ratchet.
                 // TODO: I don't know this unwrap() may panic or not. Any thoughts? > mtakeda
@@ -273,14 +849,35 @@ where
                 // That being said, I noticed errors being converted to opaque
                 // types in mizu-crypto, so fixing that and verifying that this
                 // is actually safe is TODO.
-                let message = client
-                    .create_message(rng, &data.identity_key, &data.prekey, message.as_bytes())
+                let encrypted = client
+                    .create_message(rng, &data.identity_key, &data.prekey, payload)
                     .unwrap();
 
                 // Post to Tezos.
                 // This should be panic-free
-                let payload = serialize(&message).unwrap();
-                self.tezos.post(&[&payload], &[]).map_err(TezosWrite)?;
+                let payload = serialize(&encrypted).unwrap();
+                let posted = self.tezos.post(&[&payload], &[]);
+                if let Some(message) = &saved {
+                    match &posted {
+                        Ok(operation) => self.conn.set_message_operation(
+                            message.id,
+                            &operation.hash,
+                            operation.level,
+                        ),
+                        Err(_) => self.conn.update_delivery_status(
+                            message.id,
+                            DeliveryStatus::Failed,
+                            None,
+                        ),
+                    }
+                    .map_err(UserData)?;
+                }
+                posted.map_err(TezosWrite)?;
+                self.events.publish(Event::MessageSent {
+                    identity_id: our_identity_id,
+                    contact_id: their_contact_id,
+                    content,
+                });
 
                 // Save the incremented Client.
                 self.conn
@@ -289,106 +886,719 @@ where
                         their_contact_id,
                         &client,
                         latest_message_timestamp.as_ref(),
+                        latest_message_cursor,
                     )
                     .map_err(UserData)?;
 
-                Ok(messages)
+                Ok(())
             }
             None => Err(NotFound),
         }
     }
 
-    // TODO: what if retrieving from Tezos succeeds but saving to SQLite fails?
-    pub fn get_messages<R: RngCore + CryptoRng>(
+    /// Makes our identity post only at randomized slots, about every `interval_secs` seconds.
+    /// Slots without a real message are filled with dummies, up to `daily_budget` posts a day.
+    pub fn enable_cover_traffic(
         &self,
-        rng: &mut R,
         our_identity_id: i32,
-        their_contact_id: i32,
-    ) -> DriverResult<T, Vec<Vec<u8>>> {
-        use DriverError::*;
+        interval_secs: i32,
+        daily_budget: i32,
+    ) -> DriverResult<T, ()> {
+        self.conn
+            .set_cover_traffic(
+                our_identity_id,
+                interval_secs,
+                daily_budget,
+                Utc::now().naive_utc(),
+            )
+            .map_err(DriverError::UserData)
+    }
 
-        let our_identity = self.conn.find_identity(our_identity_id).map_err(UserData)?;
-        let their_contact = self.conn.find_contact(their_contact_id).map_err(UserData)?;
+    /// Messages held at this point are sent by the next `run_cover_slot`.
+    pub fn disable_cover_traffic(&self, our_identity_id: i32) -> DriverResult<T, ()> {
+        self.conn
+            .delete_cover_traffic(our_identity_id)
+            .map_err(DriverError::UserData)
+    }
 
-        match self.retrieve_tezos_data(&their_contact.address)? {
-            Some(data) => {
-                let ClientAndTimestamp {
-                    mut client,
-                    mut latest_message_timestamp,
-                } = self.find_or_create_client(
-                    our_identity_id,
-                    their_contact_id,
-                    &our_identity.x3dh_client,
-                    &their_contact.address,
-                )?;
+    /// Messages held until the next slot, oldest first.
+    pub fn list_held_messages(&self, our_identity_id: i32) -> DriverResult<T, Vec<OutboxMessage>> {
+        self.conn
+            .list_outbox_messages(our_identity_id)
+            .map_err(DriverError::UserData)
+    }
 
-                let mut messages = vec![];
-                for message in data.postal_box.iter() {
-                    let timestamp = message.timestamp;
-                    // assuming messages are ordered from older to newer
-                    match latest_message_timestamp {
-                        // if the recorded timestamp is newer than message's timestamp, skip it.
-                        Some(latest_message_timestamp) if latest_message_timestamp >= timestamp => {
-                            continue;
-                        }
-                        // otherwise, update the timestamp.
-                        _ => {
-                            latest_message_timestamp = Some(timestamp);
-                        }
-                    }
+    /// Posts a held message or a dummy if the current slot is due.
+    /// Held messages which can't be posted are reported with `Event::SendFailed`, and stay held.
+    /// Callers are expected to call this periodically, more often than the slot interval.
+    pub fn run_cover_slot<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+    ) -> DriverResult<T, Slot> {
+        use DriverError::*;
 
-                    let message = deserialize(&message.content).map_err(InvalidMessage)?;
-                    if let Ok(message) = client.attempt_message_decryption(rng, message) {
-                        self.conn
-                            .create_message(
-                                our_identity_id,
-                                their_contact_id,
-                                &message,
-                                false,
-                                timestamp,
-                            )
-                            .map_err(UserData)?;
-                        messages.push(message);
-                    }
+        let now = Utc::now().naive_utc();
+        let held_messages = self.list_held_messages(our_identity_id)?;
+        let cover_traffic = match self
+            .conn
+            .find_cover_traffic(our_identity_id)
+            .map_err(UserData)?
+        {
+            Some(cover_traffic) => cover_traffic,
+            None => {
+                for message in self.sendable_messages(held_messages)? {
+                    // The failure is published, and the message stays held.
+                    let _ = self.send_held_message(rng, message);
                 }
+                return Ok(Slot::Disabled);
+            }
+        };
+        if now < cover_traffic.next_slot_at {
+            return Ok(Slot::NotDue);
+        }
 
-                self.conn
-                    .upsert_client(
-                        our_identity_id,
-                        their_contact_id,
-                        &client,
-                        latest_message_timestamp.as_ref(),
-                    )
-                    .map_err(UserData)?;
+        let (mut spent, mut budget_period_start) =
+            (cover_traffic.spent, cover_traffic.budget_period_start);
+        if now - budget_period_start >= chrono::Duration::days(1) {
+            spent = 0;
+            budget_period_start = now;
+        }
 
-                Ok(messages)
+        // Real messages are sent regardless of the budget, but they count against it. Messages
+        // which fail are left held, and the slot goes to the next one.
+        let mut sent = false;
+        for message in self.sendable_messages(held_messages)? {
+            if self.send_held_message(rng, message).is_ok() {
+                sent = true;
+                break;
             }
-            None => Err(NotFound),
         }
-    }
+        let posted = if sent {
+            Ok(Slot::Real)
+        } else if spent < cover_traffic.daily_budget {
+            self.post_dummy(rng, our_identity_id).map(|()| Slot::Dummy)
+        } else {
+            Ok(Slot::OverBudget)
+        };
+        if let Ok(Slot::Real) | Ok(Slot::Dummy) = posted {
+            spent += 1;
+        }
 
-    pub fn get_pokes(&self) -> DriverResult<T, Vec<Vec<u8>>> {
-        use DriverError::*;
+        // The slot is over even if posting the dummy failed.
+        let next_slot_at = now + cover_traffic::next_slot_delay(rng, cover_traffic.interval_secs);
+        self.conn
+            .update_cover_traffic(our_identity_id, spent, budget_period_start, next_slot_at)
+            .map_err(UserData)?;
 
-        match self.retrieve_tezos_data(self.tezos.address())? {
-            Some(data) => Ok(data.pokes),
-            None => Err(NotFound),
-        }
+        posted
     }
-}
 
-pub fn create_tezos_rpc(
+    /// Leaves out held messages to contacts with a pending key change, which stay held until the
+    /// change is accepted or resolved.
+    fn sendable_messages(
+        &self,
+        held_messages: Vec<OutboxMessage>,
+    ) -> DriverResult<T, Vec<OutboxMessage>> {
+        let mut sendable = vec![];
+        for message in held_messages {
+            if self
+                .conn
+                .find_pending_key_change(message.contact_id)
+                .map_err(DriverError::UserData)?
+                .is_none()
+            {
+                sendable.push(message);
+            }
+        }
+        Ok(sendable)
+    }
+
+    /// Publishes `Event::SendFailed` if the message can't be posted, leaving it held.
+    // TODO: the message is sent again if deleting it fails.
+    fn send_held_message<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        message: OutboxMessage,
+    ) -> DriverResult<T, ()> {
+        if let Err(e) = self.send_message(
+            rng,
+            message.identity_id,
+            message.contact_id,
+            &message.content,
+        ) {
+            self.events.publish(Event::SendFailed {
+                identity_id: message.identity_id,
+                contact_id: message.contact_id,
+                error: e.to_string(),
+            });
+            return Err(e);
+        }
+        self.conn
+            .delete_outbox_message(message.id)
+            .map_err(DriverError::UserData)
+    }
+
+    fn post_dummy<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+    ) -> DriverResult<T, ()> {
+        use DriverError::*;
+
+        let our_identity = self.conn.find_identity(our_identity_id).map_err(UserData)?;
+        let our_x3dh: X3DHClient = deserialize(&our_identity.x3dh_client).map_err(InvalidX3DH)?;
+        let payload = cover_traffic::create_dummy(rng, &our_x3dh.identity_key.public_key);
+        self.tezos
+            .post(&[&payload], &[])
+            .map(|_| ())
+            .map_err(TezosWrite)
+    }
+
+    /// Fetches and decrypts new messages from a contact.
+    /// Publishes `Event::MessageReceived` for each of them, or `Event::SyncFailed` on failure.
+    pub fn get_messages<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Vec<Content>> {
+        let result = self.receive_messages(rng, our_identity_id, their_contact_id);
+        if let Err(e) = &result {
+            self.events.publish(Event::SyncFailed {
+                identity_id: our_identity_id,
+                contact_id: their_contact_id,
+                error: e.to_string(),
+            });
+        }
+        result
+    }
+
+    // TODO: what if retrieving from Tezos succeeds but saving to SQLite fails?
+    fn receive_messages<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact_id: i32,
+    ) -> DriverResult<T, Vec<Content>> {
+        use DriverError::*;
+
+        let their_contact = self.find_contact(our_identity_id, their_contact_id)?;
+        // Messages before the sync cursor were received already.
+        let since = self
+            .find_client(our_identity_id, their_contact_id)?
+            .and_then(|client| client.latest_message_timestamp);
+
+        match self.retrieve_tezos_data(&their_contact.address, since)? {
+            Some(data) => {
+                // Messages are still received after a key change, but not sent.
+                self.check_identity_key(&their_contact, &data.identity_key)?;
+                self.receive_from_postal_box(rng, our_identity_id, &their_contact, &data.postal_box)
+            }
+            None => Err(NotFound),
+        }
+    }
+
+    /// Decrypts and saves the messages of a contact's postal box which come after the sync cursor.
+    fn receive_from_postal_box<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        their_contact: &Contact,
+        postal_box: &[mizu_tezos_interface::Message],
+    ) -> DriverResult<T, Vec<Content>> {
+        use DriverError::*;
+
+        let our_identity = self.conn.find_identity(our_identity_id).map_err(UserData)?;
+        let their_contact_id = their_contact.id;
+        let ClientAndCursor {
+            mut client,
+            mut latest_message_timestamp,
+            mut latest_message_cursor,
+        } = self.find_or_create_client(
+            our_identity_id,
+            their_contact_id,
+            &our_identity.x3dh_client,
+            &their_contact.address,
+        )?;
+
+        let mut postal_box: Vec<_> = postal_box.iter().collect();
+        postal_box.sort_by_key(|message| message.cursor());
+
+        let mut received = vec![];
+        // Payloads which could not be decoded before are older than new messages.
+        for undecoded in self
+            .conn
+            .list_undecoded_payloads(our_identity_id, their_contact_id)
+            .map_err(UserData)?
+        {
+            if let Ok((uid, content)) = envelope::decode(&undecoded.payload) {
+                self.receive_content(
+                    our_identity_id,
+                    their_contact_id,
+                    uid,
+                    content,
+                    undecoded.created_at,
+                    (undecoded.level, undecoded.entry_index),
+                    &mut received,
+                )?;
+                self.conn
+                    .delete_undecoded_payload(undecoded.id)
+                    .map_err(UserData)?;
+            }
+        }
+        for message in postal_box {
+            let timestamp = message.timestamp;
+            let cursor = message.cursor();
+            let processed = match (latest_message_cursor, latest_message_timestamp) {
+                (Some(latest_message_cursor), _) => cursor <= latest_message_cursor,
+                // Clients saved before cursors were introduced only know the timestamp.
+                (None, Some(latest_message_timestamp)) => timestamp <= latest_message_timestamp,
+                (None, None) => false,
+            };
+            if processed {
+                continue;
+            }
+            latest_message_cursor = Some(cursor);
+            latest_message_timestamp = Some(timestamp);
+
+            let message = deserialize(&message.content).map_err(InvalidMessage)?;
+            if let Ok(payload) = client.attempt_message_decryption(rng, message) {
+                match envelope::decode(&payload) {
+                    Ok((uid, content)) => self.receive_content(
+                        our_identity_id,
+                        their_contact_id,
+                        uid,
+                        content,
+                        timestamp,
+                        cursor,
+                        &mut received,
+                    )?,
+                    // The ratchet can't decrypt the message again, so the payload is kept until
+                    // it can be decoded, e.g. by a newer version of Mizu.
+                    Err(_) => self
+                        .conn
+                        .create_undecoded_payload(&NewUndecodedPayload {
+                            identity_id: our_identity_id,
+                            contact_id: their_contact_id,
+                            payload: &payload,
+                            created_at: timestamp,
+                            level: cursor.0,
+                            entry_index: cursor.1,
+                        })
+                        .map_err(UserData)?,
+                }
+            }
+        }
+
+        self.conn
+            .upsert_client(
+                our_identity_id,
+                their_contact_id,
+                &client,
+                latest_message_timestamp.as_ref(),
+                latest_message_cursor,
+            )
+            .map_err(UserData)?;
+
+        // Publish only after everything is saved, so subscribers can query the messages.
+        let mut messages = vec![];
+        for event in received {
+            if let Event::MessageReceived { content, .. } = &event {
+                messages.push(content.clone());
+            }
+            self.events.publish(event);
+        }
+
+        Ok(messages)
+    }
+
+    /// Saves content received from a contact, and adds the event to publish once everything is
+    /// saved to `received`. Content reusing the ID of another message is dropped, and reported
+    /// with `Event::SyncFailed`.
+    #[allow(clippy::too_many_arguments)]
+    fn receive_content(
+        &self,
+        our_identity_id: i32,
+        their_contact_id: i32,
+        uid: Option<MessageId>,
+        content: Content,
+        timestamp: NaiveDateTime,
+        cursor: (i64, i32),
+        received: &mut Vec<Event>,
+    ) -> DriverResult<T, ()> {
+        match self.apply_content(
+            our_identity_id,
+            their_contact_id,
+            false,
+            uid.as_ref(),
+            &content,
+            timestamp,
+            Some(cursor),
+        ) {
+            Ok(()) => received.push(Event::MessageReceived {
+                identity_id: our_identity_id,
+                contact_id: their_contact_id,
+                content,
+                timestamp,
+            }),
+            Err(e @ DriverError::DuplicateMessageId(_)) => received.push(Event::SyncFailed {
+                identity_id: our_identity_id,
+                contact_id: their_contact_id,
+                error: e.to_string(),
+            }),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Updates the delivery status of our messages whose operations are still pending, and
+    /// publishes `Event::DeliveryStatusChanged` for each change.
+    /// Operations whose status can't be read now are checked again on the next call.
+    pub fn track_deliveries(&self, our_identity_id: i32) -> DriverResult<T, ()> {
+        use DriverError::*;
+
+        for message in self
+            .conn
+            .list_pending_deliveries(our_identity_id)
+            .map_err(UserData)?
+        {
+            let operation = match (&message.operation_hash, message.operation_level) {
+                (Some(hash), Some(level)) => OperationHandle {
+                    hash: hash.clone(),
+                    level,
+                },
+                _ => continue,
+            };
+            let (status, included_level) = match self.tezos.operation_status(&operation) {
+                Ok(OperationStatus::Injected) => (DeliveryStatus::Injected, None),
+                Ok(OperationStatus::Included {
+                    level,
+                    confirmations,
+                }) if confirmations >= CONFIRMATIONS => (DeliveryStatus::Confirmed, Some(level)),
+                Ok(OperationStatus::Included { level, .. }) => {
+                    (DeliveryStatus::Included, Some(level))
+                }
+                Ok(OperationStatus::Failed) => (DeliveryStatus::Failed, None),
+                Ok(OperationStatus::Expired) => (DeliveryStatus::Expired, None),
+                Err(_) => continue,
+            };
+            if message.delivery_status() == Some(status) && message.included_level == included_level
+            {
+                continue;
+            }
+
+            self.conn
+                .update_delivery_status(message.id, status, included_level)
+                .map_err(UserData)?;
+            self.events.publish(Event::DeliveryStatusChanged {
+                identity_id: our_identity_id,
+                contact_id: message.contact_id,
+                message_id: message.id,
+                status,
+                included_level,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Fetches new messages from the postal boxes of all contacts, and tracks the delivery of
+    /// messages we sent (see `track_deliveries`).
+    /// A failure to sync with a contact is reported in the summary and does not stop the others.
+    /// A failure to track deliveries is published as `Event::TrackingFailed`.
+    pub fn sync_all<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+    ) -> DriverResult<T, SyncSummary<T>> {
+        let contacts = self.list_contacts(our_identity_id)?;
+
+        let summary = contacts
+            .into_iter()
+            .map(|contact| ContactSync {
+                contact_id: contact.id,
+                new_messages: self.get_messages(rng, our_identity_id, contact.id),
+            })
+            .collect();
+        if let Err(e) = self.track_deliveries(our_identity_id) {
+            self.events.publish(Event::TrackingFailed {
+                identity_id: our_identity_id,
+                error: e.to_string(),
+            });
+        }
+
+        Ok(summary)
+    }
+
+    /// Publishes `Event::ContactRequestReceived` for pokes not seen before, which are remembered
+    /// in the database across restarts.
+    pub fn get_pokes(&self) -> DriverResult<T, Vec<Vec<u8>>> {
+        use DriverError::*;
+
+        // Only the pokes are read, so none of the postal box is retrieved.
+        match self.retrieve_tezos_data(self.tezos.address(), Some(Utc::now().naive_utc()))? {
+            Some(data) => {
+                for poke in &data.pokes {
+                    if self
+                        .conn
+                        .record_seen_poke(self.tezos.address(), poke)
+                        .map_err(UserData)?
+                    {
+                        self.events
+                            .publish(Event::ContactRequestReceived { poke: poke.clone() });
+                    }
+                }
+                Ok(data.pokes)
+            }
+            None => Err(NotFound),
+        }
+    }
+
+    /// Applies contract calls seen in a block (see `mizu_tezos_rpc::monitor`) without reading
+    /// whole user data: messages posted by contacts are received, new pokes of our address are
+    /// published as in `get_pokes`, and identity keys registered by contacts are checked.
+    /// Returns the messages received from each contact who posted, like `sync_all`, along with
+    /// the failures to apply the registration of a contact, which don't stop the other updates.
+    pub fn apply_updates<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        updates: &[UserDataUpdate],
+    ) -> DriverResult<T, SyncSummary<T>> {
+        use DriverError::*;
+
+        let mut summary = vec![];
+        for update in updates {
+            let contacts: Vec<_> = self
+                .list_contacts(our_identity_id)?
+                .into_iter()
+                .filter(|contact| contact.address == update.address())
+                .collect();
+            match update {
+                UserDataUpdate::Posted { messages, .. } => {
+                    for contact in contacts {
+                        let new_messages =
+                            self.receive_from_postal_box(rng, our_identity_id, &contact, messages);
+                        if let Err(e) = &new_messages {
+                            self.events.publish(Event::SyncFailed {
+                                identity_id: our_identity_id,
+                                contact_id: contact.id,
+                                error: e.to_string(),
+                            });
+                        }
+                        summary.push(ContactSync {
+                            contact_id: contact.id,
+                            new_messages,
+                        });
+                    }
+                }
+                UserDataUpdate::Poked { address, data } => {
+                    if address == self.tezos.address()
+                        && self
+                            .conn
+                            .record_seen_poke(address, data)
+                            .map_err(UserData)?
+                    {
+                        self.events
+                            .publish(Event::ContactRequestReceived { poke: data.clone() });
+                    }
+                }
+                UserDataUpdate::Registered {
+                    identity_key: Some(identity_key),
+                    ..
+                } => {
+                    let identity_key: Option<[u8; 32]> = identity_key.as_slice().try_into().ok();
+                    for contact in contacts {
+                        let result = match identity_key {
+                            Some(identity_key) => self
+                                .check_identity_key(
+                                    &contact,
+                                    &IdentityPublicKey(identity_key.into()),
+                                )
+                                .map(|_| ()),
+                            None => Err(InvalidKeyLength),
+                        };
+                        if let Err(e) = result {
+                            self.events.publish(Event::SyncFailed {
+                                identity_id: our_identity_id,
+                                contact_id: contact.id,
+                                error: e.to_string(),
+                            });
+                            summary.push(ContactSync {
+                                contact_id: contact.id,
+                                new_messages: Err(e),
+                            });
+                        }
+                    }
+                }
+                UserDataUpdate::Registered {
+                    identity_key: None, ..
+                } => {}
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+impl Driver<TezosRpc> {
+    /// Syncs with all contacts (see `sync_all`), then follows new blocks and applies the contract
+    /// calls in them which concern us or our contacts (see `apply_updates`).
+    ///
+    /// `on_sync` is called with the level and the outcome of the first sync and of each block,
+    /// including failures to read blocks, which are retried, and following stops when it returns
+    /// `false`. Contacts whose updates fail to apply are synced in full (see `get_messages`)
+    /// before the next block, and their updates are held back until that succeeds, so that their
+    /// sync cursor doesn't move past the messages which failed. When a block isn't built on the
+    /// one before, the chain was reorganized and the updates applied from the blocks which were
+    /// dropped may be gone, so all contacts are synced in full again.
+    ///
+    /// Only the `follow` command of the CLI follows heads this way. The TUI, whose driver may be
+    /// backed by any `Tezos`, keeps polling with `sync_all`.
+    pub fn follow_heads<R, F>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        config: MonitorConfig,
+        mut on_sync: F,
+    ) -> DriverResult<TezosRpc, ()>
+    where
+        R: RngCore + CryptoRng,
+        F: FnMut(DriverResult<TezosRpc, (i64, SyncSummary<TezosRpc>)>) -> bool,
+    {
+        use DriverError::*;
+
+        // Blocks up to the current head are covered by the first sync.
+        let (head_level, mut last_hash) = self.tezos.head().map_err(TezosRead)?;
+        if !on_sync(
+            self.sync_all(rng, our_identity_id)
+                .map(|summary| (head_level, summary)),
+        ) {
+            return Ok(());
+        }
+
+        let mut monitor = self.tezos.monitor_heads(head_level, HashSet::new(), config);
+        // IDs of the contacts to sync in full before applying their updates again.
+        let mut stale = HashSet::new();
+        loop {
+            // Contacts added meanwhile are followed from the next block on.
+            let contacts = self.list_contacts(our_identity_id)?;
+            monitor.follow(self.tezos.address());
+            for contact in &contacts {
+                monitor.follow(&contact.address);
+            }
+
+            let result = match monitor.next() {
+                Some(Ok(block)) => {
+                    if block.predecessor != last_hash {
+                        stale.extend(contacts.iter().map(|contact| contact.id));
+                    }
+                    last_hash = block.hash.clone();
+                    let result =
+                        self.apply_block(rng, our_identity_id, &contacts, &mut stale, block);
+                    if result.is_err() {
+                        // We don't know how far the updates got.
+                        stale.extend(contacts.iter().map(|contact| contact.id));
+                    }
+                    result
+                }
+                Some(Err(e)) => Err(TezosRead(e)),
+                None => return Ok(()),
+            };
+            if !on_sync(result) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Syncs the `stale` contacts in full, and then applies the updates of `block` except those
+    /// of contacts still stale. Contacts whose updates fail become stale.
+    fn apply_block<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        our_identity_id: i32,
+        contacts: &[Contact],
+        stale: &mut HashSet<i32>,
+        block: BlockUpdates,
+    ) -> DriverResult<TezosRpc, (i64, SyncSummary<TezosRpc>)> {
+        let mut summary = vec![];
+        let mut held_back = HashSet::new();
+        for contact in contacts {
+            if !stale.contains(&contact.id) {
+                continue;
+            }
+            // Messages up to the head are received, so those of the block aren't received twice.
+            let new_messages = self.get_messages(rng, our_identity_id, contact.id);
+            if new_messages.is_ok() {
+                stale.remove(&contact.id);
+            } else {
+                held_back.insert(contact.address.as_str());
+            }
+            summary.push(ContactSync {
+                contact_id: contact.id,
+                new_messages,
+            });
+        }
+
+        let updates: Vec<_> = block
+            .updates
+            .into_iter()
+            .filter(|update| match update {
+                // Pokes are for our address rather than from a contact.
+                UserDataUpdate::Poked { .. } => true,
+                _ => !held_back.contains(update.address()),
+            })
+            .collect();
+        let applied = self.apply_updates(rng, our_identity_id, &updates)?;
+        stale.extend(
+            applied
+                .iter()
+                .filter(|sync| sync.new_messages.is_err())
+                .map(|sync| sync.contact_id),
+        );
+        summary.extend(applied);
+
+        if let Err(e) = self.track_deliveries(our_identity_id) {
+            self.events.publish(Event::TrackingFailed {
+                identity_id: our_identity_id,
+                error: e.to_string(),
+            });
+        }
+
+        Ok((block.level, summary))
+    }
+}
+
+/// Encrypts `secret_key` with `passphrase` and a random salt, for it to be recorded as the
+/// signer of an identity.
+pub fn encrypt_secret_key<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    secret_key: &str,
+    passphrase: &str,
+) -> Result<String, crypto::Error> {
+    let mut salt = [0; crypto::ENCRYPTION_SALT_LENGTH];
+    rng.fill_bytes(&mut salt);
+    crypto::encrypt_secret_key(secret_key, passphrase, salt)
+}
+
+pub fn create_tezos_rpc(
     faucet_output: crypto::FaucetOutput,
     contract_config: contract::ContractConfig,
 ) -> Result<TezosRpc, Box<dyn std::error::Error + Send + Sync + 'static>> {
     let host = contract_config.rpc_host.parse()?;
+    let signer = InMemorySigner::new(
+        faucet_output.pkh.clone(),
+        faucet_output.derive_secret_key()?,
+    );
     Ok(TezosRpc::new(
         contract_config.debug,
         host,
         faucet_output.pkh,
-        faucet_output.secret,
+        Box::new(signer),
         contract_config.contract_address,
-    ))
+    )
+    .with_activation_secret(faucet_output.secret))
 }
 
 pub fn create_rpc_driver(
@@ -400,11 +1610,54 @@ pub fn create_rpc_driver(
     let contract_config = contract::ContractConfig::load_from_file(contract_config)?;
     let tezos = create_tezos_rpc(faucet_output, contract_config)?;
 
-    let conn = Rc::new(MizuConnection::connect(db_path)?);
+    let conn = Arc::new(MizuConnection::connect(db_path)?);
 
     Ok(Driver::new(conn, tezos))
 }
 
+/// Originates the Mizu contract from the faucet account, and returns the configuration to use
+/// it with.
+///
+/// `script` is the compiled contract, either as Michelson (`.tz`) or as Micheline JSON.
+pub fn deploy_contract(
+    faucet_output: crypto::FaucetOutput,
+    script: &Path,
+    rpc_host: &str,
+    debug: bool,
+) -> Result<contract::ContractConfig, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let source = std::fs::read_to_string(script)?;
+    let code: Expr = if script.extension() == Some(OsStr::new("json")) {
+        serde_json::from_str(&source)?
+    } else {
+        michelson::parse_script(&source)?
+    };
+    // The storage is a big map from addresses to user data, which starts out empty.
+    let storage = BigMap::<Expr, Expr>::Literal(Vec::new()).to_michelson();
+
+    let tezos = create_tezos_rpc(
+        faucet_output,
+        contract::ContractConfig {
+            debug,
+            // There is no contract until we originate it.
+            contract_address: String::new(),
+            rpc_host: rpc_host.to_string(),
+        },
+    )?;
+    let operation = tezos.originate(code, storage)?;
+    let receipt = tezos.wait_for_operation(&operation, &WaitConfig::default())?;
+    let contract_address = receipt
+        .originated_contracts
+        .into_iter()
+        .next()
+        .ok_or("the origination didn't originate a contract")?;
+
+    Ok(contract::ContractConfig {
+        debug,
+        contract_address,
+        rpc_host: rpc_host.to_string(),
+    })
+}
+
 // ensure test related code is not included in the binary
 #[cfg(test)]
 mod test {
@@ -413,63 +1666,60 @@ mod test {
     use mizu_sqlite::MizuConnection;
     use mizu_tezos_mock::TezosMock;
     use rand::rngs::OsRng;
-    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
 
-    fn prepare_user_database() -> Rc<MizuConnection> {
-        // Create an in-memory SQLite database
-        let conn = SqliteConnection::establish(":memory:").unwrap();
+    const ALICE_SIGNER: &str = "http://localhost:6732/alice";
+    const BOB_SIGNER: &str = "http://localhost:6732/bob";
 
-        let mizu_connection = MizuConnection::new(conn);
-        mizu_connection.run_migrations();
+    fn prepare_user_database() -> Arc<MizuConnection> {
+        // Create an in-memory SQLite database
+        Arc::new(MizuConnection::connect(":memory:").unwrap())
+    }
 
-        Rc::new(mizu_connection)
+    fn prepare_mock_database() -> Arc<Mutex<SqliteConnection>> {
+        let mock_conn = SqliteConnection::establish(":memory:").unwrap();
+        mizu_tezos_mock::run_migrations(&mock_conn);
+        Arc::new(Mutex::new(mock_conn))
     }
 
-    fn wait() {
-        // wait a sec so that the next message will have distinct timestamp
-        // TODO: better handling
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    fn text(text: &str) -> Content {
+        Content::Text(text.to_string())
     }
 
     fn create_drivers() -> (Driver<TezosMock>, Driver<TezosMock>) {
         // use Tezos address
         let alice_address = "alice".to_string();
-        let alice_secret_key = "alice".to_string();
         let bob_address = "bob".to_string();
-        let bob_secret_key = "bob".to_string();
 
-        let mock_conn = Rc::new(SqliteConnection::establish(":memory:").unwrap());
-        mizu_tezos_mock::run_migrations(&*mock_conn);
+        let mock_conn = prepare_mock_database();
 
         let mut rng = OsRng;
 
         let alice = {
             let user_database = prepare_user_database();
-            let tezos_mock = TezosMock::new(
-                alice_address.clone(),
-                alice_secret_key,
-                Rc::clone(&mock_conn),
-            );
+            let tezos_mock = TezosMock::new(alice_address.clone(), Arc::clone(&mock_conn));
             Driver::new(user_database, tezos_mock)
         };
         let bob = {
             let user_database = prepare_user_database();
             // use Tezos address
-            let tezos_mock = TezosMock::new(bob_address.clone(), bob_secret_key, mock_conn);
+            let tezos_mock = TezosMock::new(bob_address.clone(), mock_conn);
             Driver::new(user_database, tezos_mock)
         };
 
         // first, each user generates identity and uploads to Tezos.
         alice
-            .generate_identity(&mut rng, "alice's identity")
+            .generate_identity(&mut rng, "alice's identity", ALICE_SIGNER)
             .unwrap();
         alice.publish_identity(1).unwrap();
-        bob.generate_identity(&mut rng, "bob's identity").unwrap();
+        bob.generate_identity(&mut rng, "bob's identity", BOB_SIGNER)
+            .unwrap();
         bob.publish_identity(1).unwrap();
 
         // next, each user adds each other to the contact list (poke is not implemented yet)
-        alice.add_contact("bob's address", &bob_address).unwrap();
-        bob.add_contact("alice's address", &alice_address).unwrap();
+        alice.add_contact(1, "bob's address", &bob_address).unwrap();
+        bob.add_contact(1, "alice's address", &alice_address)
+            .unwrap();
 
         (alice, bob)
     }
@@ -483,27 +1733,622 @@ mod test {
         alice
             .post_message(&mut rng, 1, 1, "Hello from alice!")
             .unwrap();
-        wait();
 
         alice
             .post_message(&mut rng, 1, 1, "waiting for response...")
             .unwrap();
-        wait();
 
         // bob receives the messages
         let messages = bob.get_messages(&mut rng, 1, 1).unwrap();
         assert_eq!(
             messages,
-            [b"Hello from alice!" as &[u8], b"waiting for response...",]
+            [text("Hello from alice!"), text("waiting for response...")]
         );
 
         // bob replies
         bob.post_message(&mut rng, 1, 1, "こんにちは").unwrap();
-        wait();
 
         // alice receives the reply
         let messages = alice.get_messages(&mut rng, 1, 1).unwrap();
-        assert_eq!(messages, ["こんにちは".as_bytes(),]);
+        assert_eq!(messages, [text("こんにちは")]);
+    }
+
+    #[test]
+    fn test_plain_secret_keys_are_not_recorded() {
+        let mut rng = OsRng;
+        let (alice, _bob) = create_drivers();
+        let secret_key = "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk";
+
+        assert!(matches!(
+            alice.generate_identity(&mut rng, "plain", secret_key),
+            Err(DriverError::PlainSecretKey)
+        ));
+
+        let identity = alice
+            .conn
+            .find_identity_by_name("alice's identity")
+            .unwrap();
+        assert!(matches!(
+            alice.set_identity_signer(identity.id, secret_key),
+            Err(DriverError::PlainSecretKey)
+        ));
+        let encrypted = encrypt_secret_key(&mut rng, secret_key, "passphrase").unwrap();
+        alice.set_identity_signer(identity.id, &encrypted).unwrap();
+        let identity = alice.conn.find_identity(identity.id).unwrap();
+        assert_eq!(
+            crypto::decrypt_secret_key(&identity.signer, "passphrase").unwrap(),
+            secret_key
+        );
+    }
+
+    #[test]
+    fn test_messages_are_received_exactly_once() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+
+        // These messages most likely share a timestamp.
+        alice.post_message(&mut rng, 1, 1, "one").unwrap();
+        alice.post_message(&mut rng, 1, 1, "two").unwrap();
+
+        let messages = bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert_eq!(messages, [text("one"), text("two")]);
+
+        alice.post_message(&mut rng, 1, 1, "three").unwrap();
+
+        let messages = bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert_eq!(messages, [text("three")]);
+        assert!(bob.get_messages(&mut rng, 1, 1).unwrap().is_empty());
+        assert_eq!(bob.list_messages(1, 1).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_undecoded_payloads_are_decoded_later() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+
+        // as if an envelope of a newer version had been received from alice
+        let payload = Envelope::new(&mut rng, text("from the future")).encode();
+        bob.conn
+            .create_undecoded_payload(&NewUndecodedPayload {
+                identity_id: 1,
+                contact_id: 1,
+                payload: &payload,
+                created_at: Utc::now().naive_utc(),
+                level: 10,
+                entry_index: 0,
+            })
+            .unwrap();
+
+        let messages = bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert_eq!(messages, [text("from the future")]);
+        assert!(bob.conn.list_undecoded_payloads(1, 1).unwrap().is_empty());
+        assert_eq!(bob.list_messages(1, 1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_message_ids_are_refused() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.post_message(&mut rng, 1, 1, "hi").unwrap();
+        alice.get_messages(&mut rng, 1, 1).unwrap();
+
+        // alice reuses the ID of a message of bob, which she has not received yet
+        let id = MessageId::generate(&mut rng);
+        let envelope = |text: &str| {
+            Envelope {
+                id,
+                content: Content::Text(text.to_string()),
+            }
+            .encode()
+        };
+        bob.send_message(&mut rng, 1, 1, &envelope("mine")).unwrap();
+        alice
+            .send_message(&mut rng, 1, 1, &envelope("not yours"))
+            .unwrap();
+        alice.post_message(&mut rng, 1, 1, "bye").unwrap();
+
+        let events = bob.subscribe();
+        assert_eq!(bob.get_messages(&mut rng, 1, 1).unwrap(), [text("bye")]);
+        assert!(matches!(
+            &events.try_iter().collect::<Vec<_>>()[..],
+            [
+                Event::SyncFailed { contact_id: 1, .. },
+                Event::MessageReceived { .. }
+            ]
+        ));
+        let message = bob.conn.find_message_by_uid(1, 1, &id.0).unwrap().unwrap();
+        assert!(message.my_message);
+        assert_eq!(message.content, b"mine");
+    }
+
+    #[test]
+    fn test_sync_all() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        bob.add_contact(1, "carol", "carol").unwrap();
+
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+
+        let summary = bob.sync_all(&mut rng, 1).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].contact_id, 1);
+        assert_eq!(summary[0].new_messages.as_ref().unwrap(), &[text("hello")]);
+        // carol has not published her identity.
+        assert_eq!(summary[1].contact_id, 2);
+        assert!(matches!(
+            summary[1].new_messages,
+            Err(DriverError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_updates() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        let bob_events = bob.subscribe();
+
+        alice.post_message(&mut rng, 1, 1, "one").unwrap();
+        alice.post_message(&mut rng, 1, 1, "two").unwrap();
+        // as a monitor would report them if they were included in separate blocks
+        let updates: Vec<_> = alice
+            .tezos
+            .retrieve_user_data("alice")
+            .unwrap()
+            .unwrap()
+            .postal_box
+            .into_iter()
+            .map(|message| UserDataUpdate::Posted {
+                address: "alice".to_string(),
+                messages: vec![message],
+                removed: vec![],
+            })
+            .collect();
+
+        let summary = bob.apply_updates(&mut rng, 1, &updates).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].new_messages.as_ref().unwrap(), &[text("one")]);
+        assert_eq!(summary[1].new_messages.as_ref().unwrap(), &[text("two")]);
+        // messages applied once are neither applied again nor fetched by a full sync
+        let summary = bob.apply_updates(&mut rng, 1, &updates[1..]).unwrap();
+        assert!(summary[0].new_messages.as_ref().unwrap().is_empty());
+        assert!(bob.get_messages(&mut rng, 1, 1).unwrap().is_empty());
+
+        let poke = UserDataUpdate::Poked {
+            address: "bob".to_string(),
+            data: b"hello".to_vec(),
+        };
+        let registration = UserDataUpdate::Registered {
+            address: "alice".to_string(),
+            identity_key: Some(vec![1; 32]),
+            prekey: vec![2; 32],
+        };
+        bob.apply_updates(&mut rng, 1, &[poke.clone(), poke, registration])
+            .unwrap();
+
+        assert!(matches!(
+            bob_events.try_iter().collect::<Vec<_>>().as_slice(),
+            [
+                Event::MessageReceived { .. },
+                Event::MessageReceived { .. },
+                Event::ContactRequestReceived { poke },
+                Event::KeyChanged { contact_id: 1, .. },
+            ] if poke == b"hello"
+        ));
+    }
+
+    #[test]
+    fn test_pokes_are_reported_once_across_restarts() {
+        let (alice, bob) = create_drivers();
+        alice.tezos.poke("bob", b"hello").unwrap();
+        let bob_events = bob.subscribe();
+        bob.get_pokes().unwrap();
+        bob.get_pokes().unwrap();
+
+        // bob starts again with the same database
+        let bob = Driver::new(Arc::clone(&bob.conn), bob.tezos);
+        let restarted_events = bob.subscribe();
+        assert_eq!(bob.get_pokes().unwrap(), [b"hello".to_vec()]);
+
+        assert_eq!(
+            bob_events.try_iter().collect::<Vec<_>>(),
+            [Event::ContactRequestReceived {
+                poke: b"hello".to_vec()
+            }]
+        );
+        assert!(restarted_events.try_iter().next().is_none());
+    }
+
+    #[test]
+    fn test_failed_updates_are_reported_per_contact() {
+        let mut rng = OsRng;
+        let (_alice, bob) = create_drivers();
+        let bob_events = bob.subscribe();
+
+        let registration = UserDataUpdate::Registered {
+            address: "alice".to_string(),
+            identity_key: Some(vec![1; 31]),
+            prekey: vec![2; 32],
+        };
+        let poke = UserDataUpdate::Poked {
+            address: "bob".to_string(),
+            data: b"hello".to_vec(),
+        };
+        // The invalid registration doesn't stop the updates after it.
+        let summary = bob
+            .apply_updates(&mut rng, 1, &[registration, poke])
+            .unwrap();
+        assert!(matches!(
+            summary.as_slice(),
+            [ContactSync {
+                contact_id: 1,
+                new_messages: Err(DriverError::InvalidKeyLength),
+            }]
+        ));
+        assert!(matches!(
+            bob_events.try_iter().collect::<Vec<_>>().as_slice(),
+            [
+                Event::SyncFailed { contact_id: 1, .. },
+                Event::ContactRequestReceived { poke },
+            ] if poke == b"hello"
+        ));
+    }
+
+    #[test]
+    fn test_events() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        bob.add_contact(1, "carol", "carol").unwrap();
+        let alice_events = alice.subscribe();
+        let bob_events = bob.subscribe();
+
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.sync_all(&mut rng, 1).unwrap();
+
+        assert!(matches!(
+            alice_events.try_iter().collect::<Vec<_>>().as_slice(),
+            [Event::MessageSent { identity_id: 1, contact_id: 1, content }] if content == &text("hello")
+        ));
+        assert!(matches!(
+            bob_events.try_iter().collect::<Vec<_>>().as_slice(),
+            [
+                Event::MessageReceived { identity_id: 1, contact_id: 1, content, .. },
+                Event::SyncFailed { identity_id: 1, contact_id: 2, .. },
+            ] if content == &text("hello")
+        ));
+
+        // alice publishes a new identity key
+        alice.tezos.register(Some(&[1; 32]), &[2; 32]).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+
+        assert!(matches!(
+            bob_events.try_iter().collect::<Vec<_>>().as_slice(),
+            [Event::KeyChanged { contact_id: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_cover_traffic() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        // every slot is due immediately
+        alice.enable_cover_traffic(1, 0, 3).unwrap();
+
+        alice.post_message(&mut rng, 1, 1, "held").unwrap();
+        assert_eq!(alice.list_held_messages(1).unwrap().len(), 1);
+        assert!(bob.get_messages(&mut rng, 1, 1).unwrap().is_empty());
+
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::Real);
+        assert!(alice.list_held_messages(1).unwrap().is_empty());
+        assert_eq!(bob.get_messages(&mut rng, 1, 1).unwrap(), [text("held")]);
+
+        // dummies are discarded by the recipient
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::Dummy);
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::Dummy);
+        assert!(bob.get_messages(&mut rng, 1, 1).unwrap().is_empty());
+        assert_eq!(bob.list_messages(1, 1).unwrap().len(), 1);
+
+        // the budget is spent, but real messages are still sent
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::OverBudget);
+        alice.post_message(&mut rng, 1, 1, "after dummies").unwrap();
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::Real);
+        assert_eq!(
+            bob.get_messages(&mut rng, 1, 1).unwrap(),
+            [text("after dummies")]
+        );
+
+        // held messages are sent once cover traffic is disabled
+        alice.post_message(&mut rng, 1, 1, "disabled").unwrap();
+        alice.disable_cover_traffic(1).unwrap();
+        assert_eq!(alice.run_cover_slot(&mut rng, 1).unwrap(), Slot::Disabled);
+        assert_eq!(
+            bob.get_messages(&mut rng, 1, 1).unwrap(),
+            [text("disabled")]
+        );
+        assert_eq!(alice.list_messages(1, 1).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_cover_traffic_skips_blocked_contacts() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        bob.enable_cover_traffic(1, 0, 10).unwrap();
+        bob.post_message(&mut rng, 1, 1, "held").unwrap();
+
+        // alice's key changes while bob's message is held
+        alice.tezos.register(Some(&[1; 32]), &[2; 32]).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+
+        // the held message waits for the key change, and the slots go on with dummies
+        assert_eq!(bob.run_cover_slot(&mut rng, 1).unwrap(), Slot::Dummy);
+        assert_eq!(bob.run_cover_slot(&mut rng, 1).unwrap(), Slot::Dummy);
+        assert_eq!(bob.list_held_messages(1).unwrap().len(), 1);
+
+        // a message which fails to be sent doesn't keep the slot from passing
+        bob.add_contact(1, "carol", "carol").unwrap();
+        bob.conn
+            .create_outbox_message(1, 2, b"to carol", Utc::now().naive_utc())
+            .unwrap();
+        let events = bob.subscribe();
+        assert_eq!(bob.run_cover_slot(&mut rng, 1).unwrap(), Slot::Dummy);
+        assert!(matches!(
+            events.try_iter().collect::<Vec<_>>()[..],
+            [Event::SendFailed { contact_id: 2, .. }]
+        ));
+
+        // with cover traffic disabled, the failure doesn't hold back the others
+        bob.disable_cover_traffic(1).unwrap();
+        bob.accept_identity_key(1, 1).unwrap();
+        assert_eq!(bob.run_cover_slot(&mut rng, 1).unwrap(), Slot::Disabled);
+        let held = bob.list_held_messages(1).unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].contact_id, 2);
+    }
+
+    #[test]
+    fn test_contacts_are_per_identity() {
+        let mut rng = OsRng;
+        let (alice, _bob) = create_drivers();
+
+        // alice has another identity in the same database.
+        let alice2 = Driver::new(
+            Arc::clone(&alice.conn),
+            TezosMock::new("alice2".into(), prepare_mock_database()),
+        );
+        alice2
+            .generate_identity(&mut rng, "alice's second identity", ALICE_SIGNER)
+            .unwrap();
+        alice2.add_contact(2, "carol", "carol").unwrap();
+
+        let names = |contacts: Vec<Contact>| -> Vec<String> {
+            contacts.into_iter().map(|contact| contact.name).collect()
+        };
+        assert_eq!(names(alice.list_contacts(1).unwrap()), ["bob's address"]);
+        assert_eq!(names(alice.list_contacts(2).unwrap()), ["carol"]);
+        assert!(alice.find_contact_by_address(2, "bob").is_err());
+
+        // contacts of another identity can't be used
+        assert!(matches!(
+            alice.get_messages(&mut rng, 2, 1),
+            Err(DriverError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_key_pinning() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+
+        // bob pins alice's identity key on first use
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert!(bob.list_key_changes(1, 1).unwrap().is_empty());
+
+        // alice's identity key is replaced on Tezos
+        alice.tezos.register(Some(&[1; 32]), &[2; 32]).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        let key_changes = bob.list_key_changes(1, 1).unwrap();
+        assert_eq!(key_changes.len(), 1);
+        assert_eq!(key_changes[0].new_identity_key, [1; 32]);
+        assert!(key_changes[0].accepted_at.is_none());
+
+        // sending is blocked until bob accepts the new key
+        assert!(matches!(
+            bob.post_message(&mut rng, 1, 1, "is this you?"),
+            Err(DriverError::KeyChanged(1))
+        ));
+        bob.accept_identity_key(1, 1).unwrap();
+        assert!(bob.list_key_changes(1, 1).unwrap()[0].accepted_at.is_some());
+        bob.post_message(&mut rng, 1, 1, "is this you?").unwrap();
+
+        assert!(matches!(
+            bob.accept_identity_key(1, 1),
+            Err(DriverError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_accepted_keys_start_a_new_session() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+
+        // alice starts over with a new identity on the same address
+        let new_alice = Driver::new(prepare_user_database(), alice.tezos);
+        new_alice
+            .generate_identity(&mut rng, "alice's new identity", ALICE_SIGNER)
+            .unwrap();
+        new_alice.publish_identity(1).unwrap();
+        new_alice.add_contact(1, "bob", "bob").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        bob.accept_identity_key(1, 1).unwrap();
+
+        // the old session would be unreadable for her new identity
+        bob.post_message(&mut rng, 1, 1, "is this you?").unwrap();
+        assert_eq!(
+            new_alice.get_messages(&mut rng, 1, 1).unwrap(),
+            [text("is this you?")]
+        );
+        // and bob doesn't receive alice's old messages again
+        assert_eq!(bob.list_messages(1, 1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_key_change_is_resolved_by_going_back() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+
+        alice.tezos.register(Some(&[1; 32]), &[2; 32]).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert!(bob.post_message(&mut rng, 1, 1, "is this you?").is_err());
+
+        // alice publishes her pinned key again before bob accepts the new one
+        let events = bob.subscribe();
+        alice.publish_identity(1).unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [Event::KeyRestored { contact_id: 1 }]
+        );
+
+        let key_changes = bob.list_key_changes(1, 1).unwrap();
+        assert_eq!(key_changes.len(), 2);
+        assert_eq!(key_changes[1].old_identity_key, [1; 32]);
+        assert_eq!(
+            key_changes[1].new_identity_key,
+            key_changes[0].old_identity_key
+        );
+        assert!(key_changes
+            .iter()
+            .all(|change| change.accepted_at.is_some()));
+        bob.post_message(&mut rng, 1, 1, "welcome back").unwrap();
+        assert!(matches!(
+            bob.accept_identity_key(1, 1),
+            Err(DriverError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_replies_edits_reactions_and_deletes() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        let latest =
+            |driver: &Driver<TezosMock>| driver.list_messages(1, 1).unwrap().pop().unwrap();
+
+        alice.post_message(&mut rng, 1, 1, "helo").unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        let (alice_hello, bob_hello) = (latest(&alice), latest(&bob));
+        assert!(alice_hello.uid.is_some());
+        assert_eq!(alice_hello.uid, bob_hello.uid);
+
+        // bob replies and reacts, then sends a read receipt
+        bob.reply_to_message(&mut rng, 1, 1, bob_hello.id, "hi")
+            .unwrap();
+        bob.react_to_message(&mut rng, 1, 1, bob_hello.id, "👍")
+            .unwrap();
+        bob.send_read_receipt(&mut rng, 1, 1).unwrap();
+        assert!(bob.send_read_receipt(&mut rng, 1, 1).unwrap().is_empty());
+        alice.get_messages(&mut rng, 1, 1).unwrap();
+        let reply = alice
+            .list_messages(1, 1)
+            .unwrap()
+            .into_iter()
+            .find(|message| !message.my_message)
+            .unwrap();
+        assert_eq!(reply.content, b"hi");
+        assert_eq!(reply.reply_to, alice_hello.uid);
+        let reactions = alice.list_reactions(1, 1).unwrap();
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[0].message_id, alice_hello.id);
+        assert!(!reactions[0].my_reaction);
+        assert_eq!(reactions[0].reaction, "👍");
+        assert!(alice
+            .conn
+            .find_message(alice_hello.id)
+            .unwrap()
+            .read_at
+            .is_some());
+
+        // only the sender can edit or delete a message
+        assert!(matches!(
+            bob.edit_message(&mut rng, 1, 1, bob_hello.id, "hello"),
+            Err(DriverError::InvalidTarget(_))
+        ));
+        alice
+            .edit_message(&mut rng, 1, 1, alice_hello.id, "hello")
+            .unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        let bob_hello = &bob.list_messages(1, 1).unwrap()[0];
+        assert_eq!(bob_hello.content, b"hello");
+        assert!(bob_hello.edited_at.is_some());
+
+        alice
+            .delete_message(&mut rng, 1, 1, alice_hello.id)
+            .unwrap();
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        let bob_messages = bob.list_messages(1, 1).unwrap();
+        assert_eq!(bob_messages.len(), 1);
+        assert_eq!(bob_messages[0].content, b"hi");
+        assert!(bob.list_reactions(1, 1).unwrap().is_empty());
+        assert_eq!(alice.list_messages(1, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_delivery_status() {
+        let mut rng = OsRng;
+        let (alice, bob) = create_drivers();
+        let status = |driver: &Driver<TezosMock>| {
+            let message = &driver.list_messages(1, 1).unwrap()[0];
+            (message.delivery_status(), message.included_level)
+        };
+
+        alice.post_message(&mut rng, 1, 1, "hello").unwrap();
+        assert_eq!(status(&alice), (Some(DeliveryStatus::Injected), None));
+        bob.get_messages(&mut rng, 1, 1).unwrap();
+        assert_eq!(status(&bob), (None, None));
+
+        // the mock includes operations right away
+        let events = alice.subscribe();
+        alice.track_deliveries(1).unwrap();
+        let (delivery_status, included_level) = status(&alice);
+        assert_eq!(delivery_status, Some(DeliveryStatus::Included));
+        assert!(matches!(
+            events.try_iter().collect::<Vec<_>>().as_slice(),
+            [Event::DeliveryStatusChanged {
+                status: DeliveryStatus::Included,
+                ..
+            }]
+        ));
+        alice.track_deliveries(1).unwrap();
+        assert!(events.try_iter().next().is_none());
+
+        // each write of the mock is a new block
+        for _ in 0..CONFIRMATIONS {
+            bob.publish_identity(1).unwrap();
+        }
+        alice.sync_all(&mut rng, 1).unwrap();
+        assert_eq!(
+            status(&alice),
+            (Some(DeliveryStatus::Confirmed), included_level)
+        );
+        assert!(alice.conn.list_pending_deliveries(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn drivers_are_thread_safe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Driver<TezosMock>>();
+        assert_send_sync::<Driver<TezosRpc>>();
+        assert_send_sync::<Driver<BoxedTezos<'static>>>();
     }
 
     #[test]
@@ -513,24 +2358,19 @@ mod test {
         let (alice, bob) = create_drivers();
 
         alice.post_message(&mut rng, 1, 1, "hello").unwrap();
-        wait();
 
         // Receiving X3DH might fix?
         // bob.get_messages(&mut rng, 1, 1).unwrap();
 
         // this will post X3DH to alice
         bob.post_message(&mut rng, 1, 1, "こんにちは").unwrap();
-        wait();
         bob.post_message(&mut rng, 1, 1, "上善水如").unwrap();
-        wait();
 
         // I guess this `get_messages` receives X3DH from bob and leads to inconsistent client.
         alice.get_messages(&mut rng, 1, 1).unwrap();
 
         alice.post_message(&mut rng, 1, 1, "hey").unwrap();
-        wait();
         alice.post_message(&mut rng, 1, 1, "赤月ゆに").unwrap();
-        wait();
 
         alice.get_messages(&mut rng, 1, 1).unwrap();
         bob.get_messages(&mut rng, 1, 1).unwrap();
@@ -2,13 +2,20 @@
 //! TODO: consider error conditions of encryption
 
 use diesel::prelude::*;
+use mizu_driver::poller::{Poller, PollerConfig};
 use mizu_driver::*;
 use mizu_sqlite::MizuConnection;
 use mizu_tezos_interface::Tezos;
 use mizu_tezos_mock::TezosMock;
+use mizu_tezos_rpc::crypto;
+use mizu_tezos_rpc::monitor::MonitorConfig;
+use mizu_tezos_rpc::signer;
+use mizu_tezos_rpc::TezosRpc;
 use rand::rngs::OsRng;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use structopt::StructOpt;
 
 fn uncons(input: &str) -> Option<(&str, &str)> {
@@ -70,8 +77,10 @@ fn list<T: Tezos>(driver: &Driver<T>) -> Command<T> {
         ),
         (
             "contact",
-            Box::new(move |_input: &str| {
-                for contact in driver.list_contacts()? {
+            Box::new(move |input: &str| {
+                let (our_identity_id, _input) =
+                    uncons_parse::<T, _>(input, "failed to parse identity id")?;
+                for contact in driver.list_contacts(our_identity_id)? {
                     println!("{}\t{}\t{}", contact.id, contact.name, contact.created_at);
                 }
 
@@ -87,12 +96,13 @@ fn list<T: Tezos>(driver: &Driver<T>) -> Command<T> {
                     uncons_parse::<T, _>(input, "failed to parse contact id")?;
                 for message in driver.list_messages(our_identity_id, their_contact_id)? {
                     println!(
-                        "{}\t{}\t{}\t{}\t{}",
+                        "{}\t{}\t{}\t{}\t{}\t{}",
                         message.id,
                         message.identity_id,
                         message.contact_id,
                         String::from_utf8_lossy(&message.content),
-                        message.created_at
+                        message.created_at,
+                        message.delivery_status.as_deref().unwrap_or("-"),
                     );
                 }
 
@@ -102,7 +112,7 @@ fn list<T: Tezos>(driver: &Driver<T>) -> Command<T> {
     ])
 }
 
-fn generate<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+fn generate<'a, T: Tezos>(driver: &'a Driver<T>, signer: &'a str) -> Command<'a, T> {
     use DriverError::*;
 
     subcommands::<T>(vec![(
@@ -111,7 +121,7 @@ fn generate<T: Tezos>(driver: &Driver<T>) -> Command<T> {
             let mut rng = OsRng;
 
             let (name, _) = uncons(input).ok_or_else(|| NotFound)?;
-            driver.generate_identity(&mut rng, name)?;
+            driver.generate_identity(&mut rng, name, signer)?;
             println!("generated X3DHClient as {}", name);
 
             Ok(())
@@ -138,9 +148,11 @@ fn add<T: Tezos>(driver: &Driver<T>) -> Command<T> {
     subcommands::<T>(vec![(
         "contact",
         Box::new(move |input: &str| {
+            let (our_identity_id, input) =
+                uncons_parse::<T, _>(input, "failed to parse identity id")?;
             let (name, rest) = uncons(input).ok_or(NotFound)?;
             let (address, _rest) = uncons(rest).ok_or(NotFound)?;
-            driver.add_contact(name, address)
+            driver.add_contact(our_identity_id, name, address)
         }) as Command<T>,
     )])
 }
@@ -176,6 +188,91 @@ fn post_message<T: Tezos>(driver: &Driver<T>) -> Command<T> {
     })
 }
 
+fn reply<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    use DriverError::*;
+
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        let (message_id, input) = uncons_parse::<T, _>(input, "failed to parse message id")?;
+        let (message, _input) = uncons(input).ok_or(NotFound)?;
+
+        driver.reply_to_message(
+            &mut OsRng,
+            our_identity_id,
+            their_contact_id,
+            message_id,
+            message,
+        )?;
+
+        Ok(())
+    })
+}
+
+fn edit<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    use DriverError::*;
+
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        let (message_id, input) = uncons_parse::<T, _>(input, "failed to parse message id")?;
+        let (message, _input) = uncons(input).ok_or(NotFound)?;
+
+        driver.edit_message(
+            &mut OsRng,
+            our_identity_id,
+            their_contact_id,
+            message_id,
+            message,
+        )?;
+
+        Ok(())
+    })
+}
+
+fn delete<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        let (message_id, _input) = uncons_parse::<T, _>(input, "failed to parse message id")?;
+
+        driver.delete_message(&mut OsRng, our_identity_id, their_contact_id, message_id)?;
+
+        Ok(())
+    })
+}
+
+fn react<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        let (message_id, input) = uncons_parse::<T, _>(input, "failed to parse message id")?;
+        // no reaction removes ours
+        let reaction = uncons(input).map_or("", |(reaction, _)| reaction);
+
+        driver.react_to_message(
+            &mut OsRng,
+            our_identity_id,
+            their_contact_id,
+            message_id,
+            reaction,
+        )?;
+
+        Ok(())
+    })
+}
+
+fn read<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, _input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+
+        driver.send_read_receipt(&mut OsRng, our_identity_id, their_contact_id)?;
+
+        Ok(())
+    })
+}
+
 fn get_messages<T: Tezos>(driver: &Driver<T>) -> Command<T> {
     Box::new(move |input: &str| {
         let mut rng = OsRng;
@@ -184,23 +281,207 @@ fn get_messages<T: Tezos>(driver: &Driver<T>) -> Command<T> {
         let (their_contact_id, _input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
 
         for message in driver.get_messages(&mut rng, our_identity_id, their_contact_id)? {
-            println!("message: {}", String::from_utf8_lossy(&message));
+            println!("message: {}", message);
+        }
+
+        Ok(())
+    })
+}
+
+fn print_sync_summary<T: Tezos>(summary: SyncSummary<T>) {
+    for contact_sync in summary {
+        match contact_sync.new_messages {
+            Ok(messages) => {
+                for message in messages {
+                    println!("{}\tmessage: {}", contact_sync.contact_id, message);
+                }
+            }
+            Err(e) => eprintln!("{}\tfailed to sync: {:?}", contact_sync.contact_id, e),
+        }
+    }
+}
+
+fn sync<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let mut rng = OsRng;
+
+        let (our_identity_id, _input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        print_sync_summary::<T>(driver.sync_all(&mut rng, our_identity_id)?);
+
+        Ok(())
+    })
+}
+
+fn watch<T: Tezos>(driver: &Driver<T>, poller_config: PollerConfig) -> Command<T> {
+    Box::new(move |input: &str| {
+        let mut rng = OsRng;
+
+        let (our_identity_id, _input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        eprintln!("watching for new messages (press Ctrl-C to quit)");
+
+        let (tx, rx) = channel();
+        let _poller = Poller::spawn(poller_config, move || tx.send(()).is_ok());
+        print_sync_summary::<T>(driver.sync_all(&mut rng, our_identity_id)?);
+        for () in rx {
+            print_sync_summary::<T>(driver.sync_all(&mut rng, our_identity_id)?);
+            // Held messages and dummies are posted at the first tick after each slot.
+            driver.run_cover_slot(&mut rng, our_identity_id)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Like `watch`, but follows new blocks instead of downloading whole postal boxes on each tick.
+fn follow(driver: &Driver<TezosRpc>) -> Command<TezosRpc> {
+    Box::new(move |input: &str| {
+        let mut rng = OsRng;
+
+        let (our_identity_id, _input) =
+            uncons_parse::<TezosRpc, _>(input, "failed to parse identity id")?;
+        eprintln!("following new blocks (press Ctrl-C to quit)");
+
+        driver.follow_heads(
+            &mut rng,
+            our_identity_id,
+            MonitorConfig::default(),
+            |result| {
+                match result {
+                    Ok((_level, summary)) => print_sync_summary::<TezosRpc>(summary),
+                    Err(e) => eprintln!("failed to follow: {}", e),
+                }
+                true
+            },
+        )
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn keys<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, _input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        for key_change in driver.list_key_changes(our_identity_id, their_contact_id)? {
+            println!(
+                "{}\t{} -> {}\t{}",
+                key_change.detected_at,
+                hex(&key_change.old_identity_key),
+                hex(&key_change.new_identity_key),
+                match key_change.accepted_at {
+                    Some(accepted_at) => format!("accepted at {}", accepted_at),
+                    None => "pending".to_string(),
+                }
+            );
         }
 
         Ok(())
     })
 }
 
-fn commands<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+fn accept<T: Tezos>(driver: &Driver<T>) -> Command<T> {
+    Box::new(move |input: &str| {
+        let (our_identity_id, input) = uncons_parse::<T, _>(input, "failed to parse identity id")?;
+        let (their_contact_id, _input) = uncons_parse::<T, _>(input, "failed to parse contact id")?;
+        driver.accept_identity_key(our_identity_id, their_contact_id)?;
+        println!("accepted the new identity key of {}", their_contact_id);
+
+        Ok(())
+    })
+}
+
+fn cover<T: Tezos>(driver: &Driver<T>) -> Command<T> {
     subcommands::<T>(vec![
+        (
+            "on",
+            Box::new(move |input: &str| {
+                let (our_identity_id, input) =
+                    uncons_parse::<T, _>(input, "failed to parse identity id")?;
+                let (interval_secs, input) =
+                    uncons_parse::<T, _>(input, "failed to parse interval")?;
+                let (daily_budget, _input) =
+                    uncons_parse::<T, _>(input, "failed to parse daily budget")?;
+                driver.enable_cover_traffic(our_identity_id, interval_secs, daily_budget)?;
+                println!("enabled cover traffic for {}", our_identity_id);
+
+                Ok(())
+            }) as Command<T>,
+        ),
+        (
+            "off",
+            Box::new(move |input: &str| {
+                let (our_identity_id, _input) =
+                    uncons_parse::<T, _>(input, "failed to parse identity id")?;
+                driver.disable_cover_traffic(our_identity_id)?;
+                println!("disabled cover traffic for {}", our_identity_id);
+
+                Ok(())
+            }),
+        ),
+        (
+            "slot",
+            Box::new(move |input: &str| {
+                let mut rng = OsRng;
+
+                let (our_identity_id, _input) =
+                    uncons_parse::<T, _>(input, "failed to parse identity id")?;
+                println!("{:?}", driver.run_cover_slot(&mut rng, our_identity_id)?);
+
+                Ok(())
+            }),
+        ),
+    ])
+}
+
+/// `extra` adds commands specific to `T`.
+fn commands<'a, T: Tezos>(
+    driver: &'a Driver<T>,
+    signer: &'a str,
+    poller_config: PollerConfig,
+    extra: Vec<(&'a str, Command<'a, T>)>,
+) -> Command<'a, T> {
+    let mut commands = vec![
         ("list", list(driver)),
-        ("generate", generate(driver)),
+        ("generate", generate(driver, signer)),
         ("publish", publish(driver)),
         ("add", add(driver)),
         ("exist", exist_user(driver)),
         ("post", post_message(driver)),
         ("get", get_messages(driver)),
-    ])
+        ("reply", reply(driver)),
+        ("edit", edit(driver)),
+        ("delete", delete(driver)),
+        ("react", react(driver)),
+        ("read", read(driver)),
+        ("sync", sync(driver)),
+        ("watch", watch(driver, poller_config)),
+        ("cover", cover(driver)),
+        ("keys", keys(driver)),
+        ("accept", accept(driver)),
+    ];
+    commands.extend(extra);
+    subcommands::<T>(commands)
+}
+
+#[derive(StructOpt, Debug)]
+struct PollOpt {
+    /// Seconds between syncs in the watch command
+    #[structopt(long, default_value = "30")]
+    poll_interval: u64,
+    /// Maximum random delay in seconds added to each poll interval
+    #[structopt(long, default_value = "10")]
+    poll_jitter: u64,
+}
+
+impl PollOpt {
+    fn poller_config(&self) -> PollerConfig {
+        PollerConfig::new(
+            Duration::from_secs(self.poll_interval),
+            Duration::from_secs(self.poll_jitter),
+        )
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -209,6 +490,8 @@ struct MockOpt {
     secret_key: Option<String>,
     db_path: Option<String>,
     mock_db_path: Option<String>,
+    #[structopt(flatten)]
+    poll: PollOpt,
 }
 
 #[derive(StructOpt, Debug)]
@@ -216,16 +499,95 @@ struct RpcOpt {
     faucet_output: PathBuf,
     config: PathBuf,
     db_path: Option<String>,
+    #[structopt(flatten)]
+    poll: PollOpt,
+}
+
+#[derive(StructOpt, Debug)]
+struct StorageOpt {
+    config: PathBuf,
+    /// Address whose entry in the contract storage is printed as Michelson
+    address: String,
+    /// Node to read from instead of the one in the config file
+    #[structopt(long)]
+    rpc_host: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct DeployOpt {
+    faucet_output: PathBuf,
+    /// The compiled contract, as Michelson or as Micheline JSON if it ends with .json
+    script: PathBuf,
+    /// Where to write the configuration for the new contract
+    config: PathBuf,
+    #[structopt(long, default_value = "https://carthagenet.smartpy.io")]
+    rpc_host: String,
+    #[structopt(long)]
+    debug: bool,
 }
 
 #[derive(StructOpt, Debug)]
 enum Opt {
     Mock(MockOpt),
     Rpc(RpcOpt),
+    Storage(StorageOpt),
+    Deploy(DeployOpt),
+}
+
+/// Reads the passphrase to encrypt secret keys with from `MIZU_PASSPHRASE`, or else from the
+/// terminal.
+fn read_passphrase() -> String {
+    std::env::var("MIZU_PASSPHRASE").unwrap_or_else(|_| {
+        rustyline::Editor::<()>::new()
+            .readline("passphrase to encrypt secret keys with: ")
+            .expect("passphrase not given")
+    })
+}
+
+/// Returns what to record as the signer of generated identities for `secret_key`, which is
+/// encrypted if it is a plain secret key. Plain secret keys recorded by older versions are
+/// encrypted as well. Anything else, such as the remote signer URIs or the arbitrary keys of mock
+/// addresses, is recorded as is without asking for a passphrase.
+fn protect_signers<T: Tezos>(driver: &Driver<T>, secret_key: &str) -> String {
+    let mut passphrase = None;
+    let mut encrypt = |secret_key: &str| {
+        let passphrase = passphrase.get_or_insert_with(read_passphrase);
+        encrypt_secret_key(&mut OsRng, secret_key, passphrase)
+    };
+
+    for identity in driver
+        .list_identities()
+        .expect("identities should be readable")
+    {
+        if !signer::reveals_secret_key(&identity.signer) {
+            continue;
+        }
+        match encrypt(&identity.signer) {
+            Ok(encrypted) => driver
+                .set_identity_signer(identity.id, &encrypted)
+                .expect("identities should be writable"),
+            Err(e) => eprintln!(
+                "failed to encrypt the secret key of identity {}, which is left as is: {}",
+                identity.name, e
+            ),
+        }
+    }
+
+    if signer::reveals_secret_key(secret_key) {
+        encrypt(secret_key).expect("secret key should be valid")
+    } else {
+        secret_key.to_string()
+    }
 }
 
-fn run_cli<T: Tezos>(driver: &Driver<T>) {
-    let commands = commands(&driver);
+/// `signer` is recorded in generated identities (see `Driver::generate_identity`).
+fn run_cli<'a, T: Tezos>(
+    driver: &'a Driver<T>,
+    signer: &'a str,
+    poller_config: PollerConfig,
+    extra: Vec<(&'a str, Command<'a, T>)>,
+) {
+    let commands = commands(&driver, signer, poller_config, extra);
 
     let mut rl = rustyline::Editor::<()>::new();
     while let Ok(line) = rl.readline("> ") {
@@ -250,22 +612,24 @@ fn main() {
             let db_path = opt
                 .db_path
                 .unwrap_or_else(|| std::env::var("MIZU_DB").expect("db_path not given"));
-            let conn = Rc::new(
+            let conn = Arc::new(
                 MizuConnection::connect(&db_path)
                     .expect("MizuConnection: failed to establish connection"),
             );
             let mock_db_path = opt.mock_db_path.unwrap_or_else(|| {
                 std::env::var("MIZU_TEZOS_MOCK").expect("mock_db_path not given")
             });
-            let tezos_db_conn = Rc::new(
+            let tezos_db_conn = Arc::new(Mutex::new(
                 SqliteConnection::establish(&mock_db_path)
                     .expect("SqliteConnection: failed to establish connection"),
-            );
+            ));
 
-            let tezos = TezosMock::new(address, secret_key, tezos_db_conn);
+            let tezos = TezosMock::new(address, tezos_db_conn);
             let driver = Driver::new(conn, tezos);
 
-            run_cli(&driver);
+            let signer = protect_signers(&driver, &secret_key);
+
+            run_cli(&driver, &signer, opt.poll.poller_config(), vec![]);
         }
         Opt::Rpc(opt) => {
             let db_path = opt
@@ -274,8 +638,56 @@ fn main() {
 
             let driver = create_rpc_driver(&opt.faucet_output, &opt.config, &db_path)
                 .expect("rpc driver creation should succeed");
+            let secret_key = crypto::FaucetOutput::load_from_file(&opt.faucet_output)
+                .and_then(|faucet_output| Ok(faucet_output.derive_secret_key()?))
+                .expect("faucet file should be valid");
+            let signer = protect_signers(&driver, &secret_key);
+
+            run_cli(
+                &driver,
+                &signer,
+                opt.poll.poller_config(),
+                vec![("follow", follow(&driver))],
+            );
+        }
+        Opt::Storage(opt) => {
+            let contract_config = contract::ContractConfig::load_from_file(&opt.config)
+                .expect("config file should be valid");
+            let host = opt
+                .rpc_host
+                .unwrap_or(contract_config.rpc_host)
+                .parse()
+                .expect("rpc host should be a valid url");
+            let tezos = TezosRpc::read_only(
+                contract_config.debug,
+                host,
+                contract_config.contract_address,
+            );
 
-            run_cli(&driver);
+            match tezos.get_from_big_map(&opt.address) {
+                Ok(Some(entry)) => println!("{:#}", entry),
+                Ok(None) => println!("{} has no entry in the storage", opt.address),
+                Err(e) => {
+                    eprintln!("failed to read the storage: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Opt::Deploy(opt) => {
+            let faucet_output = crypto::FaucetOutput::load_from_file(&opt.faucet_output)
+                .expect("faucet file should be valid");
+            let contract_config =
+                deploy_contract(faucet_output, &opt.script, &opt.rpc_host, opt.debug)
+                    .expect("deployment should succeed");
+            contract_config
+                .save_to_file(&opt.config)
+                .expect("config file should be writable");
+
+            println!(
+                "deployed {} and wrote {}",
+                contract_config.contract_address,
+                opt.config.display()
+            );
         }
     }
 }
@@ -0,0 +1,128 @@
+//! Periodically triggers syncs (see `Driver::sync_all`) from a background thread.
+//!
+//! The poller only decides when to sync. It calls `on_tick` from its own thread, and the callback
+//! is expected to hand the work over to whoever owns the driver (e.g. through a channel,
+//! `cursive::CbSink` or a `worker::Worker`).
+
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the poller ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct PollerConfig {
+    pub interval: Duration,
+    /// A random delay up to `jitter` is added to each interval, so that reads are not trivially
+    /// linkable by their timing.
+    pub jitter: Duration,
+}
+
+impl PollerConfig {
+    pub fn new(interval: Duration, jitter: Duration) -> Self {
+        Self { interval, jitter }
+    }
+
+    pub fn next_delay<R: Rng>(&self, rng: &mut R) -> Duration {
+        let jitter_millis = self.jitter.as_millis() as u64;
+        self.interval + Duration::from_millis(rng.gen_range(0, jitter_millis + 1))
+    }
+}
+
+impl Default for PollerConfig {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30), Duration::from_secs(10))
+    }
+}
+
+// Sleeping is done in small steps so that stopping the poller doesn't take a whole interval.
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+pub struct Poller {
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Poller {
+    /// Spawns a thread calling `on_tick` after every delay given by `config`.
+    /// The thread stops when `on_tick` returns `false` or the poller is stopped (or dropped).
+    pub fn spawn<F>(config: PollerConfig, mut on_tick: F) -> Self
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let stopped = Arc::clone(&stopped);
+            move || {
+                let mut rng = rand::thread_rng();
+                loop {
+                    let mut remaining = config.next_delay(&mut rng);
+                    while remaining > Duration::from_millis(0) {
+                        if stopped.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let step = remaining.min(SLEEP_STEP);
+                        thread::sleep(step);
+                        remaining -= step;
+                    }
+
+                    if stopped.load(Ordering::Relaxed) || !on_tick() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            // The poller thread only panics if `on_tick` does, which we can't do much about.
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn next_delay_is_within_jitter() {
+        let config = PollerConfig::new(Duration::from_millis(100), Duration::from_millis(50));
+        for _ in 0..100 {
+            let delay = config.next_delay(&mut OsRng);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn poller_ticks_until_stopped() {
+        let (tx, rx) = channel();
+        let config = PollerConfig::new(Duration::from_millis(1), Duration::from_millis(1));
+        let poller = Poller::spawn(config, move || tx.send(()).is_ok());
+
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        poller.stop();
+    }
+}
@@ -0,0 +1,103 @@
+//! A dedicated thread for running blocking driver operations (i.e. network I/O to Tezos) off the
+//! caller's thread, e.g. the UI thread of mizu-tui.
+//!
+//! Jobs are run one at a time in the order they were submitted, so operations on the same
+//! conversation never race with each other. Share the driver with jobs through an `Arc`:
+//!
+//! ```ignore
+//! let driver = Arc::new(driver);
+//! let worker = Worker::spawn();
+//! let result = worker.call({
+//!     let driver = Arc::clone(&driver);
+//!     move || driver.get_messages(&mut OsRng, identity_id, contact_id)
+//! });
+//! ```
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct Worker {
+    sender: Option<Sender<Job>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn spawn() -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let handle = thread::spawn(move || {
+            for job in receiver {
+                job();
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Runs `job` on the worker thread.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .as_ref()
+            .expect("sender is only taken when dropped")
+            .send(Box::new(job))
+            .expect("worker thread should be alive while Worker is");
+    }
+
+    /// Runs `job` on the worker thread, and returns a receiver for its result.
+    pub fn call<F, R>(&self, job: F) -> Receiver<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        self.execute(move || {
+            // The caller may have lost interest in the result.
+            let _ = sender.send(job());
+        });
+        receiver
+    }
+}
+
+impl Drop for Worker {
+    /// Waits for all submitted jobs to finish.
+    fn drop(&mut self) {
+        // Closing the channel stops the worker thread after the remaining jobs.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn jobs_run_in_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        {
+            let worker = Worker::spawn();
+            for i in 0..10 {
+                let log = Arc::clone(&log);
+                worker.execute(move || log.lock().unwrap().push(i));
+            }
+        }
+
+        assert_eq!(*log.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn call_returns_result() {
+        let worker = Worker::spawn();
+        assert_eq!(worker.call(|| 1 + 1).recv().unwrap(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+//! Derives `ToMichelson` and `FromMichelson` of `mizu_tezos_rpc::michelson`.
+//!
+//! Structs become pairs of their fields and enums become or-trees of their variants, whose
+//! fields are paired in turn. Both are right combs unless the type is annotated with
+//! `#[michelson(layout = "balanced")]`, which is how SCaml compiles records and variants.
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Fields, Generics, Ident, Lit, Meta,
+    NestedMeta,
+};
+
+#[proc_macro_derive(ToMichelson, attributes(michelson))]
+pub fn derive_to_michelson(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    to_michelson(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(FromMichelson, attributes(michelson))]
+pub fn derive_from_michelson(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_michelson(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// The path of the module the traits live in.
+fn michelson() -> TokenStream {
+    quote!(::mizu_tezos_rpc::michelson)
+}
+
+/// Reads the layout from `#[michelson(layout = "...")]`.
+fn layout(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let michelson = michelson();
+    let mut layout = quote!(#michelson::Layout::RightComb);
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("michelson")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[michelson(...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(ref pair)) if pair.path.is_ident("layout") => {
+                    layout = match &pair.lit {
+                        Lit::Str(s) if s.value() == "right_comb" => {
+                            quote!(#michelson::Layout::RightComb)
+                        }
+                        Lit::Str(s) if s.value() == "balanced" => {
+                            quote!(#michelson::Layout::Balanced)
+                        }
+                        lit => {
+                            return Err(syn::Error::new_spanned(
+                                lit,
+                                "expected \"right_comb\" or \"balanced\"",
+                            ))
+                        }
+                    }
+                }
+                nested => return Err(syn::Error::new_spanned(nested, "unknown attribute")),
+            }
+        }
+    }
+    Ok(layout)
+}
+
+/// Requires type parameters to implement `bound` too.
+fn add_bounds(generics: &Generics, bound: TokenStream) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// Returns patterns binding the fields to `field0`, `field1`, ... and the bound names.
+fn bind_fields(fields: &Fields) -> (TokenStream, Vec<Ident>) {
+    let names: Vec<_> = (0..fields.len())
+        .map(|i| format_ident!("field{}", i))
+        .collect();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let members = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote!({ #(#members: #names),* })
+        }
+        Fields::Unnamed(_) => quote!(( #(#names),* )),
+        Fields::Unit => quote!(),
+    };
+    (pattern, names)
+}
+
+fn to_michelson(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let michelson = michelson();
+    let layout = layout(input)?;
+    let name = &input.ident;
+    let generics = add_bounds(&input.generics, quote!(#michelson::ToMichelson));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let pair = |names: &[Ident]| {
+        quote! {
+            #michelson::pair_tree(
+                vec![#(#michelson::ToMichelson::to_michelson(#names)),*],
+                #layout,
+            )
+        }
+    };
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, names) = bind_fields(&data.fields);
+            let pair = pair(&names);
+            quote! {
+                let #name #pattern = self;
+                #pair
+            }
+        }
+        Data::Enum(data) => {
+            let count = data.variants.len();
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let variant_name = &variant.ident;
+                let (pattern, names) = bind_fields(&variant.fields);
+                let pair = pair(&names);
+                quote! {
+                    #name::#variant_name #pattern => {
+                        #michelson::or_tree(#index, #count, #pair, #layout)
+                    }
+                }
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "unions can't be converted to Michelson",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #michelson::ToMichelson for #name #ty_generics #where_clause {
+            fn to_michelson(&self) -> #michelson::Expr {
+                #body
+            }
+        }
+    })
+}
+
+fn from_michelson(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let michelson = michelson();
+    let layout = layout(input)?;
+    let name = &input.ident;
+    let generics = add_bounds(&input.generics, quote!(#michelson::FromMichelson));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    // Builds `constructor` from the pair in `expr`.
+    let construct = |constructor: TokenStream, fields: &Fields| {
+        let (pattern, names) = bind_fields(fields);
+        let count = names.len();
+        let indices = 0..count;
+        let split = quote!(#michelson::split_pair_tree(expr, #count, #layout)?);
+        if count == 0 {
+            quote! {
+                #split;
+                Ok(#constructor)
+            }
+        } else {
+            quote! {
+                let fields = #split;
+                #(let #names = #michelson::FromMichelson::from_michelson(fields[#indices])?;)*
+                Ok(#constructor #pattern)
+            }
+        }
+    };
+
+    let body = match &input.data {
+        Data::Struct(data) => construct(quote!(#name), &data.fields),
+        Data::Enum(data) => {
+            let count = data.variants.len();
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let variant_name = &variant.ident;
+                let construct = construct(quote!(#name::#variant_name), &variant.fields);
+                quote! {
+                    #index => { #construct }
+                }
+            });
+            quote! {
+                let (index, expr) = #michelson::split_or_tree(expr, #count, #layout)?;
+                match index {
+                    #(#arms)*
+                    _ => unreachable!("split_or_tree returns indices below the count"),
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "unions can't be converted from Michelson",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #michelson::FromMichelson for #name #ty_generics #where_clause {
+            fn from_michelson(
+                expr: &#michelson::Expr,
+            ) -> ::std::result::Result<Self, #michelson::FromMichelsonError> {
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,30 @@
+DROP INDEX messages_postal_box_position;
+
+CREATE TABLE messages_old(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    my_message BOOLEAN NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    FOREIGN KEY(identity_id) REFERENCES identities(id),
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
+INSERT INTO messages_old
+    SELECT id, identity_id, contact_id, content, my_message, created_at FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_old RENAME TO messages;
+
+CREATE TABLE clients_old(
+    identity_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    client_data BLOB NOT NULL,
+    latest_message_timestamp TIMESTAMP,
+    PRIMARY KEY(identity_id, contact_id),
+    FOREIGN KEY(identity_id) REFERENCES identities(id),
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
+INSERT INTO clients_old
+    SELECT identity_id, contact_id, client_data, latest_message_timestamp FROM clients;
+DROP TABLE clients;
+ALTER TABLE clients_old RENAME TO clients;
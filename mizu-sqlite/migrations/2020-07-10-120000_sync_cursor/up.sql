@@ -0,0 +1,15 @@
+-- Messages in the same block share a timestamp, so timestamps alone can't
+-- tell which messages have already been processed. Instead, each client
+-- remembers the position (block level, index within the block) of the latest
+-- message it processed.
+ALTER TABLE clients ADD COLUMN latest_message_level BIGINT;
+ALTER TABLE clients ADD COLUMN latest_message_index INTEGER;
+
+-- The position of received messages in the postal box of the sender.
+-- These are NULL for messages we sent.
+ALTER TABLE messages ADD COLUMN level BIGINT;
+ALTER TABLE messages ADD COLUMN entry_index INTEGER;
+
+-- Ensure each message in a postal box is stored at most once.
+CREATE UNIQUE INDEX messages_postal_box_position
+    ON messages(identity_id, contact_id, level, entry_index);
@@ -0,0 +1,26 @@
+-- Identities with a row here post at randomized slots instead of immediately,
+-- filling slots without a real message with dummy ciphertexts.
+CREATE TABLE cover_traffic(
+    identity_id INTEGER PRIMARY KEY NOT NULL,
+    -- mean number of seconds between slots
+    interval_secs INTEGER NOT NULL,
+    -- maximum number of posts (each costs a fee) per day, after which slots
+    -- without a real message are skipped
+    daily_budget INTEGER NOT NULL,
+    -- number of posts since budget_period_start
+    spent INTEGER NOT NULL,
+    budget_period_start TIMESTAMP NOT NULL,
+    next_slot_at TIMESTAMP NOT NULL,
+    FOREIGN KEY(identity_id) REFERENCES identities(id)
+);
+
+-- Messages held until the next slot.
+CREATE TABLE outbox(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    FOREIGN KEY(identity_id) REFERENCES identities(id),
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
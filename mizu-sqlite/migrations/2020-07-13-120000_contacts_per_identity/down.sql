@@ -0,0 +1,12 @@
+-- Copies of a contact made for each identity remain as separate contacts.
+DROP INDEX contacts_identity_id;
+
+CREATE TABLE contacts_old(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    address TEXT NOT NULL, -- Tezos address in "tz..." format
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO contacts_old SELECT id, address, name, created_at FROM contacts;
+DROP TABLE contacts;
+ALTER TABLE contacts_old RENAME TO contacts;
@@ -0,0 +1,73 @@
+-- Contacts used to be shared among identities. Now each contact belongs to an
+-- identity, so an existing contact is copied for each identity which talked
+-- with it (or to every identity if none did), and clients, messages and held
+-- messages of the copies are pointed to them. The identity which talked with
+-- the contact first (by id) keeps the original contact id.
+-- Without any identity, contacts are kept with identity_id 0 until the first
+-- identity is created, which adopts them (see MizuConnection::create_identity).
+
+-- (identity_id, contact_id) pairs which have to be kept
+CREATE TEMPORARY TABLE contact_owners AS
+    SELECT identity_id, contact_id FROM clients
+    UNION SELECT identity_id, contact_id FROM messages
+    UNION SELECT identity_id, contact_id FROM outbox;
+INSERT INTO contact_owners
+    SELECT identities.id, contacts.id FROM identities, contacts
+    WHERE contacts.id NOT IN (SELECT contact_id FROM contact_owners);
+INSERT INTO contact_owners
+    SELECT 0, contacts.id FROM contacts
+    WHERE contacts.id NOT IN (SELECT contact_id FROM contact_owners);
+
+CREATE TEMPORARY TABLE contact_ids(
+    new_id INTEGER PRIMARY KEY NOT NULL,
+    identity_id INTEGER NOT NULL,
+    old_id INTEGER NOT NULL
+);
+INSERT INTO contact_ids(new_id, identity_id, old_id)
+    SELECT contact_id, MIN(identity_id), contact_id FROM contact_owners
+    GROUP BY contact_id;
+-- new_id is assigned after all of the original ids.
+INSERT INTO contact_ids(identity_id, old_id)
+    SELECT identity_id, contact_id FROM contact_owners
+    WHERE NOT EXISTS (
+        SELECT 1 FROM contact_ids
+        WHERE contact_ids.identity_id = contact_owners.identity_id
+            AND contact_ids.old_id = contact_owners.contact_id
+    )
+    ORDER BY contact_id, identity_id;
+
+CREATE TABLE contacts_new(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    address TEXT NOT NULL, -- Tezos address in "tz..." format
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(identity_id) REFERENCES identities(id)
+);
+INSERT INTO contacts_new
+    SELECT contact_ids.new_id, contact_ids.identity_id,
+        contacts.address, contacts.name, contacts.created_at
+    FROM contact_ids INNER JOIN contacts ON contacts.id = contact_ids.old_id;
+
+UPDATE clients SET contact_id = (
+    SELECT new_id FROM contact_ids
+    WHERE contact_ids.identity_id = clients.identity_id
+        AND contact_ids.old_id = clients.contact_id
+);
+UPDATE messages SET contact_id = (
+    SELECT new_id FROM contact_ids
+    WHERE contact_ids.identity_id = messages.identity_id
+        AND contact_ids.old_id = messages.contact_id
+);
+UPDATE outbox SET contact_id = (
+    SELECT new_id FROM contact_ids
+    WHERE contact_ids.identity_id = outbox.identity_id
+        AND contact_ids.old_id = outbox.contact_id
+);
+
+DROP TABLE contacts;
+ALTER TABLE contacts_new RENAME TO contacts;
+CREATE INDEX contacts_identity_id ON contacts(identity_id);
+
+DROP TABLE contact_ids;
+DROP TABLE contact_owners;
@@ -0,0 +1,15 @@
+DROP TABLE key_changes;
+
+DROP INDEX contacts_identity_id;
+CREATE TABLE contacts_old(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    address TEXT NOT NULL, -- Tezos address in "tz..." format
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(identity_id) REFERENCES identities(id)
+);
+INSERT INTO contacts_old SELECT id, identity_id, address, name, created_at FROM contacts;
+DROP TABLE contacts;
+ALTER TABLE contacts_old RENAME TO contacts;
+CREATE INDEX contacts_identity_id ON contacts(identity_id);
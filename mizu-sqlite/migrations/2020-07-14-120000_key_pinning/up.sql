@@ -0,0 +1,14 @@
+-- The identity key first seen for the contact on Tezos (trust on first use).
+-- Sending to the contact is blocked while the published key differs from it.
+ALTER TABLE contacts ADD COLUMN pinned_identity_key BLOB;
+
+CREATE TABLE key_changes(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    contact_id INTEGER NOT NULL,
+    old_identity_key BLOB NOT NULL,
+    new_identity_key BLOB NOT NULL,
+    detected_at TIMESTAMP NOT NULL,
+    -- NULL until the user accepts the new key
+    accepted_at TIMESTAMP,
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
@@ -0,0 +1,22 @@
+-- Messages are sent in versioned envelopes (see mizu_driver::envelope).
+-- `uid` is the ID chosen by the sender, which both peers use to refer to the
+-- message. It is NULL for messages sent before envelopes were introduced.
+ALTER TABLE messages ADD COLUMN uid BLOB;
+-- the uid of the message this one replies to
+ALTER TABLE messages ADD COLUMN reply_to BLOB;
+ALTER TABLE messages ADD COLUMN edited_at TIMESTAMP;
+-- For our messages, when the contact read it. For theirs, when we sent a read
+-- receipt for it.
+ALTER TABLE messages ADD COLUMN read_at TIMESTAMP;
+
+CREATE UNIQUE INDEX messages_uid ON messages(identity_id, contact_id, uid);
+
+-- Each side of a conversation can put one reaction on a message.
+CREATE TABLE reactions(
+    message_id INTEGER NOT NULL,
+    my_reaction BOOLEAN NOT NULL,
+    reaction TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY(message_id, my_reaction),
+    FOREIGN KEY(message_id) REFERENCES messages(id)
+);
@@ -0,0 +1,29 @@
+DROP INDEX messages_uid;
+DROP INDEX messages_postal_box_position;
+
+CREATE TABLE messages_old(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    my_message BOOLEAN NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    level BIGINT,
+    entry_index INTEGER,
+    uid BLOB,
+    reply_to BLOB,
+    edited_at TIMESTAMP,
+    read_at TIMESTAMP,
+    FOREIGN KEY(identity_id) REFERENCES identities(id),
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
+INSERT INTO messages_old
+    SELECT id, identity_id, contact_id, content, my_message, created_at, level, entry_index,
+        uid, reply_to, edited_at, read_at
+    FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_old RENAME TO messages;
+
+CREATE UNIQUE INDEX messages_postal_box_position
+    ON messages(identity_id, contact_id, level, entry_index);
+CREATE UNIQUE INDEX messages_uid ON messages(identity_id, contact_id, uid);
@@ -0,0 +1,9 @@
+-- The operation which posted our message to Tezos, and how far it got (see
+-- mizu_sqlite::message::DeliveryStatus). These are NULL for received
+-- messages and messages sent before delivery tracking was introduced.
+ALTER TABLE messages ADD COLUMN operation_hash TEXT;
+-- the level of the head when the operation was injected
+ALTER TABLE messages ADD COLUMN operation_level BIGINT;
+ALTER TABLE messages ADD COLUMN delivery_status TEXT;
+-- the level of the block which included the operation
+ALTER TABLE messages ADD COLUMN included_level BIGINT;
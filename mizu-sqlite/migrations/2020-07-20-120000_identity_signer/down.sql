@@ -0,0 +1 @@
+ALTER TABLE identities RENAME COLUMN signer TO secret_key;
@@ -0,0 +1,5 @@
+-- How operations of the identity are signed: either its encrypted secret key, or
+-- the URL of a remote signer holding it. Plain secret keys recorded before can't
+-- be encrypted here, as that needs a passphrase, so applications encrypt them on
+-- startup (see Driver::set_identity_signer).
+ALTER TABLE identities RENAME COLUMN secret_key TO signer;
@@ -0,0 +1,20 @@
+-- Payloads received from contacts which decrypted fine but could not be
+-- decoded, e.g. envelopes of a newer version (see mizu_driver::envelope).
+-- The ratchet keys which decrypted them are gone, so they are kept here to be
+-- decoded again on later syncs.
+CREATE TABLE undecoded_payloads(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    identity_id INTEGER NOT NULL,
+    contact_id INTEGER NOT NULL,
+    payload BLOB NOT NULL,
+    -- the timestamp and postal box position of the message
+    created_at TIMESTAMP NOT NULL,
+    level BIGINT NOT NULL,
+    entry_index INTEGER NOT NULL,
+    FOREIGN KEY(identity_id) REFERENCES identities(id),
+    FOREIGN KEY(contact_id) REFERENCES contacts(id)
+);
+
+-- A payload is saved again if saving the client after it fails.
+CREATE UNIQUE INDEX undecoded_payloads_position
+    ON undecoded_payloads(identity_id, contact_id, level, entry_index);
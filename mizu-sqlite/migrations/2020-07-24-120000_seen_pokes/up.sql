@@ -0,0 +1,8 @@
+-- Pokes found in the postal box of each of our addresses, so that contact
+-- requests are reported once rather than on every start.
+CREATE TABLE seen_pokes(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    address TEXT NOT NULL,
+    data BLOB NOT NULL,
+    UNIQUE(address, data)
+);
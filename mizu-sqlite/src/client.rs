@@ -7,6 +7,16 @@ pub struct Client {
     pub contact_id: i32,
     pub client_data: Vec<u8>,
     pub latest_message_timestamp: Option<NaiveDateTime>,
+    pub latest_message_level: Option<i64>,
+    pub latest_message_index: Option<i32>,
+}
+
+impl Client {
+    /// Returns the position (block level, index within the block) of the latest message this
+    /// client processed.
+    pub fn latest_message_cursor(&self) -> Option<(i64, i32)> {
+        self.latest_message_level.zip(self.latest_message_index)
+    }
 }
 
 #[derive(Debug, Queryable)]
@@ -24,6 +34,8 @@ pub struct NewClient<'a> {
     pub contact_id: i32,
     pub client_data: &'a [u8],
     pub latest_message_timestamp: Option<&'a NaiveDateTime>,
+    pub latest_message_level: Option<i64>,
+    pub latest_message_index: Option<i32>,
 }
 
 #[derive(AsChangeset)]
@@ -31,4 +43,6 @@ pub struct NewClient<'a> {
 pub struct UpdateClient<'a> {
     pub client_data: &'a [u8],
     pub latest_message_timestamp: Option<&'a NaiveDateTime>,
+    pub latest_message_level: Option<i64>,
+    pub latest_message_index: Option<i32>,
 }
@@ -3,14 +3,18 @@ use crate::schema::*;
 #[derive(Debug, Queryable)]
 pub struct Contact {
     pub id: i32,
+    pub identity_id: i32,
     pub address: String,
     pub name: String,
     pub created_at: String,
+    /// The identity key first seen on Tezos
+    pub pinned_identity_key: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
 #[table_name = "contacts"]
 pub struct NewContact<'a> {
+    pub identity_id: i32,
     pub address: &'a str,
     pub name: &'a str,
 }
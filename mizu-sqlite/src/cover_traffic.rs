@@ -0,0 +1,31 @@
+use crate::schema::*;
+use chrono::naive::NaiveDateTime;
+
+#[derive(Debug, Queryable)]
+pub struct CoverTraffic {
+    pub identity_id: i32,
+    pub interval_secs: i32,
+    pub daily_budget: i32,
+    pub spent: i32,
+    pub budget_period_start: NaiveDateTime,
+    pub next_slot_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "cover_traffic"]
+pub struct NewCoverTraffic {
+    pub identity_id: i32,
+    pub interval_secs: i32,
+    pub daily_budget: i32,
+    pub spent: i32,
+    pub budget_period_start: NaiveDateTime,
+    pub next_slot_at: NaiveDateTime,
+}
+
+#[derive(AsChangeset)]
+#[table_name = "cover_traffic"]
+pub struct UpdateCoverTraffic {
+    pub spent: i32,
+    pub budget_period_start: NaiveDateTime,
+    pub next_slot_at: NaiveDateTime,
+}
@@ -0,0 +1,9 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("database error: {0}")]
+    Diesel(#[from] diesel::result::Error),
+    #[error("failed to get a connection from the pool: {0}")]
+    Pool(#[from] diesel::r2d2::PoolError),
+}
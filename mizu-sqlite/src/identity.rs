@@ -5,7 +5,7 @@ pub struct Identity {
     pub id: i32,
     pub name: String,
     pub address: String,
-    pub secret_key: String,
+    pub signer: String,
     pub x3dh_client: Vec<u8>,
     pub created_at: String,
 }
@@ -15,6 +15,6 @@ pub struct Identity {
 pub struct NewIdentity<'a> {
     pub name: &'a str,
     pub address: &'a str,
-    pub secret_key: &'a str,
+    pub signer: &'a str,
     pub x3dh_client: &'a [u8],
 }
@@ -0,0 +1,21 @@
+use crate::schema::*;
+use chrono::naive::NaiveDateTime;
+
+#[derive(Debug, Queryable)]
+pub struct KeyChange {
+    pub id: i32,
+    pub contact_id: i32,
+    pub old_identity_key: Vec<u8>,
+    pub new_identity_key: Vec<u8>,
+    pub detected_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "key_changes"]
+pub struct NewKeyChange<'a> {
+    pub contact_id: i32,
+    pub old_identity_key: &'a [u8],
+    pub new_identity_key: &'a [u8],
+    pub detected_at: NaiveDateTime,
+}
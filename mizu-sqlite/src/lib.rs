@@ -7,79 +7,150 @@ extern crate diesel;
 extern crate diesel_migrations;
 
 use chrono::naive::NaiveDateTime;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
 use diesel_migrations::embed_migrations;
 use mizu_crypto::x3dh::X3DHClient;
 use mizu_crypto::Client;
 
 pub mod client;
 pub mod contact;
+pub mod cover_traffic;
+mod error;
 pub mod identity;
+pub mod key_change;
 pub mod message;
+pub mod outbox;
+pub mod reaction;
+pub mod seen_poke;
+pub mod undecoded_payload;
 
 mod schema;
 
-type Result<T> = std::result::Result<T, diesel::result::Error>;
+pub use error::Error;
 
+type Result<T> = std::result::Result<T, Error>;
+
+pub type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// The identity ID of contacts which were shared among identities when there was no identity.
+const UNOWNED_CONTACTS: i32 = 0;
+
+/// A handle to the user database which can be shared among threads.
 pub struct MizuConnection {
-    conn: SqliteConnection,
+    pool: SqlitePool,
 }
 
 embed_migrations!();
 
-impl MizuConnection {
-    pub fn new(conn: SqliteConnection) -> Self {
-        MizuConnection { conn }
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> std::result::Result<(), diesel::r2d2::Error> {
+        // Wait for other connections in the pool to finish writing instead of failing
+        // immediately with SQLITE_BUSY.
+        conn.batch_execute("PRAGMA busy_timeout = 5000;")
+            .map_err(diesel::r2d2::Error::QueryError)
     }
+}
 
-    pub fn connect(url: &str) -> std::result::Result<Self, ConnectionError> {
-        let run_migration = url == ":memory:" || std::fs::metadata(url).is_err();
+impl MizuConnection {
+    pub fn new(pool: SqlitePool) -> Self {
+        MizuConnection { pool }
+    }
 
-        let mizu_connection = Self {
-            conn: SqliteConnection::establish(url)?,
+    pub fn connect(url: &str) -> Result<Self> {
+        // Each connection to ":memory:" opens a distinct database, so we must stick to a single
+        // connection in that case, and never let the pool close it.
+        let builder = if url == ":memory:" {
+            Pool::builder()
+                .max_size(1)
+                .min_idle(Some(1))
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            Pool::builder().max_size(10)
         };
+        let pool = builder
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(ConnectionManager::new(url))?;
+        let mizu_connection = Self { pool };
 
-        if run_migration {
-            mizu_connection.run_migrations();
-        }
+        // Only pending migrations are run, so existing databases are brought up to date.
+        mizu_connection.run_migrations();
 
         Ok(mizu_connection)
     }
 
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>> {
+        self.pool.get().map_err(Error::Pool)
+    }
+
     // TODO: should probably check for errors
     // TODO: embedded_migrations::run_with_output will redirect output instead
     // of throwing it away, should log this.
     pub fn run_migrations(&self) {
-        embedded_migrations::run(&self.conn).expect("migration should never fail");
+        let conn = self
+            .conn()
+            .expect("failed to get a connection for migration");
+        embedded_migrations::run(&*conn).expect("migration should never fail");
     }
 
+    /// Creates an identity, which adopts the contacts kept without an identity by the migration
+    /// making contacts per identity.
     pub fn create_identity(
         &self,
         name: &str,
         address: &str,
-        secret_key: &str,
+        signer: &str,
         x3dh: &X3DHClient,
     ) -> Result<()> {
-        diesel::insert_into(schema::identities::table)
-            .values(&identity::NewIdentity {
-                name,
-                address,
-                secret_key,
-                x3dh_client: &bincode::serialize(&x3dh).unwrap(),
-            })
-            .execute(&self.conn)?;
+        use schema::identities::dsl;
 
-        Ok(())
+        let conn = self.conn()?;
+        conn.transaction(|| {
+            diesel::insert_into(schema::identities::table)
+                .values(&identity::NewIdentity {
+                    name,
+                    address,
+                    signer,
+                    x3dh_client: &bincode::serialize(&x3dh).unwrap(),
+                })
+                .execute(&*conn)?;
+
+            let id = dsl::identities
+                .filter(dsl::address.eq(address))
+                .select(dsl::id)
+                .first::<i32>(&*conn)?;
+            diesel::update(
+                schema::contacts::dsl::contacts
+                    .filter(schema::contacts::dsl::identity_id.eq(UNOWNED_CONTACTS)),
+            )
+            .set(schema::contacts::dsl::identity_id.eq(id))
+            .execute(&*conn)?;
+
+            Ok(())
+        })
     }
 
     pub fn list_identities(&self) -> Result<Vec<identity::Identity>> {
-        schema::identities::dsl::identities.load::<identity::Identity>(&self.conn)
+        schema::identities::dsl::identities
+            .load::<identity::Identity>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn find_identity(&self, id: i32) -> Result<identity::Identity> {
         use schema::identities::dsl::identities;
 
-        identities.find(id).first::<identity::Identity>(&self.conn)
+        identities
+            .find(id)
+            .first::<identity::Identity>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn find_identity_by_name(&self, needle: &str) -> Result<identity::Identity> {
@@ -87,7 +158,8 @@ impl MizuConnection {
 
         identities
             .filter(name.eq(needle))
-            .first::<identity::Identity>(&self.conn)
+            .first::<identity::Identity>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn update_identity(&self, id: i32, name: &str, x3dh: &X3DHClient) -> Result<()> {
@@ -99,21 +171,43 @@ impl MizuConnection {
                 dsl::name.eq(name),
                 dsl::x3dh_client.eq(bincode::serialize(&x3dh).unwrap()),
             ))
-            .execute(&self.conn)?;
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
 
-    pub fn create_contact(&self, name: &str, address: &str) -> Result<()> {
+    pub fn update_identity_signer(&self, id: i32, signer: &str) -> Result<()> {
+        use schema::identities::dsl;
+
+        let target = dsl::identities.find(id);
+        diesel::update(target)
+            .set(dsl::signer.eq(signer))
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn create_contact(&self, identity_id: i32, name: &str, address: &str) -> Result<()> {
         diesel::insert_into(schema::contacts::table)
-            .values(&contact::NewContact { name, address })
-            .execute(&self.conn)?;
+            .values(&contact::NewContact {
+                identity_id,
+                name,
+                address,
+            })
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
 
-    pub fn list_contacts(&self) -> Result<Vec<contact::Contact>> {
-        schema::contacts::dsl::contacts.load::<contact::Contact>(&self.conn)
+    /// Lists the contacts of an identity.
+    pub fn list_contacts(&self, identity_id: i32) -> Result<Vec<contact::Contact>> {
+        use schema::contacts::dsl;
+
+        dsl::contacts
+            .filter(dsl::identity_id.eq(identity_id))
+            .order_by(dsl::id.asc())
+            .load::<contact::Contact>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn find_contact(&self, contact_id: i32) -> Result<contact::Contact> {
@@ -121,15 +215,114 @@ impl MizuConnection {
 
         contacts
             .find(contact_id)
-            .first::<contact::Contact>(&self.conn)
+            .first::<contact::Contact>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
-    pub fn find_contact_by_address(&self, needle: &str) -> Result<contact::Contact> {
-        use schema::contacts::dsl::*;
+    pub fn find_contact_by_address(
+        &self,
+        identity_id: i32,
+        needle: &str,
+    ) -> Result<contact::Contact> {
+        use schema::contacts::dsl;
 
-        contacts
-            .filter(address.eq(needle))
-            .first::<contact::Contact>(&self.conn)
+        dsl::contacts
+            .filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::address.eq(needle)),
+            )
+            .first::<contact::Contact>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    pub fn pin_identity_key(&self, contact_id: i32, identity_key: &[u8]) -> Result<()> {
+        use schema::contacts::dsl;
+
+        diesel::update(dsl::contacts.find(contact_id))
+            .set(dsl::pinned_identity_key.eq(identity_key))
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn create_key_change(
+        &self,
+        contact_id: i32,
+        old_identity_key: &[u8],
+        new_identity_key: &[u8],
+        detected_at: NaiveDateTime,
+    ) -> Result<()> {
+        diesel::insert_into(schema::key_changes::table)
+            .values(&key_change::NewKeyChange {
+                contact_id,
+                old_identity_key,
+                new_identity_key,
+                detected_at,
+            })
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Lists the key changes of a contact, oldest first.
+    pub fn list_key_changes(&self, contact_id: i32) -> Result<Vec<key_change::KeyChange>> {
+        use schema::key_changes::dsl;
+
+        dsl::key_changes
+            .filter(dsl::contact_id.eq(contact_id))
+            .order_by(dsl::id.asc())
+            .load::<key_change::KeyChange>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    /// Finds the latest key change of a contact which is not accepted yet.
+    pub fn find_pending_key_change(
+        &self,
+        contact_id: i32,
+    ) -> Result<Option<key_change::KeyChange>> {
+        use schema::key_changes::dsl;
+
+        dsl::key_changes
+            .filter(
+                dsl::contact_id
+                    .eq(contact_id)
+                    .and(dsl::accepted_at.is_null()),
+            )
+            .order_by(dsl::id.desc())
+            .first(&*self.conn()?)
+            .optional()
+            .map_err(Error::Diesel)
+    }
+
+    /// Pins the new key of a key change. Earlier pending changes of the contact are accepted as
+    /// well, since they are superseded.
+    pub fn accept_key_change(
+        &self,
+        key_change: &key_change::KeyChange,
+        accepted_at: NaiveDateTime,
+    ) -> Result<()> {
+        use schema::key_changes::dsl;
+
+        let conn = self.conn()?;
+        conn.transaction(|| {
+            diesel::update(
+                dsl::key_changes.filter(
+                    dsl::contact_id
+                        .eq(key_change.contact_id)
+                        .and(dsl::accepted_at.is_null())
+                        .and(dsl::id.le(key_change.id)),
+                ),
+            )
+            .set(dsl::accepted_at.eq(accepted_at))
+            .execute(&*conn)?;
+
+            diesel::update(schema::contacts::dsl::contacts.find(key_change.contact_id))
+                .set(schema::contacts::dsl::pinned_identity_key.eq(&key_change.new_identity_key))
+                .execute(&*conn)?;
+
+            Ok(())
+        })
     }
 
     pub fn create_client(
@@ -138,6 +331,7 @@ impl MizuConnection {
         contact_id: i32,
         client: &Client,
         latest_message_timestamp: Option<&NaiveDateTime>,
+        latest_message_cursor: Option<(i64, i32)>,
     ) -> Result<()> {
         diesel::insert_into(schema::clients::table)
             .values(&client::NewClient {
@@ -145,14 +339,18 @@ impl MizuConnection {
                 contact_id,
                 client_data: &bincode::serialize(client).unwrap(),
                 latest_message_timestamp,
+                latest_message_level: latest_message_cursor.map(|(level, _)| level),
+                latest_message_index: latest_message_cursor.map(|(_, index)| index),
             })
-            .execute(&self.conn)?;
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
 
     pub fn list_clients(&self) -> Result<Vec<client::Client>> {
-        schema::clients::dsl::clients.load::<client::Client>(&self.conn)
+        schema::clients::dsl::clients
+            .load::<client::Client>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn list_talking_clients(&self, identity_id: i32) -> Result<Vec<client::ClientInfo>> {
@@ -168,7 +366,8 @@ impl MizuConnection {
                 contacts_dsl::name,
                 clients_dsl::latest_message_timestamp,
             ))
-            .load::<client::ClientInfo>(&self.conn)
+            .load::<client::ClientInfo>(&*self.conn()?)
+            .map_err(Error::Diesel)
     }
 
     pub fn find_client(&self, identity_id: i32, contact_id: i32) -> Result<Option<client::Client>> {
@@ -176,8 +375,9 @@ impl MizuConnection {
 
         dsl::clients
             .find((identity_id, contact_id))
-            .first(&self.conn)
+            .first(&*self.conn()?)
             .optional()
+            .map_err(Error::Diesel)
     }
 
     pub fn update_client(
@@ -186,6 +386,7 @@ impl MizuConnection {
         contact_id: i32,
         client: &Client,
         latest_message_timestamp: Option<&NaiveDateTime>,
+        latest_message_cursor: Option<(i64, i32)>,
     ) -> Result<()> {
         use schema::clients::dsl;
 
@@ -194,8 +395,10 @@ impl MizuConnection {
             .set(client::UpdateClient {
                 client_data: &bincode::serialize(client).unwrap(),
                 latest_message_timestamp,
+                latest_message_level: latest_message_cursor.map(|(level, _)| level),
+                latest_message_index: latest_message_cursor.map(|(_, index)| index),
             })
-            .execute(&self.conn)?;
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
@@ -206,6 +409,7 @@ impl MizuConnection {
         contact_id: i32,
         client: &Client,
         latest_message_timestamp: Option<&NaiveDateTime>,
+        latest_message_cursor: Option<(i64, i32)>,
     ) -> Result<()> {
         diesel::replace_into(schema::clients::table)
             .values(&client::NewClient {
@@ -213,33 +417,236 @@ impl MizuConnection {
                 contact_id,
                 client_data: &bincode::serialize(client).unwrap(),
                 latest_message_timestamp,
+                latest_message_level: latest_message_cursor.map(|(level, _)| level),
+                latest_message_index: latest_message_cursor.map(|(_, index)| index),
             })
-            .execute(&self.conn)?;
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Saves a message. A received message which is already saved at the same position in the
+    /// postal box of the sender, or with the same uid, is silently ignored.
+    pub fn create_message(&self, message: &message::NewMessage) -> Result<()> {
+        diesel::insert_or_ignore_into(schema::messages::table)
+            .values(message)
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
 
-    pub fn create_message(
+    pub fn find_message(&self, id: i32) -> Result<message::Message> {
+        use schema::messages::dsl;
+
+        dsl::messages
+            .find(id)
+            .first(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    pub fn find_message_by_uid(
+        &self,
+        identity_id: i32,
+        contact_id: i32,
+        uid: &[u8],
+    ) -> Result<Option<message::Message>> {
+        use schema::messages::dsl;
+
+        dsl::messages
+            .filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::contact_id.eq(contact_id))
+                    .and(dsl::uid.eq(uid)),
+            )
+            .first(&*self.conn()?)
+            .optional()
+            .map_err(Error::Diesel)
+    }
+
+    pub fn edit_message(&self, id: i32, content: &[u8], edited_at: NaiveDateTime) -> Result<()> {
+        use schema::messages::dsl;
+
+        diesel::update(dsl::messages.find(id))
+            .set((dsl::content.eq(content), dsl::edited_at.eq(edited_at)))
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Deletes a message along with its reactions.
+    pub fn delete_message(&self, id: i32) -> Result<()> {
+        use schema::messages::dsl;
+
+        let conn = self.conn()?;
+        conn.transaction(|| {
+            diesel::delete(
+                schema::reactions::dsl::reactions.filter(schema::reactions::dsl::message_id.eq(id)),
+            )
+            .execute(&*conn)?;
+
+            diesel::delete(dsl::messages.find(id)).execute(&*conn)?;
+
+            Ok(())
+        })
+    }
+
+    /// Records the operation which posted our message.
+    pub fn set_message_operation(
+        &self,
+        id: i32,
+        operation_hash: &str,
+        operation_level: i64,
+    ) -> Result<()> {
+        use schema::messages::dsl;
+
+        diesel::update(dsl::messages.find(id))
+            .set((
+                dsl::operation_hash.eq(operation_hash),
+                dsl::operation_level.eq(operation_level),
+                dsl::delivery_status.eq(message::DeliveryStatus::Injected.as_str()),
+            ))
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn update_delivery_status(
+        &self,
+        id: i32,
+        delivery_status: message::DeliveryStatus,
+        included_level: Option<i64>,
+    ) -> Result<()> {
+        use schema::messages::dsl;
+
+        diesel::update(dsl::messages.find(id))
+            .set((
+                dsl::delivery_status.eq(delivery_status.as_str()),
+                dsl::included_level.eq(included_level),
+            ))
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Lists our messages whose operations are not confirmed, failed or expired yet.
+    pub fn list_pending_deliveries(&self, identity_id: i32) -> Result<Vec<message::Message>> {
+        use schema::messages::dsl;
+
+        dsl::messages
+            .filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::operation_hash.is_not_null())
+                    .and(dsl::delivery_status.eq_any(&[
+                        message::DeliveryStatus::Injected.as_str(),
+                        message::DeliveryStatus::Included.as_str(),
+                    ])),
+            )
+            .order_by(dsl::id.asc())
+            .load::<message::Message>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    /// Lists received messages we have not sent a read receipt for yet.
+    pub fn list_unread_messages(
+        &self,
+        identity_id: i32,
+        contact_id: i32,
+    ) -> Result<Vec<message::Message>> {
+        use schema::messages::dsl;
+
+        dsl::messages
+            .filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::contact_id.eq(contact_id))
+                    .and(dsl::my_message.eq(false))
+                    .and(dsl::uid.is_not_null())
+                    .and(dsl::read_at.is_null()),
+            )
+            .order_by(dsl::id.asc())
+            .load::<message::Message>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    /// Marks the messages with the given uids in a conversation as read, unless they already are.
+    pub fn mark_messages_read(
         &self,
         identity_id: i32,
         contact_id: i32,
-        content: &[u8],
         my_message: bool,
+        uids: &[Vec<u8>],
+        read_at: NaiveDateTime,
+    ) -> Result<()> {
+        use schema::messages::dsl;
+
+        diesel::update(
+            dsl::messages.filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::contact_id.eq(contact_id))
+                    .and(dsl::my_message.eq(my_message))
+                    .and(dsl::uid.eq_any(uids))
+                    .and(dsl::read_at.is_null()),
+            ),
+        )
+        .set(dsl::read_at.eq(read_at))
+        .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Sets the reaction of either side to a message, replacing the previous one.
+    pub fn set_reaction(
+        &self,
+        message_id: i32,
+        my_reaction: bool,
+        reaction: &str,
         created_at: NaiveDateTime,
     ) -> Result<()> {
-        diesel::insert_into(schema::messages::table)
-            .values(&message::NewMessage {
-                identity_id,
-                contact_id,
-                content,
-                my_message,
+        diesel::replace_into(schema::reactions::table)
+            .values(&reaction::NewReaction {
+                message_id,
+                my_reaction,
+                reaction,
                 created_at,
             })
-            .execute(&self.conn)?;
+            .execute(&*self.conn()?)?;
 
         Ok(())
     }
 
+    pub fn delete_reaction(&self, message_id: i32, my_reaction: bool) -> Result<()> {
+        use schema::reactions::dsl;
+
+        diesel::delete(dsl::reactions.find((message_id, my_reaction))).execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Lists the reactions to messages of a conversation.
+    pub fn list_reactions(
+        &self,
+        identity_id: i32,
+        contact_id: i32,
+    ) -> Result<Vec<reaction::Reaction>> {
+        use schema::messages::dsl as messages_dsl;
+        use schema::reactions::dsl;
+
+        dsl::reactions
+            .inner_join(messages_dsl::messages)
+            .filter(
+                messages_dsl::identity_id
+                    .eq(identity_id)
+                    .and(messages_dsl::contact_id.eq(contact_id)),
+            )
+            .select(schema::reactions::all_columns)
+            .order_by(dsl::created_at.asc())
+            .load::<reaction::Reaction>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
     pub fn find_messages(
         &self,
         identity_id: i32,
@@ -254,7 +661,254 @@ impl MizuConnection {
                     .eq(identity_id)
                     .and(dsl::contact_id.eq(contact_id)),
             )
-            .order_by(dsl::created_at.asc())
-            .load::<message::Message>(&self.conn)
+            .order_by((dsl::created_at.asc(), dsl::id.asc()))
+            .load::<message::Message>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    /// Enables cover traffic for an identity, or resets its settings. The first slot is due
+    /// immediately.
+    pub fn set_cover_traffic(
+        &self,
+        identity_id: i32,
+        interval_secs: i32,
+        daily_budget: i32,
+        now: NaiveDateTime,
+    ) -> Result<()> {
+        diesel::replace_into(schema::cover_traffic::table)
+            .values(&cover_traffic::NewCoverTraffic {
+                identity_id,
+                interval_secs,
+                daily_budget,
+                spent: 0,
+                budget_period_start: now,
+                next_slot_at: now,
+            })
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn delete_cover_traffic(&self, identity_id: i32) -> Result<()> {
+        use schema::cover_traffic::dsl;
+
+        diesel::delete(dsl::cover_traffic.find(identity_id)).execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn find_cover_traffic(
+        &self,
+        identity_id: i32,
+    ) -> Result<Option<cover_traffic::CoverTraffic>> {
+        use schema::cover_traffic::dsl;
+
+        dsl::cover_traffic
+            .find(identity_id)
+            .first(&*self.conn()?)
+            .optional()
+            .map_err(Error::Diesel)
+    }
+
+    pub fn update_cover_traffic(
+        &self,
+        identity_id: i32,
+        spent: i32,
+        budget_period_start: NaiveDateTime,
+        next_slot_at: NaiveDateTime,
+    ) -> Result<()> {
+        use schema::cover_traffic::dsl;
+
+        diesel::update(dsl::cover_traffic.find(identity_id))
+            .set(cover_traffic::UpdateCoverTraffic {
+                spent,
+                budget_period_start,
+                next_slot_at,
+            })
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    pub fn create_outbox_message(
+        &self,
+        identity_id: i32,
+        contact_id: i32,
+        content: &[u8],
+        created_at: NaiveDateTime,
+    ) -> Result<()> {
+        diesel::insert_into(schema::outbox::table)
+            .values(&outbox::NewOutboxMessage {
+                identity_id,
+                contact_id,
+                content,
+                created_at,
+            })
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Returns messages of an identity waiting to be sent, oldest first.
+    pub fn list_outbox_messages(&self, identity_id: i32) -> Result<Vec<outbox::OutboxMessage>> {
+        use schema::outbox::dsl;
+
+        dsl::outbox
+            .filter(dsl::identity_id.eq(identity_id))
+            .order_by(dsl::id.asc())
+            .load::<outbox::OutboxMessage>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    pub fn delete_outbox_message(&self, id: i32) -> Result<()> {
+        use schema::outbox::dsl;
+
+        diesel::delete(dsl::outbox.find(id)).execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Records a poke found in the postal box of `address`, and returns whether it wasn't
+    /// recorded before.
+    pub fn record_seen_poke(&self, address: &str, data: &[u8]) -> Result<bool> {
+        let inserted = diesel::insert_or_ignore_into(schema::seen_pokes::table)
+            .values(&seen_poke::NewSeenPoke { address, data })
+            .execute(&*self.conn()?)?;
+
+        Ok(inserted == 1)
+    }
+
+    pub fn create_undecoded_payload(
+        &self,
+        payload: &undecoded_payload::NewUndecodedPayload,
+    ) -> Result<()> {
+        diesel::insert_or_ignore_into(schema::undecoded_payloads::table)
+            .values(payload)
+            .execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+
+    /// Returns the payloads received from a contact which could not be decoded, oldest first.
+    pub fn list_undecoded_payloads(
+        &self,
+        identity_id: i32,
+        contact_id: i32,
+    ) -> Result<Vec<undecoded_payload::UndecodedPayload>> {
+        use schema::undecoded_payloads::dsl;
+
+        dsl::undecoded_payloads
+            .filter(
+                dsl::identity_id
+                    .eq(identity_id)
+                    .and(dsl::contact_id.eq(contact_id)),
+            )
+            .order_by((dsl::level.asc(), dsl::entry_index.asc()))
+            .load::<undecoded_payload::UndecodedPayload>(&*self.conn()?)
+            .map_err(Error::Diesel)
+    }
+
+    pub fn delete_undecoded_payload(&self, id: i32) -> Result<()> {
+        use schema::undecoded_payloads::dsl;
+
+        diesel::delete(dsl::undecoded_payloads.find(id)).execute(&*self.conn()?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::Path;
+
+    /// Migrations from before contacts were per identity.
+    const SHARED_CONTACTS_VERSIONS: &[&str] =
+        &["20200616100417", "20200710120000", "20200712120000"];
+
+    /// Runs the migrations up to shared contacts, then `setup`, then the pending migrations as
+    /// `MizuConnection::connect` does.
+    fn migrate_from_shared_contacts(setup: &str) -> MizuConnection {
+        let pool = Pool::builder()
+            .max_size(1)
+            .min_idle(Some(1))
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+            .unwrap();
+        {
+            let conn = pool.get().unwrap();
+            let migrations = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+            diesel_migrations::setup_database(&*conn).unwrap();
+            for version in SHARED_CONTACTS_VERSIONS {
+                diesel_migrations::run_migration_with_version(
+                    &*conn,
+                    &migrations,
+                    version,
+                    &mut io::sink(),
+                )
+                .unwrap();
+            }
+            conn.batch_execute(setup).unwrap();
+        }
+        let conn = MizuConnection::new(pool);
+        conn.run_migrations();
+        conn
+    }
+
+    fn contacts(conn: &MizuConnection, identity_id: i32) -> Vec<(i32, String)> {
+        conn.list_contacts(identity_id)
+            .unwrap()
+            .into_iter()
+            .map(|contact| (contact.id, contact.name))
+            .collect()
+    }
+
+    #[test]
+    fn shared_contacts_are_copied_per_identity() {
+        let conn = migrate_from_shared_contacts(
+            "INSERT INTO identities(name, address, secret_key, x3dh_client)
+                VALUES ('alice', 'tz1alice', 'edsk', x''), ('alice2', 'tz1alice2', 'edsk', x'');
+            INSERT INTO contacts(address, name) VALUES ('tz1bob', 'bob'), ('tz1carol', 'carol');
+            INSERT INTO messages(identity_id, contact_id, content, my_message, created_at)
+                VALUES (2, 1, x'01', 1, '2020-07-01 00:00:00'),
+                    (1, 1, x'02', 1, '2020-07-01 00:00:00');
+            INSERT INTO outbox(identity_id, contact_id, content, created_at)
+                VALUES (2, 1, x'03', '2020-07-01 00:00:00');",
+        );
+
+        // Both identities talked with bob, and carol is copied to every identity since none did.
+        // The first identity keeps the original contacts.
+        assert_eq!(
+            contacts(&conn, 1),
+            [(1, "bob".to_string()), (2, "carol".to_string())]
+        );
+        assert_eq!(
+            contacts(&conn, 2),
+            [(3, "bob".to_string()), (4, "carol".to_string())]
+        );
+        assert_eq!(conn.find_messages(1, 1).unwrap()[0].content, [2]);
+        assert_eq!(conn.find_messages(2, 3).unwrap()[0].content, [1]);
+        assert_eq!(conn.list_outbox_messages(2).unwrap()[0].contact_id, 3);
+    }
+
+    #[test]
+    fn contacts_without_identities_are_kept() {
+        let conn = migrate_from_shared_contacts(
+            "INSERT INTO contacts(address, name) VALUES ('tz1bob', 'bob'), ('tz1carol', 'carol');",
+        );
+
+        // The first identity adopts them, and the next ones don't.
+        let x3dh = X3DHClient::new(&mut rand::rngs::OsRng);
+        conn.create_identity("alice", "tz1alice", "http://localhost:6732/alice", &x3dh)
+            .unwrap();
+        conn.create_identity("alice2", "tz1alice2", "http://localhost:6732/alice2", &x3dh)
+            .unwrap();
+        assert_eq!(
+            contacts(&conn, 1),
+            [(1, "bob".to_string()), (2, "carol".to_string())]
+        );
+        assert!(contacts(&conn, 2).is_empty());
     }
 }
@@ -9,6 +9,71 @@ pub struct Message {
     pub content: Vec<u8>,
     pub my_message: bool,
     pub created_at: NaiveDateTime,
+    pub level: Option<i64>,
+    pub entry_index: Option<i32>,
+    pub uid: Option<Vec<u8>>,
+    pub reply_to: Option<Vec<u8>>,
+    pub edited_at: Option<NaiveDateTime>,
+    pub read_at: Option<NaiveDateTime>,
+    pub operation_hash: Option<String>,
+    pub operation_level: Option<i64>,
+    pub delivery_status: Option<String>,
+    pub included_level: Option<i64>,
+}
+
+impl Message {
+    /// Returns `None` for received messages and messages which have not been posted (yet).
+    pub fn delivery_status(&self) -> Option<DeliveryStatus> {
+        self.delivery_status
+            .as_deref()
+            .and_then(DeliveryStatus::parse)
+    }
+}
+
+/// How far a message we sent got on its way to the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The operation is waiting in the mempool.
+    Injected,
+    /// The operation was included in a block, which may still be reorganized away.
+    Included,
+    /// The block including the operation is deep enough.
+    Confirmed,
+    /// Posting failed, or the operation was refused or failed to apply.
+    Failed,
+    /// The operation was not included in time.
+    Expired,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Injected => "injected",
+            DeliveryStatus::Included => "included",
+            DeliveryStatus::Confirmed => "confirmed",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Expired => "expired",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "injected" => Some(DeliveryStatus::Injected),
+            "included" => Some(DeliveryStatus::Included),
+            "confirmed" => Some(DeliveryStatus::Confirmed),
+            "failed" => Some(DeliveryStatus::Failed),
+            "expired" => Some(DeliveryStatus::Expired),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the status can still change.
+    pub fn is_pending(self) -> bool {
+        match self {
+            DeliveryStatus::Injected | DeliveryStatus::Included => true,
+            DeliveryStatus::Confirmed | DeliveryStatus::Failed | DeliveryStatus::Expired => false,
+        }
+    }
 }
 
 #[derive(Insertable)]
@@ -19,4 +84,8 @@ pub struct NewMessage<'a> {
     pub content: &'a [u8],
     pub my_message: bool,
     pub created_at: NaiveDateTime,
+    pub level: Option<i64>,
+    pub entry_index: Option<i32>,
+    pub uid: Option<&'a [u8]>,
+    pub reply_to: Option<&'a [u8]>,
 }
@@ -0,0 +1,20 @@
+use crate::schema::*;
+use chrono::naive::NaiveDateTime;
+
+#[derive(Debug, Queryable)]
+pub struct OutboxMessage {
+    pub id: i32,
+    pub identity_id: i32,
+    pub contact_id: i32,
+    pub content: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "outbox"]
+pub struct NewOutboxMessage<'a> {
+    pub identity_id: i32,
+    pub contact_id: i32,
+    pub content: &'a [u8],
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,19 @@
+use crate::schema::*;
+use chrono::naive::NaiveDateTime;
+
+#[derive(Debug, Queryable)]
+pub struct Reaction {
+    pub message_id: i32,
+    pub my_reaction: bool,
+    pub reaction: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "reactions"]
+pub struct NewReaction<'a> {
+    pub message_id: i32,
+    pub my_reaction: bool,
+    pub reaction: &'a str,
+    pub created_at: NaiveDateTime,
+}
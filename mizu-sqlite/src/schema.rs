@@ -4,15 +4,30 @@ table! {
         contact_id -> Integer,
         client_data -> Binary,
         latest_message_timestamp -> Nullable<Timestamp>,
+        latest_message_level -> Nullable<BigInt>,
+        latest_message_index -> Nullable<Integer>,
     }
 }
 
 table! {
     contacts (id) {
         id -> Integer,
+        identity_id -> Integer,
         address -> Text,
         name -> Text,
         created_at -> Timestamp,
+        pinned_identity_key -> Nullable<Binary>,
+    }
+}
+
+table! {
+    cover_traffic (identity_id) {
+        identity_id -> Integer,
+        interval_secs -> Integer,
+        daily_budget -> Integer,
+        spent -> Integer,
+        budget_period_start -> Timestamp,
+        next_slot_at -> Timestamp,
     }
 }
 
@@ -21,12 +36,23 @@ table! {
         id -> Integer,
         name -> Text,
         address -> Text,
-        secret_key -> Text,
+        signer -> Text,
         x3dh_client -> Binary,
         created_at -> Timestamp,
     }
 }
 
+table! {
+    key_changes (id) {
+        id -> Integer,
+        contact_id -> Integer,
+        old_identity_key -> Binary,
+        new_identity_key -> Binary,
+        detected_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     messages (id) {
         id -> Integer,
@@ -35,12 +61,80 @@ table! {
         content -> Binary,
         my_message -> Bool,
         created_at -> Timestamp,
+        level -> Nullable<BigInt>,
+        entry_index -> Nullable<Integer>,
+        uid -> Nullable<Binary>,
+        reply_to -> Nullable<Binary>,
+        edited_at -> Nullable<Timestamp>,
+        read_at -> Nullable<Timestamp>,
+        operation_hash -> Nullable<Text>,
+        operation_level -> Nullable<BigInt>,
+        delivery_status -> Nullable<Text>,
+        included_level -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    outbox (id) {
+        id -> Integer,
+        identity_id -> Integer,
+        contact_id -> Integer,
+        content -> Binary,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    reactions (message_id, my_reaction) {
+        message_id -> Integer,
+        my_reaction -> Bool,
+        reaction -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    seen_pokes (id) {
+        id -> Integer,
+        address -> Text,
+        data -> Binary,
+    }
+}
+
+table! {
+    undecoded_payloads (id) {
+        id -> Integer,
+        identity_id -> Integer,
+        contact_id -> Integer,
+        payload -> Binary,
+        created_at -> Timestamp,
+        level -> BigInt,
+        entry_index -> Integer,
     }
 }
 
 joinable!(clients -> contacts (contact_id));
 joinable!(clients -> identities (identity_id));
+joinable!(contacts -> identities (identity_id));
+joinable!(cover_traffic -> identities (identity_id));
+joinable!(key_changes -> contacts (contact_id));
 joinable!(messages -> contacts (contact_id));
 joinable!(messages -> identities (identity_id));
+joinable!(outbox -> contacts (contact_id));
+joinable!(outbox -> identities (identity_id));
+joinable!(reactions -> messages (message_id));
+joinable!(undecoded_payloads -> contacts (contact_id));
+joinable!(undecoded_payloads -> identities (identity_id));
 
-allow_tables_to_appear_in_same_query!(clients, contacts, identities, messages,);
+allow_tables_to_appear_in_same_query!(
+    clients,
+    contacts,
+    cover_traffic,
+    identities,
+    key_changes,
+    messages,
+    outbox,
+    reactions,
+    seen_pokes,
+    undecoded_payloads,
+);
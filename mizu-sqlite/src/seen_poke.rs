@@ -0,0 +1,8 @@
+use crate::schema::*;
+
+#[derive(Insertable)]
+#[table_name = "seen_pokes"]
+pub struct NewSeenPoke<'a> {
+    pub address: &'a str,
+    pub data: &'a [u8],
+}
@@ -0,0 +1,24 @@
+use crate::schema::*;
+use chrono::naive::NaiveDateTime;
+
+#[derive(Debug, Queryable)]
+pub struct UndecodedPayload {
+    pub id: i32,
+    pub identity_id: i32,
+    pub contact_id: i32,
+    pub payload: Vec<u8>,
+    pub created_at: NaiveDateTime,
+    pub level: i64,
+    pub entry_index: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "undecoded_payloads"]
+pub struct NewUndecodedPayload<'a> {
+    pub identity_id: i32,
+    pub contact_id: i32,
+    pub payload: &'a [u8],
+    pub created_at: NaiveDateTime,
+    pub level: i64,
+    pub entry_index: i32,
+}
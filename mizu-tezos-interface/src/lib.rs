@@ -2,10 +2,23 @@ use chrono::naive::NaiveDateTime;
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     pub content: Vec<u8>,
     pub timestamp: NaiveDateTime,
+    /// Level of the block which included the message.
+    pub level: i64,
+    /// Position of the message among messages posted in the same block.
+    pub index: i32,
+}
+
+impl Message {
+    /// Returns the position of the message in the postal box.
+    /// Messages are totally ordered by this key, unlike timestamps which are shared among
+    /// messages in the same block.
+    pub fn cursor(&self) -> (i64, i32) {
+        (self.level, self.index)
+    }
 }
 
 #[derive(Debug)]
@@ -16,6 +29,77 @@ pub struct UserData {
     pub pokes: Vec<Vec<u8>>,
 }
 
+/// A change to the user data of `address`, made by a contract call seen in a block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserDataUpdate {
+    /// `address` added `messages` to its postal box, and removed the messages at `removed`.
+    Posted {
+        address: String,
+        messages: Vec<Message>,
+        removed: Vec<usize>,
+    },
+    /// Someone poked `address`.
+    Poked { address: String, data: Vec<u8> },
+    /// `address` published its keys. The identity key is kept if it is `None`.
+    Registered {
+        address: String,
+        identity_key: Option<Vec<u8>>,
+        prekey: Vec<u8>,
+    },
+}
+
+impl UserDataUpdate {
+    /// Returns the address whose user data changed.
+    pub fn address(&self) -> &str {
+        match self {
+            UserDataUpdate::Posted { address, .. }
+            | UserDataUpdate::Poked { address, .. }
+            | UserDataUpdate::Registered { address, .. } => address,
+        }
+    }
+}
+
+/// A call to the Mizu contract, for sending several in one operation with `Tezos::batch`.
+/// Each is made as by the write method of the same name.
+#[derive(Debug, Clone, Copy)]
+pub enum Call<'a> {
+    Post {
+        add: &'a [&'a [u8]],
+        remove: &'a [&'a usize],
+    },
+    Poke {
+        target_address: &'a str,
+        data: &'a [u8],
+    },
+    Register {
+        identity_key: Option<&'a [u8]>,
+        prekey: &'a [u8],
+    },
+}
+
+/// Identifies an operation injected by a write method, to query its status later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationHandle {
+    /// The operation hash.
+    pub hash: String,
+    /// The level of the head when the operation was injected. The operation can only be included
+    /// in blocks after it.
+    pub level: i64,
+}
+
+/// Where an operation is on its way to the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationStatus {
+    /// The operation is waiting in the mempool.
+    Injected,
+    /// The operation was applied in the block at `level`, which has `confirmations` blocks on top.
+    Included { level: i64, confirmations: i64 },
+    /// The operation was refused by the node or failed to apply.
+    Failed,
+    /// The operation was not included before its branch got too old.
+    Expired,
+}
+
 struct Boxed<T>(T);
 // One of the most poor parts in Rust :(
 #[derive(Debug)]
@@ -37,7 +121,8 @@ impl Error for BoxedError {
     }
 }
 
-pub type BoxedTezos<'a> = Box<dyn Tezos<ReadError = BoxedError, WriteError = BoxedError> + 'a>;
+pub type BoxedTezos<'a> =
+    Box<dyn Tezos<ReadError = BoxedError, WriteError = BoxedError> + Send + Sync + 'a>;
 
 pub trait Tezos {
     type ReadError: Error + Send + Sync + 'static;
@@ -45,7 +130,7 @@ pub trait Tezos {
 
     fn boxed<'a>(self) -> BoxedTezos<'a>
     where
-        Self: Sized + 'a,
+        Self: Sized + Send + Sync + 'a,
     {
         Box::new(Boxed(self))
     }
@@ -53,17 +138,43 @@ pub trait Tezos {
     // Read
     /// Returns Tezos address.
     fn address(&self) -> &str;
-    fn secret_key(&self) -> &str;
     /// Retrieve Mizu user data associated with the specified address in Tezos.
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError>;
+    /// Like `retrieve_user_data`, but leaves the messages posted before `since` out of the postal
+    /// box, which spares implementations looking up where they were posted.
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> Result<Option<UserData>, Self::ReadError> {
+        let mut user_data = self.retrieve_user_data(address)?;
+        if let Some(user_data) = &mut user_data {
+            user_data
+                .postal_box
+                .retain(|message| message.timestamp >= since);
+        }
+        Ok(user_data)
+    }
+    /// Finds where an operation returned by a write method is.
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError>;
 
     // Update
     // TODO: I don't think double slices is a good interface, as we can't pass &[Vec<u8>] for example.
     // The best I came up with is taking `A: IntoIter<&[u8]>`, but it will break object safety...
     // TODO: remove should take `BigUint`s
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError>;
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError>;
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError>;
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError>;
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError>;
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError>;
+    /// Makes `calls` in turn in a single operation, which is included in one block and fails as
+    /// a whole. At least one call is needed.
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError>;
 }
 
 impl<'a, T: Tezos + ?Sized> Tezos for &'a T {
@@ -74,25 +185,44 @@ impl<'a, T: Tezos + ?Sized> Tezos for &'a T {
         (**self).address()
     }
 
-    fn secret_key(&self) -> &str {
-        (**self).secret_key()
-    }
-
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError> {
         (**self).retrieve_user_data(address)
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError> {
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> Result<Option<UserData>, Self::ReadError> {
+        (**self).retrieve_user_data_since(address, since)
+    }
+
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError> {
+        (**self).operation_status(operation)
+    }
+
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError> {
         (**self).post(add, remove)
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError> {
         (**self).poke(target_address, data)
     }
 
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError> {
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError> {
         (**self).register(identity_key, prekey)
     }
+
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError> {
+        (**self).batch(calls)
+    }
 }
 
 impl<T: Tezos + ?Sized> Tezos for Box<T> {
@@ -103,25 +233,44 @@ impl<T: Tezos + ?Sized> Tezos for Box<T> {
         (**self).address()
     }
 
-    fn secret_key(&self) -> &str {
-        (**self).secret_key()
-    }
-
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError> {
         (**self).retrieve_user_data(address)
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError> {
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> Result<Option<UserData>, Self::ReadError> {
+        (**self).retrieve_user_data_since(address, since)
+    }
+
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError> {
+        (**self).operation_status(operation)
+    }
+
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError> {
         (**self).post(add, remove)
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError> {
         (**self).poke(target_address, data)
     }
 
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError> {
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError> {
         (**self).register(identity_key, prekey)
     }
+
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError> {
+        (**self).batch(calls)
+    }
 }
 
 impl<T: Tezos + ?Sized> Tezos for std::sync::Arc<T> {
@@ -132,25 +281,44 @@ impl<T: Tezos + ?Sized> Tezos for std::sync::Arc<T> {
         (**self).address()
     }
 
-    fn secret_key(&self) -> &str {
-        (**self).secret_key()
-    }
-
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError> {
         (**self).retrieve_user_data(address)
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError> {
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> Result<Option<UserData>, Self::ReadError> {
+        (**self).retrieve_user_data_since(address, since)
+    }
+
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError> {
+        (**self).operation_status(operation)
+    }
+
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError> {
         (**self).post(add, remove)
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError> {
         (**self).poke(target_address, data)
     }
 
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError> {
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError> {
         (**self).register(identity_key, prekey)
     }
+
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError> {
+        (**self).batch(calls)
+    }
 }
 
 impl<T: Tezos> Tezos for Boxed<T> {
@@ -161,25 +329,46 @@ impl<T: Tezos> Tezos for Boxed<T> {
         self.0.address()
     }
 
-    fn secret_key(&self) -> &str {
-        self.0.secret_key()
-    }
-
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError> {
         self.0.retrieve_user_data(address).map_err(into_boxed_error)
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError> {
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> Result<Option<UserData>, Self::ReadError> {
+        self.0
+            .retrieve_user_data_since(address, since)
+            .map_err(into_boxed_error)
+    }
+
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError> {
+        self.0.operation_status(operation).map_err(into_boxed_error)
+    }
+
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError> {
         self.0.post(add, remove).map_err(into_boxed_error)
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError> {
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError> {
         self.0.poke(target_address, data).map_err(into_boxed_error)
     }
 
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError> {
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError> {
         self.0
             .register(identity_key, prekey)
             .map_err(into_boxed_error)
     }
+
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError> {
+        self.0.batch(calls).map_err(into_boxed_error)
+    }
 }
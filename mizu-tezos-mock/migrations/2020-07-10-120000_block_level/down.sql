@@ -0,0 +1,10 @@
+CREATE TABLE messages_old(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id)
+);
+INSERT INTO messages_old SELECT id, user_id, content, timestamp FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_old RENAME TO messages;
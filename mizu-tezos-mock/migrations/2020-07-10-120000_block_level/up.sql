@@ -0,0 +1,8 @@
+-- Messages posted by a single operation share a timestamp, so we emulate
+-- Tezos blocks by assigning a level to each post and an index to each message
+-- within it.
+ALTER TABLE messages ADD COLUMN level BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE messages ADD COLUMN entry_index INTEGER NOT NULL DEFAULT 0;
+
+-- Existing messages are treated as if each of them were posted in its own block.
+UPDATE messages SET level = id;
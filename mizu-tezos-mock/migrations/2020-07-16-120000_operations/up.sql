@@ -0,0 +1,7 @@
+-- Each write is treated as if it were an operation included in a new block,
+-- so that its status can be queried later.
+CREATE TABLE operations(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    hash TEXT NOT NULL UNIQUE,
+    level BIGINT NOT NULL
+);
@@ -9,9 +9,10 @@ extern crate diesel_migrations;
 use diesel::prelude::*;
 use diesel_migrations::embed_migrations;
 use mizu_tezos_interface::*;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 mod message;
+mod operation;
 mod poke;
 mod schema;
 mod user;
@@ -31,8 +32,8 @@ type DieselError = diesel::result::Error;
 pub struct TezosMock {
     /// Tezos address
     address: String,
-    secret_key: String,
-    conn: Rc<SqliteConnection>,
+    /// Shared among all users of the mock, as if it were the Tezos blockchain.
+    conn: Arc<Mutex<SqliteConnection>>,
 }
 
 embed_migrations!();
@@ -45,12 +46,45 @@ pub fn run_migrations(conn: &SqliteConnection) {
 }
 
 impl TezosMock {
-    pub fn new(address: String, secret_key: String, conn: Rc<SqliteConnection>) -> Self {
-        TezosMock {
-            address,
-            secret_key,
-            conn,
-        }
+    pub fn new(address: String, conn: Arc<Mutex<SqliteConnection>>) -> Self {
+        TezosMock { address, conn }
+    }
+
+    // Holding the lock throughout each operation makes operations atomic.
+    fn lock(&self) -> MutexGuard<'_, SqliteConnection> {
+        // The lock is poisoned only if another thread panicked while using the connection,
+        // which we don't attempt to recover from.
+        self.conn.lock().expect("mock connection lock poisoned")
+    }
+
+    /// Returns the level of the latest block.
+    fn head_level(conn: &SqliteConnection) -> Result<i64, DieselError> {
+        use schema::messages::dsl as messages_dsl;
+        use schema::operations::dsl as operations_dsl;
+
+        // Messages posted before operations were recorded have levels too.
+        let messages_level = messages_dsl::messages
+            .select(diesel::dsl::max(messages_dsl::level))
+            .first::<Option<i64>>(conn)?;
+        let operations_level = operations_dsl::operations
+            .select(diesel::dsl::max(operations_dsl::level))
+            .first::<Option<i64>>(conn)?;
+        Ok(messages_level.max(operations_level).unwrap_or(0))
+    }
+
+    /// Records an operation included in a new block on top of the head.
+    fn include_operation(conn: &SqliteConnection) -> Result<OperationHandle, DieselError> {
+        let head_level = Self::head_level(conn)?;
+        let level = head_level + 1;
+        let hash = format!("mock_operation_{}", level);
+        diesel::insert_into(schema::operations::table)
+            .values(&operation::NewOperation { hash: &hash, level })
+            .execute(conn)?;
+
+        Ok(OperationHandle {
+            hash,
+            level: head_level,
+        })
     }
 
     /*
@@ -71,29 +105,26 @@ impl Tezos for TezosMock {
         &self.address
     }
 
-    fn secret_key(&self) -> &str {
-        &self.secret_key
-    }
-
     fn retrieve_user_data(&self, address: &str) -> Result<Option<UserData>, Self::ReadError> {
         // According to https://docs.diesel.rs/diesel/associations/index.html,
         // selecting three tables is better than joining them.
-        // TODO: run queries within a transaction?
         use schema::messages::dsl as messages_dsl;
         use schema::pokes::dsl as pokes_dsl;
         use schema::users::dsl as users_dsl;
 
+        let conn = self.lock();
+
         if let Some(user) = users_dsl::users
             .filter(users_dsl::address.eq(address))
-            .first::<user::User>(&*self.conn)
+            .first::<user::User>(&*conn)
             .optional()?
         {
             let messages = message::Message::belonging_to(&user)
                 .order(messages_dsl::id.asc())
-                .load::<message::Message>(&*self.conn)?;
+                .load::<message::Message>(&*conn)?;
             let pokes = poke::Poke::belonging_to(&user)
                 .order(pokes_dsl::id.asc())
-                .load::<poke::Poke>(&*self.conn)?;
+                .load::<poke::Poke>(&*conn)?;
 
             Ok(Some(UserData {
                 identity_key: user.identity_key,
@@ -103,6 +134,8 @@ impl Tezos for TezosMock {
                     .map(|m| Message {
                         content: m.content,
                         timestamp: m.timestamp,
+                        level: m.level,
+                        index: m.entry_index,
                     })
                     .collect(),
                 pokes: pokes.into_iter().map(|p| p.content).collect(),
@@ -112,31 +145,121 @@ impl Tezos for TezosMock {
         }
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<(), Self::WriteError> {
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> Result<OperationStatus, Self::ReadError> {
+        use schema::operations::dsl;
+
+        let conn = self.lock();
+        let level = dsl::operations
+            .filter(dsl::hash.eq(&operation.hash))
+            .select(dsl::level)
+            .first::<i64>(&*conn)
+            .optional()?;
+        match level {
+            Some(level) => Ok(OperationStatus::Included {
+                level,
+                confirmations: Self::head_level(&conn)? - level,
+            }),
+            // Operations are included immediately, so unknown ones never will be.
+            None => Ok(OperationStatus::Expired),
+        }
+    }
+
+    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> Result<OperationHandle, Self::WriteError> {
+        self.batch(&[Call::Post { add, remove }])
+    }
+
+    fn poke(&self, target_address: &str, data: &[u8]) -> Result<OperationHandle, Self::WriteError> {
+        self.batch(&[Call::Poke {
+            target_address,
+            data,
+        }])
+    }
+
+    fn register(
+        &self,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<OperationHandle, Self::WriteError> {
+        self.batch(&[Call::Register {
+            identity_key,
+            prekey,
+        }])
+    }
+
+    fn batch(&self, calls: &[Call]) -> Result<OperationHandle, Self::WriteError> {
+        if calls.is_empty() {
+            return Err(DieselError::QueryBuilderError(
+                "an operation group needs at least one call".into(),
+            ));
+        }
+
+        let conn = self.lock();
+        conn.transaction(|| {
+            // Each operation is treated as if it were included in a new block.
+            let handle = Self::include_operation(&conn)?;
+            // Messages posted in the block are numbered in the order they were posted.
+            let mut entry_index = 0;
+            for call in calls {
+                match *call {
+                    Call::Post { add, remove } => {
+                        self.apply_post(&conn, add, remove, handle.level + 1, &mut entry_index)?
+                    }
+                    Call::Poke {
+                        target_address,
+                        data,
+                    } => Self::apply_poke(&conn, target_address, data)?,
+                    Call::Register {
+                        identity_key,
+                        prekey,
+                    } => self.apply_register(&conn, identity_key, prekey)?,
+                }
+            }
+            Ok(handle)
+        })
+    }
+}
+
+impl TezosMock {
+    fn apply_post(
+        &self,
+        conn: &SqliteConnection,
+        add: &[&[u8]],
+        remove: &[&usize],
+        level: i64,
+        entry_index: &mut i32,
+    ) -> Result<(), DieselError> {
         use schema::messages::dsl as messages_dsl;
         use schema::users::dsl as users_dsl;
 
-        // TODO: transaction?
         // First, retrieve all our posts to determine ones to be removed.
         let user = users_dsl::users
             .filter(users_dsl::address.eq(&self.address))
-            .first::<user::User>(&*self.conn)?;
+            .first::<user::User>(conn)?;
         let messages = message::Message::belonging_to(&user)
             .order(messages_dsl::id.asc())
-            .load::<message::Message>(&*self.conn)?;
+            .load::<message::Message>(conn)?;
         // TODO: return an error if the index is out of bounds (panics now).
         let remove: Vec<i32> = remove.iter().map(|i| messages[**i].id).collect();
 
         // Next, remove the corresponding messages.
         diesel::delete(messages_dsl::messages.filter(messages_dsl::id.eq_any(&remove)))
-            .execute(&*self.conn)?;
+            .execute(conn)?;
 
         // Finally, add messages.
         let new_messages: Vec<_> = add
             .iter()
-            .map(|content| message::NewMessage {
-                user_id: user.id,
-                content,
+            .map(|content| {
+                let new_message = message::NewMessage {
+                    user_id: user.id,
+                    content,
+                    level,
+                    entry_index: *entry_index,
+                };
+                *entry_index += 1;
+                new_message
             })
             .collect();
 
@@ -145,30 +268,39 @@ impl Tezos for TezosMock {
         }
         diesel::insert_into(schema::messages::table)
             .values(&new_messages)
-            .execute(&*self.conn)?;
+            .execute(conn)?;
 
         Ok(())
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> Result<(), Self::WriteError> {
-        // TODO: transaction?
+    fn apply_poke(
+        conn: &SqliteConnection,
+        target_address: &str,
+        data: &[u8],
+    ) -> Result<(), DieselError> {
         use schema::users::dsl;
+
         let user_id = dsl::users
             .filter(dsl::address.eq(target_address))
             .select(dsl::id)
-            .first::<i32>(&*self.conn)?;
+            .first::<i32>(conn)?;
 
         diesel::insert_into(schema::pokes::table)
             .values(&poke::NewPoke {
                 user_id,
                 content: data,
             })
-            .execute(&*self.conn)?;
+            .execute(conn)?;
 
         Ok(())
     }
 
-    fn register(&self, identity_key: Option<&[u8]>, prekey: &[u8]) -> Result<(), Self::WriteError> {
+    fn apply_register(
+        &self,
+        conn: &SqliteConnection,
+        identity_key: Option<&[u8]>,
+        prekey: &[u8],
+    ) -> Result<(), DieselError> {
         use schema::users::dsl;
 
         match identity_key {
@@ -178,7 +310,7 @@ impl Tezos for TezosMock {
                 diesel::update(dsl::users.filter(dsl::address.eq(&self.address)))
                     .set(dsl::prekey.eq(prekey))
             )
-            .execute(&*self.conn)?,
+            .execute(conn)?,
             Some(identity_key) => {
                 // As our schema declares address column to be unique, this query
                 // - updates identity_key and prekey if the address already exists; or
@@ -188,7 +320,7 @@ impl Tezos for TezosMock {
                     identity_key,
                     prekey,
                 }))
-                .execute(&*self.conn)?
+                .execute(conn)?
             }
         };
 
@@ -11,6 +11,8 @@ pub struct Message {
     pub user_id: i32,
     pub content: Vec<u8>,
     pub timestamp: NaiveDateTime,
+    pub level: i64,
+    pub entry_index: i32,
 }
 
 #[derive(Insertable)]
@@ -18,4 +20,6 @@ pub struct Message {
 pub struct NewMessage<'a> {
     pub user_id: i32,
     pub content: &'a [u8],
+    pub level: i64,
+    pub entry_index: i32,
 }
@@ -0,0 +1,8 @@
+use crate::schema::operations;
+
+#[derive(Insertable)]
+#[table_name = "operations"]
+pub struct NewOperation<'a> {
+    pub hash: &'a str,
+    pub level: i64,
+}
@@ -4,6 +4,16 @@ table! {
         user_id -> Integer,
         content -> Binary,
         timestamp -> Timestamp,
+        level -> BigInt,
+        entry_index -> Integer,
+    }
+}
+
+table! {
+    operations (id) {
+        id -> Integer,
+        hash -> Text,
+        level -> BigInt,
     }
 }
 
@@ -27,4 +37,4 @@ table! {
 joinable!(messages -> users (user_id));
 joinable!(pokes -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(messages, pokes, users,);
+allow_tables_to_appear_in_same_query!(messages, operations, pokes, users,);
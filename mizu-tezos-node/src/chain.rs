@@ -0,0 +1,733 @@
+//! The chain the node serves, which grows by a block for each operation injected into it.
+
+use crate::contract::{self, Storage, StoredUserData};
+use crate::NodeError;
+use base58check::ToBase58Check;
+use blake2::VarBlake2b;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Timelike, Utc};
+use digest::{Update, VariableOutput};
+use mizu_tezos_rpc::crypto::{self, GENERIC_OPERATION_WATERMARK};
+use mizu_tezos_rpc::forge;
+use mizu_tezos_rpc::michelson::{Address, BigMap, Expr, FromMichelson, ToMichelson};
+use mizu_tezos_rpc::operation::{Content, Operation};
+use mizu_tezos_rpc::{MizuOp, Protocol};
+use num_bigint::BigInt;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// The protocol the node pretends to run.
+pub(crate) const PROTOCOL: Protocol = Protocol::Carthage;
+/// The chain ID of carthagenet.
+pub(crate) const CHAIN_ID: &str = "NetXjD3HPJJjmcd";
+
+/// Operations can be included at most this many blocks after their branch.
+const MAX_OPERATIONS_TTL: i64 = 60;
+/// Validation passes of anonymous operations, like activations, and of manager operations.
+const ANONYMOUS_PASS: usize = 2;
+const MANAGER_PASS: usize = 3;
+const VALIDATION_PASSES: usize = 4;
+
+/// What a faucet account gets when it is activated, in mutez.
+const ACTIVATION_BALANCE: u64 = 10_000_000_000;
+
+const HARD_GAS_LIMIT_PER_OPERATION: u32 = 1_040_000;
+const HARD_STORAGE_LIMIT_PER_OPERATION: u32 = 60_000;
+const COST_PER_BYTE: u32 = 1000;
+const ORIGINATION_SIZE: u32 = 257;
+/// The gas contents consume. Contracts aren't interpreted, so every call costs the same.
+const REVEAL_GAS: u32 = 10_000;
+const TRANSACTION_GAS: u32 = 25_000;
+const ORIGINATION_GAS: u32 = 15_000;
+
+const BLOCK_HASH_PREFIX: &[u8] = &[1, 52];
+const OPERATION_HASH_PREFIX: &[u8] = &[5, 116];
+const KT1_PREFIX: &[u8] = &[2, 90, 121];
+
+/// The constants of carthagenet.
+pub(crate) fn constants() -> Value {
+    json!({
+        "proof_of_work_nonce_size": 8,
+        "nonce_length": 32,
+        "max_revelations_per_block": 32,
+        "max_operation_data_length": 16384,
+        "max_proposals_per_delegate": 20,
+        "preserved_cycles": 3,
+        "blocks_per_cycle": 2048,
+        "blocks_per_commitment": 32,
+        "blocks_per_roll_snapshot": 256,
+        "blocks_per_voting_period": 2048,
+        "time_between_blocks": ["30", "20"],
+        "endorsers_per_block": 32,
+        "hard_gas_limit_per_operation": HARD_GAS_LIMIT_PER_OPERATION.to_string(),
+        "hard_gas_limit_per_block": "10400000",
+        "proof_of_work_threshold": "70368744177663",
+        "tokens_per_roll": "8000000000",
+        "michelson_maximum_type_size": 1000,
+        "seed_nonce_revelation_tip": "125000",
+        "origination_size": ORIGINATION_SIZE,
+        "block_security_deposit": "512000000",
+        "endorsement_security_deposit": "64000000",
+        "baking_reward_per_endorsement": ["1250000", "187500"],
+        "endorsement_reward": ["1250000", "833333"],
+        "cost_per_byte": COST_PER_BYTE.to_string(),
+        "hard_storage_limit_per_operation": HARD_STORAGE_LIMIT_PER_OPERATION.to_string(),
+        "test_chain_duration": "61440",
+        "quorum_min": 2000,
+        "quorum_max": 7000,
+        "min_proposal_quorum": 500,
+        "initial_endorsers": 24,
+        "delay_per_missing_endorsement": "4",
+    })
+}
+
+fn blake2b(input: &[u8], size: usize) -> Vec<u8> {
+    let mut hasher = VarBlake2b::new(size).expect("output size should be valid for blake2b");
+    hasher.update(input);
+    hasher.finalize_boxed().to_vec()
+}
+
+fn base58check_encode(prefix: &[u8], bytes: &[u8]) -> String {
+    let input = [prefix, bytes].concat();
+    input[1..].to_base58check(input[0])
+}
+
+fn format_timestamp(timestamp: NaiveDateTime) -> String {
+    timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+pub(crate) struct Block {
+    pub(crate) hash: String,
+    pub(crate) level: i64,
+    predecessor: String,
+    pub(crate) timestamp: NaiveDateTime,
+    /// Operations by validation pass, with the results of their contents.
+    pub(crate) operations: Vec<Vec<Value>>,
+}
+
+impl Block {
+    pub(crate) fn header(&self) -> Value {
+        json!({
+            "protocol": PROTOCOL.hash(),
+            "chain_id": CHAIN_ID,
+            "hash": self.hash,
+            "level": self.level,
+            "proto": 1,
+            "predecessor": self.predecessor,
+            "timestamp": format_timestamp(self.timestamp),
+            "validation_pass": VALIDATION_PASSES,
+        })
+    }
+
+    pub(crate) fn operation_hashes(&self) -> Vec<Vec<Value>> {
+        self.operations
+            .iter()
+            .map(|pass| pass.iter().map(|op| op["hash"].clone()).collect())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Account {
+    balance: BigInt,
+    counter: BigInt,
+    public_key: Option<String>,
+}
+
+/// The state of the chain at its head. Older states aren't kept, so all blocks read this one.
+#[derive(Debug, Clone, Default)]
+struct Ledger {
+    accounts: HashMap<String, Account>,
+    /// The secrets faucet accounts which aren't activated yet are activated with, by address.
+    /// The protocol keeps blinded commitments instead, which are spent by activating.
+    commitments: HashMap<String, String>,
+    /// Originated contracts, which all run the Mizu contract, and the big maps they store.
+    contracts: HashMap<String, usize>,
+    big_maps: Vec<Storage>,
+}
+
+/// Operations injected while baking is held, and the ledger they were applied to.
+struct Mempool {
+    ledger: Ledger,
+    operations: Vec<(usize, Value)>,
+}
+
+pub(crate) struct Chain {
+    blocks: Vec<Block>,
+    ledger: Ledger,
+    /// Set while injected operations wait for `release` instead of being baked at once.
+    mempool: Option<Mempool>,
+}
+
+impl Chain {
+    pub(crate) fn new() -> Self {
+        let mut chain = Chain {
+            blocks: Vec::new(),
+            ledger: Ledger::default(),
+            mempool: None,
+        };
+        chain.bake(Vec::new());
+        chain
+    }
+
+    pub(crate) fn head(&self) -> &Block {
+        self.blocks.last().expect("the chain starts with a block")
+    }
+
+    /// Finds a block by hash, by level, or relative to the head as in `head~2`.
+    pub(crate) fn block(&self, id: &str) -> Option<&Block> {
+        let head_level = self.head().level;
+        let level = if id == "head" {
+            head_level
+        } else if let Some(depth) = id.strip_prefix("head~") {
+            head_level - depth.parse::<i64>().ok()?
+        } else if let Ok(level) = id.parse::<i64>() {
+            level
+        } else {
+            return self.blocks.iter().find(|block| block.hash == id);
+        };
+        if level < 0 {
+            return None;
+        }
+        self.blocks.get(level as usize)
+    }
+
+    /// Adds a block with `operations` on top of the head.
+    fn bake(&mut self, operations: Vec<(usize, Value)>) -> &Block {
+        let now = Utc::now()
+            .naive_utc()
+            .with_nanosecond(0)
+            .expect("0 is a valid nanosecond");
+        // Blocks have strictly increasing timestamps, which is how messages are found by block.
+        let (level, predecessor, timestamp) = match self.blocks.last() {
+            Some(head) => (
+                head.level + 1,
+                head.hash.clone(),
+                now.max(head.timestamp + Duration::seconds(1)),
+            ),
+            None => (0, String::new(), now),
+        };
+
+        let mut passes = vec![Vec::new(); VALIDATION_PASSES];
+        for (pass, operation) in operations {
+            passes[pass].push(operation);
+        }
+        let preimage = json!([predecessor, level, format_timestamp(timestamp), passes]);
+        self.blocks.push(Block {
+            hash: base58check_encode(
+                BLOCK_HASH_PREFIX,
+                &blake2b(preimage.to_string().as_bytes(), 32),
+            ),
+            level,
+            predecessor,
+            timestamp,
+            operations: passes,
+        });
+        self.head()
+    }
+
+    /// The timestamp of the next block, which operations are applied as of.
+    fn next_timestamp(&self) -> NaiveDateTime {
+        let now = Utc::now().naive_utc();
+        now.max(self.head().timestamp + Duration::seconds(1))
+            .with_nanosecond(0)
+            .expect("0 is a valid nanosecond")
+    }
+
+    /// Lets the faucet account `address` be activated once with `secret`.
+    pub(crate) fn commit(&mut self, address: &str, secret: &str) {
+        self.ledger
+            .commitments
+            .insert(address.to_string(), secret.to_string());
+    }
+
+    /// Gives `mutez` to `address`, creating the account if needed.
+    pub(crate) fn fund(&mut self, address: &str, mutez: u64) {
+        let account = self.ledger.accounts.entry(address.to_string()).or_default();
+        account.balance += mutez;
+    }
+
+    /// Returns the counter of an implicit account, or `None` if it doesn't exist.
+    pub(crate) fn counter(&self, address: &str) -> Option<&BigInt> {
+        self.ledger
+            .accounts
+            .get(address)
+            .map(|account| &account.counter)
+    }
+
+    /// Returns the balance of an implicit account, or `None` if it doesn't exist.
+    pub(crate) fn balance(&self, address: &str) -> Option<&BigInt> {
+        self.ledger
+            .accounts
+            .get(address)
+            .map(|account| &account.balance)
+    }
+
+    /// Returns the revealed public key of an implicit account.
+    pub(crate) fn manager_key(&self, address: &str) -> Option<&str> {
+        self.ledger
+            .accounts
+            .get(address)
+            .and_then(|account| account.public_key.as_deref())
+    }
+
+    /// Returns the storage of a contract, which is the ID of its big map.
+    pub(crate) fn storage(&self, address: &str) -> Option<Expr> {
+        let id = *self.ledger.contracts.get(address)?;
+        Some(BigMap::<Address, StoredUserData>::Id(id.into()).to_michelson())
+    }
+
+    /// Looks up the value of a big map by the hash of its key.
+    pub(crate) fn big_map_value(&self, id: usize, key_hash: &str) -> Option<Expr> {
+        // Big maps are keyed by address rather than by hash, which is slow but only matters
+        // with many users.
+        self.ledger
+            .big_maps
+            .get(id)?
+            .iter()
+            .find(|(address, _)| key_hash_of(address).as_deref() == Some(key_hash))
+            .map(|(_, user_data)| user_data.to_michelson())
+    }
+
+    /// Forges an operation, as the node does for clients which don't forge themselves.
+    pub(crate) fn forge(&self, op: &Operation) -> Result<String, NodeError> {
+        Ok(hex::encode(forge::forge_operation(op)?))
+    }
+
+    /// Applies an operation as if it were in the next block, without checking its signature,
+    /// and returns its contents with their results.
+    pub(crate) fn run(&self, op: &Operation) -> Result<Vec<Value>, NodeError> {
+        self.check_branch(&op.branch)?;
+        let nonce = blake2b(&forge::forge_operation(op)?, 32);
+        self.ledger
+            .clone()
+            .apply(op, false, &nonce, self.next_timestamp())
+    }
+
+    /// Like `run`, but checks the signature too, as the operation would be injected.
+    pub(crate) fn preapply(&self, op: &Operation) -> Result<Vec<Value>, NodeError> {
+        self.check_branch(&op.branch)?;
+        let nonce = blake2b(&forge::forge_operation(op)?, 32);
+        self.ledger
+            .clone()
+            .apply(op, true, &nonce, self.next_timestamp())
+    }
+
+    /// Applies a signed operation in a new block, returning its hash along with the block.
+    ///
+    /// While baking is held, the operation is applied on top of those waiting in the mempool
+    /// instead, and no block is baked.
+    pub(crate) fn inject(&mut self, signed: &[u8]) -> Result<(String, Option<&Block>), NodeError> {
+        // Anonymous operations like activations are injected unsigned, and they are never
+        // grouped with manager operations, which are signed.
+        let op = match forge::unforge_operation(signed, false) {
+            Ok(op) if op.contents.iter().all(|c| c.manager().is_none()) => op,
+            _ => forge::unforge_operation(signed, true)?,
+        };
+        self.check_branch(&op.branch)?;
+
+        let digest = blake2b(signed, 32);
+        let hash = base58check_encode(OPERATION_HASH_PREFIX, &digest);
+        let now = self.next_timestamp();
+        let ledger = match &mut self.mempool {
+            Some(mempool) => &mut mempool.ledger,
+            None => &mut self.ledger,
+        };
+        let contents = ledger.apply(&op, true, &digest, now)?;
+
+        let pass = if op.contents.iter().all(|c| c.manager().is_none()) {
+            ANONYMOUS_PASS
+        } else {
+            MANAGER_PASS
+        };
+        let mut operation = json!({
+            "protocol": PROTOCOL.hash(),
+            "chain_id": CHAIN_ID,
+            "hash": hash,
+            "branch": op.branch,
+            "contents": contents,
+        });
+        if let Some(signature) = op.signature {
+            operation["signature"] = Value::String(signature);
+        }
+        if let Some(mempool) = &mut self.mempool {
+            mempool.operations.push((pass, operation));
+            return Ok((hash, None));
+        }
+        let block = self.bake(vec![(pass, operation)]);
+        Ok((hash, Some(block)))
+    }
+
+    /// Keeps the operations injected from now on in the mempool, until `release`.
+    pub(crate) fn hold(&mut self) {
+        if self.mempool.is_none() {
+            self.mempool = Some(Mempool {
+                ledger: self.ledger.clone(),
+                operations: Vec::new(),
+            });
+        }
+    }
+
+    /// Bakes the operations held in the mempool in a single block, and bakes operations at once
+    /// again. Returns the block if there were operations to bake.
+    pub(crate) fn release(&mut self) -> Option<&Block> {
+        let mempool = self.mempool.take()?;
+        if mempool.operations.is_empty() {
+            return None;
+        }
+        self.ledger = mempool.ledger;
+        Some(self.bake(mempool.operations))
+    }
+
+    /// The operations waiting in the mempool, in the order they were injected, without the
+    /// results of their contents.
+    pub(crate) fn pending_operations(&self) -> Vec<Value> {
+        let operations = self.mempool.iter().flat_map(|mempool| &mempool.operations);
+        operations
+            .map(|(_, operation)| {
+                let mut operation = operation.clone();
+                if let Some(contents) = operation["contents"].as_array_mut() {
+                    for content in contents {
+                        if let Some(content) = content.as_object_mut() {
+                            content.remove("metadata");
+                        }
+                    }
+                }
+                operation
+            })
+            .collect()
+    }
+
+    fn check_branch(&self, branch: &str) -> Result<(), NodeError> {
+        let block = self
+            .blocks
+            .iter()
+            .find(|block| block.hash == branch)
+            .ok_or_else(|| NodeError::UnknownBranch(branch.to_string()))?;
+        if self.head().level - block.level > MAX_OPERATIONS_TTL {
+            return Err(NodeError::OutdatedBranch(branch.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// The hash a big map indexes an address by.
+fn key_hash_of(address: &str) -> Option<String> {
+    let packed = forge::pack(&forge::address_expr(address).ok()?).ok()?;
+    Some(forge::script_expr_hash(&packed))
+}
+
+impl Ledger {
+    /// Applies an operation, returning its contents with their results.
+    ///
+    /// Operations which can't pay for themselves are refused, leaving the ledger as it was. If
+    /// one of their contents fails, the others are undone but the fees are still paid.
+    fn apply(
+        &mut self,
+        op: &Operation,
+        check_signature: bool,
+        nonce: &[u8],
+        now: NaiveDateTime,
+    ) -> Result<Vec<Value>, NodeError> {
+        if op.contents.is_empty() {
+            return Err(NodeError::EmptyOperation);
+        }
+        if op.contents.iter().all(|c| c.manager().is_none()) {
+            return self.activate(&op.contents);
+        }
+
+        let source = &op.contents[0]
+            .manager()
+            .ok_or(NodeError::MixedOperation)?
+            .source;
+        if op
+            .contents
+            .iter()
+            .any(|c| c.manager().map(|manager| &manager.source) != Some(source))
+        {
+            return Err(NodeError::MixedOperation);
+        }
+
+        let public_key = self.public_key_of(source, &op.contents)?;
+        if check_signature {
+            let signature = op.signature.as_ref().ok_or(NodeError::MissingSignature)?;
+            let signature = crypto::decode_signature(signature)?;
+            let bytes = forge::forge_operation(op)?;
+            if !crypto::verify(GENERIC_OPERATION_WATERMARK, &bytes, &signature, &public_key)? {
+                return Err(NodeError::InvalidSignature);
+            }
+        }
+        self.pay_fees(source, &op.contents)?;
+
+        Ok(self.apply_contents(source, &op.contents, nonce, now))
+    }
+
+    fn activate(&mut self, contents: &[Content]) -> Result<Vec<Value>, NodeError> {
+        let mut commitments = self.commitments.clone();
+        for content in contents {
+            if let Content::ActivateAccount { pkh, secret } = content {
+                if commitments.remove(pkh).as_ref() != Some(secret) {
+                    return Err(NodeError::InvalidActivation(pkh.clone()));
+                }
+            }
+        }
+        self.commitments = commitments;
+
+        Ok(contents
+            .iter()
+            .map(|content| {
+                let mut value = content.to_json();
+                if let Content::ActivateAccount { pkh, .. } = content {
+                    let account = self.accounts.entry(pkh.clone()).or_default();
+                    account.balance += ACTIVATION_BALANCE;
+                    value["metadata"] = json!({
+                        "balance_updates": [{
+                            "kind": "contract",
+                            "contract": pkh,
+                            "change": ACTIVATION_BALANCE.to_string(),
+                        }],
+                    });
+                }
+                value
+            })
+            .collect())
+    }
+
+    /// Returns the key manager operations of `source` are signed with, which the operation may
+    /// reveal itself.
+    fn public_key_of(&self, source: &str, contents: &[Content]) -> Result<String, NodeError> {
+        let account = self
+            .accounts
+            .get(source)
+            .ok_or_else(|| NodeError::EmptyImplicitContract(source.to_string()))?;
+        match (&account.public_key, &contents[0]) {
+            (Some(_), Content::Reveal { .. }) => {
+                Err(NodeError::PreviouslyRevealedKey(source.to_string()))
+            }
+            (Some(public_key), _) => Ok(public_key.clone()),
+            (None, Content::Reveal { public_key, .. }) => {
+                check_public_key(source, public_key)?;
+                Ok(public_key.clone())
+            }
+            (None, _) => Err(NodeError::UnrevealedKey(source.to_string())),
+        }
+    }
+
+    /// Checks the counters and limits of the contents, and takes their fees.
+    fn pay_fees(&mut self, source: &str, contents: &[Content]) -> Result<(), NodeError> {
+        let mut account = self.accounts[source].clone();
+        for manager in contents.iter().filter_map(Content::manager) {
+            let expected = &account.counter + 1;
+            if manager.counter != expected {
+                let (contract, found) = (source.to_string(), manager.counter.clone());
+                return Err(if manager.counter < expected {
+                    NodeError::CounterInThePast {
+                        contract,
+                        expected,
+                        found,
+                    }
+                } else {
+                    NodeError::CounterInTheFuture {
+                        contract,
+                        expected,
+                        found,
+                    }
+                });
+            }
+            if manager.gas_limit > HARD_GAS_LIMIT_PER_OPERATION.into() {
+                return Err(NodeError::GasLimitTooHigh);
+            }
+            if manager.storage_limit > HARD_STORAGE_LIMIT_PER_OPERATION.into() {
+                return Err(NodeError::StorageLimitTooHigh);
+            }
+            if manager.fee > account.balance {
+                return Err(NodeError::BalanceTooLow {
+                    contract: source.to_string(),
+                    balance: account.balance,
+                    amount: manager.fee.clone(),
+                });
+            }
+            account.counter = manager.counter.clone();
+            account.balance -= &manager.fee;
+        }
+        self.accounts.insert(source.to_string(), account);
+        Ok(())
+    }
+
+    fn apply_contents(
+        &mut self,
+        source: &str,
+        contents: &[Content],
+        nonce: &[u8],
+        now: NaiveDateTime,
+    ) -> Vec<Value> {
+        let mut applied = self.clone();
+        let mut results = Vec::new();
+        let mut failed = false;
+        for (index, content) in contents.iter().enumerate() {
+            let result = if failed {
+                json!({ "status": "skipped" })
+            } else {
+                match applied.apply_content(source, content, nonce, index, now) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        failed = true;
+                        json!({ "status": "failed", "errors": [e.to_json()] })
+                    }
+                }
+            };
+            results.push(result);
+        }
+
+        if failed {
+            for result in &mut results {
+                if result["status"] == "applied" {
+                    result["status"] = Value::from("backtracked");
+                }
+            }
+        } else {
+            *self = applied;
+        }
+
+        contents
+            .iter()
+            .zip(results)
+            .map(|(content, result)| {
+                let mut value = content.to_json();
+                value["metadata"] = json!({ "operation_result": result });
+                value
+            })
+            .collect()
+    }
+
+    /// Applies a content, returning its result. `nonce` and `index` tell contracts originated
+    /// by different contents apart.
+    fn apply_content(
+        &mut self,
+        source: &str,
+        content: &Content,
+        nonce: &[u8],
+        index: usize,
+        now: NaiveDateTime,
+    ) -> Result<Value, NodeError> {
+        let manager = content
+            .manager()
+            .expect("only manager operations have results");
+        let gas = match content {
+            Content::ActivateAccount { .. } => unreachable!("activations have no results"),
+            Content::Reveal { .. } => REVEAL_GAS,
+            Content::Transaction { .. } => TRANSACTION_GAS,
+            Content::Origination { .. } => ORIGINATION_GAS,
+        };
+        if manager.gas_limit < gas.into() {
+            return Err(NodeError::GasExhausted);
+        }
+
+        let mut result = match content {
+            Content::ActivateAccount { .. } => unreachable!("activations have no results"),
+            Content::Reveal { public_key, .. } => {
+                let account = self
+                    .accounts
+                    .get_mut(source)
+                    .expect("sources have paid fees");
+                if account.public_key.is_some() {
+                    return Err(NodeError::PreviouslyRevealedKey(source.to_string()));
+                }
+                check_public_key(source, public_key)?;
+                account.public_key = Some(public_key.clone());
+                json!({ "status": "applied" })
+            }
+            Content::Transaction {
+                destination,
+                parameters,
+                ..
+            } => {
+                let id = *self
+                    .contracts
+                    .get(destination)
+                    .ok_or_else(|| NodeError::UnknownContract(destination.clone()))?;
+                let parameter = MizuOp::from_michelson(parameters)
+                    .map_err(|_| NodeError::BadContractParameter(destination.clone()))?;
+                let size = contract::call(&mut self.big_maps[id], source, now, parameter).map_err(
+                    |with| NodeError::ScriptRejected {
+                        contract: destination.clone(),
+                        with,
+                    },
+                )?;
+                self.burn(source, size, &manager.storage_limit)?;
+                json!({
+                    "status": "applied",
+                    "storage": { "int": id.to_string() },
+                    "paid_storage_size_diff": size.to_string(),
+                })
+            }
+            Content::Origination { code, storage, .. } => {
+                let entries = match BigMap::<Address, StoredUserData>::from_michelson(storage) {
+                    Ok(BigMap::Literal(entries)) => entries,
+                    _ => return Err(NodeError::IllTypedStorage),
+                };
+                let script_size = forge::pack(code)?.len() + forge::pack(storage)?.len();
+                self.burn(
+                    source,
+                    script_size + ORIGINATION_SIZE as usize,
+                    &manager.storage_limit,
+                )?;
+
+                let address = originated_address(nonce, index);
+                self.contracts.insert(address.clone(), self.big_maps.len());
+                self.big_maps.push(
+                    entries
+                        .into_iter()
+                        .map(|(Address(address), user_data)| (address, user_data))
+                        .collect(),
+                );
+                json!({
+                    "status": "applied",
+                    "originated_contracts": [address],
+                    "paid_storage_size_diff": script_size.to_string(),
+                })
+            }
+        };
+        result["consumed_gas"] = Value::String(gas.to_string());
+        Ok(result)
+    }
+
+    /// Burns the tez paying for `size` more bytes of storage.
+    fn burn(&mut self, source: &str, size: usize, storage_limit: &BigInt) -> Result<(), NodeError> {
+        if BigInt::from(size) > *storage_limit {
+            return Err(NodeError::StorageExhausted);
+        }
+        let account = self
+            .accounts
+            .get_mut(source)
+            .expect("sources have paid fees");
+        let amount = BigInt::from(size) * COST_PER_BYTE;
+        if amount > account.balance {
+            return Err(NodeError::BalanceTooLow {
+                contract: source.to_string(),
+                balance: account.balance.clone(),
+                amount,
+            });
+        }
+        account.balance -= amount;
+        Ok(())
+    }
+}
+
+fn check_public_key(source: &str, public_key: &str) -> Result<(), NodeError> {
+    if crypto::derive_address_from_pubkey(public_key)? == source {
+        Ok(())
+    } else {
+        Err(NodeError::InconsistentPublicKey(
+            source.to_string(),
+            public_key.to_string(),
+        ))
+    }
+}
+
+/// Derives the address of the contract originated by the `index`th content of an operation,
+/// from the hash of the operation.
+fn originated_address(nonce: &[u8], index: usize) -> String {
+    let nonce = [nonce, &(index as u32).to_be_bytes()].concat();
+    base58check_encode(KT1_PREFIX, &blake2b(&nonce, 20))
+}
@@ -0,0 +1,203 @@
+//! The Mizu contract of `contract.ml`, which the node runs natively instead of interpreting its
+//! Michelson.
+
+use chrono::naive::NaiveDateTime;
+use mizu_tezos_rpc::forge;
+use mizu_tezos_rpc::michelson::{Address, Bytes, FromMichelson, ToMichelson};
+use mizu_tezos_rpc::MizuOp;
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use std::collections::BTreeMap;
+
+/// A message as the contract stores it.
+#[derive(Debug, Clone, PartialEq, ToMichelson, FromMichelson)]
+pub(crate) struct StoredMessage {
+    content: Bytes,
+    timestamp: NaiveDateTime,
+}
+
+/// User data as the contract stores it, which SCaml lays out as a balanced tree of pairs.
+#[derive(Debug, Clone, PartialEq, ToMichelson, FromMichelson)]
+#[michelson(layout = "balanced")]
+pub(crate) struct StoredUserData {
+    identity_key: Bytes,
+    prekey: Bytes,
+    postal_box: Vec<StoredMessage>,
+    pokes: Vec<Bytes>,
+}
+
+/// The storage of the contract, a big map from addresses to their user data.
+pub(crate) type Storage = BTreeMap<String, StoredUserData>;
+
+/// Calls the contract as `sender` in a block made at `now`, returning the bytes the storage
+/// grew by. Fails with the message the contract fails with.
+pub(crate) fn call(
+    storage: &mut Storage,
+    sender: &str,
+    now: NaiveDateTime,
+    parameter: MizuOp,
+) -> Result<usize, &'static str> {
+    let (address, user_data) = match parameter {
+        MizuOp::Post(add, remove) => {
+            // You can only post to your own postal box.
+            let mut user_data = storage
+                .get(sender)
+                .cloned()
+                .ok_or("user is not registered")?;
+            user_data.postal_box = post(user_data.postal_box, add, &remove, now)?;
+            (sender.to_string(), user_data)
+        }
+        MizuOp::Poke(Address(address), data) => {
+            // Anybody can poke anybody else.
+            let mut user_data = storage.get(&address).cloned().ok_or("invalid address")?;
+            user_data.pokes.insert(0, data);
+            (address, user_data)
+        }
+        MizuOp::Register(identity_key, prekey) => {
+            let user_data = match (identity_key, storage.get(sender)) {
+                (None, None) => return Err("must register with identity key"),
+                (Some(identity_key), None) => StoredUserData {
+                    identity_key,
+                    prekey,
+                    postal_box: Vec::new(),
+                    pokes: Vec::new(),
+                },
+                (None, Some(user_data)) => StoredUserData {
+                    prekey,
+                    ..user_data.clone()
+                },
+                (Some(identity_key), Some(user_data)) => StoredUserData {
+                    identity_key,
+                    prekey,
+                    ..user_data.clone()
+                },
+            };
+            (sender.to_string(), user_data)
+        }
+    };
+
+    let old_size = storage.get(&address).map_or(0, size);
+    let new_size = size(&user_data);
+    storage.insert(address, user_data);
+    Ok(new_size.saturating_sub(old_size))
+}
+
+/// Removes the messages at `remove` from `postal_box` and adds `add`, the way the contract folds
+/// over the postal box: the messages kept end up reversed, in front of the new ones.
+fn post(
+    postal_box: Vec<StoredMessage>,
+    add: Vec<Bytes>,
+    remove: &[BigUint],
+    now: NaiveDateTime,
+) -> Result<Vec<StoredMessage>, &'static str> {
+    let mut result: Vec<StoredMessage> = add
+        .into_iter()
+        .rev()
+        .map(|content| StoredMessage {
+            content,
+            timestamp: now,
+        })
+        .collect();
+    // `remove` has to be sorted in ascending order and within the postal box.
+    let mut remove = remove.iter().peekable();
+    for (index, message) in postal_box.into_iter().enumerate() {
+        if remove.peek().and_then(|i| i.to_usize()) == Some(index) {
+            remove.next();
+        } else {
+            result.push(message);
+        }
+    }
+    if remove.next().is_some() {
+        return Err("Assert failure");
+    }
+    result.reverse();
+    Ok(result)
+}
+
+/// The number of bytes user data takes in storage.
+fn size(user_data: &StoredUserData) -> usize {
+    forge::pack(&user_data.to_michelson())
+        .expect("user data is made of forgeable values")
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mizu_tezos_rpc::michelson::Expr;
+
+    fn timestamp(seconds: i64) -> NaiveDateTime {
+        NaiveDateTime::from_michelson(&Expr::Int(seconds.into())).unwrap()
+    }
+
+    fn contents(storage: &Storage, address: &str) -> Vec<Vec<u8>> {
+        storage[address]
+            .postal_box
+            .iter()
+            .map(|message| message.content.0.clone())
+            .collect()
+    }
+
+    #[test]
+    fn calls_follow_the_contract() {
+        let alice = "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB";
+        let bob = "tz1PtxhBALR5qE3heaR9AY8khUBCkuGwUKjA";
+        let mut storage = Storage::new();
+        let register = |identity_key: Option<u8>, prekey: u8| {
+            MizuOp::Register(identity_key.map(|k| Bytes(vec![k])), Bytes(vec![prekey]))
+        };
+        let post = |add: &[u8], remove: &[u32]| {
+            MizuOp::Post(
+                add.iter().map(|&b| Bytes(vec![b])).collect(),
+                remove.iter().map(|&i| BigUint::from(i)).collect(),
+            )
+        };
+
+        assert_eq!(
+            call(&mut storage, alice, timestamp(0), register(None, 1)),
+            Err("must register with identity key")
+        );
+        assert_eq!(
+            call(&mut storage, alice, timestamp(0), post(&[1], &[])),
+            Err("user is not registered")
+        );
+        assert!(call(&mut storage, alice, timestamp(0), register(Some(0), 1)).unwrap() > 0);
+
+        call(&mut storage, alice, timestamp(1), post(&[1, 2], &[])).unwrap();
+        assert_eq!(contents(&storage, alice), vec![vec![1], vec![2]]);
+        call(&mut storage, alice, timestamp(2), post(&[3], &[0])).unwrap();
+        assert_eq!(contents(&storage, alice), vec![vec![2], vec![3]]);
+        assert_eq!(storage[alice].postal_box[1].timestamp, timestamp(2));
+        call(&mut storage, alice, timestamp(3), post(&[4], &[])).unwrap();
+        // Kept messages are reversed, as the contract folds them onto the new ones.
+        assert_eq!(contents(&storage, alice), vec![vec![3], vec![2], vec![4]]);
+        assert_eq!(
+            call(&mut storage, alice, timestamp(4), post(&[], &[3])),
+            Err("Assert failure")
+        );
+        assert_eq!(
+            call(&mut storage, alice, timestamp(4), post(&[], &[1, 0])),
+            Err("Assert failure")
+        );
+
+        let poke = |data: u8| MizuOp::Poke(Address(alice.to_string()), Bytes(vec![data]));
+        call(&mut storage, bob, timestamp(5), poke(1)).unwrap();
+        call(&mut storage, bob, timestamp(5), poke(2)).unwrap();
+        assert_eq!(storage[alice].pokes, vec![Bytes(vec![2]), Bytes(vec![1])]);
+        assert_eq!(
+            call(
+                &mut storage,
+                alice,
+                timestamp(5),
+                MizuOp::Poke(Address(bob.to_string()), Bytes(Vec::new()))
+            ),
+            Err("invalid address")
+        );
+
+        call(&mut storage, alice, timestamp(6), register(None, 7)).unwrap();
+        assert_eq!(storage[alice].identity_key, Bytes(vec![0]));
+        assert_eq!(storage[alice].prekey, Bytes(vec![7]));
+        assert_eq!(storage[alice].pokes.len(), 2);
+    }
+}
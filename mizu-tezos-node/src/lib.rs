@@ -0,0 +1,853 @@
+//! A local Tezos node which serves the RPCs `TezosRpc` uses, so that it can be tested without
+//! network access.
+//!
+//! The node bakes a block for each operation injected into it, and runs the Mizu contract
+//! natively for every contract it originates. Operations are checked the way the protocol checks
+//! them, signatures included. Operations are applied or refused at once, but baking can be held
+//! to keep them in a mempool (see `Node::hold_operations`).
+
+mod chain;
+mod contract;
+mod server;
+
+use chain::{Chain, CHAIN_ID, PROTOCOL};
+use mizu_tezos_rpc::operation::Operation;
+use mizu_tezos_rpc::{crypto, forge, lock, RpcError};
+use num_bigint::BigInt;
+use serde_json::{json, Value};
+use server::{Request, Response};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use thiserror::Error;
+
+/// Errors of the protocol are prefixed with its name.
+const PROTOCOL_ERROR_PREFIX: &str = "proto.006-PsCARTHA.";
+
+/// Failures the node reports, which `TezosRpc` reads as `RpcError::Node`.
+#[derive(Error, Debug)]
+pub enum NodeError {
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("unknown block: {0}")]
+    UnknownBlock(String),
+    #[error("nothing found at {0}")]
+    NotFound(String),
+    #[error("failed to parse the operation: {0}")]
+    Forge(forge::Error),
+    #[error("invalid key or signature: {0}")]
+    Crypto(crypto::Error),
+    #[error("operations need contents")]
+    EmptyOperation,
+    #[error("manager operations must all have the same source, without anonymous operations")]
+    MixedOperation,
+    #[error("unknown branch {0}")]
+    UnknownBranch(String),
+    #[error("outdated branch {0}")]
+    OutdatedBranch(String),
+    #[error("the operation isn't signed")]
+    MissingSignature,
+    #[error("the signature doesn't match the operation")]
+    InvalidSignature,
+    #[error("{0} has no commitment for this secret, or is already activated")]
+    InvalidActivation(String),
+    #[error("{0} has no tez")]
+    EmptyImplicitContract(String),
+    #[error("the public key of {0} is already revealed")]
+    PreviouslyRevealedKey(String),
+    #[error("the public key of {0} isn't revealed")]
+    UnrevealedKey(String),
+    #[error("{1} isn't the public key of {0}")]
+    InconsistentPublicKey(String, String),
+    #[error("counter {found} of {contract} was already used, expected {expected}")]
+    CounterInThePast {
+        contract: String,
+        expected: BigInt,
+        found: BigInt,
+    },
+    #[error("counter {found} of {contract} isn't yet reached, expected {expected}")]
+    CounterInTheFuture {
+        contract: String,
+        expected: BigInt,
+        found: BigInt,
+    },
+    #[error("the balance {balance} of {contract} is too low to pay {amount}")]
+    BalanceTooLow {
+        contract: String,
+        balance: BigInt,
+        amount: BigInt,
+    },
+    #[error("the gas limit is above the hard limit")]
+    GasLimitTooHigh,
+    #[error("the storage limit is above the hard limit")]
+    StorageLimitTooHigh,
+    #[error("the operation ran out of gas")]
+    GasExhausted,
+    #[error("the operation ran out of storage")]
+    StorageExhausted,
+    #[error("unknown contract {0}")]
+    UnknownContract(String),
+    #[error("invalid parameter for {0}")]
+    BadContractParameter(String),
+    #[error("the storage of the contract is ill-typed")]
+    IllTypedStorage,
+    #[error("{contract} failed with {with}")]
+    ScriptRejected {
+        contract: String,
+        with: &'static str,
+    },
+}
+
+impl From<forge::Error> for NodeError {
+    fn from(e: forge::Error) -> Self {
+        NodeError::Forge(e)
+    }
+}
+
+impl From<crypto::Error> for NodeError {
+    fn from(e: crypto::Error) -> Self {
+        NodeError::Crypto(e)
+    }
+}
+
+impl From<RpcError> for NodeError {
+    fn from(e: RpcError) -> Self {
+        NodeError::BadRequest(e.to_string())
+    }
+}
+
+impl NodeError {
+    /// Whether the error may go away (`temporary`), may go away on another branch (`branch`),
+    /// or won't (`permanent`), and its ID, as the node would report it.
+    fn kind_and_id(&self) -> (&'static str, String) {
+        use NodeError::*;
+
+        let proto = |id: &str| [PROTOCOL_ERROR_PREFIX, id].concat();
+        match self {
+            BadRequest(_) | Forge(_) | Crypto(_) | EmptyOperation | MixedOperation => {
+                ("permanent", "node.prevalidation.parse_error".to_string())
+            }
+            UnknownBlock(_) | NotFound(_) => ("permanent", "rpc.not_found".to_string()),
+            UnknownBranch(_) => ("branch", "node.prevalidation.unknown_branch".to_string()),
+            OutdatedBranch(_) => ("branch", proto("operation.outdated")),
+            MissingSignature => ("permanent", proto("operation.missing_signature")),
+            InvalidSignature => ("permanent", proto("operation.invalid_signature")),
+            InvalidActivation(_) => ("permanent", proto("operation.invalid_activation")),
+            EmptyImplicitContract(_) => ("temporary", proto("implicit.empty_implicit_contract")),
+            PreviouslyRevealedKey(_) => ("branch", proto("contract.previously_revealed_key")),
+            UnrevealedKey(_) => ("branch", proto("contract.unrevealed_key")),
+            InconsistentPublicKey(_, _) => (
+                "permanent",
+                proto("contract.manager.inconsistent_public_key"),
+            ),
+            CounterInThePast { .. } => ("branch", proto("contract.counter_in_the_past")),
+            CounterInTheFuture { .. } => ("temporary", proto("contract.counter_in_the_future")),
+            BalanceTooLow { .. } => ("temporary", proto("contract.balance_too_low")),
+            GasLimitTooHigh => ("permanent", proto("gas_limit_too_high")),
+            StorageLimitTooHigh => ("permanent", proto("storage_limit_too_high")),
+            GasExhausted => ("temporary", proto("gas_exhausted.operation")),
+            StorageExhausted => ("temporary", proto("storage_exhausted.operation")),
+            UnknownContract(_) => ("temporary", proto("contract.non_existing_contract")),
+            BadContractParameter(_) => ("permanent", proto("michelson_v1.bad_contract_parameter")),
+            IllTypedStorage => ("permanent", proto("michelson_v1.ill_typed_data")),
+            ScriptRejected { .. } => ("temporary", proto("michelson_v1.script_rejected")),
+        }
+    }
+
+    /// The error as the node describes it, which is an element of the array it responds with.
+    pub fn to_json(&self) -> Value {
+        let (kind, id) = self.kind_and_id();
+        let mut value = json!({ "kind": kind, "id": id, "message": self.to_string() });
+        match self {
+            NodeError::CounterInThePast {
+                contract,
+                expected,
+                found,
+            }
+            | NodeError::CounterInTheFuture {
+                contract,
+                expected,
+                found,
+            } => {
+                value["contract"] = json!(contract);
+                value["expected"] = json!(expected.to_string());
+                value["found"] = json!(found.to_string());
+            }
+            NodeError::ScriptRejected { contract, with } => {
+                value["location"] = json!(0);
+                value["contract"] = json!(contract);
+                value["with"] = json!({ "string": with });
+            }
+            _ => {}
+        }
+        value
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            NodeError::BadRequest(_) => 400,
+            NodeError::UnknownBlock(_) | NodeError::NotFound(_) => 404,
+            _ => 500,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, NodeError>;
+
+/// A node with a chain of its own, shared by all the clients connected to it.
+pub struct Node {
+    chain: Mutex<Chain>,
+    /// The streams of `monitor/heads/main` which are still open.
+    heads: Mutex<Vec<Sender<Value>>>,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node {
+            chain: Mutex::new(Chain::new()),
+            heads: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn chain(&self) -> MutexGuard<'_, Chain> {
+        lock(&self.chain)
+    }
+
+    /// Lets the faucet account `address` be activated with `secret`, as if its commitment had
+    /// been in the genesis block.
+    pub fn commit(&self, address: &str, secret: &str) {
+        self.chain().commit(address, secret);
+    }
+
+    /// Gives `mutez` to the implicit account `address`, as if it had been in the genesis block.
+    pub fn fund(&self, address: &str, mutez: u64) {
+        self.chain().fund(address, mutez);
+    }
+
+    /// Closes the open streams of new heads, as a restarting node would.
+    pub fn close_head_streams(&self) {
+        lock(&self.heads).clear();
+    }
+
+    /// Keeps the operations injected from now on in the mempool rather than baking a block for
+    /// each, as when several operations wait for the same block.
+    pub fn hold_operations(&self) {
+        self.chain().hold();
+    }
+
+    /// Bakes the operations held since `hold_operations` in a single block, and bakes a block for
+    /// each operation again.
+    pub fn release_operations(&self) {
+        let mut chain = self.chain();
+        if let Some(block) = chain.release() {
+            self.publish_head(block.header());
+        }
+    }
+
+    /// Sends a new head to the open streams of `monitor/heads/main`, closing those which have
+    /// no reader left.
+    fn publish_head(&self, header: Value) {
+        lock(&self.heads).retain(|sender| sender.send(header.clone()).is_ok());
+    }
+
+    /// Serves clients connecting to `listener`, each in a thread of its own.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let node = Arc::clone(&self);
+            thread::spawn(move || {
+                server::handle_connection(stream, |request| node.handle(request))
+            });
+        }
+        Ok(())
+    }
+
+    /// Serves on a port of localhost in the background, returning its address.
+    pub fn spawn(self: Arc<Self>) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || self.serve(listener));
+        Ok(address)
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let result = match request.method.as_str() {
+            "GET" => self.get(&path),
+            "POST" => serde_json::from_slice(&request.body)
+                .map_err(|e| NodeError::BadRequest(e.to_string()))
+                .and_then(|body| self.post(&path, &body).map(Response::Json)),
+            method => Err(NodeError::BadRequest(format!(
+                "unsupported method {}",
+                method
+            ))),
+        };
+        result.unwrap_or_else(|e| Response::Error(e.status(), json!([e.to_json()])))
+    }
+
+    fn get(&self, path: &[&str]) -> Result<Response> {
+        let chain = self.chain();
+        let value = match path {
+            ["monitor", "bootstrapped"] => {
+                let head = chain.head();
+                json!({ "block": head.hash, "timestamp": head.header()["timestamp"] })
+            }
+            ["monitor", "heads", "main"] => {
+                // The stream starts with the current head, and is registered before the lock is
+                // released so that no head is missed.
+                let (sender, receiver) = channel();
+                sender
+                    .send(chain.head().header())
+                    .expect("the receiver is alive");
+                lock(&self.heads).push(sender);
+                return Ok(Response::Stream(receiver));
+            }
+            ["chains", "main", "chain_id"] => json!(CHAIN_ID),
+            ["chains", "main", "mempool", "pending_operations"] => json!({
+                "applied": chain.pending_operations(),
+                "refused": [],
+                "branch_refused": [],
+                "branch_delayed": [],
+                "unprocessed": [],
+            }),
+            ["chains", "main", "blocks", block_id, rest @ ..] => {
+                let block = chain
+                    .block(block_id)
+                    .ok_or_else(|| NodeError::UnknownBlock(block_id.to_string()))?;
+                let not_found = || NodeError::NotFound(path.join("/"));
+                let index = |s: &str| s.parse::<usize>().map_err(|_| not_found());
+                match rest {
+                    ["hash"] => json!(block.hash),
+                    ["header"] => block.header(),
+                    ["protocols"] => json!({
+                        "protocol": PROTOCOL.hash(),
+                        "next_protocol": PROTOCOL.hash(),
+                    }),
+                    ["context", "constants"] => chain::constants(),
+                    ["context", "contracts", address, "counter"] => {
+                        json!(chain.counter(address).ok_or_else(not_found)?.to_string())
+                    }
+                    ["context", "contracts", address, "balance"] => {
+                        json!(chain.balance(address).ok_or_else(not_found)?.to_string())
+                    }
+                    // Unknown accounts have no key rather than not being found.
+                    ["context", "contracts", address, "manager_key"] => {
+                        json!(chain.manager_key(address))
+                    }
+                    ["context", "contracts", address, "storage"] => {
+                        serde_json::to_value(chain.storage(address).ok_or_else(not_found)?)
+                            .expect("expressions serialize")
+                    }
+                    ["context", "big_maps", id, key_hash] => serde_json::to_value(
+                        chain
+                            .big_map_value(index(id)?, key_hash)
+                            .ok_or_else(not_found)?,
+                    )
+                    .expect("expressions serialize"),
+                    ["operation_hashes"] => json!(block.operation_hashes()),
+                    ["operation_hashes", pass] => {
+                        json!(block
+                            .operation_hashes()
+                            .get(index(pass)?)
+                            .ok_or_else(not_found)?)
+                    }
+                    ["operations"] => json!(block.operations),
+                    ["operations", pass] => {
+                        json!(block.operations.get(index(pass)?).ok_or_else(not_found)?)
+                    }
+                    ["operations", pass, i] => block
+                        .operations
+                        .get(index(pass)?)
+                        .and_then(|operations| operations.get(index(i).ok()?))
+                        .cloned()
+                        .ok_or_else(not_found)?,
+                    _ => return Err(not_found()),
+                }
+            }
+            _ => return Err(NodeError::NotFound(path.join("/"))),
+        };
+        Ok(Response::Json(value))
+    }
+
+    fn post(&self, path: &[&str], body: &Value) -> Result<Value> {
+        let operation = |value: &Value| Operation::from_json(value).map_err(NodeError::from);
+        match path {
+            ["chains", "main", "blocks", _, "helpers", "forge", "operations"] => {
+                Ok(json!(self.chain().forge(&operation(body)?)?))
+            }
+            ["chains", "main", "blocks", _, "helpers", "scripts", "run_operation"] => {
+                if body["chain_id"] != CHAIN_ID {
+                    return Err(NodeError::BadRequest(format!(
+                        "unknown chain {}",
+                        body["chain_id"]
+                    )));
+                }
+                let contents = self.chain().run(&operation(&body["operation"])?)?;
+                Ok(json!({ "contents": contents }))
+            }
+            ["chains", "main", "blocks", _, "helpers", "preapply", "operations"] => {
+                let operations = body
+                    .as_array()
+                    .ok_or_else(|| NodeError::BadRequest("expected operations".to_string()))?;
+                let chain = self.chain();
+                operations
+                    .iter()
+                    .map(|value| {
+                        let op = operation(value)?;
+                        if op.protocol.as_deref() != Some(PROTOCOL.hash()) {
+                            return Err(NodeError::BadRequest(format!(
+                                "unknown protocol {:?}",
+                                op.protocol
+                            )));
+                        }
+                        let contents = chain.preapply(&op)?;
+                        Ok(json!({ "contents": contents, "signature": op.signature }))
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .map(Value::Array)
+            }
+            ["injection", "operation"] => {
+                let signed = body
+                    .as_str()
+                    .and_then(|signed| hex::decode(signed).ok())
+                    .ok_or_else(|| NodeError::BadRequest("expected a hex string".to_string()))?;
+                let mut chain = self.chain();
+                let (hash, head) = chain.inject(&signed)?;
+                if let Some(head) = head {
+                    self.publish_head(head.header());
+                }
+                Ok(json!(hash))
+            }
+            _ => Err(NodeError::NotFound(path.join("/"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mizu_tezos_interface::{Call, OperationHandle, OperationStatus, Tezos, UserDataUpdate};
+    use mizu_tezos_rpc::michelson::{BigMap, Bytes, Expr, ToMichelson};
+    use mizu_tezos_rpc::monitor::MonitorConfig;
+    use mizu_tezos_rpc::signer::InMemorySigner;
+    use mizu_tezos_rpc::{MizuOp, TezosRpc, WaitConfig};
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use url::Url;
+
+    /// A faucet account, which activates itself.
+    const ALICE: &str = "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB";
+    const ALICE_SECRET_KEY: &str = "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk";
+    const ALICE_ACTIVATION_SECRET: &str = "41f98b15efc63fa893d61d7d6eee4a2ce9427ac4";
+    /// An account funded from the start, with a key on another curve.
+    const BOB: &str = "tz2Ch1abG7FNiibmV26Uzgdsnfni9XGrk5wD";
+    const BOB_SECRET_KEY: &str = "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE";
+    /// Another faucet account, which isn't activated by the tests sharing `rpc`.
+    const CAROL: &str = "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx";
+    const CAROL_SECRET_KEY: &str = "edsk3gUfUPyBSfrS9CCgmCiQsTCHGkviBDusMxDJstFtojtc1zcpsh";
+    const CAROL_ACTIVATION_SECRET: &str = "9e6a3a1a6cd8a3cd5b4d9bd4a1bdc6c2f4a5e3f1";
+
+    fn new_node() -> Arc<Node> {
+        let node = Arc::new(Node::new());
+        node.commit(ALICE, ALICE_ACTIVATION_SECRET);
+        node.fund(BOB, 10_000_000);
+        node
+    }
+
+    fn spawn_node() -> SocketAddr {
+        new_node().spawn().unwrap()
+    }
+
+    fn rpc(node: SocketAddr, address: &str, secret_key: &str, contract: &str) -> TezosRpc {
+        let rpc = TezosRpc::new(
+            false,
+            Url::parse(&format!("http://{}/", node)).unwrap(),
+            address.to_string(),
+            Box::new(InMemorySigner::new(
+                address.to_string(),
+                secret_key.to_string(),
+            )),
+            contract.to_string(),
+        );
+        if address == ALICE {
+            rpc.with_activation_secret(ALICE_ACTIVATION_SECRET.to_string())
+        } else {
+            rpc
+        }
+    }
+
+    fn wait_config() -> WaitConfig {
+        WaitConfig {
+            confirmations: 0,
+            poll_interval: Duration::from_millis(10),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Originates a contract, which the node runs as the Mizu contract whatever its code is.
+    fn originate(node: SocketAddr) -> String {
+        let deployer = rpc(node, ALICE, ALICE_SECRET_KEY, "");
+        let storage = BigMap::<Expr, Expr>::Literal(Vec::new()).to_michelson();
+        let operation = deployer.originate(Expr::List(Vec::new()), storage).unwrap();
+        let receipt = deployer
+            .wait_for_operation(&operation, &wait_config())
+            .unwrap();
+        assert_eq!(receipt.originated_contracts.len(), 1);
+        receipt.originated_contracts[0].clone()
+    }
+
+    #[test]
+    fn tezos_rpc_works_against_the_node() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        let bob = rpc(node, BOB, BOB_SECRET_KEY, &contract);
+        let wait = |rpc: &TezosRpc, operation: OperationHandle| {
+            rpc.wait_for_operation(&operation, &wait_config())
+        };
+        let first_level = alice.head_level().unwrap();
+
+        assert!(bob.retrieve_user_data(ALICE).unwrap().is_none());
+        wait(&alice, alice.register(Some(b"alice"), b"prekey").unwrap()).unwrap();
+        let posted = wait(&alice, alice.post(&[b"hello", b"world"], &[]).unwrap()).unwrap();
+        // Bob reveals his key along with his first call.
+        wait(&bob, bob.register(Some(b"bob"), b"prekey").unwrap()).unwrap();
+        let poke = bob.poke(ALICE, b"poke").unwrap();
+        wait(&bob, poke.clone()).unwrap();
+        wait(&alice, alice.post(&[], &[&0]).unwrap()).unwrap();
+
+        let user_data = bob.retrieve_user_data(ALICE).unwrap().unwrap();
+        assert_eq!(user_data.identity_key, b"alice");
+        assert_eq!(user_data.pokes, vec![b"poke".to_vec()]);
+        assert_eq!(user_data.postal_box.len(), 1);
+        assert_eq!(user_data.postal_box[0].content, b"world");
+        assert_eq!(user_data.postal_box[0].level, posted.level);
+        // Messages keep the index they were posted with when earlier ones are removed.
+        assert_eq!(user_data.postal_box[0].index, 1);
+        assert!(matches!(
+            bob.operation_status(&poke).unwrap(),
+            OperationStatus::Included { .. }
+        ));
+
+        // Removing messages which aren't there makes the contract fail.
+        let failed = alice.post(&[], &[&1]).unwrap();
+        assert!(wait(&alice, failed.clone()).is_err());
+        assert_eq!(
+            alice.operation_status(&failed).unwrap(),
+            OperationStatus::Failed
+        );
+        let unchanged = bob.retrieve_user_data(ALICE).unwrap().unwrap();
+        assert_eq!(unchanged.postal_box.len(), 1);
+
+        let config = MonitorConfig {
+            confirmations: 0,
+            ..MonitorConfig::default()
+        };
+        let addresses: HashSet<String> = vec![ALICE.to_string()].into_iter().collect();
+        let head_level = alice.head_level().unwrap();
+        let updates: Vec<UserDataUpdate> = alice
+            .monitor_heads(first_level, addresses, config)
+            .take((head_level - first_level) as usize)
+            .flat_map(|block| block.unwrap().updates)
+            .collect();
+        // Registering, two posts and a poke, without the failed post.
+        assert_eq!(updates.len(), 4);
+    }
+
+    #[test]
+    fn head_monitor_backfills_after_reconnecting() {
+        let node = new_node();
+        let address = Arc::clone(&node).spawn().unwrap();
+        let contract = originate(address);
+        let alice = rpc(address, ALICE, ALICE_SECRET_KEY, &contract);
+        let bob = rpc(address, BOB, BOB_SECRET_KEY, &contract);
+        alice.register(Some(b"alice"), b"prekey").unwrap();
+
+        let first_level = bob.head_level().unwrap();
+        let config = MonitorConfig {
+            confirmations: 0,
+            retry_interval: Duration::from_millis(10),
+            ..MonitorConfig::default()
+        };
+        let addresses: HashSet<String> = vec![ALICE.to_string()].into_iter().collect();
+        let mut monitor = bob.monitor_heads(first_level, addresses, config);
+
+        bob.poke(ALICE, b"connected").unwrap();
+        let block = monitor.next().unwrap().unwrap();
+        assert_eq!(block.level, first_level + 1);
+
+        // Blocks produced while the stream is closed are backfilled once reconnected.
+        node.close_head_streams();
+        bob.poke(ALICE, b"offline 1").unwrap();
+        bob.poke(ALICE, b"offline 2").unwrap();
+        let head_level = bob.head_level().unwrap();
+        assert!(monitor.next().unwrap().is_err());
+        let mut blocks = vec![];
+        while monitor.last_level() < head_level {
+            blocks.push(monitor.next().unwrap().unwrap());
+        }
+        let levels: Vec<i64> = blocks.iter().map(|block| block.level).collect();
+        assert_eq!(levels, (first_level + 2..=head_level).collect::<Vec<_>>());
+        assert_eq!(blocks[0].predecessor, block.hash);
+        assert_eq!(blocks[1].predecessor, blocks[0].hash);
+        let pokes: Vec<_> = blocks
+            .into_iter()
+            .flat_map(|block| block.updates)
+            .filter_map(|update| match update {
+                UserDataUpdate::Poked { data, .. } => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pokes, vec![b"offline 1".to_vec(), b"offline 2".to_vec()]);
+    }
+
+    #[test]
+    fn big_map_reads_work() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+
+        let register = MizuOp::Register(
+            Some(Bytes(vec![0xca, 0xfe, 0xba, 0xbe])),
+            Bytes(vec![0xca, 0xfe, 0xba, 0xbe]),
+        );
+        alice.run_mizu_operation(&register).unwrap();
+
+        assert!(alice.get_from_big_map(ALICE).unwrap().is_some());
+        // Unknown addresses have no entry rather than failing.
+        assert!(alice
+            .get_from_big_map("tz1PtxhBALR5qE3heaR9AY8khUBCkuGwUKjA")
+            .unwrap()
+            .is_none());
+
+        let reader = TezosRpc::read_only(
+            false,
+            Url::parse(&format!("http://{}/", node)).unwrap(),
+            contract,
+        );
+        assert_eq!(
+            reader.get_from_big_map(ALICE).unwrap(),
+            alice.get_from_big_map(ALICE).unwrap()
+        );
+    }
+
+    #[test]
+    fn operation_status_is_tracked_across_blocks() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        let bob = rpc(node, BOB, BOB_SECRET_KEY, &contract);
+
+        let registered = alice.register(Some(b"alice"), b"prekey").unwrap();
+        let level = match alice.operation_status(&registered).unwrap() {
+            OperationStatus::Included {
+                level,
+                confirmations: 0,
+            } => level,
+            status => panic!("unexpected status {:?}", status),
+        };
+        // Blocks scanned before are skipped, but the including one is checked again.
+        for confirmations in 1..=3 {
+            bob.poke(ALICE, b"poke").unwrap();
+            assert_eq!(
+                alice.operation_status(&registered).unwrap(),
+                OperationStatus::Included {
+                    level,
+                    confirmations
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn activated_accounts_are_not_activated_again() {
+        let node = spawn_node();
+        let contract = originate(node);
+
+        // The activation goes through, but revealing the wrong key doesn't.
+        let wrong_key = rpc(node, ALICE, BOB_SECRET_KEY, &contract);
+        assert!(wrong_key.register(Some(b"alice"), b"prekey").is_err());
+
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        alice.register(Some(b"alice"), b"prekey").unwrap();
+        assert!(alice.retrieve_user_data(ALICE).unwrap().is_some());
+    }
+
+    #[test]
+    fn wrong_activation_secrets_are_reported() {
+        let node = new_node();
+        node.commit(CAROL, CAROL_ACTIVATION_SECRET);
+        let node = node.spawn().unwrap();
+        let contract = originate(node);
+        let carol = |secret: &str| {
+            rpc(node, CAROL, CAROL_SECRET_KEY, &contract).with_activation_secret(secret.to_string())
+        };
+
+        // The node refuses a wrong secret the way it refuses activating again, but the account
+        // has no tez then.
+        match carol(ALICE_ACTIVATION_SECRET).register(Some(b"carol"), b"prekey") {
+            Err(RpcError::Node { id, .. }) => {
+                assert_eq!(id, "proto.006-PsCARTHA.operation.invalid_activation")
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        carol(CAROL_ACTIVATION_SECRET)
+            .register(Some(b"carol"), b"prekey")
+            .unwrap();
+    }
+
+    #[test]
+    fn invalid_signatures_are_refused() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        alice
+            .wait_for_operation(
+                &alice.register(Some(b"alice"), b"prekey").unwrap(),
+                &wait_config(),
+            )
+            .unwrap();
+
+        // Alice's key is revealed, so the operation is signed with a key which isn't hers.
+        let impostor = rpc(node, ALICE, BOB_SECRET_KEY, &contract);
+        match impostor.post(&[b"forged"], &[]) {
+            Err(RpcError::Node { id, .. }) => {
+                assert_eq!(id, "proto.006-PsCARTHA.operation.invalid_signature")
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(alice
+            .retrieve_user_data(ALICE)
+            .unwrap()
+            .unwrap()
+            .postal_box
+            .is_empty());
+    }
+
+    #[test]
+    fn batches_are_included_in_one_block() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        let operation = alice
+            .batch(&[
+                Call::Register {
+                    identity_key: Some(b"alice"),
+                    prekey: b"prekey",
+                },
+                Call::Post {
+                    add: &[b"hello", b"world"],
+                    remove: &[],
+                },
+                Call::Post {
+                    add: &[b"again"],
+                    remove: &[&0],
+                },
+                Call::Poke {
+                    target_address: ALICE,
+                    data: b"poke",
+                },
+            ])
+            .unwrap();
+        let receipt = alice
+            .wait_for_operation(&operation, &wait_config())
+            .unwrap();
+
+        let user_data = alice.retrieve_user_data(ALICE).unwrap().unwrap();
+        let messages: Vec<_> = user_data
+            .postal_box
+            .iter()
+            .map(|message| (message.content.as_slice(), message.level, message.index))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (&b"world"[..], receipt.level, 1),
+                (&b"again"[..], receipt.level, 2)
+            ]
+        );
+        assert_eq!(user_data.pokes, vec![b"poke".to_vec()]);
+
+        assert!(matches!(alice.batch(&[]), Err(RpcError::EmptyBatch)));
+    }
+
+    #[test]
+    fn keys_are_revealed_once_while_groups_wait() {
+        let node = new_node();
+        let address = Arc::clone(&node).spawn().unwrap();
+        let contract = originate(address);
+        let alice = rpc(address, ALICE, ALICE_SECRET_KEY, &contract);
+        let bob = rpc(address, BOB, BOB_SECRET_KEY, &contract);
+        alice
+            .wait_for_operation(
+                &alice.register(Some(b"alice"), b"prekey").unwrap(),
+                &wait_config(),
+            )
+            .unwrap();
+
+        // Bob's first group reveals his key, and the second one mustn't reveal it again although
+        // the node doesn't know the key before the first one is included.
+        node.hold_operations();
+        let first = bob.poke(ALICE, b"first").unwrap();
+        let second = bob.poke(ALICE, b"second").unwrap();
+        node.release_operations();
+
+        let first = bob.wait_for_operation(&first, &wait_config()).unwrap();
+        let second = bob.wait_for_operation(&second, &wait_config()).unwrap();
+        assert_eq!(first.level, second.level);
+        // The contract puts new pokes first.
+        assert_eq!(
+            alice.retrieve_user_data(ALICE).unwrap().unwrap().pokes,
+            vec![b"second".to_vec(), b"first".to_vec()]
+        );
+    }
+
+    #[test]
+    fn counter_errors_are_recovered_from() {
+        let node = spawn_node();
+        let contract = originate(node);
+        let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+        alice
+            .wait_for_operation(
+                &alice.register(Some(b"alice"), b"prekey").unwrap(),
+                &wait_config(),
+            )
+            .unwrap();
+
+        // Clients sharing a key race for counters, and those which lose send their operations
+        // again.
+        let clients: Vec<_> = (0..2)
+            .map(|client| {
+                let contract = contract.clone();
+                thread::spawn(move || {
+                    let alice = rpc(node, ALICE, ALICE_SECRET_KEY, &contract);
+                    for message in 0..3 {
+                        alice.post(&[&[client, message]], &[]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(
+            alice
+                .retrieve_user_data(ALICE)
+                .unwrap()
+                .unwrap()
+                .postal_box
+                .len(),
+            6
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use mizu_tezos_node::Node;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use structopt::StructOpt;
+
+/// An account to fund, given as `ADDRESS=MUTEZ`.
+#[derive(Debug)]
+struct Funding {
+    address: String,
+    mutez: u64,
+}
+
+impl std::str::FromStr for Funding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, mutez) = split_pair(s, "ADDRESS=MUTEZ")?;
+        let mutez = mutez
+            .parse()
+            .map_err(|e| format!("invalid amount in {}: {}", s, e))?;
+        Ok(Funding {
+            address: address.to_string(),
+            mutez,
+        })
+    }
+}
+
+/// A faucet account which can be activated, given as `ADDRESS=SECRET` with the secret of its
+/// faucet file.
+#[derive(Debug)]
+struct Commitment {
+    address: String,
+    secret: String,
+}
+
+impl std::str::FromStr for Commitment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, secret) = split_pair(s, "ADDRESS=SECRET")?;
+        Ok(Commitment {
+            address: address.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+fn split_pair<'a>(s: &'a str, expected: &str) -> Result<(&'a str, &'a str), String> {
+    let equals = s
+        .find('=')
+        .ok_or_else(|| format!("expected {} but found {}", expected, s))?;
+    Ok((&s[..equals], &s[equals + 1..]))
+}
+
+/// Serves the Tezos node RPCs the Mizu client uses, from a local chain.
+#[derive(StructOpt, Debug)]
+struct Opt {
+    #[structopt(long, default_value = "127.0.0.1:8732")]
+    address: SocketAddr,
+    /// Accounts to give tez to, as ADDRESS=MUTEZ.
+    #[structopt(long)]
+    fund: Vec<Funding>,
+    /// Faucet accounts which can activate themselves, as ADDRESS=SECRET.
+    #[structopt(long)]
+    commit: Vec<Commitment>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let node = Arc::new(Node::new());
+    for funding in &opt.fund {
+        node.fund(&funding.address, funding.mutez);
+    }
+    for commitment in &opt.commit {
+        node.commit(&commitment.address, &commitment.secret);
+    }
+
+    let listener = TcpListener::bind(opt.address).expect("failed to bind the address");
+    eprintln!("serving on {}", opt.address);
+    node.serve(listener).expect("failed to accept a connection");
+}
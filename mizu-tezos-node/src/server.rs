@@ -0,0 +1,127 @@
+//! Just enough HTTP/1.1 to serve JSON to `TezosRpc`: bodies with a `Content-Length`, connections
+//! kept alive, and chunked responses for streams.
+
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) body: Vec<u8>,
+}
+
+pub(crate) enum Response {
+    Json(Value),
+    /// An error status with the errors of the node.
+    Error(u16, Value),
+    /// Values sent one after another until the client disconnects, as `monitor` RPCs do.
+    Stream(Receiver<Value>),
+}
+
+/// Answers the requests of a connection with `handle` until the client closes it.
+pub(crate) fn handle_connection<F>(stream: TcpStream, handle: F)
+where
+    F: Fn(Request) -> Response,
+{
+    // Errors mean the client went away, and there is nobody to report them to.
+    let _ = serve(stream, handle);
+}
+
+fn serve<F>(stream: TcpStream, handle: F) -> io::Result<()>
+where
+    F: Fn(Request) -> Response,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(request) = read_request(&mut reader)? {
+        match handle(request) {
+            Response::Json(value) => write_json(&mut writer, 200, &value)?,
+            Response::Error(status, value) => write_json(&mut writer, status, &value)?,
+            Response::Stream(values) => {
+                write!(
+                    writer,
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: application/json\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n"
+                )?;
+                for value in values {
+                    let chunk = format!("{}\n", value);
+                    write!(writer, "{:x}\r\n{}\r\n", chunk.len(), chunk)?;
+                    writer.flush()?;
+                }
+                // The stream only ends with the node, which takes the connection with it.
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next request, or returns `None` if the client closed the connection.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid request line: {:?}", line),
+            ))
+        }
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = split_header(header) {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")
+                })?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request { method, path, body }))
+}
+
+fn split_header(header: &str) -> Option<(&str, &str)> {
+    let colon = header.find(':')?;
+    Some((&header[..colon], header[colon + 1..].trim()))
+}
+
+fn write_json<W: Write>(writer: &mut W, status: u16, value: &Value) -> io::Result<()> {
+    let body = value.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
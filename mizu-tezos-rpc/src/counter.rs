@@ -0,0 +1,213 @@
+//! Counters of manager operations, tracked locally so that an address can have several
+//! operations waiting for the same block.
+//!
+//! Each manager operation takes the next counter of its source, and the node only knows the
+//! counters of included operations. Operations still in the mempool are accounted for here.
+
+use num_bigint::BigInt;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// An operation group we injected, whose contents use the counters from `first` to `last`.
+#[derive(Debug, Clone, PartialEq)]
+struct Pending {
+    hash: String,
+    first: BigInt,
+    last: BigInt,
+    /// Whether the group reveals the public key of its source.
+    reveals: bool,
+}
+
+/// The counters an address used in operations which may not be included yet.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    /// In the order they were injected, which is the order of their counters.
+    pending: Vec<Pending>,
+}
+
+impl Counters {
+    /// Returns the last counter used by `source`, given its counter at the head and the pending
+    /// operations of the mempool, as returned by `pending_operations`.
+    ///
+    /// The mempool has to be read before the head, so that operations leaving the mempool for a
+    /// block are seen in one or the other.
+    pub(crate) fn last_used(
+        &mut self,
+        source: &str,
+        head_counter: &BigInt,
+        pending_operations: &Value,
+    ) -> BigInt {
+        // Operations with counters up to the head one are included, or never will be.
+        self.pending.retain(|pending| pending.last > *head_counter);
+        // An operation which left the mempool without being included was refused, and those
+        // after it are waiting for counters which will never be used.
+        let pending_hashes = pending_hashes(pending_operations);
+        if let Some(refused) = self
+            .pending
+            .iter()
+            .position(|pending| !pending_hashes.contains(&pending.hash.as_str()))
+        {
+            self.pending.truncate(refused);
+        }
+
+        // Other clients with the same key may have operations in the mempool too. Counters
+        // after a gap are left out, as the next operation has to fill it.
+        let mut used: BTreeSet<BigInt> = pending_counters(pending_operations, source).collect();
+        for pending in &self.pending {
+            let mut counter = pending.first.clone();
+            while counter <= pending.last {
+                used.insert(counter.clone());
+                counter += 1;
+            }
+        }
+        let mut last = head_counter.clone();
+        while used.contains(&(&last + 1)) {
+            last += 1;
+        }
+        last
+    }
+
+    /// Records an injected operation group using the counters from `first` to `last`.
+    pub(crate) fn record(&mut self, hash: String, first: BigInt, last: BigInt, reveals: bool) {
+        self.pending.push(Pending {
+            hash,
+            first,
+            last,
+            reveals,
+        });
+    }
+
+    /// Whether a group revealing the public key of the source may still be included, in which
+    /// case the groups following it must not reveal the key again.
+    ///
+    /// Like the counters, this is up to date once `last_used` has reconciled the pending groups.
+    pub(crate) fn reveal_pending(&self) -> bool {
+        self.pending.iter().any(|pending| pending.reveals)
+    }
+
+    /// Forgets the pending operations, after the node disagreed with the counters we used.
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// The operations which may still be included, which the mempool lists as hashes with their
+/// contents, or as objects for the `applied` ones.
+fn mempool_operations(pending_operations: &Value) -> impl Iterator<Item = (&str, &Value)> {
+    ["applied", "branch_delayed", "unprocessed"]
+        .iter()
+        .filter_map(move |kind| pending_operations[kind].as_array())
+        .flatten()
+        .filter_map(|operation| match operation {
+            Value::Array(pair) => Some((pair.first()?.as_str()?, pair.get(1)?)),
+            _ => Some((operation["hash"].as_str()?, operation)),
+        })
+}
+
+fn pending_hashes(pending_operations: &Value) -> Vec<&str> {
+    mempool_operations(pending_operations)
+        .map(|(hash, _)| hash)
+        .collect()
+}
+
+/// The counters of the manager operations of `source` in the mempool.
+fn pending_counters<'a>(
+    pending_operations: &'a Value,
+    source: &'a str,
+) -> impl Iterator<Item = BigInt> + 'a {
+    mempool_operations(pending_operations)
+        .filter_map(|(_, operation)| operation["contents"].as_array())
+        .flatten()
+        .filter(move |content| content["source"] == source)
+        .filter_map(|content| content["counter"].as_str()?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SOURCE: &str = "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB";
+
+    fn applied(hash: &str, source: &str, counter: u32) -> Value {
+        json!({
+            "hash": hash,
+            "branch": "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2",
+            "contents": [{ "kind": "transaction", "source": source, "counter": counter.to_string() }],
+        })
+    }
+
+    fn mempool(applied: Vec<Value>) -> Value {
+        json!({
+            "applied": applied,
+            "refused": [],
+            "branch_refused": [],
+            "branch_delayed": [],
+            "unprocessed": [],
+        })
+    }
+
+    #[test]
+    fn pending_counters_are_reconciled() {
+        let mut counters = Counters::default();
+        let head = BigInt::from(10);
+        assert_eq!(counters.last_used(SOURCE, &head, &mempool(vec![])), head);
+
+        // Operations waiting in the mempool keep their counters taken.
+        counters.record("oo1".to_string(), 11.into(), 12.into(), false);
+        counters.record("oo2".to_string(), 13.into(), 13.into(), false);
+        let both = mempool(vec![applied("oo1", SOURCE, 12), applied("oo2", SOURCE, 13)]);
+        assert_eq!(counters.last_used(SOURCE, &head, &both), 13.into());
+
+        // Included operations are forgotten.
+        let second = mempool(vec![applied("oo2", SOURCE, 13)]);
+        assert_eq!(counters.last_used(SOURCE, &12.into(), &second), 13.into());
+        assert_eq!(counters.pending.len(), 1);
+
+        // So are refused operations, and those after them, whose counters are reused.
+        counters.record("oo3".to_string(), 14.into(), 14.into(), false);
+        let third = mempool(vec![applied("oo3", SOURCE, 14)]);
+        assert_eq!(counters.last_used(SOURCE, &12.into(), &third), 12.into());
+        assert!(counters.pending.is_empty());
+    }
+
+    #[test]
+    fn pending_reveals_are_tracked() {
+        let mut counters = Counters::default();
+        counters.record("oo1".to_string(), 1.into(), 2.into(), true);
+        counters.record("oo2".to_string(), 3.into(), 3.into(), false);
+        let both = mempool(vec![applied("oo1", SOURCE, 2), applied("oo2", SOURCE, 3)]);
+        counters.last_used(SOURCE, &0.into(), &both);
+        assert!(counters.reveal_pending());
+
+        // Once the reveal is included, the key is known to the node instead.
+        let second = mempool(vec![applied("oo2", SOURCE, 3)]);
+        counters.last_used(SOURCE, &2.into(), &second);
+        assert!(!counters.reveal_pending());
+
+        // A refused reveal has to be sent again, as the groups after it are refused too.
+        counters.record("oo3".to_string(), 1.into(), 2.into(), true);
+        counters.last_used(SOURCE, &0.into(), &mempool(vec![]));
+        assert!(!counters.reveal_pending());
+    }
+
+    #[test]
+    fn other_clients_are_accounted_for() {
+        let mut counters = Counters::default();
+        let others = mempool(vec![
+            applied("oo1", SOURCE, 11),
+            applied("oo2", "tz1PtxhBALR5qE3heaR9AY8khUBCkuGwUKjA", 20),
+        ]);
+        assert_eq!(counters.last_used(SOURCE, &10.into(), &others), 11.into());
+
+        let delayed = json!({
+            "applied": [applied("oo1", SOURCE, 11)],
+            "branch_delayed": [["oo3", applied("oo3", SOURCE, 12)]],
+        });
+        assert_eq!(counters.last_used(SOURCE, &10.into(), &delayed), 12.into());
+
+        // Operations after a gap wait for an operation to fill it.
+        let gap = mempool(vec![applied("oo4", SOURCE, 13)]);
+        assert_eq!(counters.last_used(SOURCE, &11.into(), &gap), 11.into());
+    }
+}
@@ -1,10 +1,16 @@
 use base58check::{FromBase58Check, ToBase58Check};
 use blake2::VarBlake2b;
+use crypto_secretbox::aead::{AeadInPlace, KeyInit};
+use crypto_secretbox::{Nonce, Tag, XSalsa20Poly1305};
 use digest::{Update, VariableOutput};
+use hmac::Hmac;
+use k256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
 use serde::{Deserialize, Serialize};
+use sha2::Sha512;
 use signatory::public_key::PublicKeyed;
 use signatory_ring::ed25519;
 use signature::Signer;
+use std::convert::TryFrom;
 use std::fs::read_to_string;
 use std::path::Path;
 use thiserror::Error;
@@ -19,6 +25,12 @@ pub enum Error {
     KeyContent(base58check::FromBase58CheckError),
     #[error("invalid secret key length: expected 32 bytes but found {0} bytes")]
     SeedLength(usize),
+    #[error("invalid {0:?} secret key")]
+    InvalidKey(Curve),
+    #[error("invalid {0:?} public key")]
+    InvalidPublicKey(Curve),
+    #[error("incorrect passphrase")]
+    Passphrase,
     #[error("some error occured when creating signature")]
     Signature,
     #[error(
@@ -29,68 +41,366 @@ pub enum Error {
     AddressMismatch(String, String),
 }
 
+const EDSK_PREFIX: &[u8] = &[13, 15, 58, 7];
+const EDESK_PREFIX: &[u8] = &[7, 90, 60, 179, 41];
+pub(crate) const EDPK_PREFIX: &[u8] = &[13, 15, 37, 217];
+const EDSIG_PREFIX: &[u8] = &[9, 245, 205, 134, 18];
+pub(crate) const TZ1_PREFIX: &[u8] = &[6, 161, 159];
+const SPSK_PREFIX: &[u8] = &[17, 162, 224, 201];
+const SPESK_PREFIX: &[u8] = &[9, 237, 241, 174, 150];
+pub(crate) const SPPK_PREFIX: &[u8] = &[3, 254, 226, 86];
+const SPSIG_PREFIX: &[u8] = &[13, 115, 101, 19, 63];
+pub(crate) const TZ2_PREFIX: &[u8] = &[6, 161, 161];
+const P2SK_PREFIX: &[u8] = &[16, 81, 238, 189];
+const P2ESK_PREFIX: &[u8] = &[9, 48, 57, 115, 171];
+pub(crate) const P2PK_PREFIX: &[u8] = &[3, 178, 139, 127];
+const P2SIG_PREFIX: &[u8] = &[54, 240, 44, 52];
+pub(crate) const TZ3_PREFIX: &[u8] = &[6, 161, 164];
+const GENERIC_SIG_PREFIX: &[u8] = &[4, 130, 43];
+
+/// Parameters of encrypted secret keys, which are the same as those of tezos-client.
+pub const ENCRYPTION_SALT_LENGTH: usize = 8;
+const ENCRYPTION_ROUNDS: u32 = 32768;
+const TAG_LENGTH: usize = 16;
+
+/// The watermark of operations, which is prepended to them before signing.
+pub const GENERIC_OPERATION_WATERMARK: u8 = 0x03;
+
+/// The curves of tz1, tz2 and tz3 accounts respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Ed25519,
+    Secp256k1,
+    P256,
+}
+
+impl Curve {
+    fn of_secret_key(secret_key: &str) -> Result<Self, Error> {
+        match secret_key.get(0..4) {
+            Some("edsk") => Ok(Curve::Ed25519),
+            Some("spsk") => Ok(Curve::Secp256k1),
+            Some("p2sk") => Ok(Curve::P256),
+            _ => Err(Error::KeyType(
+                "key starting with edsk, spsk or p2sk".to_string(),
+                secret_key.to_string(),
+            )),
+        }
+    }
+
+    /// Returns the curve of an encrypted secret key (`edesk`, `spesk` or `p2esk`), if it is one.
+    fn of_encrypted_secret_key(secret_key: &str) -> Option<Self> {
+        match secret_key.get(0..5) {
+            Some("edesk") => Some(Curve::Ed25519),
+            Some("spesk") => Some(Curve::Secp256k1),
+            Some("p2esk") => Some(Curve::P256),
+            _ => None,
+        }
+    }
+
+    fn of_public_key(public_key: &str) -> Result<Self, Error> {
+        match public_key.get(0..4) {
+            Some("edpk") => Ok(Curve::Ed25519),
+            Some("sppk") => Ok(Curve::Secp256k1),
+            Some("p2pk") => Ok(Curve::P256),
+            _ => Err(Error::KeyType(
+                "key starting with edpk, sppk or p2pk".to_string(),
+                public_key.to_string(),
+            )),
+        }
+    }
+
+    fn secret_key_prefix(self) -> &'static [u8] {
+        match self {
+            Curve::Ed25519 => EDSK_PREFIX,
+            Curve::Secp256k1 => SPSK_PREFIX,
+            Curve::P256 => P2SK_PREFIX,
+        }
+    }
+
+    fn encrypted_secret_key_prefix(self) -> &'static [u8] {
+        match self {
+            Curve::Ed25519 => EDESK_PREFIX,
+            Curve::Secp256k1 => SPESK_PREFIX,
+            Curve::P256 => P2ESK_PREFIX,
+        }
+    }
+
+    fn public_key_prefix(self) -> &'static [u8] {
+        match self {
+            Curve::Ed25519 => EDPK_PREFIX,
+            Curve::Secp256k1 => SPPK_PREFIX,
+            Curve::P256 => P2PK_PREFIX,
+        }
+    }
+
+    fn address_prefix(self) -> &'static [u8] {
+        match self {
+            Curve::Ed25519 => TZ1_PREFIX,
+            Curve::Secp256k1 => TZ2_PREFIX,
+            Curve::P256 => TZ3_PREFIX,
+        }
+    }
+
+    fn signature_prefix(self) -> &'static [u8] {
+        match self {
+            Curve::Ed25519 => EDSIG_PREFIX,
+            Curve::Secp256k1 => SPSIG_PREFIX,
+            Curve::P256 => P2SIG_PREFIX,
+        }
+    }
+}
+
 fn base58check_decode(input: &str) -> Result<Vec<u8>, Error> {
     let (head, rest) = input.from_base58check().map_err(Error::KeyContent)?;
     Ok([vec![head], rest].concat())
 }
 
-fn base58check_encode(input: &[u8]) -> String {
+pub(crate) fn base58check_encode(input: &[u8]) -> String {
     input[1..].to_base58check(input[0])
 }
 
-// TODO: test this when turning this into a library later
-pub fn derive_address_from_pubkey(public_key: &str) -> Result<String, Error> {
-    if &public_key[0..4] != "edpk" {
+/// Decodes a key, checking and stripping its prefix.
+fn decode_key(key: &str, prefix: &[u8]) -> Result<Vec<u8>, Error> {
+    let bytes = base58check_decode(key)?;
+    if !bytes.starts_with(prefix) {
         return Err(Error::KeyType(
-            "key starting with edpk".to_string(),
-            public_key.to_string(),
+            format!("key with prefix {:?}", prefix),
+            key.to_string(),
         ));
     }
-    let public_key = &base58check_decode(public_key)?[4..];
+    Ok(bytes[prefix.len()..].to_vec())
+}
 
-    let mut hasher = VarBlake2b::new(20).expect("20 byte output should be valid for blake2b");
-    hasher.update(public_key);
-    let hash = hasher.finalize_boxed();
+fn blake2b(input: &[u8], size: usize) -> Box<[u8]> {
+    let mut hasher = VarBlake2b::new(size).expect("output size should be valid for blake2b");
+    hasher.update(input);
+    hasher.finalize_boxed()
+}
+
+pub fn derive_address_from_pubkey(public_key: &str) -> Result<String, Error> {
+    let curve = Curve::of_public_key(public_key)?;
+    let public_key = decode_key(public_key, curve.public_key_prefix())?;
+    let hash = blake2b(&public_key, 20);
+
+    Ok(base58check_encode(
+        &[curve.address_prefix(), &hash].concat(),
+    ))
+}
+
+/// Derives the public key (`edpk`, `sppk` or `p2pk`) of a secret key.
+pub fn derive_public_key(secret_key: &str) -> Result<String, Error> {
+    let curve = Curve::of_secret_key(secret_key)?;
+    let secret_key = decode_key(secret_key, curve.secret_key_prefix())?;
+
+    let public_key = match curve {
+        Curve::Ed25519 => ed25519_signer(&secret_key)?
+            .public_key()
+            .map_err(|_| Error::Signature)?
+            .as_ref()
+            .to_vec(),
+        // Public keys of both ECDSA curves are compressed points.
+        Curve::Secp256k1 => k256::ecdsa::SigningKey::from_bytes(&secret_key)
+            .map_err(|_| Error::InvalidKey(curve))?
+            .verifying_key()
+            .to_bytes()
+            .to_vec(),
+        Curve::P256 => p256::ecdsa::SigningKey::from_bytes(&secret_key)
+            .map_err(|_| Error::InvalidKey(curve))?
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec(),
+    };
+
+    Ok(base58check_encode(
+        &[curve.public_key_prefix(), &public_key].concat(),
+    ))
+}
+
+/// Returns whether a secret key is valid and can be used as is, without a passphrase.
+pub fn is_plain_secret_key(secret_key: &str) -> bool {
+    derive_public_key(secret_key).is_ok()
+}
+
+/// Returns whether a secret key needs a passphrase to be used.
+pub fn is_encrypted_secret_key(secret_key: &str) -> bool {
+    Curve::of_encrypted_secret_key(strip_encrypted_scheme(secret_key)).is_some()
+}
+
+/// Strips the `encrypted:` scheme tezos-client writes before encrypted keys in its `secret_keys`
+/// file, so that they can be pasted as is.
+fn strip_encrypted_scheme(secret_key: &str) -> &str {
+    secret_key.strip_prefix("encrypted:").unwrap_or(secret_key)
+}
+
+fn encryption_key(passphrase: &str, salt: &[u8]) -> crypto_secretbox::Key {
+    let mut key = crypto_secretbox::Key::default();
+    pbkdf2::pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, ENCRYPTION_ROUNDS, &mut key);
+    key
+}
+
+/// Decrypts an `edesk`, `spesk` or `p2esk` key into the corresponding plain secret key.
+///
+/// Encrypted keys are the salt followed by the secret key in a secretbox whose key is derived
+/// from the passphrase. The nonce is always zero, which is fine since each salt gives a new key.
+pub fn decrypt_secret_key(secret_key: &str, passphrase: &str) -> Result<String, Error> {
+    let secret_key = strip_encrypted_scheme(secret_key);
+    let curve = Curve::of_encrypted_secret_key(secret_key).ok_or_else(|| {
+        Error::KeyType(
+            "key starting with edesk, spesk or p2esk".to_string(),
+            secret_key.to_string(),
+        )
+    })?;
+    let bytes = decode_key(secret_key, curve.encrypted_secret_key_prefix())?;
+    if bytes.len() < ENCRYPTION_SALT_LENGTH + TAG_LENGTH {
+        return Err(Error::InvalidKey(curve));
+    }
+    let (salt, sealed) = bytes.split_at(ENCRYPTION_SALT_LENGTH);
+    // NaCl puts the tag in front of the ciphertext.
+    let (tag, ciphertext) = sealed.split_at(TAG_LENGTH);
+
+    let mut plaintext = ciphertext.to_vec();
+    XSalsa20Poly1305::new(&encryption_key(passphrase, salt))
+        .decrypt_in_place_detached(&Nonce::default(), b"", &mut plaintext, Tag::from_slice(tag))
+        .map_err(|_| Error::Passphrase)?;
+
+    Ok(base58check_encode(
+        &[curve.secret_key_prefix(), &plaintext].concat(),
+    ))
+}
+
+/// Encrypts a secret key with a passphrase, as `tezos-client import secret key` does.
+pub fn encrypt_secret_key(
+    secret_key: &str,
+    passphrase: &str,
+    salt: [u8; ENCRYPTION_SALT_LENGTH],
+) -> Result<String, Error> {
+    let curve = Curve::of_secret_key(secret_key)?;
+    let mut ciphertext = decode_key(secret_key, curve.secret_key_prefix())?;
+    let tag = XSalsa20Poly1305::new(&encryption_key(passphrase, &salt))
+        .encrypt_in_place_detached(&Nonce::default(), b"", &mut ciphertext)
+        .expect("secret keys are short enough to encrypt");
 
     Ok(base58check_encode(
-        &[vec![6, 161, 159], hash.to_vec()].concat(),
+        &[
+            curve.encrypted_secret_key_prefix(),
+            &salt,
+            tag.as_slice(),
+            &ciphertext,
+        ]
+        .concat(),
     ))
 }
 
+fn ed25519_signer(secret_key: &[u8]) -> Result<ed25519::Signer, Error> {
+    Ok((&ed25519::Seed::from_bytes(secret_key)
+        .ok_or_else(|| Error::SeedLength(secret_key.len()))?)
+        .into())
+}
+
 // Based on https://www.ocamlpro.com/2018/11/21/an-introduction-to-tezos-rpcs-signing-operations/
 pub fn sign_serialized_operation(
     serialized_operation: &str,
     secret_key: &str,
 ) -> Result<(String, Vec<u8>), Error> {
     let op = hex::decode(&serialized_operation).map_err(Error::HexDecode)?;
+    sign(GENERIC_OPERATION_WATERMARK, &op, secret_key)
+}
 
-    if &secret_key[0..4] != "edsk" {
-        return Err(Error::KeyType(
-            "key starting with edsk".to_string(),
-            secret_key.to_string(),
-        ));
-    }
-
-    let secret_key = &base58check_decode(secret_key)?[4..];
-    let signer: ed25519::Signer = (&ed25519::Seed::from_bytes(&secret_key)
-        .ok_or_else(|| Error::SeedLength(secret_key.len()))?)
-        .into();
+/// Signs `bytes` prefixed with `watermark`, returning the signature base58check-encoded and
+/// as raw bytes.
+pub fn sign(watermark: u8, bytes: &[u8], secret_key: &str) -> Result<(String, Vec<u8>), Error> {
+    let curve = Curve::of_secret_key(secret_key)?;
+    let secret_key = decode_key(secret_key, curve.secret_key_prefix())?;
 
-    let mut hasher = VarBlake2b::new(32).expect("32 byte output should be valid for blake2b");
-    hasher.update(&[vec![0x03], op].concat());
-    let hash = hasher.finalize_boxed();
+    let hash = blake2b(&[&[watermark], bytes].concat(), 32);
 
-    let signature = signer
-        .try_sign(&hash)
-        .map_err(|_| Error::Signature)?
-        .to_bytes();
+    // All curves sign the hash as is, and signatures of ECDSA curves are r and s concatenated.
+    let signature = match curve {
+        Curve::Ed25519 => ed25519_signer(&secret_key)?
+            .try_sign(&hash)
+            .map_err(|_| Error::Signature)?
+            .to_bytes()
+            .to_vec(),
+        // Signatures are normalized to a low s as the protocol requires.
+        Curve::Secp256k1 => {
+            let signature: k256::ecdsa::Signature =
+                k256::ecdsa::SigningKey::from_bytes(&secret_key)
+                    .map_err(|_| Error::InvalidKey(curve))?
+                    .sign_prehash(&hash)
+                    .map_err(|_| Error::Signature)?;
+            signature.as_ref().to_vec()
+        }
+        Curve::P256 => {
+            let signature: p256::ecdsa::Signature =
+                p256::ecdsa::SigningKey::from_bytes(&secret_key)
+                    .map_err(|_| Error::InvalidKey(curve))?
+                    .sign_prehash(&hash)
+                    .map_err(|_| Error::Signature)?;
+            signature.as_ref().to_vec()
+        }
+    };
 
     Ok((
-        base58check_encode(&[vec![0x09, 0xf5, 0xcd, 0x86, 0x12], signature.to_vec()].concat()),
-        signature.to_vec(),
+        base58check_encode(&[curve.signature_prefix(), &signature].concat()),
+        signature,
     ))
 }
 
+/// Checks that `signature` was made by `sign` from `watermark` and `bytes`, with the secret key
+/// of `public_key`.
+pub fn verify(
+    watermark: u8,
+    bytes: &[u8],
+    signature: &[u8],
+    public_key: &str,
+) -> Result<bool, Error> {
+    let curve = Curve::of_public_key(public_key)?;
+    let public_key = decode_key(public_key, curve.public_key_prefix())?;
+
+    let hash = blake2b(&[&[watermark], bytes].concat(), 32);
+
+    // Signatures of ECDSA curves with a high s are invalid, as they are malleable.
+    let valid = match curve {
+        Curve::Ed25519 => {
+            ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &public_key)
+                .verify(&hash, signature)
+                .is_ok()
+        }
+        Curve::Secp256k1 => {
+            let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+                .map_err(|_| Error::InvalidPublicKey(curve))?;
+            k256::ecdsa::Signature::try_from(signature)
+                .and_then(|signature| key.verify_prehash(&hash, &signature))
+                .is_ok()
+        }
+        Curve::P256 => {
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+                .map_err(|_| Error::InvalidPublicKey(curve))?;
+            p256::ecdsa::Signature::try_from(signature)
+                .and_then(|signature| key.verify_prehash(&hash, &signature))
+                .is_ok()
+        }
+    };
+    Ok(valid)
+}
+
+/// Decodes a signature of any curve, or a generic `sig` one, into its raw bytes.
+pub fn decode_signature(signature: &str) -> Result<Vec<u8>, Error> {
+    let prefix = match signature.get(0..5) {
+        Some("edsig") => EDSIG_PREFIX,
+        Some("spsig") => SPSIG_PREFIX,
+        Some("p2sig") => P2SIG_PREFIX,
+        _ => GENERIC_SIG_PREFIX,
+    };
+    decode_key(signature, prefix)
+}
+
+/// Encodes raw signature bytes as a generic `sig` signature, valid for any curve.
+pub fn encode_generic_signature(signature: &[u8]) -> String {
+    base58check_encode(&[GENERIC_SIG_PREFIX, signature].concat())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FaucetOutput {
     pub mnemonic: Vec<String>,
@@ -125,15 +435,13 @@ impl FaucetOutput {
         let signer = ed25519::Signer::from(&seed);
         let public_key = signer.public_key().map_err(|_| Error::Signature)?;
 
-        let edpk_prefix: &[u8] = &[0x0d, 0x0f, 0x25, 0xd9];
-        let encoded_public_key = base58check_encode(&[edpk_prefix, public_key.as_ref()].concat());
+        let encoded_public_key = base58check_encode(&[EDPK_PREFIX, public_key.as_ref()].concat());
         let address = derive_address_from_pubkey(&encoded_public_key)?;
         if address != self.pkh {
             return Err(Error::AddressMismatch(address, self.pkh.clone()));
         }
 
-        let edsk_prefix = vec![0x0d, 0x0f, 0x3a, 0x07];
-        Ok(base58check_encode(&[&edsk_prefix, seed_bytes].concat()))
+        Ok(base58check_encode(&[EDSK_PREFIX, seed_bytes].concat()))
     }
 }
 
@@ -149,6 +457,173 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn secp256k1_and_p256_keys_work() -> Result<(), Error> {
+        let secret_key = "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE";
+        let public_key = derive_public_key(secret_key)?;
+        assert_eq!(
+            public_key,
+            "sppk7aqSksZan1AGXuKtCz9UBLZZ77e3ZWGpFxR7ig1Z17GneEhSSbH"
+        );
+        assert_eq!(
+            derive_address_from_pubkey(&public_key)?,
+            "tz2Ch1abG7FNiibmV26Uzgdsnfni9XGrk5wD"
+        );
+
+        let secret_key = "p2sk2obfVMEuPUnadAConLWk7Tf4Dt3n4svSgJwrgpamRqJXvaYcg1";
+        let public_key = derive_public_key(secret_key)?;
+        assert_eq!(
+            public_key,
+            "p2pk66tTYL5EvahKAXncbtbRPBkAnxo3CszzUho5wPCgWauBMyvybuB"
+        );
+        assert_eq!(
+            derive_address_from_pubkey(&public_key)?,
+            "tz3Lfm6CyfSTZ7EgMckptZZGiPxzs9GK59At"
+        );
+        Ok(())
+    }
+
+    // Known answers for the operation below, computed with RFC 6979 deterministic nonces (HMAC
+    // with SHA-256) by an implementation checked against the test vectors of the RFC, and with a
+    // low s for secp256k1 as libsecp256k1 does.
+    const SPSIG: &str = "spsig18C9qoW6exLp1kChHSfdQzMK5AGxFjjxkGn2vpNRcug7ubkc4z9iyMcsdnPWvQBXCfdrXZbmNfXFYY1hztMTBCfBNqXPUW";
+    const P2SIG: &str = "p2sigggFHLHiy2Pm5ceNvkPVFkaX2jPFz6i43rbLXVZDLvWNVmd7bALkUw56Ks524PzwbJgdtw4vmLhSxGKzP7cGCXKQ2of7fZ";
+
+    #[test]
+    fn secp256k1_and_p256_signatures_verify() -> Result<(), Error> {
+        use k256::ecdsa::signature::hazmat::PrehashVerifier;
+        use std::convert::TryFrom;
+
+        let sop = "ce69c5713dac3537254e7be59759cf59c15abd530d10501ccf9028a5786314cf6c00";
+        let hash = blake2b(&[vec![0x03], hex::decode(sop).unwrap()].concat(), 32);
+
+        let secret_key = "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE";
+        let (signature, raw_signature) = sign_serialized_operation(sop, secret_key)?;
+        assert_eq!(signature, SPSIG);
+        let raw_signature = k256::ecdsa::Signature::try_from(&raw_signature[..]).unwrap();
+        assert!(raw_signature.normalize_s().is_none());
+        let public_key = decode_key(&derive_public_key(secret_key)?, SPPK_PREFIX)?;
+        k256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+            .unwrap()
+            .verify_prehash(&hash, &raw_signature)
+            .unwrap();
+
+        let secret_key = "p2sk2obfVMEuPUnadAConLWk7Tf4Dt3n4svSgJwrgpamRqJXvaYcg1";
+        let (signature, raw_signature) = sign_serialized_operation(sop, secret_key)?;
+        assert_eq!(signature, P2SIG);
+        let raw_signature = p256::ecdsa::Signature::try_from(&raw_signature[..]).unwrap();
+        let public_key = decode_key(&derive_public_key(secret_key)?, P2PK_PREFIX)?;
+        p256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+            .unwrap()
+            .verify_prehash(&hash, &raw_signature)
+            .unwrap();
+
+        assert!(matches!(
+            sign_serialized_operation(sop, "sk1234"),
+            Err(Error::KeyType(_, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn signatures_are_verified() -> Result<(), Error> {
+        let bytes = b"operation";
+        for secret_key in &[
+            "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+            "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE",
+            "p2sk2obfVMEuPUnadAConLWk7Tf4Dt3n4svSgJwrgpamRqJXvaYcg1",
+        ] {
+            let public_key = derive_public_key(secret_key)?;
+            let (_, signature) = sign(GENERIC_OPERATION_WATERMARK, bytes, secret_key)?;
+            assert!(verify(
+                GENERIC_OPERATION_WATERMARK,
+                bytes,
+                &signature,
+                &public_key
+            )?);
+            assert!(!verify(0x04, bytes, &signature, &public_key)?);
+            assert!(!verify(
+                GENERIC_OPERATION_WATERMARK,
+                b"another operation",
+                &signature,
+                &public_key
+            )?);
+            assert!(!verify(
+                GENERIC_OPERATION_WATERMARK,
+                bytes,
+                &signature[1..],
+                &public_key
+            )?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_secret_keys_work() -> Result<(), Error> {
+        for &(secret_key, prefix) in &[
+            (
+                "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+                "edesk",
+            ),
+            (
+                "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE",
+                "spesk",
+            ),
+            (
+                "p2sk2obfVMEuPUnadAConLWk7Tf4Dt3n4svSgJwrgpamRqJXvaYcg1",
+                "p2esk",
+            ),
+        ] {
+            let encrypted = encrypt_secret_key(secret_key, "passphrase", [7; 8])?;
+            assert!(encrypted.starts_with(prefix));
+            assert!(is_encrypted_secret_key(&encrypted));
+            assert!(!is_encrypted_secret_key(secret_key));
+            assert_eq!(decrypt_secret_key(&encrypted, "passphrase")?, secret_key);
+        }
+
+        let encrypted = encrypt_secret_key(
+            "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+            "passphrase",
+            [0; 8],
+        )?;
+        // tezos-client keys are 88 characters long.
+        assert_eq!(encrypted.len(), 88);
+        assert!(matches!(
+            decrypt_secret_key(&encrypted, "wrong"),
+            Err(Error::Passphrase)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn tezos_client_encrypted_keys_are_decrypted() -> Result<(), Error> {
+        // Encrypted with the passphrase "correct horse" and the salt "Mizusalt" by an
+        // implementation of NaCl's secretbox checked against the test vectors of NaCl, the way
+        // tezos-client encrypts keys.
+        for &(encrypted, secret_key) in &[
+            (
+                "encrypted:edesk1VihRg4snb4n8g6z4R2a16s77k8RJxDukcBiLENGWZidG9dojMfiYH5rwfKF31n6WnwDa11KE7CM3A8mfrG",
+                "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+            ),
+            (
+                "encrypted:spesk1fYEMMWU1hXqt9bwJqVwP82H7o2HQwLBXPZbZUQ1k79WvKKEsFjYUMH6D6xBvnvMGBR6urED6WgmAmveKY4",
+                "spsk2rBDDeUqakQ42nBHDGQTtP3GErb6AahHPwF9bhca3Q5KA5HESE",
+            ),
+            (
+                "p2esk1zMsBeQjZdf9ScBR2ZnCsn5kCnxaiG1NaqzWNLHzJhXarNbwg7k1pYGSHxVrF5neLn9r79YMQezw4jeKVpa",
+                "p2sk2obfVMEuPUnadAConLWk7Tf4Dt3n4svSgJwrgpamRqJXvaYcg1",
+            ),
+        ] {
+            assert!(is_encrypted_secret_key(encrypted));
+            assert_eq!(decrypt_secret_key(encrypted, "correct horse")?, secret_key);
+            assert_eq!(
+                encrypt_secret_key(secret_key, "correct horse", *b"Mizusalt")?,
+                strip_encrypted_scheme(encrypted)
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn sign_serialized_operation_works() -> Result<(), Error> {
         let sop = "ce69c5713dac3537254e7be59759cf59c15abd530d10501ccf9028a5786314cf08000002298c03ed7d454a101eb7022bc95f7e5f41ac78d0860303c8010080c2d72f0000e7670f32038107a59a2b9cfefae36ea21f5aa63c00";
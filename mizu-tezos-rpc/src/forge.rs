@@ -0,0 +1,912 @@
+//! Binary encoding of operations and Michelson values.
+//!
+//! Forging operations ourselves means we sign bytes we built rather than whatever
+//! `helpers/forge/operations` of the node returns, and packing values lets us compute the key
+//! hashes big maps are indexed by. Forged operations can be read back, as a node reads the
+//! operations injected into it.
+//!
+//! Based on the encodings of the Carthage protocol, which Delphi didn't change:
+//! https://tezos.gitlab.io/006/michelson.html and `tezos-codec describe`.
+
+use crate::crypto::{
+    base58check_encode, encode_generic_signature, EDPK_PREFIX, P2PK_PREFIX, SPPK_PREFIX,
+    TZ1_PREFIX, TZ2_PREFIX, TZ3_PREFIX,
+};
+use crate::michelson::Expr;
+use crate::operation::{Content, Manager, Operation};
+use base58check::FromBase58Check;
+use blake2::VarBlake2b;
+use digest::{Update, VariableOutput};
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid base58check value {0}: {1:?}")]
+    Base58(String, base58check::FromBase58CheckError),
+    #[error("expected {0} but found {1}")]
+    Prefix(&'static str, String),
+    #[error("natural numbers cannot be negative: {0}")]
+    Negative(BigInt),
+    #[error("unknown Michelson primitive: {0}")]
+    UnknownPrimitive(String),
+    #[error("too long to encode: {0} bytes")]
+    TooLong(usize),
+    #[error("invalid activation secret: {0}")]
+    ActivationSecret(String),
+    #[error("invalid optimized address: {0}")]
+    Address(String),
+    #[error("unexpected end of forged bytes")]
+    Truncated,
+    #[error("unknown {0} tag: {1}")]
+    UnknownTag(&'static str, u8),
+    #[error("unknown Michelson primitive code: {0}")]
+    UnknownPrimitiveCode(u8),
+    #[error("invalid string: {0}")]
+    InvalidString(std::string::FromUtf8Error),
+    #[error("{0} bytes left after the expression")]
+    TrailingBytes(usize),
+    #[error("unsupported operation content: {0}")]
+    Unsupported(&'static str),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+const ACTIVATE_ACCOUNT_TAG: u8 = 4;
+const REVEAL_TAG: u8 = 107;
+const TRANSACTION_TAG: u8 = 108;
+const ORIGINATION_TAG: u8 = 109;
+const DEFAULT_ENTRYPOINT_TAG: u8 = 0;
+
+const BLOCK_HASH_PREFIX: &[u8] = &[1, 52];
+const KT1_PREFIX: &[u8] = &[2, 90, 121];
+/// The length of the secrets of faucet accounts.
+const ACTIVATION_SECRET_LENGTH: usize = 20;
+const SCRIPT_EXPR_HASH_PREFIX: &[u8] = &[13, 44, 64, 27];
+const BLOCK_HASH_LENGTH: usize = 32;
+const PUBLIC_KEY_HASH_LENGTH: usize = 20;
+const SIGNATURE_LENGTH: usize = 64;
+
+/// The tag `PACK` puts in front of Micheline expressions.
+const PACK_TAG: u8 = 0x05;
+
+/// Michelson primitives in the order of their binary codes.
+#[rustfmt::skip]
+pub const PRIMITIVES: &[&str] = &[
+    "parameter", "storage", "code", "False", "Elt", "Left", "None", "Pair", "Right", "Some", "True",
+    "Unit", "PACK", "UNPACK", "BLAKE2B", "SHA256", "SHA512", "ABS", "ADD", "AMOUNT", "AND",
+    "BALANCE", "CAR", "CDR", "CHECK_SIGNATURE", "COMPARE", "CONCAT", "CONS", "CREATE_ACCOUNT",
+    "CREATE_CONTRACT", "IMPLICIT_ACCOUNT", "DIP", "DROP", "DUP", "EDIV", "EMPTY_MAP", "EMPTY_SET",
+    "EQ", "EXEC", "FAILWITH", "GE", "GET", "GT", "HASH_KEY", "IF", "IF_CONS", "IF_LEFT", "IF_NONE",
+    "INT", "LAMBDA", "LE", "LEFT", "LOOP", "LSL", "LSR", "LT", "MAP", "MEM", "MUL", "NEG", "NEQ",
+    "NIL", "NONE", "NOT", "NOW", "OR", "PAIR", "PUSH", "RIGHT", "SIZE", "SOME", "SOURCE", "SENDER",
+    "SELF", "STEPS_TO_QUOTA", "SUB", "SWAP", "TRANSFER_TOKENS", "SET_DELEGATE", "UNIT", "UPDATE",
+    "XOR", "ITER", "LOOP_LEFT", "ADDRESS", "CONTRACT", "ISNAT", "CAST", "RENAME", "bool",
+    "contract", "int", "key", "key_hash", "lambda", "list", "map", "big_map", "nat", "option", "or",
+    "pair", "set", "signature", "string", "bytes", "mutez", "timestamp", "unit", "operation",
+    "address", "SLICE", "DIG", "DUG", "EMPTY_BIG_MAP", "APPLY", "chain_id", "CHAIN_ID",
+];
+
+/// Forges an unsigned operation, as `helpers/forge/operations` would.
+pub fn forge_operation(op: &Operation) -> Result<Vec<u8>> {
+    let mut out = decode_prefixed(&op.branch, "a block hash", BLOCK_HASH_PREFIX)?;
+    for content in &op.contents {
+        forge_content(&mut out, content)?;
+    }
+    Ok(out)
+}
+
+/// Forges one of the contents of an operation, which are simply concatenated.
+pub(crate) fn forge_content(out: &mut Vec<u8>, content: &Content) -> Result<()> {
+    match content {
+        Content::ActivateAccount { pkh, secret } => {
+            out.push(ACTIVATE_ACCOUNT_TAG);
+            // Faucet accounts are always tz1, so the hash isn't tagged with its curve.
+            out.extend(decode_prefixed(pkh, "a tz1 address", TZ1_PREFIX)?);
+            match hex::decode(secret) {
+                Ok(bytes) if bytes.len() == ACTIVATION_SECRET_LENGTH => out.extend(bytes),
+                _ => return Err(Error::ActivationSecret(secret.clone())),
+            }
+        }
+        Content::Reveal {
+            manager,
+            public_key,
+        } => {
+            out.push(REVEAL_TAG);
+            forge_manager(out, manager)?;
+            forge_public_key(out, public_key)?;
+        }
+        Content::Transaction {
+            manager,
+            destination,
+            parameters,
+        } => {
+            out.push(TRANSACTION_TAG);
+            forge_manager(out, manager)?;
+            // We never transfer tez.
+            forge_nat(out, &BigInt::zero())?;
+            forge_contract_id(out, destination)?;
+
+            // Parameters are optional, and we always pass them to the default entrypoint.
+            out.push(0xff);
+            out.push(DEFAULT_ENTRYPOINT_TAG);
+            forge_dynamic(out, |out| forge_expr(out, parameters))?;
+        }
+        Content::Origination {
+            manager,
+            code,
+            storage,
+        } => {
+            out.push(ORIGINATION_TAG);
+            forge_manager(out, manager)?;
+            // We never transfer tez, nor set a delegate.
+            forge_nat(out, &BigInt::zero())?;
+            out.push(0x00);
+            forge_dynamic(out, |out| forge_expr(out, code))?;
+            forge_dynamic(out, |out| forge_expr(out, storage))?;
+        }
+    }
+    Ok(())
+}
+
+fn forge_manager(out: &mut Vec<u8>, manager: &Manager) -> Result<()> {
+    forge_public_key_hash(out, &manager.source)?;
+    for n in &[
+        &manager.fee,
+        &manager.counter,
+        &manager.gas_limit,
+        &manager.storage_limit,
+    ] {
+        forge_nat(out, n)?;
+    }
+    Ok(())
+}
+
+/// Serializes a value as `PACK` does, which needs values to be in their optimized form (see
+/// `address_expr`).
+pub fn pack(expr: &Expr) -> Result<Vec<u8>> {
+    let mut out = vec![PACK_TAG];
+    forge_expr(&mut out, expr)?;
+    Ok(out)
+}
+
+/// Returns the optimized form of a value of type `address`.
+pub fn address_expr(address: &str) -> Result<Expr> {
+    let mut out = Vec::new();
+    forge_contract_id(&mut out, address)?;
+    Ok(Expr::Bytes(out))
+}
+
+/// Reads an address in its optimized form, which is what `address_expr` returns.
+pub fn parse_address(bytes: &[u8]) -> Result<String> {
+    let invalid = || Error::Address(hex::encode(bytes));
+    if bytes.len() != 22 {
+        return Err(invalid());
+    }
+    let (prefix, hash) = match (bytes[0], bytes[1]) {
+        (0, 0) => (TZ1_PREFIX, &bytes[2..]),
+        (0, 1) => (TZ2_PREFIX, &bytes[2..]),
+        (0, 2) => (TZ3_PREFIX, &bytes[2..]),
+        (1, _) if bytes[21] == 0 => (KT1_PREFIX, &bytes[1..21]),
+        _ => return Err(invalid()),
+    };
+    Ok(base58check_encode(&[prefix, hash].concat()))
+}
+
+/// Returns the `expr...` hash of a packed value, which big maps are indexed by.
+pub fn script_expr_hash(packed: &[u8]) -> String {
+    let mut hasher = VarBlake2b::new(32).expect("32 byte output should be valid for blake2b");
+    hasher.update(packed);
+    let hash = hasher.finalize_boxed();
+    base58check_encode(&[SCRIPT_EXPR_HASH_PREFIX, &hash].concat())
+}
+
+/// Decodes a base58check value, checking and stripping its prefix.
+fn decode_prefixed(value: &str, kind: &'static str, prefix: &[u8]) -> Result<Vec<u8>> {
+    let (version, rest) = value
+        .from_base58check()
+        .map_err(|e| Error::Base58(value.to_string(), e))?;
+    let bytes = [vec![version], rest].concat();
+    if bytes.starts_with(prefix) {
+        Ok(bytes[prefix.len()..].to_vec())
+    } else {
+        Err(Error::Prefix(kind, value.to_string()))
+    }
+}
+
+fn forge_public_key_hash(out: &mut Vec<u8>, address: &str) -> Result<()> {
+    let (tag, prefix) = match address.get(0..3) {
+        Some("tz1") => (0, TZ1_PREFIX),
+        Some("tz2") => (1, TZ2_PREFIX),
+        Some("tz3") => (2, TZ3_PREFIX),
+        _ => return Err(Error::Prefix("an implicit account", address.to_string())),
+    };
+    out.push(tag);
+    out.extend(decode_prefixed(address, "an implicit account", prefix)?);
+    Ok(())
+}
+
+fn forge_public_key(out: &mut Vec<u8>, public_key: &str) -> Result<()> {
+    let (tag, prefix) = match public_key.get(0..4) {
+        Some("edpk") => (0, EDPK_PREFIX),
+        Some("sppk") => (1, SPPK_PREFIX),
+        Some("p2pk") => (2, P2PK_PREFIX),
+        _ => return Err(Error::Prefix("a public key", public_key.to_string())),
+    };
+    out.push(tag);
+    out.extend(decode_prefixed(public_key, "a public key", prefix)?);
+    Ok(())
+}
+
+fn forge_contract_id(out: &mut Vec<u8>, address: &str) -> Result<()> {
+    if address.starts_with("KT1") {
+        out.push(1);
+        out.extend(decode_prefixed(
+            address,
+            "an originated contract",
+            KT1_PREFIX,
+        )?);
+        // Padding to the length of implicit accounts.
+        out.push(0);
+        Ok(())
+    } else {
+        out.push(0);
+        forge_public_key_hash(out, address)
+    }
+}
+
+/// Encodes a natural number in zarith: 7 bits per byte, least significant first, with the
+/// highest bit telling whether more bytes follow.
+fn forge_nat(out: &mut Vec<u8>, n: &BigInt) -> Result<()> {
+    if n.sign() == Sign::Minus {
+        return Err(Error::Negative(n.clone()));
+    }
+    forge_groups(out, n.clone());
+    Ok(())
+}
+
+/// Encodes an integer in zarith: like natural numbers, except the first byte holds only 6 bits
+/// of the absolute value and a sign bit.
+fn forge_int(out: &mut Vec<u8>, n: &BigInt) {
+    let sign = if n.sign() == Sign::Minus { 0x40 } else { 0 };
+    let abs = if n.sign() == Sign::Minus {
+        -n
+    } else {
+        n.clone()
+    };
+    let first = (&abs & BigInt::from(0x3f))
+        .to_u8()
+        .expect("masked to 6 bits");
+    let rest: BigInt = abs >> 6;
+    if rest.is_zero() {
+        out.push(sign | first);
+    } else {
+        out.push(0x80 | sign | first);
+        forge_groups(out, rest);
+    }
+}
+
+fn forge_groups(out: &mut Vec<u8>, mut n: BigInt) {
+    loop {
+        let group = (&n & BigInt::from(0x7f)).to_u8().expect("masked to 7 bits");
+        n >>= 7;
+        if n.is_zero() {
+            out.push(group);
+            return;
+        }
+        out.push(0x80 | group);
+    }
+}
+
+/// Writes what `write` outputs prefixed by its length as 4 bytes.
+fn forge_dynamic<F>(out: &mut Vec<u8>, write: F) -> Result<()>
+where
+    F: FnOnce(&mut Vec<u8>) -> Result<()>,
+{
+    let mut body = Vec::new();
+    write(&mut body)?;
+    forge_length(out, body.len())?;
+    out.extend(body);
+    Ok(())
+}
+
+fn forge_length(out: &mut Vec<u8>, length: usize) -> Result<()> {
+    let length = length.to_u32().ok_or(Error::TooLong(length))?;
+    out.extend(&length.to_be_bytes());
+    Ok(())
+}
+
+/// Encodes a Micheline expression in binary.
+pub(crate) fn forge_expr(out: &mut Vec<u8>, expr: &Expr) -> Result<()> {
+    match expr {
+        Expr::Int(n) => {
+            out.push(0x00);
+            forge_int(out, n);
+        }
+        Expr::String(s) => {
+            out.push(0x01);
+            forge_length(out, s.len())?;
+            out.extend(s.as_bytes());
+        }
+        Expr::List(exprs) => {
+            out.push(0x02);
+            forge_dynamic(out, |out| {
+                exprs.iter().try_for_each(|expr| forge_expr(out, expr))
+            })?;
+        }
+        Expr::Prim { prim, args, annots } => {
+            let code = PRIMITIVES
+                .iter()
+                .position(|p| p == prim)
+                .ok_or_else(|| Error::UnknownPrimitive(prim.clone()))?;
+            // Primitives with up to two arguments have tags of their own, with and without
+            // annotations.
+            let annotated = !annots.is_empty() as u8;
+            match args.len() {
+                0 => out.push(0x03 + annotated),
+                1 => out.push(0x05 + annotated),
+                2 => out.push(0x07 + annotated),
+                _ => out.push(0x09),
+            }
+            out.push(code as u8);
+            if args.len() > 2 {
+                forge_dynamic(out, |out| {
+                    args.iter().try_for_each(|arg| forge_expr(out, arg))
+                })?;
+            } else {
+                for arg in args {
+                    forge_expr(out, arg)?;
+                }
+            }
+            // Annotations are separated by spaces, and always present in the generic encoding.
+            if args.len() > 2 || !annots.is_empty() {
+                let annots = annots.join(" ");
+                forge_length(out, annots.len())?;
+                out.extend(annots.as_bytes());
+            }
+        }
+        Expr::Bytes(bytes) => {
+            out.push(0x0a);
+            forge_length(out, bytes.len())?;
+            out.extend(bytes);
+        }
+    }
+    Ok(())
+}
+
+/// Reads an operation forged by `forge_operation`, followed by its signature if `signed`.
+/// Manager operations are signed, but anonymous operations like activations aren't.
+///
+/// The signature is returned as a generic `sig` one, as its curve isn't forged.
+pub fn unforge_operation(bytes: &[u8], signed: bool) -> Result<Operation> {
+    let (bytes, signature) = if signed {
+        if bytes.len() < SIGNATURE_LENGTH {
+            return Err(Error::Truncated);
+        }
+        let (bytes, signature) = bytes.split_at(bytes.len() - SIGNATURE_LENGTH);
+        (bytes, Some(encode_generic_signature(signature)))
+    } else {
+        (bytes, None)
+    };
+
+    let mut reader = Reader(bytes);
+    let branch = base58check_encode(&[BLOCK_HASH_PREFIX, reader.take(BLOCK_HASH_LENGTH)?].concat());
+    let mut contents = Vec::new();
+    while !reader.is_empty() {
+        contents.push(unforge_content(&mut reader)?);
+    }
+    Ok(Operation {
+        protocol: None,
+        signature,
+        branch,
+        contents,
+    })
+}
+
+/// Reads a value serialized by `pack`.
+pub fn unpack(bytes: &[u8]) -> Result<Expr> {
+    let mut reader = Reader(bytes);
+    match reader.byte()? {
+        PACK_TAG => {}
+        tag => return Err(Error::UnknownTag("packed value", tag)),
+    }
+    let expr = read_expr(&mut reader)?;
+    reader.finish()?;
+    Ok(expr)
+}
+
+/// The bytes left to read.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.0.len() < length {
+            return Err(Error::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a field prefixed with its length, as `forge_dynamic` writes them.
+    fn dynamic(&mut self) -> Result<Reader<'a>> {
+        let mut length = [0; 4];
+        length.copy_from_slice(self.take(4)?);
+        let length = u32::from_be_bytes(length) as usize;
+        Ok(Reader(self.take(length)?))
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::TrailingBytes(self.0.len()))
+        }
+    }
+}
+
+fn unforge_content(reader: &mut Reader) -> Result<Content> {
+    let content = match reader.byte()? {
+        ACTIVATE_ACCOUNT_TAG => Content::ActivateAccount {
+            pkh: base58check_encode(&[TZ1_PREFIX, reader.take(PUBLIC_KEY_HASH_LENGTH)?].concat()),
+            secret: hex::encode(reader.take(ACTIVATION_SECRET_LENGTH)?),
+        },
+        REVEAL_TAG => Content::Reveal {
+            manager: unforge_manager(reader)?,
+            public_key: unforge_public_key(reader)?,
+        },
+        TRANSACTION_TAG => {
+            let manager = unforge_manager(reader)?;
+            if !unforge_nat(reader)?.is_zero() {
+                return Err(Error::Unsupported("transaction transferring tez"));
+            }
+            let destination = parse_address(reader.take(22)?)?;
+            if reader.take(2)? != [0xff, DEFAULT_ENTRYPOINT_TAG] {
+                return Err(Error::Unsupported(
+                    "transaction without parameters or to another entrypoint",
+                ));
+            }
+            Content::Transaction {
+                manager,
+                destination,
+                parameters: unforge_dynamic_expr(reader)?,
+            }
+        }
+        ORIGINATION_TAG => {
+            let manager = unforge_manager(reader)?;
+            if !unforge_nat(reader)?.is_zero() {
+                return Err(Error::Unsupported("origination transferring tez"));
+            }
+            if reader.byte()? != 0x00 {
+                return Err(Error::Unsupported("origination setting a delegate"));
+            }
+            Content::Origination {
+                manager,
+                code: unforge_dynamic_expr(reader)?,
+                storage: unforge_dynamic_expr(reader)?,
+            }
+        }
+        tag => return Err(Error::UnknownTag("operation content", tag)),
+    };
+    Ok(content)
+}
+
+fn unforge_manager(reader: &mut Reader) -> Result<Manager> {
+    // Public key hashes are contract IDs without the tag of implicit accounts.
+    let source = parse_address(&[&[0], reader.take(1 + PUBLIC_KEY_HASH_LENGTH)?].concat())?;
+    Ok(Manager {
+        source,
+        fee: unforge_nat(reader)?,
+        counter: unforge_nat(reader)?,
+        gas_limit: unforge_nat(reader)?,
+        storage_limit: unforge_nat(reader)?,
+    })
+}
+
+fn unforge_public_key(reader: &mut Reader) -> Result<String> {
+    let (prefix, length) = match reader.byte()? {
+        0 => (EDPK_PREFIX, 32),
+        1 => (SPPK_PREFIX, 33),
+        2 => (P2PK_PREFIX, 33),
+        tag => return Err(Error::UnknownTag("public key", tag)),
+    };
+    Ok(base58check_encode(&[prefix, reader.take(length)?].concat()))
+}
+
+fn unforge_nat(reader: &mut Reader) -> Result<BigInt> {
+    let mut n = BigInt::zero();
+    let mut shift = 0;
+    loop {
+        let byte = reader.byte()?;
+        n += BigInt::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+}
+
+fn unforge_int(reader: &mut Reader) -> Result<BigInt> {
+    let first = reader.byte()?;
+    let mut abs = BigInt::from(first & 0x3f);
+    if first & 0x80 != 0 {
+        abs += unforge_nat(reader)? << 6;
+    }
+    Ok(if first & 0x40 != 0 { -abs } else { abs })
+}
+
+fn unforge_string(reader: &mut Reader) -> Result<String> {
+    String::from_utf8(reader.dynamic()?.0.to_vec()).map_err(Error::InvalidString)
+}
+
+/// Reads an expression filling a field prefixed with its length.
+fn unforge_dynamic_expr(reader: &mut Reader) -> Result<Expr> {
+    let mut field = reader.dynamic()?;
+    let expr = read_expr(&mut field)?;
+    field.finish()?;
+    Ok(expr)
+}
+
+/// Reads the expressions filling a field prefixed with its length.
+fn read_exprs(reader: &mut Reader) -> Result<Vec<Expr>> {
+    let mut items = reader.dynamic()?;
+    let mut exprs = Vec::new();
+    while !items.is_empty() {
+        exprs.push(read_expr(&mut items)?);
+    }
+    Ok(exprs)
+}
+
+fn read_expr(reader: &mut Reader) -> Result<Expr> {
+    let expr = match reader.byte()? {
+        0x00 => Expr::Int(unforge_int(reader)?),
+        0x01 => Expr::String(unforge_string(reader)?),
+        0x02 => Expr::List(read_exprs(reader)?),
+        tag @ 0x03..=0x09 => {
+            let code = reader.byte()?;
+            let prim = PRIMITIVES
+                .get(code as usize)
+                .ok_or(Error::UnknownPrimitiveCode(code))?;
+            let args = match tag {
+                0x03 | 0x04 => Vec::new(),
+                0x05 | 0x06 => vec![read_expr(reader)?],
+                0x07 | 0x08 => vec![read_expr(reader)?, read_expr(reader)?],
+                _ => read_exprs(reader)?,
+            };
+            // Even tags and the generic one have annotations.
+            let annots = if tag % 2 == 0 || tag == 0x09 {
+                unforge_string(reader)?
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            Expr::Prim {
+                prim: prim.to_string(),
+                args,
+                annots,
+            }
+        }
+        0x0a => Expr::Bytes(reader.dynamic()?.0.to_vec()),
+        tag => return Err(Error::UnknownTag("Micheline expression", tag)),
+    };
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nat(n: u64) -> String {
+        let mut out = Vec::new();
+        forge_nat(&mut out, &BigInt::from(n)).unwrap();
+        hex::encode(out)
+    }
+
+    fn expr(expr: &Expr) -> String {
+        let mut out = Vec::new();
+        forge_expr(&mut out, expr).unwrap();
+        hex::encode(out)
+    }
+
+    #[test]
+    fn numbers_are_forged() {
+        assert_eq!(nat(0), "00");
+        assert_eq!(nat(127), "7f");
+        assert_eq!(nat(128), "8001");
+        assert_eq!(nat(50000), "d08603");
+        assert!(forge_nat(&mut Vec::new(), &BigInt::from(-1)).is_err());
+
+        assert_eq!(expr(&Expr::Int(BigInt::from(0))), "0000");
+        assert_eq!(expr(&Expr::Int(BigInt::from(-1))), "0041");
+        assert_eq!(expr(&Expr::Int(BigInt::from(63))), "003f");
+        assert_eq!(expr(&Expr::Int(BigInt::from(64))), "008001");
+        assert_eq!(expr(&Expr::Int(BigInt::from(-1000))), "00e80f");
+    }
+
+    #[test]
+    fn expressions_are_forged() {
+        assert_eq!(PRIMITIVES.iter().position(|&p| p == "nat"), Some(98));
+        assert_eq!(PRIMITIVES.len(), 118);
+        let one_two = Expr::pair(Expr::Int(BigInt::from(1)), Expr::Int(BigInt::from(2)));
+        // PACK (Pair 1 2) is 0x05 followed by this.
+        assert_eq!(expr(&one_two), "070700010002");
+        assert_eq!(expr(&Expr::String("abc".into())), "0100000003616263");
+        assert_eq!(expr(&Expr::Bytes(vec![0xca, 0xfe])), "0a00000002cafe");
+        assert_eq!(
+            expr(&Expr::List(vec![Expr::Int(BigInt::from(1))])),
+            "02000000020001"
+        );
+        assert_eq!(expr(&Expr::some(None)), "0306");
+        assert_eq!(
+            expr(&Expr::prim(
+                "CREATE_CONTRACT",
+                vec![Expr::List(Vec::new()); 3]
+            )),
+            [
+                "091d0000000f",
+                "0200000000",
+                "0200000000",
+                "0200000000",
+                "00000000"
+            ]
+            .concat()
+        );
+        let annotated = |args: Vec<Expr>| Expr::Prim {
+            prim: "pair".into(),
+            args,
+            annots: vec![":p".into(), "%a".into()],
+        };
+        // The length of ":p %a" followed by it.
+        let annots = "000000053a70202561";
+        assert_eq!(expr(&annotated(Vec::new())), "0465".to_string() + annots);
+        assert_eq!(
+            expr(&annotated(vec![Expr::Int(BigInt::from(1)); 2])),
+            "086500010001".to_string() + annots
+        );
+        assert_eq!(
+            expr(&annotated(vec![Expr::Int(BigInt::from(1)); 3])),
+            "096500000006000100010001".to_string() + annots
+        );
+        let mut out = Vec::new();
+        assert!(matches!(
+            forge_expr(&mut out, &Expr::prim("NOPE", Vec::new())),
+            Err(Error::UnknownPrimitive(_))
+        ));
+    }
+
+    #[test]
+    fn keys_are_hashed() {
+        let packed =
+            hex::decode("050a000000160000b2e19a9e74440d86c59f13dab8a18ff873e889ea").unwrap();
+        assert_eq!(
+            script_expr_hash(&packed),
+            "exprv6UsC1sN3Fk2XfgcJCL8NCerP5rCGy1PRESZAqr7L2JdzX55EN"
+        );
+    }
+
+    #[test]
+    fn optimized_addresses_are_parsed() {
+        for address in &[
+            "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB",
+            "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk",
+        ] {
+            let bytes = match address_expr(address).unwrap() {
+                Expr::Bytes(bytes) => bytes,
+                expr => panic!("expected bytes but found {:?}", expr),
+            };
+            assert_eq!(parse_address(&bytes).unwrap(), *address);
+        }
+        assert!(matches!(parse_address(&[0; 21]), Err(Error::Address(_))));
+        assert!(matches!(parse_address(&[1; 22]), Err(Error::Address(_))));
+    }
+
+    fn manager(fee: i64, counter: i64, gas_limit: i64, storage_limit: i64) -> Manager {
+        Manager {
+            source: "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
+            fee: BigInt::from(fee),
+            counter: BigInt::from(counter),
+            gas_limit: BigInt::from(gas_limit),
+            storage_limit: BigInt::from(storage_limit),
+        }
+    }
+
+    #[test]
+    fn transactions_are_forged() {
+        let op = Operation {
+            protocol: None,
+            signature: None,
+            branch: "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2".to_string(),
+            contents: vec![Content::Transaction {
+                manager: manager(50000, 128, 127, 0),
+                destination: "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string(),
+                parameters: Expr::left(Expr::Bytes(vec![0xca, 0xfe])),
+            }],
+        };
+        let forged = hex::encode(forge_operation(&op).unwrap());
+
+        let branch = decode_prefixed(&op.branch, "", BLOCK_HASH_PREFIX).unwrap();
+        let source =
+            decode_prefixed("tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB", "", TZ1_PREFIX).unwrap();
+        let destination =
+            decode_prefixed("KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk", "", KT1_PREFIX).unwrap();
+        assert_eq!(
+            forged,
+            [
+                hex::encode(&branch),
+                "6c".to_string(),
+                "00".to_string() + &hex::encode(source),
+                "d08603".to_string() + "8001" + "7f" + "00" + "00",
+                "01".to_string() + &hex::encode(destination) + "00",
+                "ff00".to_string() + "00000009" + "0505" + "0a00000002cafe",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            hex::encode(&branch),
+            "8fcf233671b6a04fcf679d2a381c2544ea6c1ea29ba6157776ed8424c7ccd00b"
+        );
+
+        let wrong_destination = Operation {
+            contents: vec![Content::Transaction {
+                manager: manager(0, 0, 0, 0),
+                destination: "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2".to_string(),
+                parameters: Expr::Bytes(Vec::new()),
+            }],
+            ..op
+        };
+        assert!(matches!(
+            forge_operation(&wrong_destination),
+            Err(Error::Prefix(_, _))
+        ));
+    }
+
+    #[test]
+    fn activations_and_reveals_are_forged() {
+        let pkh = "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB";
+        let secret = "41f98b15efc63fa893d61d7d6eee4a2ce9427ac4";
+        let mut forged = Vec::new();
+        forge_content(
+            &mut forged,
+            &Content::ActivateAccount {
+                pkh: pkh.to_string(),
+                secret: secret.to_string(),
+            },
+        )
+        .unwrap();
+        let pkh_bytes = decode_prefixed(pkh, "", TZ1_PREFIX).unwrap();
+        assert_eq!(
+            hex::encode(forged),
+            ["04", &hex::encode(&pkh_bytes), secret].concat()
+        );
+
+        let public_key = crate::crypto::derive_public_key(
+            "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+        )
+        .unwrap();
+        let public_key = public_key.as_str();
+        let mut forged = Vec::new();
+        forge_content(
+            &mut forged,
+            &Content::Reveal {
+                manager: manager(1269, 1, 10000, 0),
+                public_key: public_key.to_string(),
+            },
+        )
+        .unwrap();
+        let key_bytes = decode_prefixed(public_key, "", EDPK_PREFIX).unwrap();
+        assert_eq!(
+            hex::encode(forged),
+            [
+                "6b".to_string(),
+                "00".to_string() + &hex::encode(&pkh_bytes),
+                "f509".to_string() + "01" + "904e" + "00",
+                "00".to_string() + &hex::encode(key_bytes),
+            ]
+            .concat()
+        );
+
+        let short_secret = Content::ActivateAccount {
+            pkh: pkh.to_string(),
+            secret: "41f98b15".to_string(),
+        };
+        assert!(matches!(
+            forge_content(&mut Vec::new(), &short_secret),
+            Err(Error::ActivationSecret(_))
+        ));
+    }
+
+    #[test]
+    fn originations_are_forged() {
+        let mut forged = Vec::new();
+        forge_content(
+            &mut forged,
+            &Content::Origination {
+                manager: manager(1000, 1, 10000, 500),
+                code: Expr::List(vec![Expr::prim("FAILWITH", Vec::new())]),
+                storage: Expr::List(Vec::new()),
+            },
+        )
+        .unwrap();
+        let source =
+            decode_prefixed("tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB", "", TZ1_PREFIX).unwrap();
+        assert_eq!(
+            hex::encode(forged),
+            [
+                "6d".to_string(),
+                "00".to_string() + &hex::encode(source),
+                "e807".to_string() + "01" + "904e" + "f403",
+                // No balance and no delegate.
+                "00".to_string() + "00",
+                "00000007".to_string() + "02000000020327",
+                "00000005".to_string() + "0200000000",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn operations_are_read_back() {
+        let public_key = crate::crypto::derive_public_key(
+            "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk",
+        )
+        .unwrap();
+        let mut op = Operation {
+            protocol: None,
+            signature: None,
+            branch: "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2".to_string(),
+            contents: vec![
+                Content::Reveal {
+                    manager: manager(1269, 1, 10000, 0),
+                    public_key,
+                },
+                Content::Transaction {
+                    manager: manager(50000, 2, 127, 0),
+                    destination: "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string(),
+                    parameters: Expr::right(Expr::pair(
+                        Expr::String("tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".into()),
+                        Expr::Bytes(vec![0xca, 0xfe]),
+                    )),
+                },
+                Content::Origination {
+                    manager: manager(1000, 3, 10000, 500),
+                    code: Expr::List(vec![Expr::prim("FAILWITH", Vec::new())]),
+                    storage: Expr::Int(BigInt::from(-1000)),
+                },
+            ],
+        };
+        let forged = forge_operation(&op).unwrap();
+        let signature = [7; SIGNATURE_LENGTH];
+        op.signature = Some(encode_generic_signature(&signature));
+        assert_eq!(
+            unforge_operation(&[&forged[..], &signature].concat(), true).unwrap(),
+            op
+        );
+        assert!(matches!(
+            unforge_operation(&forged[..forged.len() - 1], false),
+            Err(Error::Truncated)
+        ));
+
+        let activation = Operation {
+            signature: None,
+            contents: vec![Content::ActivateAccount {
+                pkh: "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
+                secret: "41f98b15efc63fa893d61d7d6eee4a2ce9427ac4".to_string(),
+            }],
+            ..op
+        };
+        let forged = forge_operation(&activation).unwrap();
+        assert_eq!(unforge_operation(&forged, false).unwrap(), activation);
+        assert!(unforge_operation(&forged, true).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Requests to the node, with timeouts, retries of reads, and errors of the node parsed.
+
+use super::{Result, RpcError};
+use serde_json::Value;
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// How `TezosRpc` talks to the node.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// How long the node may take to send each part of a response.
+    pub read_timeout: Duration,
+    /// How many times a read which failed in a way that may be transient is sent again.
+    pub read_retries: u32,
+    /// How long to wait before the first retry. The wait doubles with each retry.
+    pub backoff: Duration,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            read_retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// What sending a request does to the node, which decides whether it can be sent again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Effect {
+    /// Reads or simulates something, which is safe to repeat.
+    Read,
+    /// Changes the state of the node, like injecting an operation. Requests which failed may
+    /// still have been applied, so they are sent exactly once.
+    Write,
+}
+
+#[derive(Debug)]
+pub(crate) struct Http {
+    config: HttpConfig,
+}
+
+impl Http {
+    pub(crate) fn new(config: HttpConfig) -> Self {
+        Http { config }
+    }
+
+    pub(crate) fn get(&self, url: &Url) -> Result<Value> {
+        self.send(Effect::Read, url, None)
+    }
+
+    /// Like `get`, but returns `None` if the node has nothing at `url`.
+    pub(crate) fn get_optional(&self, url: &Url) -> Result<Option<Value>> {
+        match self.get(url) {
+            Ok(value) => Ok(Some(value)),
+            Err(RpcError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn post(&self, effect: Effect, url: &Url, body: &Value) -> Result<Value> {
+        self.send(effect, url, Some(body))
+    }
+
+    /// Opens a response which streams JSON values, as `monitor` RPCs do, waiting at most
+    /// `read_timeout` for each part of it. Failures are left to the caller to retry.
+    pub(crate) fn stream(&self, url: &Url, read_timeout: Duration) -> Result<impl Read + Send> {
+        let response = self.call(url, None, read_timeout)?;
+        if !response.ok() {
+            return Err(status_error(response));
+        }
+        Ok(response.into_reader())
+    }
+
+    fn send(&self, effect: Effect, url: &Url, body: Option<&Value>) -> Result<Value> {
+        let mut backoff = self.config.backoff;
+        let mut retries = match effect {
+            Effect::Read => self.config.read_retries,
+            Effect::Write => 0,
+        };
+        loop {
+            match self.send_once(url, body) {
+                Err(e) if retries > 0 && is_transient(&e) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries -= 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send_once(&self, url: &Url, body: Option<&Value>) -> Result<Value> {
+        let response = self.call(url, body, self.config.read_timeout)?;
+        if !response.ok() {
+            return Err(status_error(response));
+        }
+        response.into_json().map_err(RpcError::IO)
+    }
+
+    /// Sends a request, `POST`ing `body` if there is one.
+    fn call(
+        &self,
+        url: &Url,
+        body: Option<&Value>,
+        read_timeout: Duration,
+    ) -> Result<ureq::Response> {
+        let mut request = match body {
+            Some(_) => ureq::post(url.as_str()),
+            None => ureq::get(url.as_str()),
+        };
+        request
+            .timeout_connect(self.config.connect_timeout.as_millis() as u64)
+            .timeout_read(read_timeout.as_millis() as u64);
+        let response = match body {
+            Some(body) => request.send_json(body.clone()),
+            None => request.call(),
+        };
+
+        // Failures to get a response at all come as synthetic responses.
+        if response.synthetic() {
+            let error = response
+                .into_synthetic_error()
+                .expect("synthetic responses carry their error");
+            return Err(RpcError::Http(error));
+        }
+        Ok(response)
+    }
+}
+
+/// Turns a response with an error status into an error.
+fn status_error(response: ureq::Response) -> RpcError {
+    let status = response.status();
+    let url = response.get_url().to_string();
+    match response.into_string() {
+        Ok(body) => parse_status_error(status, url, body),
+        Err(e) => RpcError::IO(e),
+    }
+}
+
+/// The node describes failures with an array of errors, each with a `kind` telling whether it
+/// may go away (`temporary`, `branch`) or not (`permanent`), and an `id` telling what it is.
+fn parse_status_error(status: u16, url: String, body: String) -> RpcError {
+    if status == 404 {
+        return RpcError::NotFound(url);
+    }
+
+    let errors: Value = match serde_json::from_str(&body) {
+        Ok(errors) => errors,
+        Err(_) => return RpcError::Status { status, body },
+    };
+    let (kind, id) = match errors.as_array().and_then(|errors| errors.first()) {
+        Some(error) => match (error["kind"].as_str(), error["id"].as_str()) {
+            (Some(kind), Some(id)) => (kind.to_string(), id.to_string()),
+            _ => return RpcError::Status { status, body },
+        },
+        None => return RpcError::Status { status, body },
+    };
+    RpcError::Node {
+        status,
+        kind,
+        id,
+        errors,
+    }
+}
+
+/// Whether sending the request again may succeed.
+fn is_transient(error: &RpcError) -> bool {
+    match error {
+        RpcError::Http(ureq::Error::DnsFailed(_))
+        | RpcError::Http(ureq::Error::ConnectionFailed(_)) => true,
+        RpcError::Http(ureq::Error::Io(e)) | RpcError::IO(e) => is_transient_io(e),
+        // Proxies in front of public nodes answer these while the node is busy or restarting.
+        RpcError::Status { status, .. } => matches!(status, 408 | 429 | 502 | 503 | 504),
+        _ => false,
+    }
+}
+
+/// Whether the connection timed out, was reset or was cut off, unlike malformed responses which
+/// would come again.
+fn is_transient_io(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut
+            // Read timeouts come as this on Unix.
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serves `responses` in turn, one per request, and counts the requests.
+    fn serve(responses: Vec<&'static str>) -> (Url, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let url = test_server::serve(move |_| {
+            responses[counter.fetch_add(1, Ordering::SeqCst)].to_string()
+        });
+        (Url::parse(&url).unwrap(), requests)
+    }
+
+    fn http() -> Http {
+        Http::new(HttpConfig {
+            backoff: Duration::from_millis(1),
+            ..HttpConfig::default()
+        })
+    }
+
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n\"ok\"";
+    const MALFORMED: &str = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nnot";
+
+    #[test]
+    fn reads_are_retried() {
+        let (url, requests) = serve(vec![UNAVAILABLE, UNAVAILABLE, OK]);
+        assert_eq!(http().get(&url).unwrap(), json!("ok"));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn malformed_responses_are_not_retried() {
+        let (url, requests) = serve(vec![MALFORMED, OK]);
+        assert!(matches!(http().get(&url), Err(RpcError::IO(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn writes_are_sent_once() {
+        let (url, requests) = serve(vec![UNAVAILABLE, OK]);
+        assert!(matches!(
+            http().post(Effect::Write, &url, &json!("op")),
+            Err(RpcError::Status { status: 503, .. })
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn node_errors_are_parsed() {
+        let body = json!([
+            {
+                "kind": "temporary",
+                "id": "proto.006-PsCARTHA.contract.counter_in_the_past",
+                "contract": "tz1X8dUY8H7x1ybPSfAYqqnkihpb6Q6VaSFz",
+                "expected": "1047",
+                "found": "1046",
+            },
+        ]);
+        match parse_status_error(500, String::new(), body.to_string()) {
+            RpcError::Node {
+                status,
+                kind,
+                id,
+                errors,
+            } => {
+                assert_eq!(status, 500);
+                assert_eq!(kind, "temporary");
+                assert_eq!(id, "proto.006-PsCARTHA.contract.counter_in_the_past");
+                assert_eq!(errors, body);
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+
+        assert!(matches!(
+            parse_status_error(500, String::new(), "Internal error".to_string()),
+            RpcError::Status { status: 500, .. }
+        ));
+        assert!(matches!(
+            parse_status_error(404, "http://node/".to_string(), String::new()),
+            RpcError::NotFound(_)
+        ));
+    }
+}
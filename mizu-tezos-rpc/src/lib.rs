@@ -1,20 +1,52 @@
+// Lets the derives of `mizu_michelson_derive` refer to this crate by name from inside it.
+extern crate self as mizu_tezos_rpc;
+
+mod counter;
 pub mod crypto;
-mod helper;
+pub mod forge;
+mod http;
 pub mod michelson;
+pub mod monitor;
+pub mod operation;
+mod protocol;
+pub mod signer;
+#[cfg(test)]
+mod test_server;
 
-use michelson::Expr;
+use counter::Counters;
+use michelson::{Address, BigMap, Bytes, Expr, FromMichelson, FromMichelsonError, ToMichelson};
 use num_bigint::{BigInt, BigUint};
 use num_traits::Zero;
+use operation::{Content, Manager, Operation};
+use protocol::Constants;
+pub use protocol::Protocol;
 use serde::Deserialize;
 use serde_json::Value;
+use signer::{Signer, SignerError};
+use std::collections::HashMap;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use url::Url;
 
-use chrono::DateTime;
+use chrono::{naive::NaiveDateTime, DateTime};
+pub use http::HttpConfig;
+use http::{Effect, Http};
 use mizu_tezos_interface::*;
 
-static PROTOCOL_CARTHAGE: &str = "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb";
+/// Operations can be included at most this many blocks after their branch (`max_operations_ttl`).
+const MAX_OPERATIONS_TTL: i64 = 60;
+/// How many times an operation is sent again after the node refused its counters.
+const COUNTER_RETRIES: u32 = 3;
+/// The validation pass of manager operations, which include contract calls.
+const MANAGER_OPERATIONS_PASS: &str = "3";
+
+/// Where an operation is in a block: its validation pass, and its index among the operations of
+/// the pass.
+type Position = (usize, usize);
 
 #[derive(Error, Debug)]
 pub enum RpcError {
@@ -22,136 +54,149 @@ pub enum RpcError {
     UrlParse(url::ParseError),
     #[error("error: {0}")]
     IO(io::Error),
+    #[error("failed to reach the node: {0}")]
+    Http(ureq::Error),
+    #[error("nothing found at {0}")]
+    NotFound(String),
+    #[error("the node answered {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("the node answered {status} with {id} ({kind}): {errors}")]
+    Node {
+        status: u16,
+        /// Whether the error may go away: `temporary`, `branch`, or `permanent`.
+        kind: String,
+        id: String,
+        /// All the errors the node returned.
+        errors: Value,
+    },
     #[error("deserialization error: {0} ({1})")]
     SerdeDeserialize(serde_json::error::Error, Value),
     #[error("deserialization error: {0}")]
     DeserializeBigInt(num_bigint::ParseBigIntError),
     #[error("crypto error: {0}")]
     Crypto(crypto::Error),
+    #[error("failed to forge operation: {0}")]
+    Forge(forge::Error),
+    #[error("the node forged {remote} but we forged {local}")]
+    ForgeMismatch { local: String, remote: String },
     #[error("tezos node rpc error: {0}")]
     Rpc(Value),
     #[error("error when decoding user data: {0}")]
     UserData(String),
+    #[error("unexpected Michelson value: {0}")]
+    Michelson(FromMichelsonError),
+    #[error("failed to sign operation: {0}")]
+    Signer(SignerError),
+    #[error("unsupported protocol: {0}")]
+    UnsupportedProtocol(String),
+    #[error("unsupported operation content: {0}")]
+    UnsupportedContent(Value),
+    #[error("operation {0} was refused by the node: {1}")]
+    Refused(String, Value),
+    #[error("an operation group needs at least one call")]
+    EmptyBatch,
+    #[error("operation {0} expired before being included")]
+    Expired(String),
+    #[error("operation {0} was not confirmed in time")]
+    Timeout(String),
+    #[error("operation {} failed at level {}: {}", .0.hash, .0.level, Value::from(.0.errors.clone()))]
+    Failed(Box<Receipt>),
+    #[error("operation {} was backtracked at level {}", .0.hash, .0.level)]
+    Backtracked(Box<Receipt>),
 }
 
 type Result<T> = std::result::Result<T, RpcError>;
 
-#[derive(Deserialize, Debug)]
-struct Bootstrapped {
-    block: String,
-    timestamp: String,
+/// The result of an operation, as reported in the metadata of its block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResultStatus {
+    Applied,
+    Skipped,
+    Backtracked,
+    Failed,
 }
 
-#[derive(Deserialize, Debug)]
-struct Constants {
-    proof_of_work_nonce_size: u8,
-    nonce_length: u8,
-    max_revelations_per_block: u8,
-    max_operation_data_length: i32,
-    max_proposals_per_delegate: u8,
-    preserved_cycles: u8,
-    blocks_per_cycle: i32,
-    blocks_per_commitment: i32,
-    blocks_per_roll_snapshot: i32,
-    blocks_per_voting_period: i32,
-    #[serde(with = "helper::seq_display_fromstr")]
-    time_between_blocks: Vec<i64>,
-    endorsers_per_block: u16,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    hard_gas_limit_per_operation: BigInt,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    hard_gas_limit_per_block: BigInt,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    proof_of_work_threshold: i64,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    tokens_per_roll: BigUint,
-    michelson_maximum_type_size: u16,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    seed_nonce_revelation_tip: BigUint,
-    origination_size: i32,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    block_security_deposit: BigUint,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    endorsement_security_deposit: BigUint,
-    #[serde(with = "helper::seq_display_fromstr")]
-    baking_reward_per_endorsement: Vec<BigUint>,
-    #[serde(with = "helper::seq_display_fromstr")]
-    endorsement_reward: Vec<BigUint>,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    cost_per_byte: BigUint,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    hard_storage_limit_per_operation: BigInt,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    test_chain_duration: i64,
-    quorum_min: i32,
-    quorum_max: i32,
-    min_proposal_quorum: i32,
-    initial_endorsers: u16,
-    #[serde(with = "serde_with::rust::display_fromstr")]
-    delay_per_missing_endorsement: i64,
+/// What happened to an operation included in a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub hash: String,
+    pub level: i64,
+    pub block_hash: String,
+    /// The worst status among the contents of the operation.
+    pub status: ResultStatus,
+    pub consumed_gas: BigInt,
+    pub paid_storage_size_diff: BigInt,
+    /// Mutez burnt to pay for the storage.
+    pub storage_burn: BigInt,
+    /// The addresses of the contracts the operation originated.
+    pub originated_contracts: Vec<String>,
+    pub errors: Vec<Value>,
 }
 
-fn parse_bigint(s: String) -> Result<BigInt> {
-    s.parse::<BigInt>().map_err(RpcError::DeserializeBigInt)
+impl Receipt {
+    /// Turns receipts of operations which weren't applied into errors.
+    pub fn into_result(self) -> Result<Receipt> {
+        match self.status {
+            ResultStatus::Applied => Ok(self),
+            ResultStatus::Failed => Err(RpcError::Failed(Box::new(self))),
+            ResultStatus::Backtracked | ResultStatus::Skipped => {
+                Err(RpcError::Backtracked(Box::new(self)))
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
-struct Operation {
-    protocol: Option<String>,
-    signature: Option<String>,
-    branch: String,
-    source: String,
-    destination: String,
-    fee: BigInt,
-    counter: BigInt,
-    gas_limit: BigInt,
-    storage_limit: BigInt,
-    parameters: Expr,
+/// How `TezosRpc::wait_for_operation` waits for an operation.
+#[derive(Debug, Clone)]
+pub struct WaitConfig {
+    /// The number of blocks on top of the including block before an operation counts as
+    /// confirmed.
+    pub confirmations: i64,
+    pub poll_interval: Duration,
+    pub timeout: Duration,
 }
 
-fn build_json(op: &Operation) -> Value {
-    let mut value = serde_json::json!(
-        { "branch": op.branch
-            , "contents":
-                [
-                    { "kind": "transaction"
-                    , "source": op.source
-                    , "fee": op.fee.to_string()
-                    , "counter": op.counter.to_string()
-                    , "gas_limit": op.gas_limit.to_string()
-                    , "storage_limit": op.storage_limit.to_string()
-                    , "amount": "0"
-                    , "destination": op.destination
-                    , "parameters":
-                        { "entrypoint": "default"
-                        , "value": op.parameters
-                        }
-                    }
-                ]
+impl Default for WaitConfig {
+    fn default() -> Self {
+        WaitConfig {
+            confirmations: 1,
+            poll_interval: Duration::from_secs(10),
+            // Operations expire after MAX_OPERATIONS_TTL blocks, which is within an hour.
+            timeout: Duration::from_secs(60 * 60),
         }
-    );
-
-    if let Some(protocol) = &op.protocol {
-        value
-            .as_object_mut()
-            .expect("value is an object")
-            .insert("protocol".into(), Value::String(protocol.into()));
     }
+}
 
-    if let Some(signature) = &op.signature {
-        value
-            .as_object_mut()
-            .expect("value is an object")
-            .insert("signature".into(), Value::String(signature.into()));
-    }
+#[derive(Deserialize, Debug)]
+struct Bootstrapped {
+    block: String,
+    timestamp: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BlockHeader {
+    hash: String,
+    level: i64,
+    predecessor: String,
+    timestamp: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BlockProtocols {
+    next_protocol: String,
+}
 
-    value
+fn parse_bigint(s: String) -> Result<BigInt> {
+    s.parse::<BigInt>().map_err(RpcError::DeserializeBigInt)
 }
 
 #[derive(Debug)]
 struct DryRunResult {
     consumed_gas: BigInt,
     paid_storage_size_diff: BigInt,
+    /// How many contracts the operation would originate, each of which pays for
+    /// `Constants::origination_size` more bytes.
+    originated_contracts: usize,
 }
 
 fn from_value<T>(value: &Value) -> Result<T>
@@ -166,40 +211,82 @@ fn deserialize_bigint_from_value(value: &Value) -> Result<BigInt> {
     from_value(value).and_then(parse_bigint)
 }
 
-// TODO: test remaining enums
-#[derive(Debug)]
+/// The parameter of the Mizu contract.
+#[derive(Debug, Clone, PartialEq, ToMichelson, FromMichelson)]
 pub enum MizuOp {
-    Post(Vec<Vec<u8>>, Vec<BigInt>),
-    Poke(String, Vec<u8>),
-    Register(Option<Vec<u8>>, Vec<u8>),
+    /// Messages to add to the postal box of the sender, and indices of messages to remove.
+    Post(Vec<Bytes>, Vec<BigUint>),
+    Poke(Address, Bytes),
+    Register(Option<Bytes>, Bytes),
 }
 
-impl MizuOp {
-    pub fn to_expr(&self) -> Expr {
-        match self {
-            MizuOp::Post(add, remove) => Expr::left(Expr::pair(
-                Expr::List(add.iter().cloned().map(Expr::Bytes).collect()),
-                Expr::List(remove.iter().cloned().map(Expr::nat).collect()),
-            )),
-            MizuOp::Poke(address, data) => Expr::right(Expr::left(Expr::pair(
-                Expr::String(address.to_string()),
-                Expr::Bytes(data.to_vec()),
-            ))),
-            MizuOp::Register(identity_key, prekey) => Expr::right(Expr::right(Expr::pair(
-                Expr::some(identity_key.clone().map(Expr::Bytes)),
-                Expr::Bytes(prekey.to_vec()),
-            ))),
+impl From<&Call<'_>> for MizuOp {
+    fn from(call: &Call) -> Self {
+        match *call {
+            Call::Post { add, remove } => MizuOp::Post(
+                add.iter().map(|x| Bytes(x.to_vec())).collect(),
+                remove.iter().map(|&&x| x.into()).collect(),
+            ),
+            Call::Poke {
+                target_address,
+                data,
+            } => MizuOp::Poke(Address(target_address.to_string()), Bytes(data.to_vec())),
+            Call::Register {
+                identity_key,
+                prekey,
+            } => MizuOp::Register(
+                identity_key.map(|x| Bytes(x.to_vec())),
+                Bytes(prekey.to_vec()),
+            ),
         }
     }
 }
 
+/// A message as the contract stores it.
+#[derive(Debug, Clone, PartialEq, ToMichelson, FromMichelson)]
+struct StoredMessage {
+    content: Bytes,
+    timestamp: NaiveDateTime,
+}
+
+/// User data as the contract stores it, which SCaml lays out as a balanced tree of pairs.
+#[derive(Debug, Clone, PartialEq, ToMichelson, FromMichelson)]
+#[michelson(layout = "balanced")]
+struct StoredUserData {
+    identity_key: Bytes,
+    prekey: Bytes,
+    postal_box: Vec<StoredMessage>,
+    pokes: Vec<Bytes>,
+}
+
 #[derive(Debug)]
 pub struct TezosRpc {
     debug: bool,
     host: Url,
     address: String,
-    secret_key: String,
+    signer: Box<dyn Signer>,
     contract_address: String,
+    /// The secret of the faucet file of the address, to activate it with if needed.
+    activation_secret: Option<String>,
+    /// Set once the public key of the address is known to be revealed, which implies that the
+    /// address is activated too.
+    revealed: AtomicBool,
+    /// Set once the address is known to be activated, when it has an activation secret.
+    activated: AtomicBool,
+    /// Block levels of timestamps we have looked up so far.
+    levels: Mutex<HashMap<NaiveDateTime, i64>>,
+    /// Messages posted by each address in the block with each timestamp, as we have looked them
+    /// up so far.
+    posts: Mutex<HashMap<(String, NaiveDateTime), Vec<Message>>>,
+    /// The first level which may include each operation `find_operation_status` looked for, by
+    /// operation hash. Blocks below it were scanned already.
+    scanned_levels: Mutex<HashMap<String, i64>>,
+    big_map_id: Mutex<Option<BigInt>>,
+    /// The protocol of operations branched from a block, along with the hash of the block.
+    next_protocol: Mutex<Option<(String, Protocol)>>,
+    /// Counters used by our operations which may still be in the mempool, by source address.
+    counters: Mutex<HashMap<String, Counters>>,
+    http: Http,
 }
 
 impl TezosRpc {
@@ -207,18 +294,69 @@ impl TezosRpc {
         debug: bool,
         host: Url,
         address: String,
-        secret_key: String,
+        signer: Box<dyn Signer>,
         contract_address: String,
     ) -> Self {
         Self {
             debug,
             host,
             address,
-            secret_key,
+            signer,
             contract_address,
+            activation_secret: None,
+            revealed: AtomicBool::new(false),
+            activated: AtomicBool::new(false),
+            levels: Mutex::new(HashMap::new()),
+            posts: Mutex::new(HashMap::new()),
+            scanned_levels: Mutex::new(HashMap::new()),
+            big_map_id: Mutex::new(None),
+            next_protocol: Mutex::new(None),
+            counters: Mutex::new(HashMap::new()),
+            http: Http::new(HttpConfig::default()),
         }
     }
 
+    /// Reads the contract without an address of our own. Operations fail with
+    /// `SignerError::NoKey`.
+    pub fn read_only(debug: bool, host: Url, contract_address: String) -> Self {
+        Self::new(
+            debug,
+            host,
+            String::new(),
+            Box::new(signer::NoSigner),
+            contract_address,
+        )
+    }
+
+    /// Activates the address with the `secret` of its faucet file before its first operation,
+    /// if it hasn't been activated yet.
+    pub fn with_activation_secret(mut self, secret: String) -> Self {
+        self.activation_secret = Some(secret);
+        self
+    }
+
+    /// Talks to the node with timeouts and retries from `config` instead of the default ones.
+    pub fn with_http_config(mut self, config: HttpConfig) -> Self {
+        self.http = Http::new(config);
+        self
+    }
+
+    fn levels(&self) -> MutexGuard<'_, HashMap<NaiveDateTime, i64>> {
+        lock(&self.levels)
+    }
+
+    fn posts(&self) -> MutexGuard<'_, HashMap<(String, NaiveDateTime), Vec<Message>>> {
+        lock(&self.posts)
+    }
+
+    fn scanned_levels(&self) -> MutexGuard<'_, HashMap<String, i64>> {
+        lock(&self.scanned_levels)
+    }
+
+    fn next_protocol_lock(&self) -> MutexGuard<'_, Option<(String, Protocol)>> {
+        lock(&self.next_protocol)
+    }
+
     fn resolve_path(&self, path: &str) -> Result<Url> {
         self.host.join(path).map_err(RpcError::UrlParse)
     }
@@ -226,41 +364,116 @@ impl TezosRpc {
     fn bootstrapped(&self) -> Result<Bootstrapped> {
         let url = self.resolve_path("monitor/bootstrapped")?;
 
-        ureq::get(url.as_str())
-            .call()
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))
+        self.http.get(&url).and_then(|x| from_value(&x))
     }
 
-    fn constants(&self) -> Result<Constants> {
-        let url = self.resolve_path("chains/main/blocks/head/context/constants")?;
+    fn constants(&self, block_id: &str) -> Result<Constants> {
+        let url =
+            self.resolve_path(&["chains/main/blocks/", block_id, "/context/constants"].concat())?;
 
-        ureq::get(url.as_str())
-            .call()
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))
+        self.http.get(&url).and_then(|x| from_value(&x))
     }
 
-    fn head_hash(&self) -> Result<String> {
-        let url = self.resolve_path("chains/main/blocks/head/hash")?;
+    fn chain_id(&self) -> Result<String> {
+        let url = self.resolve_path("chains/main/chain_id")?;
 
-        ureq::get(url.as_str())
-            .call()
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))
+        self.http.get(&url).and_then(|x| from_value(&x))
     }
 
-    fn chain_id(&self) -> Result<String> {
-        let url = self.resolve_path("chains/main/chain_id")?;
+    fn block_header(&self, block_id: &str) -> Result<BlockHeader> {
+        let url = self.resolve_path(&["chains/main/blocks/", block_id, "/header"].concat())?;
 
-        ureq::get(url.as_str())
-            .call()
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))
+        self.http.get(&url).and_then(|x| from_value(&x))
+    }
+
+    /// Returns the protocol operations branched from the block `block_hash` are validated by,
+    /// which differs from the protocol of the block itself at the last block of a protocol.
+    fn next_protocol(&self, block_hash: &str) -> Result<Protocol> {
+        if let Some((hash, protocol)) = &*self.next_protocol_lock() {
+            if hash == block_hash {
+                return Ok(*protocol);
+            }
+        }
+
+        let url = self.resolve_path(&["chains/main/blocks/", block_hash, "/protocols"].concat())?;
+        let protocols: BlockProtocols = self.http.get(&url).and_then(|x| from_value(&x))?;
+        let protocol = Protocol::from_hash(&protocols.next_protocol)?;
+        *self.next_protocol_lock() = Some((block_hash.to_string(), protocol));
+        Ok(protocol)
+    }
+
+    fn block_timestamp(&self, level: i64) -> Result<NaiveDateTime> {
+        let header = self.block_header(&level.to_string())?;
+        parse_timestamp(&header.timestamp)
+    }
+
+    /// Finds the level of the block with the given timestamp.
+    ///
+    /// The contract only records timestamps of messages, but since block timestamps are strictly
+    /// increasing, we can binary search the chain for the corresponding block.
+    fn level_at(&self, timestamp: NaiveDateTime) -> Result<i64> {
+        if let Some(&level) = self.levels().get(&timestamp) {
+            return Ok(level);
+        }
+
+        let head = self.block_header("head")?;
+        let (mut low, mut high) = (0, head.level);
+        // Invariant: the block we are looking for lies in (low, high].
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.block_timestamp(mid)? < timestamp {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        if self.block_timestamp(high)? != timestamp {
+            return Err(RpcError::UserData(format!(
+                "no block found with timestamp {}",
+                timestamp
+            )));
+        }
+
+        self.levels().insert(timestamp, high);
+        Ok(high)
+    }
+
+    fn user_data(&self, address: &str, since: Option<NaiveDateTime>) -> Result<Option<UserData>> {
+        let value = self.get_from_big_map(address)?;
+        value
+            .map(|value| {
+                parse_user_data(&value, since, |timestamp| {
+                    self.posted_at(address, timestamp)
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the messages `address` posted in the block with the given timestamp.
+    ///
+    /// They are numbered in the order they were posted in the block, which unlike their position
+    /// in the postal box doesn't shift when earlier messages are removed.
+    fn posted_at(&self, address: &str, timestamp: NaiveDateTime) -> Result<Vec<Message>> {
+        let key = (address.to_string(), timestamp);
+        if let Some(messages) = self.posts().get(&key) {
+            return Ok(messages.clone());
+        }
+
+        let level = self.level_at(timestamp)?;
+        let addresses = vec![address.to_string()].into_iter().collect();
+        let messages: Vec<_> = self
+            .block_updates(level, &addresses)?
+            .updates
+            .into_iter()
+            .flat_map(|update| match update {
+                UserDataUpdate::Posted { messages, .. } => messages,
+                _ => vec![],
+            })
+            .collect();
+
+        self.posts().insert(key, messages.clone());
+        Ok(messages)
     }
 
     fn counter(&self) -> Result<BigInt> {
@@ -273,158 +486,601 @@ impl TezosRpc {
             .concat(),
         )?;
 
-        let s: String = ureq::get(url.as_str())
-            .call()
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))?;
+        let s: String = self.http.get(&url).and_then(|x| from_value(&x))?;
         parse_bigint(s)
     }
 
+    fn balance(&self) -> Result<BigInt> {
+        let url = self.resolve_path(
+            &[
+                "chains/main/blocks/head/context/contracts/",
+                &self.address,
+                "/balance",
+            ]
+            .concat(),
+        )?;
+
+        // Implicit accounts without tez don't exist as far as the node is concerned.
+        match self.http.get_optional(&url)? {
+            Some(balance) => from_value(&balance).and_then(parse_bigint),
+            None => Ok(Zero::zero()),
+        }
+    }
+
+    /// Returns the public key of the address, or `None` if it hasn't been revealed yet.
+    fn manager_key(&self) -> Result<Option<String>> {
+        let url = self.resolve_path(
+            &[
+                "chains/main/blocks/head/context/contracts/",
+                &self.address,
+                "/manager_key",
+            ]
+            .concat(),
+        )?;
+
+        self.http.get(&url).and_then(|x| from_value(&x))
+    }
+
+    /// Activates the address and waits until the activation is included, as operations of
+    /// the address can't spend its tez before then.
+    ///
+    /// The node refuses to activate an address twice, in which case there is nothing to do, but
+    /// it refuses a wrong secret the same way. The address has tez only in the first case.
+    fn activate(&self, secret: &str) -> Result<()> {
+        let head = self.block_header("head")?;
+        let protocol = self.next_protocol(&head.hash)?;
+
+        // Activations are anonymous operations, which are neither signed nor grouped with
+        // manager operations.
+        let mut op = Operation {
+            protocol: None,
+            signature: None,
+            branch: head.hash,
+            contents: vec![Content::ActivateAccount {
+                pkh: self.address.clone(),
+                secret: secret.to_string(),
+            }],
+        };
+        let sop = self.serialize_operation(&op)?;
+
+        op.protocol = Some(protocol.hash().to_string());
+        match self.preapply_operation(&op) {
+            Err(e) if error_id(&e).ends_with(".operation.invalid_activation") => {
+                return if self.balance()? > Zero::zero() {
+                    Ok(())
+                } else {
+                    Err(e)
+                };
+            }
+            result => result?,
+        };
+
+        let hash = self.inject_operation(&sop)?;
+
+        if self.debug {
+            eprintln!("activation hash: {}", hash);
+        }
+
+        let handle = OperationHandle {
+            hash,
+            level: head.level,
+        };
+        let config = WaitConfig {
+            confirmations: 0,
+            ..WaitConfig::default()
+        };
+        self.wait_for_operation(&handle, &config).map(|_| ())
+    }
+
     fn serialize_operation(&self, op: &Operation) -> Result<String> {
+        let local = hex::encode(forge::forge_operation(op).map_err(RpcError::Forge)?);
+
+        if self.debug {
+            let remote = self.forge_operation_remotely(op)?;
+            if remote != local {
+                return Err(RpcError::ForgeMismatch { local, remote });
+            }
+        }
+
+        Ok(local)
+    }
+
+    fn forge_operation_remotely(&self, op: &Operation) -> Result<String> {
         let url = self.resolve_path("chains/main/blocks/head/helpers/forge/operations")?;
 
-        let payload = build_json(op);
+        let payload = op.to_json();
 
-        ureq::post(url.as_str())
-            .send_json(payload)
-            .into_json()
-            .map_err(RpcError::IO)
+        self.http
+            .post(Effect::Read, &url, &payload)
             .and_then(|x| from_value(&x))
     }
 
-    fn dry_run_contract(&self, op: &Operation, chain_id: &str) -> Result<DryRunResult> {
+    /// Runs an operation without checking its signature, returning the results of its contents.
+    fn dry_run_operation(&self, op: &Operation, chain_id: &str) -> Result<Vec<DryRunResult>> {
         let url = self.resolve_path("chains/main/blocks/head/helpers/scripts/run_operation")?;
 
         let payload = serde_json::json!(
-            { "operation": build_json(op)
+            { "operation": op.to_json()
             , "chain_id": chain_id
             }
         );
 
-        let result: Value = ureq::post(url.as_str())
-            .send_json(payload)
-            .into_json()
-            .map_err(RpcError::IO)
+        let result: Value = self
+            .http
+            .post(Effect::Read, &url, &payload)
             .and_then(|x| from_value(&x))?;
 
-        let op_result = &result["contents"][0]["metadata"]["operation_result"];
-        let consumed_gas = op_result
-            .get("consumed_gas")
-            .map(deserialize_bigint_from_value)
-            .unwrap_or_else(|| Ok(Zero::zero()))?;
-        let paid_storage_size_diff = op_result
-            .get("paid_storage_size_diff")
-            .map(deserialize_bigint_from_value)
-            .unwrap_or_else(|| Ok(Zero::zero()))?;
-
-        Ok(DryRunResult {
-            consumed_gas,
-            paid_storage_size_diff,
-        })
+        let contents = result["contents"]
+            .as_array()
+            .ok_or_else(|| RpcError::UserData("expected operation contents".to_string()))?;
+        contents
+            .iter()
+            .map(|content| {
+                let op_result = &content["metadata"]["operation_result"];
+                let consumed_gas = op_result
+                    .get("consumed_gas")
+                    .map(deserialize_bigint_from_value)
+                    .unwrap_or_else(|| Ok(Zero::zero()))?;
+                let paid_storage_size_diff = op_result
+                    .get("paid_storage_size_diff")
+                    .map(deserialize_bigint_from_value)
+                    .unwrap_or_else(|| Ok(Zero::zero()))?;
+                let originated_contracts = op_result["originated_contracts"]
+                    .as_array()
+                    .map_or(0, Vec::len);
+
+                Ok(DryRunResult {
+                    consumed_gas,
+                    paid_storage_size_diff,
+                    originated_contracts,
+                })
+            })
+            .collect()
     }
 
     fn preapply_operation(&self, op: &Operation) -> Result<Value> {
         let url = self.resolve_path("chains/main/blocks/head/helpers/preapply/operations")?;
 
-        let payload = serde_json::json!(vec![build_json(op)]);
+        let payload = serde_json::json!(vec![op.to_json()]);
 
-        ureq::post(url.as_str())
-            .send_json(payload)
-            .into_json()
-            .map_err(RpcError::IO)
-            .and_then(|x| from_value(&x))
+        let result: Value = self
+            .http
+            .post(Effect::Read, &url, &payload)
+            .and_then(|x| from_value(&x))?;
+
+        if result[0].get("id").is_some() {
+            // some error occurred
+            eprintln!("preapply error: {}", result);
+
+            return Err(RpcError::Rpc(result));
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the hashes of the operations in the block at `level`, by validation pass.
+    fn operation_hashes(&self, level: i64) -> Result<Vec<Vec<String>>> {
+        let url = self.resolve_path(&format!("chains/main/blocks/{}/operation_hashes", level))?;
+
+        self.http.get(&url).and_then(|x| from_value(&x))
+    }
+
+    fn block_operation(&self, level: i64, (pass, index): Position) -> Result<Value> {
+        let url = self.resolve_path(&format!(
+            "chains/main/blocks/{}/operations/{}/{}",
+            level, pass, index
+        ))?;
+
+        self.http.get(&url)
+    }
+
+    fn pending_operations(&self) -> Result<Value> {
+        let url = self.resolve_path("chains/main/mempool/pending_operations")?;
+
+        self.http.get(&url)
     }
 
+    /// Returns where the operation is in the block at `level`, if it is there.
+    ///
+    /// Contract calls are manager operations, but activations are anonymous ones, which are
+    /// validated in an earlier pass.
+    fn find_in_block(&self, operation: &OperationHandle, level: i64) -> Result<Option<Position>> {
+        let hashes = self.operation_hashes(level)?;
+        Ok(hashes.iter().enumerate().find_map(|(pass, hashes)| {
+            let index = hashes.iter().position(|hash| hash == &operation.hash)?;
+            Some((pass, index))
+        }))
+    }
+
+    /// Looks for the operation in blocks from `first_level` to `last_level`.
+    fn find_inclusion(
+        &self,
+        operation: &OperationHandle,
+        first_level: i64,
+        last_level: i64,
+    ) -> Result<Option<(i64, Position)>> {
+        for level in first_level..=last_level {
+            if let Some(position) = self.find_in_block(operation, level)? {
+                return Ok(Some((level, position)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn receipt(
+        &self,
+        operation: &OperationHandle,
+        level: i64,
+        position: Position,
+    ) -> Result<Receipt> {
+        let block_id = level.to_string();
+        let header = self.block_header(&block_id)?;
+        let constants = self.constants(&block_id)?;
+        parse_receipt(
+            &operation.hash,
+            level,
+            &header.hash,
+            &self.block_operation(level, position)?,
+            &constants,
+        )
+    }
+
+    /// Looks for the operation in blocks after its branch, then in the mempool.
+    ///
+    /// Blocks scanned by previous calls are skipped, except the one including the operation,
+    /// which may be reorganized away.
+    pub fn find_operation_status(&self, operation: &OperationHandle) -> Result<OperationStatus> {
+        let head_level = self.block_header("head")?.level;
+        let last_level = head_level.min(operation.level + MAX_OPERATIONS_TTL);
+        let first_level = self
+            .scanned_levels()
+            .get(&operation.hash)
+            .copied()
+            .unwrap_or(operation.level + 1);
+        let inclusion = self.find_inclusion(operation, first_level, last_level)?;
+        {
+            let mut scanned_levels = self.scanned_levels();
+            // Operations are not looked for long after they are included or expire.
+            scanned_levels.retain(|_, level| *level + MAX_OPERATIONS_TTL >= head_level);
+            scanned_levels.insert(
+                operation.hash.clone(),
+                inclusion.map_or(last_level + 1, |(level, _)| level),
+            );
+        }
+
+        if let Some((level, position)) = inclusion {
+            let status = match self.receipt(operation, level, position)?.status {
+                ResultStatus::Applied => OperationStatus::Included {
+                    level,
+                    confirmations: head_level - level,
+                },
+                _ => OperationStatus::Failed,
+            };
+            return Ok(status);
+        }
+
+        if head_level - operation.level > MAX_OPERATIONS_TTL {
+            Ok(OperationStatus::Expired)
+        } else if refusal(&self.pending_operations()?, &operation.hash).is_some() {
+            Ok(OperationStatus::Failed)
+        } else {
+            Ok(OperationStatus::Injected)
+        }
+    }
+
+    /// Polls the chain until the operation is included and confirmed.
+    ///
+    /// Fails if the operation is refused by the mempool, expires, or isn't applied, and gives
+    /// up after `config.timeout`.
+    pub fn wait_for_operation(
+        &self,
+        operation: &OperationHandle,
+        config: &WaitConfig,
+    ) -> Result<Receipt> {
+        let deadline = Instant::now() + config.timeout;
+        // Blocks before this level are known not to include the operation.
+        let mut next_level = operation.level + 1;
+        let mut receipt: Option<Receipt> = None;
+        loop {
+            let head_level = self.block_header("head")?.level;
+
+            // Check again that the block still includes the operation, as it may have been
+            // replaced by a reorganization.
+            if let Some(level) = receipt.as_ref().map(|receipt| receipt.level) {
+                if self.find_in_block(operation, level)?.is_none() {
+                    receipt = None;
+                    next_level = level;
+                }
+            }
+            if receipt.is_none() {
+                let last_level = head_level.min(operation.level + MAX_OPERATIONS_TTL);
+                if let Some((level, position)) =
+                    self.find_inclusion(operation, next_level, last_level)?
+                {
+                    // Results don't change with confirmations, so failures are reported at once.
+                    receipt = Some(self.receipt(operation, level, position)?.into_result()?);
+                }
+                next_level = next_level.max(last_level + 1);
+            }
+
+            match receipt {
+                Some(ref receipt) if head_level - receipt.level >= config.confirmations => {
+                    return Ok(receipt.clone());
+                }
+                Some(ref receipt) => {
+                    if self.debug {
+                        eprintln!(
+                            "operation {} included at level {}, head at {}",
+                            operation.hash, receipt.level, head_level
+                        );
+                    }
+                }
+                None => {
+                    if head_level - operation.level > MAX_OPERATIONS_TTL {
+                        return Err(RpcError::Expired(operation.hash.clone()));
+                    }
+                    if let Some(errors) = refusal(&self.pending_operations()?, &operation.hash) {
+                        return Err(RpcError::Refused(operation.hash.clone(), errors));
+                    }
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(RpcError::Timeout(operation.hash.clone()));
+            }
+            thread::sleep(config.poll_interval);
+        }
+    }
+
+    /// Injects a signed operation, which is never sent again on failure: the node may have
+    /// received it anyway, and the same operation can only be included once at best.
     fn inject_operation(&self, signed_sop: &str) -> Result<String> {
         let url = self.resolve_path("injection/operation?chain=main")?;
 
         let payload = serde_json::json!(signed_sop);
 
-        ureq::post(url.as_str())
-            .send_json(payload)
-            .into_json()
-            .map_err(RpcError::IO)
+        self.http
+            .post(Effect::Write, &url, &payload)
             .and_then(|x| from_value(&x))
     }
 
     pub fn get_from_big_map(&self, key: &str) -> Result<Option<Expr>> {
+        // The contract stores user data in a big map keyed by address.
+        let packed = forge::pack(&forge::address_expr(key).map_err(RpcError::Forge)?)
+            .map_err(RpcError::Forge)?;
+        let key_hash = forge::script_expr_hash(&packed);
+        let url = self.resolve_path(
+            &[
+                "chains/main/blocks/head/context/big_maps/",
+                &self.big_map_id()?.to_string(),
+                "/",
+                &key_hash,
+            ]
+            .concat(),
+        )?;
+
+        // Missing keys are reported as not found.
+        self.http
+            .get_optional(&url)?
+            .map(|x| from_value(&x))
+            .transpose()
+    }
+
+    /// Returns the ID of the big map the contract stores user data in.
+    fn big_map_id(&self) -> Result<BigInt> {
+        // The storage of a contract is its big map, whose ID never changes.
+        let mut big_map_id = lock(&self.big_map_id);
+        if let Some(id) = &*big_map_id {
+            return Ok(id.clone());
+        }
+
         let url = self.resolve_path(
             &[
                 "chains/main/blocks/head/context/contracts/",
                 &self.contract_address,
-                "/big_map_get",
+                "/storage",
             ]
             .concat(),
         )?;
-
-        let payload = serde_json::json!(
-        { "key": Expr::String(key.to_string())
-        , "type": Expr::Prim {
-                prim: "address".to_string(),
-                args: Vec::new()
+        let storage: Expr = self.http.get(&url).and_then(|x| from_value(&x))?;
+        match BigMap::<Address, StoredUserData>::from_michelson(&storage)
+            .map_err(RpcError::Michelson)?
+        {
+            BigMap::Id(id) => {
+                *big_map_id = Some(id.clone());
+                Ok(id)
             }
-        });
+            BigMap::Literal(_) => Err(RpcError::UserData(
+                "expected the storage to be the ID of a big map".to_string(),
+            )),
+        }
+    }
 
-        let value = ureq::post(url.as_str())
-            .send_json(payload)
-            .into_json()
-            .map_err(RpcError::IO)?;
+    /// Sets the fee of each manager operation in the group to what bakers require by default,
+    /// and returns the serialized operation.
+    ///
+    /// Each content pays the minimal fee as if it were alone, which overestimates the fee of
+    /// groups slightly but never makes them unacceptable.
+    fn serialize_and_set_fees(&self, op: &mut Operation) -> Result<String> {
+        // currently hardcoded, since it seems we can't get these values programmatically:
+        // https://gitlab.com/tezos/tezos/-/issues/425
+        let minimal_fees = 100;
+        let minimal_nanotez_per_gas_unit = 100;
+        let minimal_nanotez_per_byte = 1000;
 
-        if value.is_null() {
-            Ok(None)
-        } else {
-            from_value(&value).map(Some)
+        // Fees are part of what they pay for, so we repeat until they are large enough.
+        loop {
+            let mut changed = false;
+            for (i, content) in op.contents.iter_mut().enumerate() {
+                let mut forged = Vec::new();
+                forge::forge_content(&mut forged, content).map_err(RpcError::Forge)?;
+                let manager = match content.manager_mut() {
+                    Some(manager) => manager,
+                    None => continue,
+                };
+                // The first content also pays for the branch and the appended signature.
+                let byte_length = forged.len() + if i == 0 { 32 + 64 } else { 0 };
+
+                let fee = (minimal_fees * 1000
+                    + minimal_nanotez_per_byte * byte_length
+                    + minimal_nanotez_per_gas_unit * manager.gas_limit.clone())
+                    / 1000;
+
+                if manager.fee <= fee {
+                    manager.fee = fee + 1;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
         }
-    }
 
-    fn serialize_and_set_fee(&self, op: &mut Operation) -> Result<String> {
         let sop = self.serialize_operation(&op)?;
 
         if self.debug {
             eprintln!("serialized_operation: {}", &sop);
         }
 
-        // sop is hex-encoded so we divide by 2 and add 64 bytes for the appended signature.
-        let op_byte_length = sop.len() / 2 + 64;
+        Ok(sop)
+    }
 
-        // currently hardcoded, since it seems we can't get these values programmatically:
-        // https://gitlab.com/tezos/tezos/-/issues/425
-        let minimal_fees = 100;
-        let minimal_nanotez_per_gas_unit = 100;
-        let minimal_nanotez_per_byte = 1000;
+    pub fn run_mizu_operation(&self, parameters: &MizuOp) -> Result<OperationHandle> {
+        self.run_mizu_operations(std::slice::from_ref(parameters))
+    }
 
-        let total_fee = (minimal_fees * 1000
-            + minimal_nanotez_per_byte * op_byte_length
-            + minimal_nanotez_per_gas_unit * op.gas_limit.clone())
-            / 1000;
+    /// Calls the contract with each of `parameters` in turn, in a single operation group which
+    /// is included in one block and fails as a whole.
+    ///
+    /// Separate operations can wait for the same block too, as the counters of our operations
+    /// in the mempool are tracked.
+    pub fn run_mizu_operations(&self, parameters: &[MizuOp]) -> Result<OperationHandle> {
+        if parameters.is_empty() {
+            return Err(RpcError::EmptyBatch);
+        }
 
-        if op.fee <= total_fee {
-            op.fee = total_fee + 1;
-            if self.debug {
-                eprintln!("fee set to {}", op.fee);
-            }
-            self.serialize_and_set_fee(op)
+        let contents = parameters
+            .iter()
+            .map(|parameters| {
+                let parameters = parameters.to_michelson();
+                if self.debug {
+                    eprintln!("parameters: {:#}", parameters);
+                }
+
+                Content::Transaction {
+                    manager: self.manager(),
+                    destination: self.contract_address.to_string(),
+                    parameters,
+                }
+            })
+            .collect();
+        self.run_manager_operation(contents)
+    }
+
+    /// Originates a contract running `code` from `storage`, whose address is in the receipt of
+    /// the operation (see `wait_for_operation`).
+    ///
+    /// This is the only call which doesn't use the contract address of `self`.
+    pub fn originate(&self, code: Expr, storage: Expr) -> Result<OperationHandle> {
+        if self.debug {
+            eprintln!("storage: {:#}", storage);
+        }
+
+        self.run_manager_operation(vec![Content::Origination {
+            manager: self.manager(),
+            code,
+            storage,
+        }])
+    }
+
+    /// The manager of contents we send, whose other fields are set when they are sent.
+    fn manager(&self) -> Manager {
+        Manager {
+            source: self.address.to_string(),
+            fee: Zero::zero(),
+            counter: Zero::zero(),
+            gas_limit: Zero::zero(),
+            storage_limit: Zero::zero(),
+        }
+    }
+
+    fn counters(&self) -> MutexGuard<'_, HashMap<String, Counters>> {
+        lock(&self.counters)
+    }
+
+    /// Sends the manager operations `contents` in a group, after activating our account and
+    /// revealing its public key if needed.
+    ///
+    /// If the node disagrees with the counters we used, which happens when another client with
+    /// the same key sends operations too, they are looked up again and the group sent anew.
+    fn run_manager_operation(&self, contents: Vec<Content>) -> Result<OperationHandle> {
+        // A new account has to be activated and reveal its public key before anything else.
+        let public_key = if self.revealed.load(Ordering::Relaxed) {
+            None
+        } else if self.manager_key()?.is_some() {
+            self.revealed.store(true, Ordering::Relaxed);
+            None
         } else {
-            Ok(sop)
+            if let Some(secret) = &self.activation_secret {
+                if !self.activated.load(Ordering::Relaxed) {
+                    self.activate(secret)?;
+                    self.activated.store(true, Ordering::Relaxed);
+                }
+            }
+            Some(self.signer.public_key().map_err(RpcError::Signer)?)
+        };
+
+        // Operations of the address are sent one at a time, so that each takes the counters
+        // after those of the previous one.
+        let mut counters = self.counters();
+        let counters = counters.entry(self.address.clone()).or_default();
+        let mut retries = COUNTER_RETRIES;
+        loop {
+            match self.send_manager_operation(counters, contents.clone(), public_key.as_deref()) {
+                Err(e) if retries > 0 && is_counter_error(&e) => {
+                    if self.debug {
+                        eprintln!("sending again after a counter error: {}", e);
+                    }
+                    counters.reset();
+                    retries -= 1;
+                }
+                result => return result,
+            }
         }
     }
 
     // Code here was written based on the following sources:
     // - https://www.ocamlpro.com/2018/11/15/an-introduction-to-tezos-rpcs-a-basic-wallet/
     // - https://medium.com/chain-accelerator/how-to-use-tezos-rpcs-16c362f45d64
-    pub fn run_mizu_operation(&self, parameters: &MizuOp) -> Result<String> {
-        let parameters = parameters.to_expr();
-        let s = serde_json::to_string(&parameters).expect("serde should deserialize any MizuOp");
+    //
+    // `public_key` is revealed along with `contents` unless a group of ours revealing it is still
+    // in the mempool.
+    fn send_manager_operation(
+        &self,
+        counters: &mut Counters,
+        mut contents: Vec<Content>,
+        public_key: Option<&str>,
+    ) -> Result<OperationHandle> {
+        // The mempool is read before the head, see `Counters::last_used`.
+        let pending_operations = self.pending_operations()?;
+        let head_counter = self.counter()?;
+        let counter = counters.last_used(&self.address, &head_counter, &pending_operations);
+        let reveal_pending = counters.reveal_pending();
+
         if self.debug {
-            eprintln!("{}", s);
-            eprintln!("{:?}", serde_json::from_str::<michelson::Expr>(&s));
+            eprintln!("counter: {} (at head: {})", counter, head_counter);
+            eprintln!("reveal pending: {}", reveal_pending);
         }
 
-        let counter = self.counter()? + 1;
-
-        if self.debug {
-            eprintln!("counter: {}", counter);
+        // The dry run reveals the key even if it is pending, as the key isn't known at the head.
+        if let Some(public_key) = public_key {
+            contents.insert(
+                0,
+                Content::Reveal {
+                    manager: self.manager(),
+                    public_key: public_key.to_string(),
+                },
+            );
         }
 
         let bootstrapped = self.bootstrapped()?;
@@ -433,16 +1089,19 @@ impl TezosRpc {
             eprintln!("bootstrapped: {:?}", bootstrapped);
         }
 
-        let constants = self.constants()?;
+        let head = self.block_header("head")?;
+        let branch = head.hash;
+        let protocol = self.next_protocol(&branch)?;
 
         if self.debug {
-            eprintln!("constants: {:?}", constants);
+            eprintln!("head hash: {}", branch);
+            eprintln!("protocol: {:?}", protocol);
         }
 
-        let branch = self.head_hash()?;
+        let constants = self.constants(&branch)?;
 
         if self.debug {
-            eprintln!("head hash: {}", branch);
+            eprintln!("constants: {:?}", constants);
         }
 
         let chain_id = self.chain_id()?;
@@ -451,64 +1110,76 @@ impl TezosRpc {
             eprintln!("chain_id: {}", chain_id);
         }
 
+        for manager in contents.iter_mut().filter_map(Content::manager_mut) {
+            manager.gas_limit = constants.hard_gas_limit_per_operation.clone();
+            manager.storage_limit = constants.hard_storage_limit_per_operation.clone();
+        }
         let mut op = Operation {
             branch,
-            source: self.address.to_string(),
-            counter,
-            fee: Zero::zero(),
-            gas_limit: constants.hard_gas_limit_per_operation,
-            storage_limit: constants.hard_storage_limit_per_operation,
-            destination: self.contract_address.to_string(),
-            parameters,
+            contents,
             protocol: None,
             signature: None,
         };
 
-        let dummy_signature =
-            crypto::sign_serialized_operation(&self.serialize_operation(&op)?, &self.secret_key)
-                .map_err(RpcError::Crypto)?
-                .0;
+        // Operations are run on top of the head rather than the mempool, so the dry run takes
+        // the counters following the one at the head. Its results don't depend on them.
+        set_counters(&mut op, &head_counter);
+        // Signatures aren't checked when running operations, so we don't bother the signer.
+        op.signature = Some(crypto::encode_generic_signature(&[0; 64]));
 
-        op.signature = Some(dummy_signature);
+        let dry_run_results = self.dry_run_operation(&op, &chain_id)?;
 
-        let dry_run_result = self.dry_run_contract(&op, &chain_id)?;
+        for (content, dry_run_result) in op.contents.iter_mut().zip(dry_run_results) {
+            if self.debug {
+                eprintln!("consumed_gas: {}", dry_run_result.consumed_gas);
+                eprintln!(
+                    "paid_storage_size_diff: {}",
+                    dry_run_result.paid_storage_size_diff
+                );
+            }
 
-        if self.debug {
-            eprintln!("consumed_gas: {}", dry_run_result.consumed_gas);
-            eprintln!(
-                "paid_storage_size_diff: {}",
-                dry_run_result.paid_storage_size_diff
-            );
+            let manager = content
+                .manager_mut()
+                .expect("groups only contain manager operations");
+            manager.gas_limit = dry_run_result.consumed_gas + 100;
+            manager.storage_limit = dry_run_result.paid_storage_size_diff
+                + BigInt::from(constants.origination_size)
+                    * BigInt::from(dry_run_result.originated_contracts)
+                + 20;
+        }
+        let reveals = public_key.is_some() && !reveal_pending;
+        if public_key.is_some() && reveal_pending {
+            op.contents.remove(0);
         }
-
-        op.gas_limit = dry_run_result.consumed_gas + 100;
-        op.storage_limit = dry_run_result.paid_storage_size_diff + 20;
         op.signature = None;
+        let last_counter = set_counters(&mut op, &counter);
 
-        let sop = self.serialize_and_set_fee(&mut op)?;
+        let sop = self.serialize_and_set_fees(&mut op)?;
 
-        let (signature, raw_signature) =
-            crypto::sign_serialized_operation(&sop, &self.secret_key).map_err(RpcError::Crypto)?;
+        let (signature, raw_signature) = self
+            .signer
+            .sign(
+                crypto::GENERIC_OPERATION_WATERMARK,
+                &hex::decode(&sop).expect("we encode operations in hex"),
+            )
+            .map_err(RpcError::Signer)?;
 
         if self.debug {
             eprintln!("signature: {}", signature);
             eprintln!("raw_signature length: {}", raw_signature.len()); // 64
         }
 
-        op.protocol = Some(PROTOCOL_CARTHAGE.to_string());
+        op.protocol = Some(protocol.hash().to_string());
         op.signature = Some(signature);
 
-        let preapply_result = self.preapply_operation(&op)?;
+        // Preapplying works on top of the head too, so operations queued behind others of ours
+        // are left for the mempool to check.
+        if counter == head_counter {
+            let preapply_result = self.preapply_operation(&op)?;
 
-        if preapply_result[0].get("id").is_some() {
-            // some error occurred
-            eprintln!("preapply error: {}", preapply_result);
-
-            return Err(RpcError::Rpc(preapply_result));
-        }
-
-        if self.debug {
-            eprintln!("preapply_result: {}", preapply_result);
+            if self.debug {
+                eprintln!("preapply_result: {}", preapply_result);
+            }
         }
 
         let signed_sop = [sop, hex::encode(raw_signature)].concat();
@@ -523,55 +1194,182 @@ impl TezosRpc {
             eprintln!("operation hash: {}", hash);
         }
 
-        Ok(hash)
+        counters.record(hash.clone(), counter + 1, last_counter, reveals);
+        Ok(OperationHandle {
+            hash,
+            level: head.level,
+        })
     }
 }
 
-fn decode_bytes(value: &Value) -> Result<Vec<u8>> {
-    let s = value
-        .get("bytes")
-        .ok_or_else(|| RpcError::UserData("expected bytes".to_string()))?
-        .as_str()
-        .ok_or_else(|| RpcError::UserData("expected string".to_string()))?;
-    hex::decode(s).map_err(|e| RpcError::UserData(format!("{}", e)))
+/// Gives the manager contents of `op` the counters following `last`, and returns the last one
+/// given.
+fn set_counters(op: &mut Operation, last: &BigInt) -> BigInt {
+    let mut counter = last.clone();
+    for manager in op.contents.iter_mut().filter_map(Content::manager_mut) {
+        counter += 1;
+        manager.counter = counter.clone();
+    }
+    counter
 }
 
-fn decode_message(value: &Value) -> Result<Message> {
-    let content = decode_bytes(&value["args"][0])?;
-    let timestamp_str = value["args"][1]
-        .get("string")
-        .ok_or_else(|| RpcError::UserData("expected string".to_string()))?
-        .as_str()
-        .ok_or_else(|| RpcError::UserData("expected string".to_string()))?;
-    let timestamp = DateTime::parse_from_rfc3339(&timestamp_str)
-        .map_err(|e| RpcError::UserData(format!("error parsing data: {}", e)))?
-        .naive_utc();
+/// Returns the ID of the first error the node refused an operation with, if it did.
+fn error_id(error: &RpcError) -> &str {
+    match error {
+        RpcError::Node { id, .. } => id.as_str(),
+        // Errors of preapplying come in the response.
+        RpcError::Rpc(errors) => errors[0]["id"].as_str().unwrap_or_default(),
+        _ => "",
+    }
+}
 
-    Ok(Message { content, timestamp })
+/// Whether the node refused an operation for its counters, which are then looked up again.
+///
+/// Revealing a key which is already revealed isn't one of them, as sending the group again
+/// would reveal it again.
+fn is_counter_error(error: &RpcError) -> bool {
+    let id = error_id(error);
+    id.ends_with(".contract.counter_in_the_past") || id.ends_with(".contract.counter_in_the_future")
 }
 
-fn parse_user_data(expr: &Expr) -> Result<UserData> {
-    let value = serde_json::json!(expr);
-    let identity_key = decode_bytes(&value["args"][0]["args"][0])?;
-    let prekey = decode_bytes(&value["args"][0]["args"][1])?;
-    let postal_box = value["args"][1]["args"][0]
+/// Builds the receipt of an operation included in the block at `level`.
+fn parse_receipt(
+    hash: &str,
+    level: i64,
+    block_hash: &str,
+    operation: &Value,
+    constants: &Constants,
+) -> Result<Receipt> {
+    let contents = operation["contents"]
         .as_array()
-        .ok_or_else(|| RpcError::UserData("expected array".to_string()))?
-        .iter()
-        .map(decode_message)
-        .collect::<Result<Vec<_>>>()?;
-    let pokes = value["args"][1]["args"][1]
-        .as_array()
-        .ok_or_else(|| RpcError::UserData("expected array".to_string()))?
+        .ok_or_else(|| RpcError::UserData("expected operation contents".to_string()))?;
+
+    let mut status = ResultStatus::Applied;
+    let mut consumed_gas = BigInt::zero();
+    let mut paid_storage_size_diff = BigInt::zero();
+    let mut originated_contracts = Vec::new();
+    let mut errors = Vec::new();
+    for content in contents {
+        // Anonymous operations like activations have no result, and are applied if included.
+        let result = match content["metadata"].get("operation_result") {
+            Some(result) => result,
+            None => continue,
+        };
+        let content_status = match result["status"].as_str() {
+            Some("applied") => ResultStatus::Applied,
+            Some("failed") => ResultStatus::Failed,
+            Some("backtracked") => ResultStatus::Backtracked,
+            Some("skipped") => ResultStatus::Skipped,
+            _ => {
+                return Err(RpcError::UserData(format!(
+                    "unknown operation result: {}",
+                    result
+                )))
+            }
+        };
+        // A failed content makes the others backtracked or skipped, so the failure is what
+        // matters most.
+        status = status.max(content_status);
+        if let Some(gas) = result.get("consumed_gas") {
+            consumed_gas += deserialize_bigint_from_value(gas)?;
+        }
+        if let Some(size) = result.get("paid_storage_size_diff") {
+            paid_storage_size_diff += deserialize_bigint_from_value(size)?;
+        }
+        if let Some(contracts) = result["originated_contracts"].as_array() {
+            for contract in contracts {
+                originated_contracts.push(from_value(contract)?);
+            }
+        }
+        if let Some(content_errors) = result["errors"].as_array() {
+            errors.extend(content_errors.iter().cloned());
+        }
+    }
+
+    let burnt_bytes = &paid_storage_size_diff
+        + BigInt::from(constants.origination_size) * BigInt::from(originated_contracts.len());
+    let storage_burn = burnt_bytes * BigInt::from(constants.cost_per_byte.clone());
+    Ok(Receipt {
+        hash: hash.to_string(),
+        level,
+        block_hash: block_hash.to_string(),
+        status,
+        consumed_gas,
+        paid_storage_size_diff,
+        storage_burn,
+        originated_contracts,
+        errors,
+    })
+}
+
+/// Locks `mutex` even if a thread panicked while holding it.
+///
+/// Only for data which is updated in one go, such as caches, which the panic can't have left
+/// inconsistent.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Returns the errors of an operation the mempool refused.
+///
+/// Operations refused because of their branch are left out, as they may become valid again
+/// after a reorganization until they expire.
+fn refusal(pending_operations: &Value, hash: &str) -> Option<Value> {
+    pending_operations["refused"]
+        .as_array()?
         .iter()
-        .map(decode_bytes)
+        .find(|operation| operation[0] == hash)
+        .map(|operation| operation[1]["error"].clone())
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<NaiveDateTime> {
+    Ok(DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|e| RpcError::UserData(format!("error parsing data: {}", e)))?
+        .naive_utc())
+}
+
+/// Messages posted before `since` are left out of the postal box. `posted_at` returns the
+/// messages posted in the block with a timestamp, which tell where each message was posted.
+fn parse_user_data<F>(
+    expr: &Expr,
+    since: Option<NaiveDateTime>,
+    mut posted_at: F,
+) -> Result<UserData>
+where
+    F: FnMut(NaiveDateTime) -> Result<Vec<Message>>,
+{
+    let stored = StoredUserData::from_michelson(expr).map_err(RpcError::Michelson)?;
+    // Messages are ciphertexts, so the same content is hardly posted twice in a block, but if it
+    // is, the copies are matched in order.
+    let mut copies = HashMap::new();
+    let postal_box = stored
+        .postal_box
+        .into_iter()
+        .filter(|message| match since {
+            Some(since) => message.timestamp >= since,
+            None => true,
+        })
+        .map(|StoredMessage { content, timestamp }| {
+            let copy = copies.entry((timestamp, content.0.clone())).or_insert(0);
+            let message = posted_at(timestamp)?
+                .into_iter()
+                .filter(|message| message.content == content.0)
+                .nth(*copy)
+                .ok_or_else(|| {
+                    RpcError::UserData(format!("no message posted at {} matches", timestamp))
+                })?;
+            *copy += 1;
+            Ok(message)
+        })
         .collect::<Result<Vec<_>>>()?;
 
     Ok(UserData {
-        identity_key,
-        prekey,
+        identity_key: stored.identity_key.0,
+        prekey: stored.prekey.0,
         postal_box,
-        pokes,
+        pokes: stored.pokes.into_iter().map(|poke| poke.0).collect(),
     })
 }
 
@@ -583,46 +1381,61 @@ impl Tezos for TezosRpc {
         &self.address
     }
 
-    fn secret_key(&self) -> &str {
-        &self.secret_key
-    }
-
     fn retrieve_user_data(
         &self,
         address: &str,
     ) -> std::result::Result<Option<UserData>, Self::ReadError> {
-        let value = self.get_from_big_map(address)?;
-        match value {
-            None => Ok(None),
-            Some(value) => parse_user_data(&value).map(Some),
-        }
+        self.user_data(address, None)
     }
 
-    fn post(&self, add: &[&[u8]], remove: &[&usize]) -> std::result::Result<(), Self::WriteError> {
-        let add = add.iter().map(|x| x.to_vec()).collect();
-        let remove = remove.iter().map(|&&x| x.into()).collect();
-        let op = MizuOp::Post(add, remove);
+    fn retrieve_user_data_since(
+        &self,
+        address: &str,
+        since: NaiveDateTime,
+    ) -> std::result::Result<Option<UserData>, Self::ReadError> {
+        self.user_data(address, Some(since))
+    }
 
-        let _hash = self.run_mizu_operation(&op)?;
-        Ok(())
+    fn operation_status(
+        &self,
+        operation: &OperationHandle,
+    ) -> std::result::Result<OperationStatus, Self::ReadError> {
+        self.find_operation_status(operation)
     }
 
-    fn poke(&self, target_address: &str, data: &[u8]) -> std::result::Result<(), Self::WriteError> {
-        let op = MizuOp::Poke(target_address.to_string(), data.to_vec());
+    fn post(
+        &self,
+        add: &[&[u8]],
+        remove: &[&usize],
+    ) -> std::result::Result<OperationHandle, Self::WriteError> {
+        self.run_mizu_operation(&MizuOp::from(&Call::Post { add, remove }))
+    }
 
-        let _hash = self.run_mizu_operation(&op)?;
-        Ok(())
+    fn poke(
+        &self,
+        target_address: &str,
+        data: &[u8],
+    ) -> std::result::Result<OperationHandle, Self::WriteError> {
+        self.run_mizu_operation(&MizuOp::from(&Call::Poke {
+            target_address,
+            data,
+        }))
     }
 
     fn register(
         &self,
         identity_key: Option<&[u8]>,
         prekey: &[u8],
-    ) -> std::result::Result<(), Self::WriteError> {
-        let op = MizuOp::Register(identity_key.map(|x| x.to_vec()), prekey.to_vec());
+    ) -> std::result::Result<OperationHandle, Self::WriteError> {
+        self.run_mizu_operation(&MizuOp::from(&Call::Register {
+            identity_key,
+            prekey,
+        }))
+    }
 
-        let _hash = self.run_mizu_operation(&op)?;
-        Ok(())
+    fn batch(&self, calls: &[Call]) -> std::result::Result<OperationHandle, Self::WriteError> {
+        let parameters: Vec<_> = calls.iter().map(MizuOp::from).collect();
+        self.run_mizu_operations(&parameters)
     }
 }
 
@@ -631,53 +1444,258 @@ mod tests {
     use super::*;
 
     fn get_tezos_rpc() -> Result<TezosRpc> {
-        Ok(TezosRpc {
-            debug: false,
-            host: Url::parse("https://carthagenet.smartpy.io").map_err(RpcError::UrlParse)?,
-            address: "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
-            secret_key: "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk".to_string(),
-            contract_address: "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string(),
-        })
+        Ok(TezosRpc::new(
+            false,
+            Url::parse("https://carthagenet.smartpy.io").map_err(RpcError::UrlParse)?,
+            "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
+            Box::new(signer::InMemorySigner::new(
+                "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
+                "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk".to_string(),
+            )),
+            "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string(),
+        ))
     }
 
-    // This test writes data out to a contract every time it is run, so
-    // shouldn't be called unnecessarily!
     #[test]
-    #[ignore]
-    fn contract_call_succeeds() -> Result<()> {
+    fn groups_pay_for_each_content() -> Result<()> {
         let rpc = get_tezos_rpc()?;
-
-        let parameters = MizuOp::Register(
-            Some(vec![
-                0xca, 0xfe, 0xba, 0xbe, 0xca, 0xfe, 0xba, 0xbe, 0xca, 0xfe, 0xba, 0xbe,
-            ]),
-            vec![
-                0xca, 0xfe, 0xba, 0xbe, 0xca, 0xfe, 0xba, 0xbe, 0xca, 0xfe, 0xba, 0xbe,
+        let manager = |counter: i64| Manager {
+            source: rpc.address.clone(),
+            fee: Zero::zero(),
+            counter: BigInt::from(counter),
+            gas_limit: BigInt::from(10000),
+            storage_limit: Zero::zero(),
+        };
+        let mut op = Operation {
+            protocol: None,
+            signature: None,
+            branch: "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2".to_string(),
+            contents: vec![
+                Content::Reveal {
+                    manager: manager(1),
+                    public_key: rpc.signer.public_key().map_err(RpcError::Signer)?,
+                },
+                Content::Transaction {
+                    manager: manager(2),
+                    destination: rpc.contract_address.clone(),
+                    parameters: Expr::left(Expr::Bytes(vec![0xca, 0xfe])),
+                },
             ],
-        );
-
-        assert!(rpc.run_mizu_operation(&parameters).is_ok());
+        };
+        let sop = rpc.serialize_and_set_fees(&mut op)?;
+
+        let fees: Vec<BigInt> = op
+            .contents
+            .iter_mut()
+            .map(|content| content.manager_mut().unwrap().fee.clone())
+            .collect();
+        // 100 mutez, 0.1 mutez per gas unit, and 1 mutez per byte including the signature.
+        let total: BigInt = fees.iter().sum();
+        assert!(total >= BigInt::from(100 + 1000 + (sop.len() / 2 + 64)));
+        assert!(fees.iter().all(|fee| *fee > BigInt::from(100 + 1000)));
+
+        let json = op.to_json();
+        assert_eq!(json["contents"][0]["kind"], "reveal");
+        assert_eq!(json["contents"][0]["counter"], "1");
+        assert_eq!(json["contents"][1]["kind"], "transaction");
+        assert_eq!(json["contents"][1]["counter"], "2");
+        assert_eq!(json["contents"][1]["fee"], fees[1].to_string());
 
         Ok(())
     }
 
     #[test]
-    fn reads_work() -> Result<()> {
-        let rpc = get_tezos_rpc()?;
-
-        assert!(rpc.get_from_big_map(&rpc.address).is_ok());
+    fn mizu_parameters_are_encoded() {
+        let json = |op: MizuOp| serde_json::json!(op.to_michelson());
+        assert_eq!(
+            json(MizuOp::Post(
+                vec![Bytes(vec![0xca, 0xfe])],
+                vec![BigUint::from(3u32)]
+            )),
+            serde_json::json!({ "prim": "Left", "args": [{ "prim": "Pair", "args": [
+                [{ "bytes": "cafe" }],
+                [{ "int": "3" }],
+            ] }] })
+        );
+        assert_eq!(
+            json(MizuOp::Register(None, Bytes(vec![0xbe, 0xef]))),
+            serde_json::json!({ "prim": "Right", "args": [{ "prim": "Right", "args": [
+                { "prim": "Pair", "args": [{ "prim": "None" }, { "bytes": "beef" }] },
+            ] }] })
+        );
+    }
 
+    #[test]
+    fn user_data_is_decoded() -> Result<()> {
+        let message = |content: &str, timestamp: &str| {
+            serde_json::json!({ "prim": "Pair", "args": [
+                { "bytes": content },
+                { "string": timestamp },
+            ] })
+        };
+        let storage = serde_json::json!({ "prim": "Pair", "args": [
+            { "prim": "Pair", "args": [{ "bytes": "01" }, { "bytes": "02" }] },
+            { "prim": "Pair", "args": [
+                [
+                    message("aa", "2020-07-01T00:00:00Z"),
+                    message("bb", "2020-07-01T00:00:00Z"),
+                    message("cc", "2020-07-01T00:00:30Z"),
+                ],
+                [{ "bytes": "03" }],
+            ] },
+        ] });
+        let earlier = parse_timestamp("2020-07-01T00:00:00Z")?;
+        let later = parse_timestamp("2020-07-01T00:00:30Z")?;
+        let posted = |content: &[u8], timestamp, level, index| Message {
+            content: content.to_vec(),
+            timestamp,
+            level,
+            index,
+        };
+        // A message posted first in the block of "aa" and "bb" was removed since.
+        let posted_at = |timestamp| {
+            Ok(if timestamp == later {
+                vec![posted(&[0xcc], later, 2, 0)]
+            } else {
+                vec![
+                    posted(&[0x99], earlier, 1, 0),
+                    posted(&[0xaa], earlier, 1, 1),
+                    posted(&[0xbb], earlier, 1, 2),
+                ]
+            })
+        };
+        let user_data = parse_user_data(&from_value(&storage)?, None, posted_at)?;
+
+        assert_eq!(user_data.identity_key, vec![1]);
+        assert_eq!(user_data.prekey, vec![2]);
+        assert_eq!(user_data.pokes, vec![vec![3]]);
+        let messages: Vec<_> = user_data
+            .postal_box
+            .iter()
+            .map(|message| (message.content.clone(), message.index))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![(vec![0xaa], 1), (vec![0xbb], 2), (vec![0xcc], 0)]
+        );
+        assert_eq!(user_data.postal_box[2].level, 2);
+
+        let recent = parse_user_data(&from_value(&storage)?, Some(later), posted_at)?;
+        assert_eq!(recent.postal_box, vec![posted(&[0xcc], later, 2, 0)]);
+        assert!(matches!(
+            parse_user_data(&from_value(&storage)?, None, |_| Ok(vec![])),
+            Err(RpcError::UserData(_))
+        ));
+        assert!(matches!(
+            parse_user_data(&Expr::Bytes(Vec::new()), None, |_| Ok(vec![])),
+            Err(RpcError::Michelson(_))
+        ));
         Ok(())
     }
 
     #[test]
-    fn reads_to_unknown_address() -> Result<()> {
-        let rpc = get_tezos_rpc()?;
+    fn operation_results_are_checked() -> Result<()> {
+        let operation = |status: &str| {
+            serde_json::json!({
+                "contents": [
+                    { "kind": "transaction", "metadata": { "operation_result": {
+                        "status": "applied",
+                        "consumed_gas": "10207",
+                        "paid_storage_size_diff": "67",
+                    } } },
+                    { "kind": "transaction", "metadata": { "operation_result": {
+                        "status": status,
+                        "consumed_gas": "100",
+                    } } },
+                ]
+            })
+        };
+        let constants = Constants {
+            hard_gas_limit_per_operation: BigInt::from(1040000),
+            hard_storage_limit_per_operation: BigInt::from(60000),
+            cost_per_byte: BigUint::from(1000u32),
+            origination_size: 257,
+        };
+        let receipt = parse_receipt("ooHash", 10, "BLHash", &operation("applied"), &constants)?;
+        assert_eq!(receipt.status, ResultStatus::Applied);
+        assert_eq!(receipt.consumed_gas, BigInt::from(10307));
+        assert_eq!(receipt.paid_storage_size_diff, BigInt::from(67));
+        assert_eq!(receipt.storage_burn, BigInt::from(67000));
+        assert!(receipt.into_result().is_ok());
+
+        let receipt = parse_receipt(
+            "ooHash",
+            10,
+            "BLHash",
+            &operation("backtracked"),
+            &constants,
+        )?;
+        assert!(matches!(
+            receipt.into_result(),
+            Err(RpcError::Backtracked(_))
+        ));
+
+        let mut failed = operation("failed");
+        failed["contents"][1]["metadata"]["operation_result"]["errors"] = serde_json::json!([{ "kind": "temporary", "id": "proto.006-PsCARTHA.gas_exhausted.operation" }]);
+        let receipt = parse_receipt("ooHash", 10, "BLHash", &failed, &constants)?;
+        assert_eq!(receipt.status, ResultStatus::Failed);
+        assert_eq!(receipt.errors.len(), 1);
+        assert!(matches!(receipt.into_result(), Err(RpcError::Failed(_))));
+
+        let mut originated = operation("applied");
+        originated["contents"][1]["metadata"]["operation_result"]["originated_contracts"] =
+            serde_json::json!(["KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk"]);
+        let receipt = parse_receipt("ooHash", 10, "BLHash", &originated, &constants)?;
+        assert_eq!(
+            receipt.originated_contracts,
+            vec!["KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string()]
+        );
+        assert_eq!(receipt.storage_burn, BigInt::from((67 + 257) * 1000));
 
-        assert!(rpc
-            .get_from_big_map("tz1PtxhBALR5qE3heaR9AY8khUBCkuGwUKjA")
-            .is_ok());
+        let activation = serde_json::json!({
+            "contents": [
+                { "kind": "activate_account", "metadata": { "balance_updates": [] } },
+            ]
+        });
+        let receipt = parse_receipt("ooHash", 10, "BLHash", &activation, &constants)?;
+        assert_eq!(receipt.status, ResultStatus::Applied);
+
+        let pending_operations = serde_json::json!({
+            "applied": [{ "hash": "ooApplied" }],
+            "refused": [["ooRefused", { "error": [{ "id": "refused" }] }]],
+            "branch_refused": [["ooBranchRefused", { "error": [] }]],
+            "branch_delayed": [],
+            "unprocessed": [],
+        });
+        assert_eq!(refusal(&pending_operations, "ooApplied"), None);
+        assert_eq!(
+            refusal(&pending_operations, "ooRefused"),
+            Some(serde_json::json!([{ "id": "refused" }]))
+        );
+        assert_eq!(refusal(&pending_operations, "ooBranchRefused"), None);
+        assert_eq!(refusal(&pending_operations, "ooUnknown"), None);
 
         Ok(())
     }
+
+    #[test]
+    fn only_counter_errors_are_retried() {
+        let node_error = |id: &str| RpcError::Node {
+            status: 500,
+            kind: "branch".to_string(),
+            id: id.to_string(),
+            errors: serde_json::json!([{ "kind": "branch", "id": id }]),
+        };
+        assert!(is_counter_error(&node_error(
+            "proto.006-PsCARTHA.contract.counter_in_the_past"
+        )));
+        // Errors of preapplying come in the response.
+        assert!(is_counter_error(&RpcError::Rpc(serde_json::json!([{
+            "kind": "temporary",
+            "id": "proto.006-PsCARTHA.contract.counter_in_the_future",
+        }]))));
+        assert!(!is_counter_error(&node_error(
+            "proto.006-PsCARTHA.contract.previously_revealed_key"
+        )));
+    }
 }
@@ -1,54 +1,65 @@
-use num_bigint::BigInt;
+//! Michelson expressions, and conversions between them and Rust values.
+//!
+//! `ToMichelson` and `FromMichelson` can be derived for structs, which become pairs of their
+//! fields, and enums, which become or-trees of their variants (see `mizu_michelson_derive`).
+//! Expressions can be parsed from and printed as text too (see `text`).
+
+use crate::forge;
+use chrono::naive::NaiveDateTime;
+use chrono::{DateTime, TimeZone, Utc};
+use num_bigint::{BigInt, BigUint};
+use num_traits::ToPrimitive;
 use serde::de;
 use serde::de::{Deserializer, MapAccess, SeqAccess, Visitor};
 use serde::ser::{SerializeSeq, SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use thiserror::Error;
+
+mod text;
 
-#[derive(Debug, Clone)]
+pub use mizu_michelson_derive::{FromMichelson, ToMichelson};
+pub use text::{parse, parse_script, ParseError};
+
+/// A Micheline expression, which is how Michelson code and values are written.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Int(BigInt),
     String(String),
     Bytes(Vec<u8>),
+    /// A sequence, which lists, sets, maps and code blocks are written as.
     List(Vec<Expr>),
-    Prim { prim: String, args: Vec<Expr> },
+    /// A primitive applied to arguments, e.g. `Pair 1 2` or `pair (nat %count) string`.
+    Prim {
+        prim: String,
+        args: Vec<Expr>,
+        /// Annotations such as `%entrypoint`, `:type` or `@variable`.
+        annots: Vec<String>,
+    },
 }
 
 impl Expr {
-    pub fn left(arg: Expr) -> Expr {
+    /// Returns the primitive `prim` without annotations.
+    pub fn prim(prim: &str, args: Vec<Expr>) -> Expr {
         Expr::Prim {
-            prim: "Left".into(),
-            args: vec![arg],
+            prim: prim.into(),
+            args,
+            annots: Vec::new(),
         }
     }
-    pub fn right(arg: Expr) -> Expr {
-        Expr::Prim {
-            prim: "Right".into(),
-            args: vec![arg],
-        }
+    pub fn left(arg: Expr) -> Expr {
+        Expr::prim("Left", vec![arg])
     }
-    pub fn nat(value: BigInt) -> Expr {
-        Expr::Prim {
-            prim: "nat".into(),
-            args: vec![Expr::Int(value)],
-        }
+    pub fn right(arg: Expr) -> Expr {
+        Expr::prim("Right", vec![arg])
     }
     pub fn pair(left: Expr, right: Expr) -> Expr {
-        Expr::Prim {
-            prim: "Pair".into(),
-            args: vec![left, right],
-        }
+        Expr::prim("Pair", vec![left, right])
     }
     pub fn some(arg: Option<Expr>) -> Expr {
         match arg {
-            Some(arg) => Expr::Prim {
-                prim: "Some".into(),
-                args: vec![arg],
-            },
-            None => Expr::Prim {
-                prim: "None".into(),
-                args: Vec::new(),
-            },
+            Some(arg) => Expr::prim("Some", vec![arg]),
+            None => Expr::prim("None", Vec::new()),
         }
     }
 }
@@ -83,84 +94,95 @@ impl Serialize for Expr {
                 }
                 seq.end()
             }
-            Expr::Prim { prim, args } => {
-                if args.is_empty() {
-                    let mut state = serializer.serialize_struct("Expr", 1)?;
-                    state.serialize_field("prim", prim)?;
-                    state.end()
-                } else {
-                    let mut state = serializer.serialize_struct("Expr", 2)?;
-                    state.serialize_field("prim", prim)?;
+            Expr::Prim { prim, args, annots } => {
+                // Empty arguments and annotations are left out, as the node does.
+                let len = 1 + !args.is_empty() as usize + !annots.is_empty() as usize;
+                let mut state = serializer.serialize_struct("Expr", len)?;
+                state.serialize_field("prim", prim)?;
+                if !args.is_empty() {
                     state.serialize_field("args", args)?;
-                    state.end()
                 }
+                if !annots.is_empty() {
+                    state.serialize_field("annots", annots)?;
+                }
+                state.end()
             }
         }
     }
 }
 
+const FIELDS: &[&str] = &["int", "string", "bytes", "prim", "args", "annots"];
+
 struct ExprVisitor;
 
+impl ExprVisitor {
+    /// Reads the value of `key`, which may appear only once.
+    fn next_value<'de, A, T>(
+        map: &mut A,
+        key: &'static str,
+        slot: &mut Option<T>,
+    ) -> Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+        T: Deserialize<'de>,
+    {
+        if slot.is_some() {
+            return Err(de::Error::duplicate_field(key));
+        }
+        *slot = Some(map.next_value()?);
+        Ok(())
+    }
+}
+
 impl<'de> Visitor<'de> for ExprVisitor {
     type Value = Expr;
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         formatter.write_str("a Michelson expression")
     }
 
-    fn visit_map<A>(self, mut value: A) -> Result<Expr, A::Error>
+    fn visit_map<A>(self, mut map: A) -> Result<Expr, A::Error>
     where
         A: MapAccess<'de>,
     {
-        let key: String = value
-            .next_key()?
-            .ok_or_else(|| de::Error::custom("no key found"))?;
-        match &key[..] {
-            "int" => value.next_value().map(Expr::Int),
-            "string" => value.next_value().map(Expr::String),
-            "bytes" => {
-                let hex_string: String = value.next_value()?;
-                let bytes = hex::decode(hex_string).map_err(|e| match e {
-                    hex::FromHexError::InvalidHexCharacter { c, index } => {
-                        de::Error::custom(format!("invalid character '{}' at index {}", c, index))
-                    }
-                    hex::FromHexError::OddLength => de::Error::custom("odd length"),
-                    hex::FromHexError::InvalidStringLength => {
-                        panic!("internal error (hex::FromHexError::InvalidStringLength)")
-                    }
-                })?;
-                Ok(Expr::Bytes(bytes))
-            }
-            "args" => {
-                let args = value.next_value()?;
-                let key: String = value
-                    .next_key()?
-                    .ok_or_else(|| de::Error::custom("expecting \"prim\" key"))?;
-                if key == "prim" {
-                    let prim = value.next_value()?;
-                    Ok(Expr::Prim { prim, args })
-                } else {
-                    Err(de::Error::custom(format!(
-                        "expecting \"prim\" but found \"{}\"",
-                        key
-                    )))
-                }
-            }
-            "prim" => {
-                let prim = value.next_value()?;
-                let key: String = value
-                    .next_key()?
-                    .ok_or_else(|| de::Error::custom("expecting \"args\" key"))?;
-                if key == "args" {
-                    let args = value.next_value()?;
-                    Ok(Expr::Prim { prim, args })
-                } else {
-                    Err(de::Error::custom(format!(
-                        "expecting \"args\" but found \"{}\"",
-                        key
-                    )))
-                }
+        // Keys may come in any order, so we collect all of them before deciding what we have.
+        let mut int: Option<String> = None;
+        let mut string = None;
+        let mut bytes: Option<String> = None;
+        let mut prim = None;
+        let mut args = None;
+        let mut annots = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match &key[..] {
+                "int" => Self::next_value(&mut map, "int", &mut int)?,
+                "string" => Self::next_value(&mut map, "string", &mut string)?,
+                "bytes" => Self::next_value(&mut map, "bytes", &mut bytes)?,
+                "prim" => Self::next_value(&mut map, "prim", &mut prim)?,
+                "args" => Self::next_value(&mut map, "args", &mut args)?,
+                "annots" => Self::next_value(&mut map, "annots", &mut annots)?,
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
             }
-            _ => Err(de::Error::custom(format!("unexpected key \"{}\"", key))),
+        }
+
+        if prim.is_none() && (args.is_some() || annots.is_some()) {
+            return Err(de::Error::custom("\"args\" and \"annots\" need \"prim\""));
+        }
+        match (int, string, bytes, prim) {
+            (Some(n), None, None, None) => n
+                .parse()
+                .map(Expr::Int)
+                .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&n), &"an integer")),
+            (None, Some(s), None, None) => Ok(Expr::String(s)),
+            (None, None, Some(b), None) => hex::decode(&b)
+                .map(Expr::Bytes)
+                .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&b), &"hex bytes")),
+            (None, None, None, Some(prim)) => Ok(Expr::Prim {
+                prim,
+                args: args.unwrap_or_default(),
+                annots: annots.unwrap_or_default(),
+            }),
+            _ => Err(de::Error::custom(
+                "expected exactly one of \"int\", \"string\", \"bytes\" or \"prim\"",
+            )),
         }
     }
 
@@ -184,3 +206,649 @@ impl<'de> Deserialize<'de> for Expr {
         deserializer.deserialize_any(ExprVisitor)
     }
 }
+
+#[derive(Error, Debug)]
+pub enum FromMichelsonError {
+    #[error("expected {0} but found {1:?}")]
+    Unexpected(&'static str, Expr),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+type FromResult<T> = std::result::Result<T, FromMichelsonError>;
+
+/// Converts values to Michelson expressions, which is how contracts receive parameters.
+pub trait ToMichelson {
+    fn to_michelson(&self) -> Expr;
+}
+
+/// Converts Michelson expressions to values, which is how we read the storage of contracts.
+pub trait FromMichelson: Sized {
+    fn from_michelson(expr: &Expr) -> FromResult<Self>;
+}
+
+/// How the fields of a pair or the cases of an or-tree are nested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `Pair a (Pair b c)`, as Michelson's own comb pairs.
+    RightComb,
+    /// `Pair (Pair a b) (Pair c d)`, as SCaml compiles records and variants.
+    Balanced,
+}
+
+impl Layout {
+    /// Returns how many of `count` (at least two) elements go to the left.
+    fn left_count(self, count: usize) -> usize {
+        match self {
+            Layout::RightComb => 1,
+            Layout::Balanced => count / 2,
+        }
+    }
+}
+
+/// Returns the arguments of `expr` if it is the primitive `name` with `count` arguments.
+fn prim_args<'a>(expr: &'a Expr, name: &str, count: usize) -> Option<&'a [Expr]> {
+    match expr {
+        Expr::Prim { prim, args, .. } if prim == name && args.len() == count => Some(args),
+        _ => None,
+    }
+}
+
+fn unexpected<T>(expected: &'static str, found: &Expr) -> FromResult<T> {
+    Err(FromMichelsonError::Unexpected(expected, found.clone()))
+}
+
+/// Pairs up `exprs`, which is `Unit` if there are none.
+pub fn pair_tree(mut exprs: Vec<Expr>, layout: Layout) -> Expr {
+    match exprs.len() {
+        0 => Expr::prim("Unit", Vec::new()),
+        1 => exprs.remove(0),
+        count => {
+            let right = exprs.split_off(layout.left_count(count));
+            Expr::pair(pair_tree(exprs, layout), pair_tree(right, layout))
+        }
+    }
+}
+
+/// Splits a pair built by `pair_tree` back into its `count` elements.
+pub fn split_pair_tree(expr: &Expr, count: usize, layout: Layout) -> FromResult<Vec<&Expr>> {
+    match count {
+        0 => match prim_args(expr, "Unit", 0) {
+            Some(_) => Ok(Vec::new()),
+            None => unexpected("Unit", expr),
+        },
+        1 => Ok(vec![expr]),
+        count => match prim_args(expr, "Pair", 2) {
+            Some(args) => {
+                let left_count = layout.left_count(count);
+                let mut exprs = split_pair_tree(&args[0], left_count, layout)?;
+                exprs.extend(split_pair_tree(&args[1], count - left_count, layout)?);
+                Ok(exprs)
+            }
+            None => unexpected("a pair", expr),
+        },
+    }
+}
+
+/// Wraps `expr` in the `Left`s and `Right`s selecting case `index` out of `count`.
+pub fn or_tree(index: usize, count: usize, expr: Expr, layout: Layout) -> Expr {
+    if count <= 1 {
+        return expr;
+    }
+    let left_count = layout.left_count(count);
+    if index < left_count {
+        Expr::left(or_tree(index, left_count, expr, layout))
+    } else {
+        Expr::right(or_tree(
+            index - left_count,
+            count - left_count,
+            expr,
+            layout,
+        ))
+    }
+}
+
+/// Returns the case an or-tree built by `or_tree` selects and the expression in it.
+pub fn split_or_tree(expr: &Expr, count: usize, layout: Layout) -> FromResult<(usize, &Expr)> {
+    match count {
+        0 => unexpected("nothing, as the type has no cases", expr),
+        1 => Ok((0, expr)),
+        count => {
+            let left_count = layout.left_count(count);
+            if let Some(args) = prim_args(expr, "Left", 1) {
+                split_or_tree(&args[0], left_count, layout)
+            } else if let Some(args) = prim_args(expr, "Right", 1) {
+                let (index, expr) = split_or_tree(&args[0], count - left_count, layout)?;
+                Ok((left_count + index, expr))
+            } else {
+                unexpected("Left or Right", expr)
+            }
+        }
+    }
+}
+
+impl ToMichelson for Expr {
+    fn to_michelson(&self) -> Expr {
+        self.clone()
+    }
+}
+
+impl FromMichelson for Expr {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        Ok(expr.clone())
+    }
+}
+
+impl ToMichelson for () {
+    fn to_michelson(&self) -> Expr {
+        Expr::prim("Unit", Vec::new())
+    }
+}
+
+impl FromMichelson for () {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        split_pair_tree(expr, 0, Layout::RightComb).map(|_| ())
+    }
+}
+
+/// Michelson's `bytes`, since `Vec<u8>` is a list of numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Bytes(pub Vec<u8>);
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes(bytes)
+    }
+}
+
+impl ToMichelson for Bytes {
+    fn to_michelson(&self) -> Expr {
+        Expr::Bytes(self.0.clone())
+    }
+}
+
+impl FromMichelson for Bytes {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::Bytes(bytes) => Ok(Bytes(bytes.clone())),
+            _ => unexpected("bytes", expr),
+        }
+    }
+}
+
+impl ToMichelson for String {
+    fn to_michelson(&self) -> Expr {
+        Expr::String(self.clone())
+    }
+}
+
+impl FromMichelson for String {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::String(s) => Ok(s.clone()),
+            _ => unexpected("a string", expr),
+        }
+    }
+}
+
+impl ToMichelson for BigInt {
+    fn to_michelson(&self) -> Expr {
+        Expr::Int(self.clone())
+    }
+}
+
+impl FromMichelson for BigInt {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::Int(n) => Ok(n.clone()),
+            _ => unexpected("an int", expr),
+        }
+    }
+}
+
+/// Michelson's `nat`.
+impl ToMichelson for BigUint {
+    fn to_michelson(&self) -> Expr {
+        Expr::Int(BigInt::from(self.clone()))
+    }
+}
+
+impl FromMichelson for BigUint {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::Int(n) => n
+                .to_biguint()
+                .ok_or_else(|| FromMichelsonError::Invalid("nat", n.to_string())),
+            _ => unexpected("a nat", expr),
+        }
+    }
+}
+
+/// Michelson's `timestamp`, which is written as an RFC 3339 string.
+impl ToMichelson for NaiveDateTime {
+    fn to_michelson(&self) -> Expr {
+        Expr::String(self.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    }
+}
+
+/// Timestamps can also be seconds since the epoch, which is what the optimized form uses.
+impl FromMichelson for NaiveDateTime {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        let invalid = || FromMichelsonError::Invalid("timestamp", format!("{:?}", expr));
+        match expr {
+            Expr::String(s) => DateTime::parse_from_rfc3339(s)
+                .map(|timestamp| timestamp.naive_utc())
+                .map_err(|e| FromMichelsonError::Invalid("timestamp", format!("{}: {}", s, e))),
+            Expr::Int(seconds) => seconds
+                .to_i64()
+                .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single())
+                .map(|timestamp| timestamp.naive_utc())
+                .ok_or_else(invalid),
+            _ => unexpected("a timestamp", expr),
+        }
+    }
+}
+
+/// Michelson's `address`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address(pub String);
+
+impl ToMichelson for Address {
+    fn to_michelson(&self) -> Expr {
+        Expr::String(self.0.clone())
+    }
+}
+
+/// Addresses can also be in their optimized, binary form.
+impl FromMichelson for Address {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::String(s) => Ok(Address(s.clone())),
+            Expr::Bytes(bytes) => forge::parse_address(bytes)
+                .map(Address)
+                .map_err(|e| FromMichelsonError::Invalid("address", e.to_string())),
+            _ => unexpected("an address", expr),
+        }
+    }
+}
+
+impl<T: ToMichelson> ToMichelson for Option<T> {
+    fn to_michelson(&self) -> Expr {
+        Expr::some(self.as_ref().map(ToMichelson::to_michelson))
+    }
+}
+
+impl<T: FromMichelson> FromMichelson for Option<T> {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        if let Some(args) = prim_args(expr, "Some", 1) {
+            T::from_michelson(&args[0]).map(Some)
+        } else if prim_args(expr, "None", 0).is_some() {
+            Ok(None)
+        } else {
+            unexpected("Some or None", expr)
+        }
+    }
+}
+
+impl<T: ToMichelson> ToMichelson for Vec<T> {
+    fn to_michelson(&self) -> Expr {
+        Expr::List(self.iter().map(ToMichelson::to_michelson).collect())
+    }
+}
+
+impl<T: FromMichelson> FromMichelson for Vec<T> {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::List(exprs) => exprs.iter().map(T::from_michelson).collect(),
+            _ => unexpected("a list", expr),
+        }
+    }
+}
+
+impl<A: ToMichelson, B: ToMichelson> ToMichelson for (A, B) {
+    fn to_michelson(&self) -> Expr {
+        Expr::pair(self.0.to_michelson(), self.1.to_michelson())
+    }
+}
+
+impl<A: FromMichelson, B: FromMichelson> FromMichelson for (A, B) {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        let exprs = split_pair_tree(expr, 2, Layout::RightComb)?;
+        Ok((A::from_michelson(exprs[0])?, B::from_michelson(exprs[1])?))
+    }
+}
+
+/// Michelson's `big_map`, which is only written out in full when it is created. Afterwards,
+/// contracts refer to it by its ID and its values have to be looked up one by one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BigMap<K, V> {
+    Id(BigInt),
+    Literal(Vec<(K, V)>),
+}
+
+impl<K: ToMichelson, V: ToMichelson> ToMichelson for BigMap<K, V> {
+    fn to_michelson(&self) -> Expr {
+        match self {
+            BigMap::Id(id) => Expr::Int(id.clone()),
+            BigMap::Literal(elements) => Expr::List(
+                elements
+                    .iter()
+                    .map(|(key, value)| {
+                        Expr::prim("Elt", vec![key.to_michelson(), value.to_michelson()])
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<K: FromMichelson, V: FromMichelson> FromMichelson for BigMap<K, V> {
+    fn from_michelson(expr: &Expr) -> FromResult<Self> {
+        match expr {
+            Expr::Int(id) => Ok(BigMap::Id(id.clone())),
+            Expr::List(exprs) => exprs
+                .iter()
+                .map(|expr| match prim_args(expr, "Elt", 2) {
+                    Some(args) => Ok((K::from_michelson(&args[0])?, V::from_michelson(&args[1])?)),
+                    None => unexpected("Elt", expr),
+                })
+                .collect::<FromResult<_>>()
+                .map(BigMap::Literal),
+            _ => unexpected("a big map", expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::Sign;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    fn int(n: i64) -> Expr {
+        Expr::Int(BigInt::from(n))
+    }
+
+    fn round_trip<T>(value: T) -> Expr
+    where
+        T: ToMichelson + FromMichelson + PartialEq + fmt::Debug,
+    {
+        let expr = value.to_michelson();
+        assert_eq!(T::from_michelson(&expr).unwrap(), value);
+        expr
+    }
+
+    #[derive(Debug, PartialEq, ToMichelson, FromMichelson)]
+    struct Comb {
+        a: BigInt,
+        b: BigInt,
+        c: BigInt,
+        d: BigInt,
+    }
+
+    #[derive(Debug, PartialEq, ToMichelson, FromMichelson)]
+    #[michelson(layout = "balanced")]
+    struct Balanced(BigInt, BigInt, BigInt, BigInt, BigInt);
+
+    #[derive(Debug, PartialEq, ToMichelson, FromMichelson)]
+    struct Wrapper<T>(Option<T>);
+
+    #[derive(Debug, PartialEq, ToMichelson, FromMichelson)]
+    enum Action {
+        First,
+        Second(BigInt),
+        Third { x: String, y: Bytes },
+    }
+
+    #[derive(Debug, PartialEq, ToMichelson, FromMichelson)]
+    #[michelson(layout = "balanced")]
+    enum Four {
+        A,
+        B,
+        C,
+        D(BigInt),
+    }
+
+    #[test]
+    fn structs_are_pairs() {
+        assert_eq!(
+            round_trip(Comb {
+                a: 1.into(),
+                b: 2.into(),
+                c: 3.into(),
+                d: 4.into(),
+            }),
+            Expr::pair(int(1), Expr::pair(int(2), Expr::pair(int(3), int(4))))
+        );
+        assert_eq!(
+            round_trip(Balanced(1.into(), 2.into(), 3.into(), 4.into(), 5.into())),
+            Expr::pair(
+                Expr::pair(int(1), int(2)),
+                Expr::pair(int(3), Expr::pair(int(4), int(5)))
+            )
+        );
+        assert_eq!(
+            round_trip(Wrapper(Some(BigInt::from(1)))),
+            Expr::some(Some(int(1)))
+        );
+        assert!(matches!(
+            Comb::from_michelson(&Expr::pair(int(1), int(2))),
+            Err(FromMichelsonError::Unexpected(_, _))
+        ));
+    }
+
+    #[test]
+    fn enums_are_or_trees() {
+        let unit = Expr::prim("Unit", Vec::new());
+        assert_eq!(round_trip(Action::First), Expr::left(unit.clone()));
+        assert_eq!(
+            round_trip(Action::Second(7.into())),
+            Expr::right(Expr::left(int(7)))
+        );
+        assert_eq!(
+            round_trip(Action::Third {
+                x: "x".to_string(),
+                y: Bytes(vec![0xca, 0xfe]),
+            }),
+            Expr::right(Expr::right(Expr::pair(
+                Expr::String("x".to_string()),
+                Expr::Bytes(vec![0xca, 0xfe])
+            )))
+        );
+
+        assert_eq!(round_trip(Four::A), Expr::left(Expr::left(unit.clone())));
+        assert_eq!(round_trip(Four::B), Expr::left(Expr::right(unit.clone())));
+        assert_eq!(round_trip(Four::C), Expr::right(Expr::left(unit)));
+        assert_eq!(
+            round_trip(Four::D(1.into())),
+            Expr::right(Expr::right(int(1)))
+        );
+        assert!(Four::from_michelson(&int(1)).is_err());
+    }
+
+    #[test]
+    fn values_are_converted() {
+        assert_eq!(round_trip(BigUint::from(3u32)), int(3));
+        assert!(matches!(
+            BigUint::from_michelson(&int(-1)),
+            Err(FromMichelsonError::Invalid("nat", _))
+        ));
+
+        let timestamp =
+            NaiveDateTime::parse_from_str("2020-07-01 12:34:56", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(
+            round_trip(timestamp),
+            Expr::String("2020-07-01T12:34:56Z".to_string())
+        );
+        assert_eq!(
+            NaiveDateTime::from_michelson(&int(1_593_606_896)).unwrap(),
+            timestamp
+        );
+        assert_eq!(
+            NaiveDateTime::from_michelson(&Expr::String("2020-07-01T21:34:56+09:00".into()))
+                .unwrap(),
+            timestamp
+        );
+
+        let address = "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk";
+        round_trip(Address(address.to_string()));
+        assert_eq!(
+            Address::from_michelson(&forge::address_expr(address).unwrap()).unwrap(),
+            Address(address.to_string())
+        );
+
+        round_trip(vec![Some(Bytes(vec![1])), None]);
+        round_trip((String::from("a"), ()));
+        round_trip(BigMap::<Address, BigInt>::Id(5.into()));
+        assert_eq!(
+            round_trip(BigMap::Literal(vec![(String::from("a"), BigInt::from(1))])),
+            Expr::List(vec![Expr::prim(
+                "Elt",
+                vec![Expr::String("a".to_string()), int(1)]
+            )])
+        );
+    }
+
+    fn parse(json: &str) -> serde_json::Result<Expr> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn micheline_json_is_parsed() {
+        // Part of what `contracts/<KT1>/script` returns.
+        let parameter = parse(
+            r#"{ "prim": "parameter", "args": [
+                { "prim": "or", "annots": ["%post"], "args": [
+                    { "prim": "bytes" },
+                    { "args": [], "prim": "nat", "annots": [":count", "@n"] }
+                ] }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parameter,
+            Expr::prim(
+                "parameter",
+                vec![Expr::Prim {
+                    prim: "or".into(),
+                    args: vec![
+                        Expr::prim("bytes", Vec::new()),
+                        Expr::Prim {
+                            prim: "nat".into(),
+                            args: Vec::new(),
+                            annots: vec![":count".into(), "@n".into()],
+                        },
+                    ],
+                    annots: vec!["%post".into()],
+                }]
+            )
+        );
+        assert_eq!(
+            parse(r#"[[{ "int": "-12" }], [], { "bytes": "CAFE" }]"#).unwrap(),
+            Expr::List(vec![
+                Expr::List(vec![int(-12)]),
+                Expr::List(Vec::new()),
+                Expr::Bytes(vec![0xca, 0xfe]),
+            ])
+        );
+
+        for invalid in &[
+            r#"{ "prim": "Unit", "kind": "x" }"#,
+            r#"{ "prim": "Unit", "prim": "Unit" }"#,
+            r#"{ "int": "1", "prim": "Unit" }"#,
+            r#"{ "int": "1", "annots": [] }"#,
+            r#"{ "int": "0x1" }"#,
+            r#"{ "bytes": "abc" }"#,
+            r#"{}"#,
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn empty_args_and_annots_are_left_out() {
+        assert_eq!(
+            serde_json::to_value(Expr::prim("Unit", Vec::new())).unwrap(),
+            json!({ "prim": "Unit" })
+        );
+    }
+
+    fn arb_bigint() -> impl Strategy<Value = BigInt> {
+        (any::<bool>(), vec(any::<u32>(), 0..4)).prop_map(|(negative, digits)| {
+            BigInt::new(if negative { Sign::Minus } else { Sign::Plus }, digits)
+        })
+    }
+
+    fn arb_prim(args: impl Strategy<Value = Vec<Expr>>) -> impl Strategy<Value = Expr> {
+        (
+            proptest::sample::select(forge::PRIMITIVES),
+            args,
+            vec("[@:%][a-z_.0-9]{0,8}", 0..3),
+        )
+            .prop_map(|(prim, args, annots)| Expr::Prim {
+                prim: prim.to_string(),
+                args,
+                annots,
+            })
+    }
+
+    pub(super) fn arb_expr() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            arb_bigint().prop_map(Expr::Int),
+            any::<String>().prop_map(Expr::String),
+            vec(any::<u8>(), 0..8).prop_map(Expr::Bytes),
+            arb_prim(Just(Vec::new())),
+        ];
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..4).prop_map(Expr::List),
+                arb_prim(vec(inner, 0..4)),
+            ]
+        })
+    }
+
+    /// Writes `value` out as JSON with the keys of objects in reverse order.
+    fn reversed(value: &Value) -> String {
+        match value {
+            Value::Object(map) => {
+                let fields: Vec<_> = map
+                    .iter()
+                    .rev()
+                    .map(|(key, value)| format!("{}:{}", json!(key), reversed(value)))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+            Value::Array(values) => {
+                let values: Vec<_> = values.iter().map(reversed).collect();
+                format!("[{}]", values.join(","))
+            }
+            value => value.to_string(),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn json_round_trips(expr in arb_expr()) {
+            let json = serde_json::to_string(&expr).unwrap();
+            prop_assert_eq!(&parse(&json).unwrap(), &expr);
+
+            // `Value` sorts keys, so this reads them as "annots", "args", "prim".
+            let value = serde_json::to_value(&expr).unwrap();
+            prop_assert_eq!(&serde_json::from_value::<Expr>(value.clone()).unwrap(), &expr);
+            prop_assert_eq!(&parse(&reversed(&value)).unwrap(), &expr);
+        }
+
+        #[test]
+        fn annotated_expressions_are_forged(expr in arb_expr()) {
+            prop_assert!(forge::forge_expr(&mut Vec::new(), &expr).is_ok());
+        }
+
+        #[test]
+        fn packed_expressions_are_read_back(expr in arb_expr()) {
+            let packed = forge::pack(&expr).unwrap();
+            prop_assert_eq!(forge::unpack(&packed).unwrap(), expr);
+        }
+    }
+}
@@ -0,0 +1,504 @@
+//! The textual syntax of Micheline, e.g. `Pair (Left 0xcafe) { Elt "key" 1 }`.
+//!
+//! `{}` prints expressions on one line, and `{:#}` breaks those wider than 80 columns over
+//! lines the way `tezos-client` does.
+
+use super::Expr;
+use num_bigint::BigInt;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// How wide `{:#}` lets lines get before breaking them.
+const WIDTH: usize = 80;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message} at offset {offset}")]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String,
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+/// Parses an expression, where the outermost primitive doesn't need parentheses around its
+/// arguments.
+pub fn parse(input: &str) -> Result<Expr> {
+    let mut parser = Parser { input, offset: 0 };
+    let expr = parser.application()?;
+    parser.skip_whitespace()?;
+    if parser.offset < input.len() {
+        return Err(parser.error("expected the end of the input"));
+    }
+    Ok(expr)
+}
+
+/// Parses a script as `.tz` files contain it, i.e. `parameter ...; storage ...; code ...`
+/// without the braces around the sequence.
+pub fn parse_script(input: &str) -> Result<Expr> {
+    // The newline ends a comment on the last line.
+    parse(&format!("{{{}\n}}", input)).map_err(|error| ParseError {
+        offset: error.offset.saturating_sub(1).min(input.len()),
+        ..error
+    })
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Expr> {
+        parse(s)
+    }
+}
+
+fn is_prim_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_prim_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether `c` ends an annotation, number or bytes.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || ";{}()\"".contains(c)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            offset: self.offset,
+            message: message.into(),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.offset += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {:?}", c)))
+        }
+    }
+
+    /// Takes the longest prefix whose characters satisfy `f`.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.offset += len;
+        &rest[..len]
+    }
+
+    /// Skips whitespace and `# ...` and `/* ... */` comments.
+    fn skip_whitespace(&mut self) -> Result<()> {
+        loop {
+            self.take_while(char::is_whitespace);
+            let rest = self.rest();
+            if rest.starts_with('#') {
+                self.offset += rest.find('\n').unwrap_or(rest.len());
+            } else if rest.starts_with("/*") {
+                match rest.find("*/") {
+                    Some(end) => self.offset += end + 2,
+                    None => return Err(self.error("unterminated comment")),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Numbers and bytes have to be followed by a delimiter, so `0xzz` isn't `0x` and `zz`.
+    fn expect_delimiter(&self) -> Result<()> {
+        match self.peek() {
+            Some(c) if !is_delimiter(c) => Err(self.error(format!("unexpected {:?}", c))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a primitive with its annotations and arguments, or any other expression.
+    fn application(&mut self) -> Result<Expr> {
+        self.skip_whitespace()?;
+        match self.peek() {
+            Some(c) if is_prim_start(c) => {
+                let (prim, annots) = self.prim()?;
+                let mut args = Vec::new();
+                loop {
+                    self.skip_whitespace()?;
+                    match self.peek() {
+                        None | Some(';') | Some('}') | Some(')') => break,
+                        Some(_) => args.push(self.atom()?),
+                    }
+                }
+                Ok(Expr::Prim { prim, args, annots })
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn prim(&mut self) -> Result<(String, Vec<String>)> {
+        let prim = self.take_while(is_prim_char).to_string();
+        let mut annots = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            match self.peek() {
+                Some('@') | Some(':') | Some('%') => {
+                    annots.push(self.take_while(|c| !is_delimiter(c)).to_string())
+                }
+                _ => return Ok((prim, annots)),
+            }
+        }
+    }
+
+    /// Parses an expression which can be an argument without parentheses.
+    fn atom(&mut self) -> Result<Expr> {
+        self.skip_whitespace()?;
+        match self.peek() {
+            None => Err(self.error("expected an expression")),
+            Some('(') => {
+                self.offset += 1;
+                let expr = self.application()?;
+                self.skip_whitespace()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some('{') => self.sequence(),
+            Some('"') => self.string(),
+            Some(_) if self.rest().starts_with("0x") => self.bytes(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.int(),
+            Some(c) if is_prim_start(c) => {
+                let (prim, annots) = self.prim()?;
+                Ok(Expr::Prim {
+                    prim,
+                    args: Vec::new(),
+                    annots,
+                })
+            }
+            Some(c) => Err(self.error(format!("unexpected {:?}", c))),
+        }
+    }
+
+    fn int(&mut self) -> Result<Expr> {
+        let start = self.offset;
+        if self.peek() == Some('-') {
+            self.offset += 1;
+        }
+        if self.take_while(|c| c.is_ascii_digit()).is_empty() {
+            return Err(self.error("expected digits"));
+        }
+        self.expect_delimiter()?;
+        let n: BigInt = self.input[start..self.offset]
+            .parse()
+            .expect("digits with an optional sign should parse");
+        Ok(Expr::Int(n))
+    }
+
+    fn bytes(&mut self) -> Result<Expr> {
+        let start = self.offset;
+        self.offset += 2;
+        let digits = self.take_while(|c| c.is_ascii_hexdigit());
+        self.expect_delimiter()?;
+        hex::decode(digits)
+            .map(Expr::Bytes)
+            .map_err(|_| ParseError {
+                offset: start,
+                message: "expected an even number of hex digits".to_string(),
+            })
+    }
+
+    fn string(&mut self) -> Result<Expr> {
+        let start = self.offset;
+        self.expect('"')?;
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset += i + 1;
+                    return Ok(Expr::String(s));
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, '"')) => s.push('"'),
+                    _ => {
+                        self.offset += i;
+                        return Err(self.error("invalid escape sequence"));
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+        Err(ParseError {
+            offset: start,
+            message: "unterminated string".to_string(),
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr> {
+        self.expect('{')?;
+        let mut exprs = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            if self.peek() == Some('}') {
+                self.offset += 1;
+                return Ok(Expr::List(exprs));
+            }
+            exprs.push(self.application()?);
+            self.skip_whitespace()?;
+            match self.peek() {
+                Some(';') => self.offset += 1,
+                Some('}') => {}
+                _ => return Err(self.error("expected ';' or '}'")),
+            }
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\u{8}' => quoted.push_str("\\b"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Prints `expr` on one line. Arguments (`nested`) need parentheses if they have arguments or
+/// annotations of their own.
+fn flat(expr: &Expr, nested: bool) -> String {
+    match expr {
+        Expr::Int(n) => n.to_string(),
+        Expr::String(s) => quote(s),
+        Expr::Bytes(bytes) => format!("0x{}", hex::encode(bytes)),
+        Expr::List(exprs) if exprs.is_empty() => "{}".to_string(),
+        Expr::List(exprs) => {
+            let exprs: Vec<_> = exprs.iter().map(|expr| flat(expr, false)).collect();
+            format!("{{ {} }}", exprs.join(" ; "))
+        }
+        Expr::Prim { prim, args, annots } => {
+            let words: Vec<_> = std::iter::once(prim.clone())
+                .chain(annots.iter().cloned())
+                .chain(args.iter().map(|arg| flat(arg, true)))
+                .collect();
+            if nested && words.len() > 1 {
+                format!("({})", words.join(" "))
+            } else {
+                words.join(" ")
+            }
+        }
+    }
+}
+
+/// Prints `expr` starting at column `indent`, breaking sequences and arguments over lines
+/// where they don't fit.
+fn pretty(expr: &Expr, indent: usize, nested: bool) -> String {
+    let line = flat(expr, nested);
+    if indent + line.len() <= WIDTH {
+        return line;
+    }
+    let separator = format!("\n{}", " ".repeat(indent + 2));
+    match expr {
+        Expr::List(exprs) => {
+            let exprs: Vec<_> = exprs
+                .iter()
+                .map(|expr| pretty(expr, indent + 2, false))
+                .collect();
+            format!("{{ {} }}", exprs.join(&format!(" ;{}", separator)))
+        }
+        Expr::Prim { prim, args, annots } if !args.is_empty() => {
+            let mut out = if nested {
+                "(".to_string()
+            } else {
+                String::new()
+            };
+            out.push_str(prim);
+            for annot in annots {
+                out.push(' ');
+                out.push_str(annot);
+            }
+            for arg in args {
+                out.push_str(&separator);
+                out.push_str(&pretty(arg, indent + 2, true));
+            }
+            if nested {
+                out.push(')');
+            }
+            out
+        }
+        _ => line,
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            f.write_str(&pretty(self, 0, false))
+        } else {
+            f.write_str(&flat(self, false))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::arb_expr;
+    use super::*;
+    use proptest::prelude::*;
+
+    fn int(n: i64) -> Expr {
+        Expr::Int(BigInt::from(n))
+    }
+
+    #[test]
+    fn expressions_are_parsed() {
+        assert_eq!(
+            parse("Pair (Left 0xcafe) { Elt \"a\\\"b\" -1 ; Elt \"\" 0 ; }").unwrap(),
+            Expr::pair(
+                Expr::left(Expr::Bytes(vec![0xca, 0xfe])),
+                Expr::List(vec![
+                    Expr::prim("Elt", vec![Expr::String("a\"b".into()), int(-1)]),
+                    Expr::prim("Elt", vec![Expr::String(String::new()), int(0)]),
+                ])
+            )
+        );
+        assert_eq!(
+            parse(
+                "# the parameter of Mizu
+                parameter (or %post (bytes :content) /* removed */ nat)"
+            )
+            .unwrap(),
+            Expr::prim(
+                "parameter",
+                vec![Expr::Prim {
+                    prim: "or".into(),
+                    args: vec![
+                        Expr::Prim {
+                            prim: "bytes".into(),
+                            args: Vec::new(),
+                            annots: vec![":content".into()],
+                        },
+                        Expr::prim("nat", Vec::new()),
+                    ],
+                    annots: vec!["%post".into()],
+                }]
+            )
+        );
+        assert_eq!(
+            parse("{ {} ; { 1 } }").unwrap(),
+            Expr::List(vec![Expr::List(Vec::new()), Expr::List(vec![int(1)])])
+        );
+
+        for (invalid, offset) in &[
+            ("", 0),
+            ("Pair 1 2)", 8),
+            ("{ 1 2 }", 4),
+            ("0xabc", 0),
+            ("0xzz", 2),
+            ("12ab", 2),
+            ("\"abc", 0),
+            ("\"\\q\"", 1),
+            ("/* 1", 0),
+            ("(Pair 1", 7),
+        ] {
+            let error = parse(invalid).unwrap_err();
+            assert_eq!(error.offset, *offset, "{}: {}", invalid, error);
+        }
+    }
+
+    #[test]
+    fn scripts_are_parsed() {
+        let script = "parameter unit;\nstorage (big_map address bytes);\ncode { FAILWITH }; # done";
+        assert_eq!(
+            parse_script(script).unwrap(),
+            Expr::List(vec![
+                Expr::prim("parameter", vec![Expr::prim("unit", Vec::new())]),
+                Expr::prim(
+                    "storage",
+                    vec![Expr::prim(
+                        "big_map",
+                        vec![
+                            Expr::prim("address", Vec::new()),
+                            Expr::prim("bytes", Vec::new())
+                        ]
+                    )]
+                ),
+                Expr::prim(
+                    "code",
+                    vec![Expr::List(vec![Expr::prim("FAILWITH", Vec::new())])]
+                ),
+            ])
+        );
+        assert_eq!(parse_script("parameter unit ; )").unwrap_err().offset, 17);
+    }
+
+    #[test]
+    fn expressions_are_printed() {
+        let expr = Expr::pair(
+            Expr::left(Expr::Bytes(vec![0xca, 0xfe])),
+            Expr::List(vec![
+                Expr::some(Some(Expr::String("a\nb".into()))),
+                Expr::Prim {
+                    prim: "nat".into(),
+                    args: Vec::new(),
+                    annots: vec!["%count".into()],
+                },
+            ]),
+        );
+        assert_eq!(
+            expr.to_string(),
+            "Pair (Left 0xcafe) { Some \"a\\nb\" ; nat %count }"
+        );
+        assert_eq!(format!("{:#}", expr), expr.to_string());
+
+        let long = Expr::pair(
+            Expr::List(vec![
+                Expr::Bytes(vec![0xaa; 20]),
+                Expr::Bytes(vec![0xbb; 20]),
+            ]),
+            Expr::some(Some(Expr::Bytes(vec![0xcc; 30]))),
+        );
+        assert_eq!(
+            format!("{:#}", long),
+            [
+                "Pair",
+                "  { 0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa ;",
+                "    0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb }",
+                "  (Some 0xcccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc)",
+            ]
+            .join("\n")
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn text_round_trips(expr in arb_expr()) {
+            prop_assert_eq!(&parse(&expr.to_string()).unwrap(), &expr);
+            prop_assert_eq!(&parse(&format!("{:#}", expr)).unwrap(), &expr);
+        }
+    }
+}
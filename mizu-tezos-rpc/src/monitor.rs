@@ -0,0 +1,403 @@
+//! Follows the chain head by head, extracting calls to the Mizu contract as they get included.
+//!
+//! This lets clients keep up with the user data they care about without downloading it whole
+//! on every sync, as `Tezos::retrieve_user_data` does.
+
+use super::{
+    from_value, parse_timestamp, MizuOp, Result, RpcError, TezosRpc, MANAGER_OPERATIONS_PASS,
+};
+use crate::michelson::{Address, Bytes, Expr, FromMichelson};
+use chrono::naive::NaiveDateTime;
+use mizu_tezos_interface::{Message, UserDataUpdate};
+use num_traits::ToPrimitive;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// How `HeadMonitor` follows the chain.
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    /// The number of blocks on top of a block before its contract calls are returned, so that
+    /// calls in blocks which get reorganized away are mostly not seen.
+    pub confirmations: i64,
+    /// How long to wait before reconnecting after a failure.
+    pub retry_interval: Duration,
+    /// How long the node may stay silent before the connection is considered lost.
+    /// Blocks are produced every 30 seconds or so, which makes silences of minutes suspicious.
+    pub stall_timeout: Duration,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig {
+            confirmations: 1,
+            retry_interval: Duration::from_secs(10),
+            stall_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// The calls to the Mizu contract in a block which concern the followed addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockUpdates {
+    pub level: i64,
+    pub hash: String,
+    /// The hash of the block this one is built on. It differs from the hash of the block
+    /// returned before when the chain was reorganized in between.
+    pub predecessor: String,
+    pub updates: Vec<UserDataUpdate>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Head {
+    level: i64,
+}
+
+type Heads = Box<dyn Iterator<Item = serde_json::Result<Value>> + Send>;
+
+/// An endless iterator over the contract calls of each block, created by
+/// `TezosRpc::monitor_heads`.
+///
+/// Blocks are returned in order of level and without gaps once they have
+/// `MonitorConfig::confirmations` blocks on top. New heads are streamed from the node, and when
+/// the stream breaks, the monitor reconnects after `MonitorConfig::retry_interval` and backfills
+/// the blocks produced in the meantime. Errors are returned as they occur, after which the block
+/// which failed is retried.
+pub struct HeadMonitor<'a> {
+    rpc: &'a TezosRpc,
+    config: MonitorConfig,
+    addresses: HashSet<String>,
+    /// The level of the last block returned.
+    last_level: i64,
+    /// The level of the latest head seen.
+    head_level: i64,
+    heads: Option<Heads>,
+    /// Set when the last call failed, so the next one waits before retrying.
+    failed: bool,
+}
+
+impl<'a> HeadMonitor<'a> {
+    /// Starts returning calls which concern `address` too, from the next block on.
+    pub fn follow(&mut self, address: &str) {
+        self.addresses.insert(address.to_string());
+    }
+
+    /// Returns the level of the last block returned.
+    pub fn last_level(&self) -> i64 {
+        self.last_level
+    }
+
+    fn next_block(&mut self) -> Result<BlockUpdates> {
+        while self.last_level + self.config.confirmations >= self.head_level {
+            let level = self.next_head()?;
+            self.head_level = self.head_level.max(level);
+        }
+
+        let level = self.last_level + 1;
+        let block = self.rpc.block_updates(level, &self.addresses)?;
+        self.last_level = level;
+        Ok(block)
+    }
+
+    /// Waits for the level of the next head, connecting to the node first if needed.
+    fn next_head(&mut self) -> Result<i64> {
+        let heads = match &mut self.heads {
+            Some(heads) => heads,
+            None => {
+                let heads = self.rpc.heads(self.config.stall_timeout)?;
+                // Heads produced while we weren't connected aren't streamed, so we backfill up to
+                // the current one.
+                let level = self.rpc.head_level()?;
+                self.heads = Some(heads);
+                return Ok(level);
+            }
+        };
+
+        match heads.next() {
+            Some(Ok(head)) => from_value::<Head>(&head).map(|head| head.level),
+            Some(Err(e)) => Err(RpcError::IO(e.into())),
+            None => Err(RpcError::IO(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the node closed the stream of heads",
+            ))),
+        }
+    }
+}
+
+impl<'a> Iterator for HeadMonitor<'a> {
+    type Item = Result<BlockUpdates>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            thread::sleep(self.config.retry_interval);
+        }
+
+        let result = self.next_block();
+        self.failed = result.is_err();
+        if self.failed {
+            // The node is likely unreachable, so we start over with a new connection.
+            self.heads = None;
+        }
+        Some(result)
+    }
+}
+
+impl TezosRpc {
+    /// Follows the calls to the contract which concern `addresses`, starting with the block after
+    /// `last_level`.
+    pub fn monitor_heads(
+        &self,
+        last_level: i64,
+        addresses: HashSet<String>,
+        config: MonitorConfig,
+    ) -> HeadMonitor<'_> {
+        HeadMonitor {
+            rpc: self,
+            config,
+            addresses,
+            last_level,
+            head_level: last_level,
+            heads: None,
+            failed: false,
+        }
+    }
+
+    /// Returns the level of the head block.
+    pub fn head_level(&self) -> Result<i64> {
+        Ok(self.block_header("head")?.level)
+    }
+
+    /// Returns the level and the hash of the head block.
+    pub fn head(&self) -> Result<(i64, String)> {
+        let header = self.block_header("head")?;
+        Ok((header.level, header.hash))
+    }
+
+    /// Opens the stream of new heads of the main chain.
+    fn heads(&self, stall_timeout: Duration) -> Result<Heads> {
+        let url = self.resolve_path("monitor/heads/main")?;
+
+        let reader = self.http.stream(&url, stall_timeout)?;
+        Ok(Box::new(
+            serde_json::Deserializer::from_reader(reader).into_iter::<Value>(),
+        ))
+    }
+
+    pub(crate) fn block_updates(
+        &self,
+        level: i64,
+        addresses: &HashSet<String>,
+    ) -> Result<BlockUpdates> {
+        let header = self.block_header(&level.to_string())?;
+        let url = self.resolve_path(&format!(
+            "chains/main/blocks/{}/operations/{}",
+            header.hash, MANAGER_OPERATIONS_PASS
+        ))?;
+        let operations = self.http.get(&url)?;
+
+        let updates = parse_block_updates(
+            &self.contract_address,
+            addresses,
+            level,
+            parse_timestamp(&header.timestamp)?,
+            &operations,
+        )?;
+        Ok(BlockUpdates {
+            level,
+            hash: header.hash,
+            predecessor: header.predecessor,
+            updates,
+        })
+    }
+}
+
+/// Extracts the applied calls to `contract` from the manager operations of a block, keeping
+/// those which concern `addresses`.
+///
+/// Calls made by other contracts are internal operations and are not looked into.
+fn parse_block_updates(
+    contract: &str,
+    addresses: &HashSet<String>,
+    level: i64,
+    timestamp: NaiveDateTime,
+    operations: &Value,
+) -> Result<Vec<UserDataUpdate>> {
+    // Messages posted by the same address in a block are numbered in the order they were posted.
+    let mut indices = HashMap::new();
+    let mut updates = vec![];
+    for operation in from_value::<Vec<Value>>(operations)? {
+        for content in from_value::<Vec<Value>>(&operation["contents"])? {
+            if content["kind"] != "transaction"
+                || content["destination"] != contract
+                || content["metadata"]["operation_result"]["status"] != "applied"
+            {
+                continue;
+            }
+            let parameters = &content["parameters"];
+            // Plain transfers carry no parameters.
+            if parameters.is_null()
+                || !(parameters["entrypoint"].is_null() || parameters["entrypoint"] == "default")
+            {
+                continue;
+            }
+
+            let source: String = from_value(&content["source"])?;
+            let expr: Expr = from_value(&parameters["value"])?;
+            let update = match MizuOp::from_michelson(&expr).map_err(RpcError::Michelson)? {
+                MizuOp::Post(add, remove) => {
+                    let index = indices.entry(source.clone()).or_insert(0);
+                    let messages = add
+                        .into_iter()
+                        .map(|Bytes(content)| {
+                            let message = Message {
+                                content,
+                                timestamp,
+                                level,
+                                index: *index,
+                            };
+                            *index += 1;
+                            message
+                        })
+                        .collect();
+                    let removed = remove
+                        .iter()
+                        .map(|index| {
+                            index.to_usize().ok_or_else(|| {
+                                RpcError::UserData(format!("index {} is out of range", index))
+                            })
+                        })
+                        .collect::<Result<_>>()?;
+                    UserDataUpdate::Posted {
+                        address: source,
+                        messages,
+                        removed,
+                    }
+                }
+                MizuOp::Poke(Address(address), Bytes(data)) => {
+                    UserDataUpdate::Poked { address, data }
+                }
+                MizuOp::Register(identity_key, Bytes(prekey)) => UserDataUpdate::Registered {
+                    address: source,
+                    identity_key: identity_key.map(|Bytes(key)| key),
+                    prekey,
+                },
+            };
+            if addresses.contains(update.address()) {
+                updates.push(update);
+            }
+        }
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::michelson::ToMichelson;
+    use num_bigint::BigUint;
+    use serde_json::json;
+
+    const CONTRACT: &str = "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk";
+    const ALICE: &str = "tz1X8dUY8H7x1ybPSfAYqqnkihpb6Q6VaSFz";
+    const BOB: &str = "tz1hCDmJN4qsUMENwW1uLbqJqVC1aMSXWtMi";
+    const CAROL: &str = "tz1RYM7KBQWWMmNq8YkZu6Ba3D7WwWmzHLx9";
+
+    fn call(source: &str, destination: &str, parameters: &MizuOp, status: &str) -> Value {
+        json!({
+            "kind": "transaction",
+            "source": source,
+            "amount": "0",
+            "destination": destination,
+            "parameters": {
+                "entrypoint": "default",
+                "value": parameters.to_michelson(),
+            },
+            "metadata": {
+                "balance_updates": [],
+                "operation_result": { "status": status },
+            },
+        })
+    }
+
+    fn post(content: &[u8]) -> MizuOp {
+        MizuOp::Post(vec![Bytes(content.to_vec())], vec![])
+    }
+
+    #[test]
+    fn contract_calls_are_extracted() -> Result<()> {
+        let operations = json!([
+            {
+                "hash": "ooWJ2Gd1Zzq3EvV6ZdkUrDWuypmRMPSaHQPBTeWxVTw5nh8sSxn",
+                "contents": [
+                    call(ALICE, CONTRACT, &post(b"first"), "applied"),
+                    call(BOB, CONTRACT, &post(b"failed"), "failed"),
+                    call(BOB, "KT1Hkg5qeNhfwpKW4fXvq7HGZB9z2EnmCCA9", &post(b"elsewhere"), "applied"),
+                ],
+            },
+            {
+                "hash": "opBQHWvN9L44ifiZqhaQZrH6GBCLSR9bMY3g2CXK8HmQSVeN7ca",
+                "contents": [
+                    { "kind": "reveal", "source": BOB, "public_key": "edpk" },
+                    call(BOB, CONTRACT, &MizuOp::Poke(Address(ALICE.into()), Bytes(b"hi".to_vec())), "applied"),
+                    call(ALICE, CONTRACT, &MizuOp::Post(vec![Bytes(b"second".to_vec())], vec![BigUint::from(3u32)]), "applied"),
+                    call(BOB, CONTRACT, &MizuOp::Register(None, Bytes(vec![1; 32])), "applied"),
+                    call(CAROL, CONTRACT, &MizuOp::Register(Some(Bytes(vec![2; 32])), Bytes(vec![3; 32])), "applied"),
+                ],
+            },
+        ]);
+        let addresses = [ALICE, BOB].iter().map(|a| a.to_string()).collect();
+        let timestamp = parse_timestamp("2020-07-01T12:00:00Z")?;
+        let message = |content: &[u8], index| Message {
+            content: content.to_vec(),
+            timestamp,
+            level: 42,
+            index,
+        };
+
+        assert_eq!(
+            parse_block_updates(CONTRACT, &addresses, 42, timestamp, &operations)?,
+            vec![
+                UserDataUpdate::Posted {
+                    address: ALICE.into(),
+                    messages: vec![message(b"first", 0)],
+                    removed: vec![],
+                },
+                UserDataUpdate::Poked {
+                    address: ALICE.into(),
+                    data: b"hi".to_vec(),
+                },
+                UserDataUpdate::Posted {
+                    address: ALICE.into(),
+                    messages: vec![message(b"second", 1)],
+                    removed: vec![3],
+                },
+                UserDataUpdate::Registered {
+                    address: BOB.into(),
+                    identity_key: None,
+                    prekey: vec![1; 32],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn other_calls_are_ignored() -> Result<()> {
+        let mut transfer = call(ALICE, CONTRACT, &post(b"unused"), "applied");
+        transfer.as_object_mut().unwrap().remove("parameters");
+        let mut entrypoint = call(ALICE, CONTRACT, &post(b"unused"), "applied");
+        entrypoint["parameters"]["entrypoint"] = json!("other");
+        let operations = json!([{ "contents": [transfer, entrypoint] }]);
+        let addresses = vec![ALICE.to_string()].into_iter().collect();
+        let timestamp = parse_timestamp("2020-07-01T12:00:00Z")?;
+
+        assert_eq!(
+            parse_block_updates(CONTRACT, &addresses, 42, timestamp, &operations)?,
+            vec![]
+        );
+        Ok(())
+    }
+}
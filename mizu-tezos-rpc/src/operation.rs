@@ -0,0 +1,280 @@
+//! The operations the client sends, in the JSON form RPCs take them in.
+//!
+//! Only the contents the client builds are supported: transactions don't transfer tez and call
+//! the default entrypoint, and originations don't set a delegate nor transfer tez.
+
+use crate::michelson::Expr;
+use crate::{from_value, parse_bigint, Result, RpcError};
+use num_bigint::BigInt;
+use serde_json::Value;
+
+/// An operation group, whose contents are signed and applied together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub protocol: Option<String>,
+    pub signature: Option<String>,
+    pub branch: String,
+    pub contents: Vec<Content>,
+}
+
+/// The fields common to manager operations, which are paid for by `source`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manager {
+    pub source: String,
+    pub fee: BigInt,
+    pub counter: BigInt,
+    pub gas_limit: BigInt,
+    pub storage_limit: BigInt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    /// Claims the tez of a faucet account, given the hex-encoded `secret` of the faucet file.
+    ActivateAccount { pkh: String, secret: String },
+    /// Publishes the public key of `source`, which it needs to sign manager operations.
+    Reveal {
+        manager: Manager,
+        public_key: String,
+    },
+    /// Calls the default entrypoint of `destination` without transferring tez.
+    Transaction {
+        manager: Manager,
+        destination: String,
+        parameters: Expr,
+    },
+    /// Creates a contract running `code` from `storage`, without transferring tez to it or
+    /// setting a delegate.
+    Origination {
+        manager: Manager,
+        code: Expr,
+        storage: Expr,
+    },
+}
+
+impl Content {
+    pub fn manager(&self) -> Option<&Manager> {
+        match self {
+            Content::ActivateAccount { .. } => None,
+            Content::Reveal { manager, .. }
+            | Content::Transaction { manager, .. }
+            | Content::Origination { manager, .. } => Some(manager),
+        }
+    }
+
+    pub fn manager_mut(&mut self) -> Option<&mut Manager> {
+        match self {
+            Content::ActivateAccount { .. } => None,
+            Content::Reveal { manager, .. }
+            | Content::Transaction { manager, .. }
+            | Content::Origination { manager, .. } => Some(manager),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Content::ActivateAccount { pkh, secret } => serde_json::json!(
+                { "kind": "activate_account"
+                , "pkh": pkh
+                , "secret": secret
+                }
+            ),
+            Content::Reveal {
+                manager,
+                public_key,
+            } => with_manager_json(
+                serde_json::json!(
+                    { "kind": "reveal"
+                    , "public_key": public_key
+                    }
+                ),
+                manager,
+            ),
+            Content::Transaction {
+                manager,
+                destination,
+                parameters,
+            } => with_manager_json(
+                serde_json::json!(
+                    { "kind": "transaction"
+                    , "amount": "0"
+                    , "destination": destination
+                    , "parameters":
+                        { "entrypoint": "default"
+                        , "value": parameters
+                        }
+                    }
+                ),
+                manager,
+            ),
+            Content::Origination {
+                manager,
+                code,
+                storage,
+            } => with_manager_json(
+                serde_json::json!(
+                    { "kind": "origination"
+                    , "balance": "0"
+                    , "script":
+                        { "code": code
+                        , "storage": storage
+                        }
+                    }
+                ),
+                manager,
+            ),
+        }
+    }
+
+    /// Reads a content in the form `to_json` writes it.
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let unsupported = || RpcError::UnsupportedContent(value.clone());
+        let content = match value["kind"].as_str() {
+            Some("activate_account") => Content::ActivateAccount {
+                pkh: from_value(&value["pkh"])?,
+                secret: from_value(&value["secret"])?,
+            },
+            Some("reveal") => Content::Reveal {
+                manager: manager_from_json(value)?,
+                public_key: from_value(&value["public_key"])?,
+            },
+            Some("transaction") => {
+                let parameters = &value["parameters"];
+                if value["amount"] != "0" || parameters["entrypoint"] != "default" {
+                    return Err(unsupported());
+                }
+                Content::Transaction {
+                    manager: manager_from_json(value)?,
+                    destination: from_value(&value["destination"])?,
+                    parameters: from_value(&parameters["value"])?,
+                }
+            }
+            Some("origination") => {
+                if value["balance"] != "0" || value.get("delegate").is_some() {
+                    return Err(unsupported());
+                }
+                Content::Origination {
+                    manager: manager_from_json(value)?,
+                    code: from_value(&value["script"]["code"])?,
+                    storage: from_value(&value["script"]["storage"])?,
+                }
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(content)
+    }
+}
+
+fn with_manager_json(mut value: Value, manager: &Manager) -> Value {
+    let object = value.as_object_mut().expect("value is an object");
+    object.insert("source".into(), Value::String(manager.source.clone()));
+    for &(key, n) in &[
+        ("fee", &manager.fee),
+        ("counter", &manager.counter),
+        ("gas_limit", &manager.gas_limit),
+        ("storage_limit", &manager.storage_limit),
+    ] {
+        object.insert(key.into(), Value::String(n.to_string()));
+    }
+    value
+}
+
+fn manager_from_json(value: &Value) -> Result<Manager> {
+    let number = |key: &str| from_value(&value[key]).and_then(parse_bigint);
+    Ok(Manager {
+        source: from_value(&value["source"])?,
+        fee: number("fee")?,
+        counter: number("counter")?,
+        gas_limit: number("gas_limit")?,
+        storage_limit: number("storage_limit")?,
+    })
+}
+
+impl Operation {
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::json!(
+            { "branch": self.branch
+            , "contents": self.contents.iter().map(Content::to_json).collect::<Vec<_>>()
+            }
+        );
+
+        if let Some(protocol) = &self.protocol {
+            value
+                .as_object_mut()
+                .expect("value is an object")
+                .insert("protocol".into(), Value::String(protocol.into()));
+        }
+
+        if let Some(signature) = &self.signature {
+            value
+                .as_object_mut()
+                .expect("value is an object")
+                .insert("signature".into(), Value::String(signature.into()));
+        }
+
+        value
+    }
+
+    /// Reads an operation in the form `to_json` writes it.
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let optional = |key: &str| match value.get(key) {
+            Some(field) => from_value(field).map(Some),
+            None => Ok(None),
+        };
+        let contents: Vec<Value> = from_value(&value["contents"])?;
+        Ok(Operation {
+            protocol: optional("protocol")?,
+            signature: optional("signature")?,
+            branch: from_value(&value["branch"])?,
+            contents: contents
+                .iter()
+                .map(Content::from_json)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_are_read_back() {
+        let manager = |counter: i64| Manager {
+            source: "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB".to_string(),
+            fee: BigInt::from(1269),
+            counter: BigInt::from(counter),
+            gas_limit: BigInt::from(10000),
+            storage_limit: BigInt::from(257),
+        };
+        let op = Operation {
+            protocol: Some("PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb".to_string()),
+            signature: None,
+            branch: "BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2".to_string(),
+            contents: vec![
+                Content::Reveal {
+                    manager: manager(1),
+                    public_key: "edpkvGfYw3LyB1UcCahKQk4rF2tvbMUk8GFiTuMjL75uGXrpvKXhjn"
+                        .to_string(),
+                },
+                Content::Transaction {
+                    manager: manager(2),
+                    destination: "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string(),
+                    parameters: Expr::left(Expr::Bytes(vec![0xca, 0xfe])),
+                },
+                Content::Origination {
+                    manager: manager(3),
+                    code: Expr::List(Vec::new()),
+                    storage: Expr::List(Vec::new()),
+                },
+            ],
+        };
+        assert_eq!(Operation::from_json(&op.to_json()).unwrap(), op);
+
+        let mut transfer = op.contents[1].to_json();
+        transfer["amount"] = Value::from("1000000");
+        assert!(matches!(
+            Content::from_json(&transfer),
+            Err(RpcError::UnsupportedContent(_))
+        ));
+    }
+}
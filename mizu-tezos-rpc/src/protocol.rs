@@ -0,0 +1,110 @@
+//! The Tezos protocols the client knows how to talk to.
+
+use crate::{Result, RpcError};
+use num_bigint::{BigInt, BigUint};
+use serde::Deserialize;
+
+const CARTHAGE: &str = "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb";
+const DELPHI: &str = "PsDELPH1Kxsxt8f9eWbxQeRxkjfbxoqM52jvs5Y5fBxWWh4ifpo";
+
+/// A protocol, as named by the `protocols` RPC of blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Carthage,
+    Delphi,
+}
+
+impl Protocol {
+    pub fn from_hash(hash: &str) -> Result<Self> {
+        match hash {
+            CARTHAGE => Ok(Protocol::Carthage),
+            DELPHI => Ok(Protocol::Delphi),
+            _ => Err(RpcError::UnsupportedProtocol(hash.to_string())),
+        }
+    }
+
+    pub fn hash(self) -> &'static str {
+        match self {
+            Protocol::Carthage => CARTHAGE,
+            Protocol::Delphi => DELPHI,
+        }
+    }
+}
+
+/// The constants the client uses. Carthage and Delphi only differ in their values, and the
+/// other constants are left out so that changes to them don't break deserialization.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Constants {
+    #[serde(with = "serde_with::rust::display_fromstr")]
+    pub hard_gas_limit_per_operation: BigInt,
+    #[serde(with = "serde_with::rust::display_fromstr")]
+    pub hard_storage_limit_per_operation: BigInt,
+    #[serde(with = "serde_with::rust::display_fromstr")]
+    pub cost_per_byte: BigUint,
+    /// The bytes paid for when originating a contract, on top of the size of its script.
+    pub origination_size: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocols_are_detected() {
+        for &protocol in &[Protocol::Carthage, Protocol::Delphi] {
+            assert_eq!(Protocol::from_hash(protocol.hash()).unwrap(), protocol);
+        }
+        assert!(matches!(
+            Protocol::from_hash("PtEdo2ZkT9oKpimTah6x2embF25oss54njMuPzkJTEi5RqfdZFA"),
+            Err(RpcError::UnsupportedProtocol(_))
+        ));
+    }
+
+    #[test]
+    fn constants_are_parsed() {
+        let value = serde_json::json!({
+            "proof_of_work_nonce_size": 8,
+            "nonce_length": 32,
+            "max_revelations_per_block": 32,
+            "max_operation_data_length": 16384,
+            "max_proposals_per_delegate": 20,
+            "preserved_cycles": 3,
+            "blocks_per_cycle": 2048,
+            "blocks_per_commitment": 32,
+            "blocks_per_roll_snapshot": 256,
+            "blocks_per_voting_period": 2048,
+            "time_between_blocks": ["30", "20"],
+            "endorsers_per_block": 32,
+            "hard_gas_limit_per_operation": "1040000",
+            "hard_gas_limit_per_block": "10400000",
+            "proof_of_work_threshold": "70368744177663",
+            "tokens_per_roll": "8000000000",
+            "michelson_maximum_type_size": 1000,
+            "seed_nonce_revelation_tip": "125000",
+            "origination_size": 257,
+            "block_security_deposit": "512000000",
+            "endorsement_security_deposit": "64000000",
+            "baking_reward_per_endorsement": ["1250000", "187500"],
+            "endorsement_reward": ["1250000", "833333"],
+            "cost_per_byte": "1000",
+            "hard_storage_limit_per_operation": "60000",
+            "test_chain_duration": "61440",
+            "quorum_min": 2000,
+            "quorum_max": 7000,
+            "min_proposal_quorum": 500,
+            "initial_endorsers": 24,
+            "delay_per_missing_endorsement": "4",
+        });
+        let constants: Constants = crate::from_value(&value).unwrap();
+        assert_eq!(constants.cost_per_byte, BigUint::from(1000u32));
+        assert_eq!(constants.origination_size, 257);
+        assert_eq!(
+            constants.hard_storage_limit_per_operation,
+            BigInt::from(60000)
+        );
+        assert_eq!(
+            constants.hard_gas_limit_per_operation,
+            BigInt::from(1040000)
+        );
+    }
+}
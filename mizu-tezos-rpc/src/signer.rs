@@ -0,0 +1,377 @@
+//! Backends holding the keys we sign operations with.
+//!
+//! Callers only ever see public keys and signatures, so a backend can keep the secret key out
+//! of this process entirely, as [`HttpSigner`] does.
+
+use crate::crypto;
+use crate::http::{Effect, Http, HttpConfig};
+use crate::RpcError;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use thiserror::Error;
+use url::Url;
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("crypto error: {0}")]
+    Crypto(crypto::Error),
+    #[error("the secret key of {0} is encrypted and has not been unlocked")]
+    Locked(String),
+    #[error("no key to sign with was given")]
+    NoKey,
+    #[error("failed to parse signer url: {0}")]
+    UrlParse(url::ParseError),
+    #[error("remote signer uri has no address: {0}")]
+    Uri(String),
+    #[error("failed to reach the remote signer: {0}")]
+    Unreachable(String),
+    #[error("error: {0}")]
+    IO(io::Error),
+    #[error("the remote signer responded with status {0}: {1}")]
+    Status(u16, String),
+    #[error("the remote signer holds the key of {1} instead of {0}")]
+    WrongKey(String, String),
+    #[error("unexpected response from the remote signer: {0} ({1})")]
+    Response(serde_json::error::Error, Value),
+}
+
+type Result<T> = std::result::Result<T, SignerError>;
+
+/// Something which can sign operations for an address.
+pub trait Signer: fmt::Debug + Send + Sync {
+    /// Returns the public key (`edpk`, `sppk` or `p2pk`) of the address.
+    fn public_key(&self) -> Result<String>;
+
+    /// Signs `bytes` prefixed with `watermark` (see [`crypto::sign`]), returning the signature
+    /// base58check-encoded and as raw bytes.
+    fn sign(&self, watermark: u8, bytes: &[u8]) -> Result<(String, Vec<u8>)>;
+}
+
+/// Returns whether `signer` is the URI of a remote signer, see [`HttpSigner::from_uri`].
+pub fn is_remote_signer(signer: &str) -> bool {
+    signer.starts_with("http://") || signer.starts_with("https://")
+}
+
+/// Returns whether recording `signer` would leave a secret key readable by anyone with the
+/// database, being a plain secret key, possibly prefixed with `unencrypted:` as tezos-client
+/// does. The URI of a remote signer or an encrypted secret key is recorded instead.
+pub fn reveals_secret_key(signer: &str) -> bool {
+    let secret_key = signer.strip_prefix("unencrypted:").unwrap_or(signer);
+    crypto::is_plain_secret_key(secret_key)
+}
+
+type PromptFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Asks for the passphrase of the encrypted secret key of an address.
+pub struct PassphrasePrompt(Box<PromptFn>);
+
+impl fmt::Debug for PassphrasePrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PassphrasePrompt")
+    }
+}
+
+/// Signs with a secret key held in memory.
+#[derive(Debug)]
+pub struct InMemorySigner {
+    address: String,
+    /// Either a plain or an encrypted secret key.
+    secret_key: String,
+    /// The decrypted secret key, once an encrypted one has been unlocked.
+    unlocked_secret_key: Mutex<Option<String>>,
+    passphrase_prompt: Option<PassphrasePrompt>,
+}
+
+impl InMemorySigner {
+    pub fn new(address: String, secret_key: String) -> Self {
+        Self {
+            address,
+            secret_key,
+            unlocked_secret_key: Mutex::new(None),
+            passphrase_prompt: None,
+        }
+    }
+
+    /// Asks `prompt` for the passphrase of an encrypted secret key the first time we sign
+    /// something, instead of failing until `unlock` is called.
+    pub fn with_passphrase_prompt<F>(mut self, prompt: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.passphrase_prompt = Some(PassphrasePrompt(Box::new(prompt)));
+        self
+    }
+
+    fn unlocked_secret_key(&self) -> MutexGuard<'_, Option<String>> {
+        crate::lock(&self.unlocked_secret_key)
+    }
+
+    /// Returns whether the secret key is encrypted and hasn't been unlocked yet.
+    pub fn is_locked(&self) -> bool {
+        crypto::is_encrypted_secret_key(&self.secret_key) && self.unlocked_secret_key().is_none()
+    }
+
+    /// Decrypts the secret key for the rest of the session.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        let secret_key = crypto::decrypt_secret_key(&self.secret_key, passphrase)
+            .map_err(SignerError::Crypto)?;
+        *self.unlocked_secret_key() = Some(secret_key);
+        Ok(())
+    }
+
+    /// Returns the plain secret key to sign with.
+    fn signing_key(&self) -> Result<String> {
+        if !crypto::is_encrypted_secret_key(&self.secret_key) {
+            return Ok(self.secret_key.clone());
+        }
+        if self.is_locked() {
+            let passphrase = self
+                .passphrase_prompt
+                .as_ref()
+                .and_then(|prompt| (prompt.0)(&self.address))
+                .ok_or_else(|| SignerError::Locked(self.address.clone()))?;
+            self.unlock(&passphrase)?;
+        }
+        Ok(self.unlocked_secret_key().clone().expect("unlocked above"))
+    }
+}
+
+impl Signer for InMemorySigner {
+    fn public_key(&self) -> Result<String> {
+        crypto::derive_public_key(&self.signing_key()?).map_err(SignerError::Crypto)
+    }
+
+    fn sign(&self, watermark: u8, bytes: &[u8]) -> Result<(String, Vec<u8>)> {
+        crypto::sign(watermark, bytes, &self.signing_key()?).map_err(SignerError::Crypto)
+    }
+}
+
+/// Stands in for the signer of a `TezosRpc` which only reads from the chain.
+#[derive(Debug)]
+pub struct NoSigner;
+
+impl Signer for NoSigner {
+    fn public_key(&self) -> Result<String> {
+        Err(SignerError::NoKey)
+    }
+
+    fn sign(&self, _watermark: u8, _bytes: &[u8]) -> Result<(String, Vec<u8>)> {
+        Err(SignerError::NoKey)
+    }
+}
+
+#[derive(Deserialize)]
+struct PublicKeyResponse {
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+struct SignatureResponse {
+    signature: String,
+}
+
+/// Signs with a remote signer speaking the HTTP protocol of `tezos-signer`:
+///
+/// - `GET /keys/<address>` returns `{ "public_key": <public key> }`;
+/// - `POST /keys/<address>` with the watermarked bytes as a hex JSON string returns
+///   `{ "signature": <signature> }`.
+#[derive(Debug)]
+pub struct HttpSigner {
+    /// Where the signer serves `keys/<address>`.
+    url: Url,
+    http: Http,
+}
+
+impl HttpSigner {
+    /// Creates a signer for `address` served at `base_url`, e.g. `http://localhost:6732`.
+    pub fn new(base_url: &str, address: &str) -> Result<Self> {
+        let mut base_url = Url::parse(base_url).map_err(SignerError::UrlParse)?;
+        // Keep the path of the base URL when joining.
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&[base_url.path(), "/"].concat());
+        }
+        let url = base_url
+            .join(&["keys/", address].concat())
+            .map_err(SignerError::UrlParse)?;
+        Ok(Self {
+            url,
+            http: Http::new(HttpConfig::default()),
+        })
+    }
+
+    /// Talks to the signer with timeouts and retries from `config` instead of the default ones.
+    pub fn with_http_config(mut self, config: HttpConfig) -> Self {
+        self.http = Http::new(config);
+        self
+    }
+
+    /// Creates a signer from a URI of the form `http://localhost:6732/<address>`, which is how
+    /// `tezos-client` refers to keys held by remote signers.
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).map_err(SignerError::UrlParse)?;
+        let address = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|address| !address.is_empty())
+            .ok_or_else(|| SignerError::Uri(uri.to_string()))?;
+        Self::new(
+            url.join(".").map_err(SignerError::UrlParse)?.as_str(),
+            address,
+        )
+    }
+
+    fn address(&self) -> &str {
+        self.url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .expect("the url ends with the address")
+    }
+}
+
+impl Signer for HttpSigner {
+    fn public_key(&self) -> Result<String> {
+        let response: PublicKeyResponse = parse_response(self.http.get(&self.url))?;
+        // Don't trust a misconfigured signer to hold the key of the address.
+        let address = crypto::derive_address_from_pubkey(&response.public_key)
+            .map_err(SignerError::Crypto)?;
+        if address != self.address() {
+            return Err(SignerError::WrongKey(self.address().to_string(), address));
+        }
+        Ok(response.public_key)
+    }
+
+    fn sign(&self, watermark: u8, bytes: &[u8]) -> Result<(String, Vec<u8>)> {
+        let payload = Value::String(hex::encode([&[watermark], bytes].concat()));
+        // Signers may ask their user to confirm each request, so failed ones aren't repeated.
+        let response: SignatureResponse =
+            parse_response(self.http.post(Effect::Write, &self.url, &payload))?;
+        let raw_signature =
+            crypto::decode_signature(&response.signature).map_err(SignerError::Crypto)?;
+        Ok((response.signature, raw_signature))
+    }
+}
+
+fn parse_response<T: serde::de::DeserializeOwned>(
+    response: std::result::Result<Value, RpcError>,
+) -> Result<T> {
+    let value = response.map_err(|e| match e {
+        RpcError::Http(e) => SignerError::Unreachable(e.to_string()),
+        RpcError::IO(e) => SignerError::IO(e),
+        RpcError::NotFound(url) => SignerError::Status(404, url),
+        RpcError::Status { status, body } => SignerError::Status(status, body),
+        RpcError::Node { status, errors, .. } => SignerError::Status(status, errors.to_string()),
+        e => SignerError::Unreachable(e.to_string()),
+    })?;
+    serde_json::from_value(value.clone()).map_err(|e| SignerError::Response(e, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    const ADDRESS: &str = "tz1RNhvTfU11uBkJ7ZLxRDn25asLj4tj7JJB";
+    const SECRET_KEY: &str = "edsk2yRWMofVt5oqk1BWP4tJGeWZ4ikoZJ4psdMzoBqyqpT9g8tvpk";
+
+    /// Serves the remote signer protocol for `signer` on a local port, like `tezos-signer`
+    /// would, and returns its URL.
+    fn serve(signer: InMemorySigner) -> String {
+        test_server::serve(move |request| {
+            let (status, body) = respond(&signer, &request.method, &request.path, &request.body);
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+        })
+    }
+
+    fn respond(
+        signer: &InMemorySigner,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> (&'static str, String) {
+        if path != ["/keys/", ADDRESS].concat() {
+            return ("404 Not Found", "[]".to_string());
+        }
+        match method {
+            "GET" => (
+                "200 OK",
+                serde_json::json!({ "public_key": signer.public_key().unwrap() }).to_string(),
+            ),
+            "POST" => {
+                let data: String = serde_json::from_slice(body).unwrap();
+                let data = hex::decode(data).unwrap();
+                let (signature, _) = signer.sign(data[0], &data[1..]).unwrap();
+                (
+                    "200 OK",
+                    serde_json::json!({ "signature": signature }).to_string(),
+                )
+            }
+            _ => ("405 Method Not Allowed", "[]".to_string()),
+        }
+    }
+
+    #[test]
+    fn remote_signer_matches_in_memory_signer() -> Result<()> {
+        let local = InMemorySigner::new(ADDRESS.to_string(), SECRET_KEY.to_string());
+        let url = serve(InMemorySigner::new(
+            ADDRESS.to_string(),
+            SECRET_KEY.to_string(),
+        ));
+        let remote = HttpSigner::new(&url, ADDRESS)?;
+
+        assert_eq!(remote.public_key()?, local.public_key()?);
+        let bytes = b"an operation";
+        // Ed25519 signatures are deterministic.
+        assert_eq!(
+            remote.sign(crypto::GENERIC_OPERATION_WATERMARK, bytes)?,
+            local.sign(crypto::GENERIC_OPERATION_WATERMARK, bytes)?
+        );
+
+        let remote = HttpSigner::from_uri(&[&url, "/", ADDRESS].concat())?;
+        assert_eq!(remote.public_key()?, local.public_key()?);
+
+        let unknown = HttpSigner::new(&url, "tz1bwsEWCwSEXdRvnJxvegQZKeX5dj6oKEys")?;
+        assert!(matches!(
+            unknown.sign(crypto::GENERIC_OPERATION_WATERMARK, bytes),
+            Err(SignerError::Status(404, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_secret_keys_are_unlocked() -> Result<()> {
+        let encrypted = crypto::encrypt_secret_key(SECRET_KEY, "passphrase", [0; 8])
+            .map_err(SignerError::Crypto)?;
+
+        let signer = InMemorySigner::new(ADDRESS.to_string(), encrypted.clone());
+        assert!(signer.is_locked());
+        assert!(matches!(signer.signing_key(), Err(SignerError::Locked(_))));
+
+        let signer = InMemorySigner::new(ADDRESS.to_string(), encrypted)
+            .with_passphrase_prompt(|_| Some("passphrase".to_string()));
+        assert_eq!(signer.signing_key()?, SECRET_KEY);
+        assert!(!signer.is_locked());
+        Ok(())
+    }
+
+    #[test]
+    fn plain_secret_keys_are_revealing() -> Result<()> {
+        let encrypted = crypto::encrypt_secret_key(SECRET_KEY, "passphrase", [0; 8])
+            .map_err(SignerError::Crypto)?;
+        assert!(!reveals_secret_key(&encrypted));
+        assert!(!reveals_secret_key(
+            &["http://localhost:6732/", ADDRESS].concat()
+        ));
+        assert!(reveals_secret_key(SECRET_KEY));
+        assert!(reveals_secret_key(&["unencrypted:", SECRET_KEY].concat()));
+        assert!(!reveals_secret_key("edsk_mock"));
+        Ok(())
+    }
+}
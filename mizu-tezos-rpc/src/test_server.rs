@@ -0,0 +1,69 @@
+//! A local HTTP server for the tests of the modules which talk to nodes and signers.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Answers every request with the raw HTTP response `handle` returns for it, and returns the URL
+/// of the server, without a trailing slash.
+pub(crate) fn serve<F>(handle: F) -> String
+where
+    F: Fn(Request) -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handle = Arc::clone(&handle);
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.unwrap());
+                // Connections may be kept alive for several requests.
+                while let Some(request) = read_request(&mut reader) {
+                    let response = handle(request);
+                    if reader.get_mut().write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    url
+}
+
+/// Reads the next request, or returns `None` once the client closed the connection.
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut split = header.splitn(2, ':');
+        let name = split.next()?;
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = split.next()?.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request { method, path, body })
+}
@@ -7,62 +7,159 @@ use cursive::traits::*;
 use cursive::utils::markup::StyledString;
 use cursive::view::SizeConstraint;
 use cursive::views::*;
-use cursive::Cursive;
+use cursive::{CbSink, Cursive};
 use diesel::prelude::*;
-use mizu_driver::Driver;
+use mizu_driver::cover_traffic::Slot;
+use mizu_driver::envelope::{self, Content};
+use mizu_driver::events::Event as DriverEvent;
+use mizu_driver::poller::{Poller, PollerConfig};
+use mizu_driver::worker::Worker;
+use mizu_driver::{encrypt_secret_key, Driver};
+use mizu_sqlite::message::DeliveryStatus;
 use mizu_sqlite::MizuConnection;
 use mizu_tezos_interface::{BoxedTezos, Tezos};
 use mizu_tezos_mock::TezosMock;
-use mizu_tezos_rpc::TezosRpc;
+use mizu_tezos_rpc::signer::{self, HttpSigner, InMemorySigner, Signer};
+use mizu_tezos_rpc::{crypto, TezosRpc};
 use rand::rngs::OsRng;
 use std::collections::HashMap;
 use std::error::Error;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use structopt::StructOpt;
 use url::Url;
 
 type DynamicDriver = Driver<BoxedTezos<'static>>;
 type DynamicError = Box<dyn Error + Send + Sync + 'static>;
-type Drivers = HashMap<String, DynamicDriver>;
-// address * secret_key -> Tezos
-type TezosFactory = Rc<dyn Fn(&str, &str) -> BoxedTezos<'static>>;
+type Drivers = HashMap<String, Arc<DynamicDriver>>;
+// address * signer (see open_signer) * activation secret of a faucet account -> Tezos
+type TezosFactory = Arc<
+    dyn Fn(&str, &str, Option<&str>) -> Result<BoxedTezos<'static>, DynamicError> + Send + Sync,
+>;
+// address -> passphrase of its encrypted secret key, entered once per session
+type Passphrases = Arc<Mutex<HashMap<String, String>>>;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const IDENTITY_MENU_INDEX: usize = 1;
 const LEFT_WIDTH: usize = 45;
 const IDENTITY_HEIGHT: usize = 4;
+const COVER_SLOT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const CONTACT_LIST: &str = "CONTACT_LIST";
+const MESSAGE_LIST: &str = "MESSAGE_LIST";
 
 struct CursiveData {
     current_identity_id: Option<i32>,
     current_contact_id: Option<i32>,
+    /// contact_id -> number of messages received while the conversation was not shown
+    unread: HashMap<i32, usize>,
     drivers: Drivers,
-    user_db: Rc<MizuConnection>,
+    user_db: Arc<MizuConnection>,
     factory: TezosFactory,
+    passphrases: Passphrases,
+    cb_sink: CbSink,
+    /// Runs driver operations which talk to Tezos, so that the UI doesn't freeze
+    worker: Worker,
 }
 
 impl CursiveData {
+    /// switches to an identity and its first contact
+    fn select_identity(&mut self, identity_id: i32) {
+        self.current_identity_id = Some(identity_id);
+        self.current_contact_id = self
+            .user_db
+            .list_contacts(identity_id)
+            .unwrap_or_else(|e| {
+                eprintln!("failed to retrieve contacts from local DB: {:?}", e);
+                vec![]
+            })
+            .first()
+            .map(|contact| contact.id);
+    }
+
     /// returns a driver for the current identity
-    fn current_driver(&mut self) -> Option<&DynamicDriver> {
-        match self.current_identity_id {
-            Some(identity_id) => {
-                let identity = self.user_db.find_identity(identity_id).ok()?;
-                let user_db = Rc::clone(&self.user_db);
-                let factory = Rc::clone(&self.factory);
-                Some(
-                    self.drivers
-                        .entry(identity.name.to_string())
-                        .or_insert_with(|| {
-                            let tezos = (factory)(&identity.address, &identity.secret_key);
-                            Driver::new(user_db, tezos)
-                        }),
-                )
+    ///
+    /// If the signer of the identity can't be opened, the error is shown and the identity is
+    /// deselected, so that it isn't reported again on every sync.
+    fn current_driver(&mut self) -> Option<Arc<DynamicDriver>> {
+        let identity = self.user_db.find_identity(self.current_identity_id?).ok()?;
+        if let Some(driver) = self.drivers.get(&identity.name) {
+            return Some(Arc::clone(driver));
+        }
+        match (self.factory)(&identity.address, &identity.signer, None) {
+            Ok(tezos) => {
+                let driver = Arc::new(Driver::new(Arc::clone(&self.user_db), tezos));
+                subscribe(&driver, self.cb_sink.clone());
+                self.drivers.insert(identity.name, Arc::clone(&driver));
+                Some(driver)
+            }
+            Err(e) => {
+                self.current_identity_id = None;
+                self.current_contact_id = None;
+                let _ = self.cb_sink.send(Box::new(move |c| {
+                    render_world(c);
+                    c.add_layer(error_dialog(e));
+                }));
+                None
             }
-            None => None,
         }
     }
 }
 
+/// Runs `job` on the worker thread, and then `then` with its result on the UI thread.
+fn run_on_worker<J, K, R>(siv: &mut Cursive, job: J, then: K)
+where
+    J: FnOnce() -> R + Send + 'static,
+    K: FnOnce(&mut Cursive, R) + Send + 'static,
+    R: Send + 'static,
+{
+    let cb_sink = siv.cb_sink().clone();
+    siv.with_user_data(|data: &mut CursiveData| {
+        data.worker.execute(move || {
+            let result = job();
+            // The UI may have been closed in the meantime.
+            let _ = cb_sink.send(Box::new(move |s| then(s, result)));
+        });
+    });
+}
+
+/// Runs `job` with the current driver on the worker thread, and then `then` with its result on
+/// the UI thread. Does nothing if no identity is selected.
+fn run_in_background<J, K, R>(siv: &mut Cursive, job: J, then: K)
+where
+    J: FnOnce(&DynamicDriver) -> R + Send + 'static,
+    K: FnOnce(&mut Cursive, R) + Send + 'static,
+    R: Send + 'static,
+{
+    let driver = siv
+        .with_user_data(|data: &mut CursiveData| data.current_driver())
+        .flatten();
+    if let Some(driver) = driver {
+        run_on_worker(siv, move || job(&driver), then);
+    }
+}
+
+/// Rerenders the world, and fetches new messages of the current conversation in the background.
+fn refresh(siv: &mut Cursive) {
+    render_world(siv);
+
+    let ids = siv
+        .with_user_data(|data: &mut CursiveData| {
+            data.current_identity_id.zip(data.current_contact_id)
+        })
+        .flatten();
+    if let Some((identity_id, contact_id)) = ids {
+        // New messages and failures are delivered as events (see `handle_event`).
+        run_in_background(
+            siv,
+            move |driver| {
+                let _ = driver.get_messages(&mut OsRng, identity_id, contact_id);
+            },
+            |_, ()| {},
+        );
+    }
+}
+
 fn render_identity(identity: &Option<mizu_sqlite::identity::Identity>) -> impl View {
     // id. **name**
     //     tezos_address
@@ -88,12 +185,19 @@ fn render_identity(identity: &Option<mizu_sqlite::identity::Identity>) -> impl V
     }
 }
 
-fn render_contact(client: &mizu_sqlite::contact::Contact) -> (StyledString, i32) {
-    // contact_id. **name**       timestamp
+fn render_contact(
+    client: &mizu_sqlite::contact::Contact,
+    unread: &HashMap<i32, usize>,
+) -> (StyledString, i32) {
+    // contact_id. **name**  (unread)  timestamp
     //             tezos_address
     // TODO: show last message like Signal?
     let mut styled = StyledString::plain(format!("{:>3}. ", client.id));
     styled.append_styled(format!("{:<15}", client.name), Effect::Bold);
+    match unread.get(&client.id) {
+        Some(count) => styled.append_styled(format!("({}) ", count), Effect::Reverse),
+        None => styled.append(" "),
+    }
     /*match client.latest_message_timestamp {
         Some(ts) => styled.append(format!("{}\n", ts)),
         None => styled.append("\n"),
@@ -102,7 +206,10 @@ fn render_contact(client: &mizu_sqlite::contact::Contact) -> (StyledString, i32)
     (styled, client.id)
 }
 
-fn render_contacts(contacts: Vec<mizu_sqlite::contact::Contact>) -> impl View {
+fn render_contacts(
+    contacts: Vec<mizu_sqlite::contact::Contact>,
+    unread: &HashMap<i32, usize>,
+) -> impl View {
     // -----Contacts-----
     // | contacts here  |
     // ------------------
@@ -112,7 +219,7 @@ fn render_contacts(contacts: Vec<mizu_sqlite::contact::Contact>) -> impl View {
             data.current_contact_id = Some(contact_id);
         })
         .unwrap();
-        render_world(c);
+        refresh(c);
     }
 
     fn on_select(c: &mut Cursive, contact_id: &i32) {
@@ -127,10 +234,14 @@ fn render_contacts(contacts: Vec<mizu_sqlite::contact::Contact>) -> impl View {
 
     let contacts = Panel::new(
         SelectView::new()
-            .with_all(contacts.iter().map(render_contact))
+            .with_all(
+                contacts
+                    .iter()
+                    .map(|contact| render_contact(contact, unread)),
+            )
             .on_select(on_select)
             .on_submit(on_submit)
-            .with_name("SELECTON"),
+            .with_name(CONTACT_LIST),
     )
     .title("Contacts")
     .min_height(5);
@@ -173,10 +284,19 @@ fn render_contacts(contacts: Vec<mizu_sqlite::contact::Contact>) -> impl View {
 
                     match c
                         .with_user_data(|data: &mut CursiveData| {
-                            let driver = data.current_driver().unwrap();
-                            driver.add_contact(&name.get_content(), &address.get_content())?;
+                            let identity_id = data.current_identity_id.unwrap();
+                            let driver = match data.current_driver() {
+                                Some(driver) => driver,
+                                // The error has been shown already.
+                                None => return Ok(()),
+                            };
+                            driver.add_contact(
+                                identity_id,
+                                &name.get_content(),
+                                &address.get_content(),
+                            )?;
                             driver
-                                .find_contact_by_address(&address.get_content())
+                                .find_contact_by_address(identity_id, &address.get_content())
                                 .map(|contact| {
                                     data.current_contact_id = Some(contact.id);
                                 })
@@ -197,31 +317,160 @@ fn render_contacts(contacts: Vec<mizu_sqlite::contact::Contact>) -> impl View {
         .fixed_width(LEFT_WIDTH)
 }
 
-fn render_messages<I: Iterator<Item = mizu_sqlite::message::Message>>(iter: I) -> impl View {
+fn render_messages<I: Iterator<Item = mizu_sqlite::message::Message>>(
+    iter: I,
+    reactions: Vec<mizu_sqlite::reaction::Reaction>,
+    held_messages: Vec<mizu_sqlite::outbox::OutboxMessage>,
+) -> impl View {
     // messages from me:
-    // <right align> content
-    //             timestamp
+    //      <right align> > replied message
+    //                            content
+    //   #id timestamp (edited) (delivery status) (read)
+    //           reaction (them) reaction (you)
 
     // messages from the other guy in the conversation:
-    // content   <left align>
-    // timestamp
-
-    iter.fold(LinearLayout::vertical(), |view, message| {
-        let content = format!("{}\n", String::from_utf8_lossy(&message.content));
-        let timestamp = message.created_at.format("%Y-%m-%d %H:%M:%S").to_string();
-        let mut styled = StyledString::new();
-        styled.append_styled(content, Effect::Bold);
-        styled.append(timestamp);
-
-        view.child(TextView::new(styled).h_align(if message.my_message {
-            HAlign::Right
-        } else {
-            HAlign::Left
-        }))
+    // > replied message   <left align>
+    // content
+    // #id timestamp (edited)
+    // reaction (them) reaction (you)
+
+    // messages held until the next cover traffic slot:
+    // <right align> content
+    //              (queued)
+
+    const QUOTE_LENGTH: usize = 30;
+
+    let messages: Vec<_> = iter.collect();
+    let quotes: HashMap<&[u8], String> = messages
+        .iter()
+        .filter_map(|message| {
+            let uid = message.uid.as_deref()?;
+            let content = String::from_utf8_lossy(&message.content);
+            let first_line = content.lines().next().unwrap_or("");
+            Some((uid, first_line.chars().take(QUOTE_LENGTH).collect()))
+        })
+        .collect();
+
+    let view = messages
+        .iter()
+        .fold(LinearLayout::vertical(), |view, message| {
+            let mut styled = StyledString::new();
+            if let Some(reply_to) = &message.reply_to {
+                let quote = quotes
+                    .get(&reply_to[..])
+                    .map_or("(deleted message)", |quote| quote.as_str());
+                styled.append_styled(format!("> {}\n", quote), Effect::Italic);
+            }
+            styled.append_styled(
+                format!("{}\n", String::from_utf8_lossy(&message.content)),
+                Effect::Bold,
+            );
+            styled.append(format!(
+                "#{} {}",
+                message.id,
+                message.created_at.format("%Y-%m-%d %H:%M:%S")
+            ));
+            if message.edited_at.is_some() {
+                styled.append_styled(" (edited)", Effect::Italic);
+            }
+            match message.delivery_status() {
+                Some(DeliveryStatus::Included) => styled.append_styled(
+                    format!(
+                        " (included at {})",
+                        message.included_level.unwrap_or_default()
+                    ),
+                    Effect::Italic,
+                ),
+                Some(status) => {
+                    styled.append_styled(format!(" ({})", status.as_str()), Effect::Italic)
+                }
+                None => {}
+            }
+            if message.my_message && message.read_at.is_some() {
+                styled.append_styled(" (read)", Effect::Italic);
+            }
+            let reactions: Vec<_> = reactions
+                .iter()
+                .filter(|reaction| reaction.message_id == message.id)
+                .map(|reaction| {
+                    let by = if reaction.my_reaction { "you" } else { "them" };
+                    format!("{} ({})", reaction.reaction, by)
+                })
+                .collect();
+            if !reactions.is_empty() {
+                styled.append(format!("\n{}", reactions.join(" ")));
+            }
+
+            view.child(TextView::new(styled).h_align(if message.my_message {
+                HAlign::Right
+            } else {
+                HAlign::Left
+            }))
+        });
+    held_messages
+        .into_iter()
+        .fold(view, |view, message| {
+            let content = match envelope::decode(&message.content) {
+                Ok((_, content)) => format!("{}\n", content),
+                Err(e) => format!("{}\n", e),
+            };
+            let mut styled = StyledString::new();
+            styled.append_styled(content, Effect::Bold);
+            styled.append_styled("(queued)", Effect::Italic);
+
+            view.child(TextView::new(styled).h_align(HAlign::Right))
+        })
+        .min_height(5)
+        .full_width()
+        .scrollable()
+}
+
+/// What the message input asks for. Lines starting with a slash are commands, which refer to
+/// messages by the number shown next to them.
+enum Input {
+    Text(String),
+    Reply(i32, String),
+    Edit(i32, String),
+    Delete(i32),
+    React(i32, String),
+    Read,
+}
+
+const INPUT_HELP: &str =
+    "/reply <#> <text>, /edit <#> <text>, /delete <#>, /react <#> [reaction], /read";
+
+fn parse_input(input: &str) -> Result<Input, String> {
+    if !input.starts_with('/') {
+        return Ok(Input::Text(input.to_string()));
+    }
+
+    let mut words = input.splitn(3, char::is_whitespace);
+    let command = words.next().unwrap_or("");
+    let mut message_id = || -> Result<i32, String> {
+        let message_id = words.next().unwrap_or("");
+        message_id
+            .trim_start_matches('#')
+            .parse()
+            .map_err(|_| format!("invalid message number: {:?}", message_id))
+    };
+    let input = match command {
+        "/reply" => Input::Reply(message_id()?, String::new()),
+        "/edit" => Input::Edit(message_id()?, String::new()),
+        "/delete" => Input::Delete(message_id()?),
+        "/react" => Input::React(message_id()?, String::new()),
+        "/read" => Input::Read,
+        _ => return Err(format!("unknown command: {}\n{}", command, INPUT_HELP)),
+    };
+    let rest = words.next().unwrap_or("").trim().to_string();
+    Ok(match input {
+        Input::Reply(_, _) | Input::Edit(_, _) if rest.is_empty() => {
+            return Err(format!("{} needs a text", command))
+        }
+        Input::Reply(message_id, _) => Input::Reply(message_id, rest),
+        Input::Edit(message_id, _) => Input::Edit(message_id, rest),
+        Input::React(message_id, _) => Input::React(message_id, rest),
+        input => input,
     })
-    .min_height(5)
-    .full_width()
-    .scrollable()
 }
 
 fn send_message(s: &mut Cursive) {
@@ -231,32 +480,54 @@ fn send_message(s: &mut Cursive) {
     if content.trim().is_empty() {
         return;
     }
+    let input = match parse_input(content.trim_start()) {
+        Ok(input) => input,
+        Err(e) => {
+            s.add_layer(Dialog::info(e).title("Error"));
+            return;
+        }
+    };
 
-    if let Some(dialog) = s
+    let ids = s
         .with_user_data(|data: &mut CursiveData| {
-            match (data.current_identity_id, data.current_contact_id) {
-                (None, _) => Some(Dialog::info("Please select an identity").title("Error")),
-                (_, None) => Some(Dialog::info("Please select a contact").title("Error")),
-                (Some(our_identity_id), Some(their_contact_id)) => match data
-                    .current_driver()
-                    .unwrap()
-                    .post_message(&mut OsRng, our_identity_id, their_contact_id, &content)
-                {
-                    Ok(_) => None,
-                    Err(e) => Some(
-                        Dialog::info(format!("failed to send message: {:?}", e)).title("Error"),
-                    ),
-                },
-            }
+            (data.current_identity_id, data.current_contact_id)
         })
-        .unwrap()
-    {
-        // Rerender the world BEFORE showing a dialog
-        render_world(s);
-        s.add_layer(dialog);
-    } else {
-        render_world(s);
-    };
+        .unwrap();
+    match ids {
+        (None, _) => s.add_layer(Dialog::info("Please select an identity").title("Error")),
+        (_, None) => s.add_layer(Dialog::info("Please select a contact").title("Error")),
+        (Some(our_identity_id), Some(their_contact_id)) => run_in_background(
+            s,
+            move |driver| {
+                let (identity, contact) = (our_identity_id, their_contact_id);
+                match input {
+                    Input::Text(text) => driver.post_message(&mut OsRng, identity, contact, &text),
+                    Input::Reply(message_id, text) => {
+                        driver.reply_to_message(&mut OsRng, identity, contact, message_id, &text)
+                    }
+                    Input::Edit(message_id, text) => {
+                        driver.edit_message(&mut OsRng, identity, contact, message_id, &text)
+                    }
+                    Input::Delete(message_id) => {
+                        driver.delete_message(&mut OsRng, identity, contact, message_id)
+                    }
+                    Input::React(message_id, reaction) => driver
+                        .react_to_message(&mut OsRng, identity, contact, message_id, &reaction),
+                    Input::Read => driver.send_read_receipt(&mut OsRng, identity, contact),
+                }
+                .map_err(|e| format!("{:?}", e))
+            },
+            |s, result| {
+                // Rerender the world BEFORE showing a dialog
+                render_world(s);
+                if let Err(e) = result {
+                    s.add_layer(
+                        Dialog::info(format!("failed to send message: {}", e)).title("Error"),
+                    );
+                }
+            },
+        ),
+    }
 }
 
 fn render_input_view() -> impl View {
@@ -275,6 +546,7 @@ fn render_input_view() -> impl View {
             ))
             .child(Button::new("send", send_message)),
     )
+    .title(INPUT_HELP)
 }
 
 fn error_dialog<E: std::fmt::Debug>(error: E) -> impl View {
@@ -283,24 +555,94 @@ fn error_dialog<E: std::fmt::Debug>(error: E) -> impl View {
         .dismiss_button("Ok")
 }
 
+/// Returns the signer we recorded for an identity: either the URI of a remote signer or an
+/// encrypted secret key, which is unlocked with the passphrase entered for the address.
+fn open_signer(
+    address: &str,
+    signer: &str,
+    passphrases: &Passphrases,
+) -> Result<Box<dyn Signer>, DynamicError> {
+    if signer::is_remote_signer(signer) {
+        Ok(Box::new(HttpSigner::from_uri(signer)?))
+    } else {
+        let passphrases = Arc::clone(passphrases);
+        Ok(Box::new(
+            InMemorySigner::new(address.to_string(), signer.to_string()).with_passphrase_prompt(
+                move |address| passphrases.lock().unwrap().get(address).cloned(),
+            ),
+        ))
+    }
+}
+
+/// Returns the address and signer to register from a faucet file, a secret key or the URI of
+/// a remote signer (`http://host:port/<address>`), and the activation secret of faucet files.
+///
+/// Secret keys are registered encrypted with `passphrase`, and unlocked for the session.
+fn import_key(
+    input: &str,
+    passphrase: &str,
+    passphrases: &Passphrases,
+) -> Result<(String, String, Option<String>), DynamicError> {
+    if signer::is_remote_signer(input) {
+        // Make sure the signer is up and holds the key of the address.
+        let public_key = HttpSigner::from_uri(input)?.public_key()?;
+        let address = crypto::derive_address_from_pubkey(&public_key)?;
+        return Ok((address, input.to_string(), None));
+    }
+
+    let (secret_key, activation_secret) = if crypto::is_encrypted_secret_key(input) {
+        (crypto::decrypt_secret_key(input, passphrase)?, None)
+    } else if crypto::derive_public_key(input).is_ok() {
+        (input.to_string(), None)
+    } else {
+        let file = crypto::FaucetOutput::load_from_file(input)?;
+        (file.derive_secret_key()?, Some(file.secret))
+    };
+    if passphrase.is_empty() {
+        return Err("a passphrase is needed to encrypt the secret key with".into());
+    }
+    let signer = if crypto::is_encrypted_secret_key(input) {
+        input.to_string()
+    } else {
+        encrypt_secret_key(&mut OsRng, &secret_key, passphrase)?
+    };
+    let address = crypto::derive_address_from_pubkey(&crypto::derive_public_key(&secret_key)?)?;
+    passphrases
+        .lock()
+        .unwrap()
+        .insert(address.clone(), passphrase.to_string());
+    Ok((address, signer, activation_secret))
+}
+
 fn register_callback(
-    user_db: Rc<MizuConnection>,
+    user_db: Arc<MizuConnection>,
     factory: TezosFactory,
 ) -> impl Fn(&mut Cursive) + 'static {
     move |c| {
-        const FAUCET_FILE_EDIT: &str = "FAUCET_FILE_EDIT";
+        const KEY_EDIT: &str = "KEY_EDIT";
+        const PASSPHRASE_EDIT: &str = "PASSPHRASE_EDIT";
         const NAME_EDIT: &str = "NAME_EDIT";
 
         let content = LinearLayout::vertical()
             .child(
                 LinearLayout::horizontal()
-                    .child(TextView::new("       Name: "))
+                    .child(TextView::new("                           Name: "))
                     .child(EditView::new().with_name(NAME_EDIT).min_width(50)),
             )
             .child(
                 LinearLayout::horizontal()
-                    .child(TextView::new("Faucet file: "))
-                    .child(EditView::new().with_name(FAUCET_FILE_EDIT).min_width(50)),
+                    .child(TextView::new("Faucet file, key or signer URI: "))
+                    .child(EditView::new().with_name(KEY_EDIT).min_width(50)),
+            )
+            .child(
+                LinearLayout::horizontal()
+                    .child(TextView::new("  Passphrase of the secret key: "))
+                    .child(
+                        EditView::new()
+                            .secret()
+                            .with_name(PASSPHRASE_EDIT)
+                            .min_width(50),
+                    ),
             );
 
         c.add_layer(
@@ -308,54 +650,86 @@ fn register_callback(
                 .title("Register your identity with Mizu")
                 .dismiss_button("Cancel")
                 .button("Ok", {
-                    let user_db = Rc::clone(&user_db);
-                    let factory = Rc::clone(&factory);
+                    let user_db = Arc::clone(&user_db);
+                    let factory = Arc::clone(&factory);
                     move |c| {
-                        let edit: ViewRef<EditView> = c.find_name(FAUCET_FILE_EDIT).unwrap();
-                        let name_edit: ViewRef<EditView> = c.find_name(NAME_EDIT).unwrap();
+                        let key = c
+                            .find_name::<EditView>(KEY_EDIT)
+                            .unwrap()
+                            .get_content()
+                            .to_string();
+                        let passphrase = c
+                            .find_name::<EditView>(PASSPHRASE_EDIT)
+                            .unwrap()
+                            .get_content()
+                            .to_string();
+                        let name = c
+                            .find_name::<EditView>(NAME_EDIT)
+                            .unwrap()
+                            .get_content()
+                            .to_string();
                         c.pop_layer();
-
-                        match mizu_tezos_rpc::crypto::FaucetOutput::load_from_file(
-                            edit.get_content().to_string(),
-                        )
-                        .and_then(|file| {
-                            let secret_key =
-                                mizu_tezos_rpc::crypto::FaucetOutput::derive_secret_key(&file)?;
-                            eprintln!("loaded secret: {}", secret_key);
-                            let name = name_edit.get_content().to_string();
-                            let tezos = factory(&file.pkh, &secret_key);
-                            let driver = Driver::new(Rc::clone(&user_db), tezos);
-                            driver.generate_identity(&mut OsRng, &name)?;
-                            let identity = user_db.find_identity_by_name(&name)?;
-                            driver.publish_identity(identity.id)?;
-                            c.with_user_data(|data: &mut CursiveData| {
-                                data.drivers.insert(name.clone(), driver);
-                                data.current_identity_id = Some(identity.id);
-                            })
+                        let passphrases = c
+                            .with_user_data(|data: &mut CursiveData| Arc::clone(&data.passphrases))
                             .unwrap();
+                        c.add_layer(Dialog::text("Registering yourself...").title("Registration"));
 
-                            render_world(c);
-                            // rerender the Identity menu
-                            render_identity_menu(
-                                // 1st subtree corresponds to "Identity" menu
-                                c.menubar().get_subtree(IDENTITY_MENU_INDEX).unwrap(),
-                                Rc::clone(&user_db),
-                                Rc::clone(&factory),
-                            )?;
-
-                            Ok(name)
-                        }) {
-                            Ok(name) => c.add_layer(
-                                Dialog::around({
-                                    let mut styled = StyledString::plain("Registered yourself as ");
-                                    styled.append_styled(name, Effect::Bold);
-                                    TextView::new(styled)
+                        // Talking to the signer and Tezos takes a while, activating a faucet
+                        // account even more so.
+                        let job = {
+                            let user_db = Arc::clone(&user_db);
+                            let factory = Arc::clone(&factory);
+                            let cb_sink = c.cb_sink().clone();
+                            move || -> Result<(String, i32, DynamicDriver), DynamicError> {
+                                let (address, signer, activation_secret) =
+                                    import_key(&key, &passphrase, &passphrases)?;
+                                // The account is activated when publishing the identity below.
+                                let tezos =
+                                    factory(&address, &signer, activation_secret.as_deref())?;
+                                let driver = Driver::new(Arc::clone(&user_db), tezos);
+                                subscribe(&driver, cb_sink);
+                                driver.generate_identity(&mut OsRng, &name, &signer)?;
+                                let identity = user_db.find_identity_by_name(&name)?;
+                                driver.publish_identity(identity.id)?;
+                                Ok((name, identity.id, driver))
+                            }
+                        };
+                        let user_db = Arc::clone(&user_db);
+                        let factory = Arc::clone(&factory);
+                        run_on_worker(c, job, move |c, result| {
+                            c.pop_layer();
+                            let result = result.and_then(|(name, identity_id, driver)| {
+                                c.with_user_data(|data: &mut CursiveData| {
+                                    data.drivers.insert(name.clone(), Arc::new(driver));
+                                    data.select_identity(identity_id);
                                 })
-                                .title("Registration succeeded")
-                                .dismiss_button("Ok"),
-                            ),
-                            Err(e) => c.add_layer(error_dialog(e)),
-                        }
+                                .unwrap();
+
+                                render_world(c);
+                                // rerender the Identity menu
+                                render_identity_menu(
+                                    // 1st subtree corresponds to "Identity" menu
+                                    c.menubar().get_subtree(IDENTITY_MENU_INDEX).unwrap(),
+                                    user_db,
+                                    factory,
+                                )?;
+
+                                Ok(name)
+                            });
+                            match result {
+                                Ok(name) => c.add_layer(
+                                    Dialog::around({
+                                        let mut styled =
+                                            StyledString::plain("Registered yourself as ");
+                                        styled.append_styled(name, Effect::Bold);
+                                        TextView::new(styled)
+                                    })
+                                    .title("Registration succeeded")
+                                    .dismiss_button("Ok"),
+                                ),
+                                Err(e) => c.add_layer(error_dialog(e)),
+                            }
+                        });
                     }
                 })
                 .h_align(HAlign::Center),
@@ -363,9 +737,115 @@ fn register_callback(
     }
 }
 
+/// Asks for the passphrase of an identity with an encrypted secret key until it is correct or
+/// the user gives up, in which case operations of the identity fail.
+fn unlock_dialog(name: String, address: String, secret_key: String) -> impl View {
+    let edit_name = format!("PASSPHRASE_EDIT_{}", address);
+
+    Dialog::around(
+        LinearLayout::vertical()
+            .child(TextView::new(format!(
+                "Passphrase of {} ({}):",
+                name, address
+            )))
+            .child(
+                EditView::new()
+                    .secret()
+                    .with_name(edit_name.clone())
+                    .min_width(50),
+            ),
+    )
+    .title("Unlock identity")
+    .dismiss_button("Later")
+    .button("Unlock", move |c| {
+        let passphrase = c
+            .find_name::<EditView>(&edit_name)
+            .unwrap()
+            .get_content()
+            .to_string();
+        c.pop_layer();
+        match crypto::decrypt_secret_key(&secret_key, &passphrase) {
+            Ok(_) => {
+                c.with_user_data(|data: &mut CursiveData| {
+                    data.passphrases
+                        .lock()
+                        .unwrap()
+                        .insert(address.clone(), passphrase)
+                });
+            }
+            Err(e) => {
+                c.add_layer(unlock_dialog(
+                    name.clone(),
+                    address.clone(),
+                    secret_key.clone(),
+                ));
+                c.add_layer(error_dialog(e));
+            }
+        }
+    })
+}
+
+/// Asks for a passphrase to encrypt the plain secret key an older version recorded for an
+/// identity with, until the key is encrypted or the user gives up.
+fn protect_dialog(
+    identity_id: i32,
+    name: String,
+    address: String,
+    secret_key: String,
+) -> impl View {
+    let edit_name = format!("PASSPHRASE_EDIT_{}", address);
+
+    Dialog::around(
+        LinearLayout::vertical()
+            .child(TextView::new(format!(
+                "The secret key of {} ({}) is stored unencrypted.\nPassphrase to encrypt it with:",
+                name, address
+            )))
+            .child(
+                EditView::new()
+                    .secret()
+                    .with_name(edit_name.clone())
+                    .min_width(50),
+            ),
+    )
+    .title("Encrypt secret key")
+    .dismiss_button("Later")
+    .button("Encrypt", move |c| {
+        let passphrase = c
+            .find_name::<EditView>(&edit_name)
+            .unwrap()
+            .get_content()
+            .to_string();
+        c.pop_layer();
+        let result = c
+            .with_user_data(|data: &mut CursiveData| -> Result<(), DynamicError> {
+                if passphrase.is_empty() {
+                    return Err("a passphrase is needed to encrypt the secret key with".into());
+                }
+                let signer = encrypt_secret_key(&mut OsRng, &secret_key, &passphrase)?;
+                data.user_db.update_identity_signer(identity_id, &signer)?;
+                data.passphrases
+                    .lock()
+                    .unwrap()
+                    .insert(address.clone(), passphrase);
+                Ok(())
+            })
+            .unwrap();
+        if let Err(e) = result {
+            c.add_layer(protect_dialog(
+                identity_id,
+                name.clone(),
+                address.clone(),
+                secret_key.clone(),
+            ));
+            c.add_layer(error_dialog(e));
+        }
+    })
+}
+
 fn render_identity_menu(
     tree: &mut MenuTree,
-    user_db: Rc<MizuConnection>,
+    user_db: Arc<MizuConnection>,
     factory: TezosFactory,
 ) -> Result<(), DynamicError> {
     // identity
@@ -377,7 +857,7 @@ fn render_identity_menu(
     tree.clear();
     tree.add_leaf(
         "register",
-        register_callback(Rc::clone(&user_db), Rc::clone(&factory)),
+        register_callback(Arc::clone(&user_db), Arc::clone(&factory)),
     );
 
     if !identities.is_empty() {
@@ -386,16 +866,421 @@ fn render_identity_menu(
     for identity in identities.iter() {
         let id = identity.id;
         tree.add_leaf(&identity.name, move |c| {
-            c.with_user_data(move |data: &mut CursiveData| {
-                data.current_identity_id = Some(id);
-            });
-            render_world(c);
+            c.with_user_data(move |data: &mut CursiveData| data.select_identity(id));
+            refresh(c);
         });
     }
 
     Ok(())
 }
 
+/// Fetches new messages of all contacts of the current identity in the background.
+fn sync_all(siv: &mut Cursive) {
+    let identity_id = match siv
+        .with_user_data(|data: &mut CursiveData| data.current_identity_id)
+        .flatten()
+    {
+        Some(identity_id) => identity_id,
+        None => return,
+    };
+
+    // New messages and per-contact failures are delivered as events (see `handle_event`).
+    run_in_background(
+        siv,
+        move |driver| driver.sync_all(&mut OsRng, identity_id).map(|_| ()),
+        move |_, result| {
+            if let Err(e) = result {
+                eprintln!("failed to sync: identity = {}, {:?}", identity_id, e);
+            }
+        },
+    );
+}
+
+/// Posts a held message or a dummy in the background if the current identity has a slot due.
+fn run_cover_slot(siv: &mut Cursive) {
+    let identity_id = match siv
+        .with_user_data(|data: &mut CursiveData| data.current_identity_id)
+        .flatten()
+    {
+        Some(identity_id) => identity_id,
+        None => return,
+    };
+
+    run_in_background(
+        siv,
+        move |driver| driver.run_cover_slot(&mut OsRng, identity_id),
+        move |_, result| match result {
+            Ok(Slot::NotDue) | Ok(Slot::Disabled) => {}
+            Ok(slot) => eprintln!("cover traffic slot: identity = {}, {:?}", identity_id, slot),
+            Err(e) => eprintln!(
+                "failed to run cover traffic slot: identity = {}, {:?}",
+                identity_id, e
+            ),
+        },
+    );
+}
+
+fn cover_traffic_dialog(c: &mut Cursive) {
+    const INTERVAL_EDIT: &str = "INTERVAL_EDIT";
+    const BUDGET_EDIT: &str = "BUDGET_EDIT";
+
+    let current = c
+        .with_user_data(|data: &mut CursiveData| {
+            data.current_identity_id
+                .map(|identity_id| data.user_db.find_cover_traffic(identity_id))
+        })
+        .unwrap();
+    let (interval_secs, daily_budget) = match current {
+        None => {
+            c.add_layer(Dialog::info("Please select an identity").title("Error"));
+            return;
+        }
+        Some(Err(e)) => {
+            c.add_layer(error_dialog(e));
+            return;
+        }
+        Some(Ok(Some(cover_traffic))) => (cover_traffic.interval_secs, cover_traffic.daily_budget),
+        Some(Ok(None)) => (600, 144),
+    };
+
+    let content = LinearLayout::vertical()
+        .child(TextView::new(
+            "Post only at random slots, filling slots without messages with dummies.\n\
+             Each post costs a transaction fee.",
+        ))
+        .child(
+            LinearLayout::horizontal()
+                .child(TextView::new("Mean interval (seconds): "))
+                .child(
+                    EditView::new()
+                        .content(interval_secs.to_string())
+                        .with_name(INTERVAL_EDIT)
+                        .min_width(10),
+                ),
+        )
+        .child(
+            LinearLayout::horizontal()
+                .child(TextView::new("   Max. posts per day: "))
+                .child(
+                    EditView::new()
+                        .content(daily_budget.to_string())
+                        .with_name(BUDGET_EDIT)
+                        .min_width(10),
+                ),
+        );
+
+    c.add_layer(
+        Dialog::around(content)
+            .title("Cover traffic")
+            .dismiss_button("Cancel")
+            .button("Disable", |c| {
+                c.pop_layer();
+                let result = c
+                    .with_user_data(|data: &mut CursiveData| {
+                        let identity_id = data.current_identity_id.unwrap();
+                        data.current_driver()
+                            .map_or(Ok(()), |driver| driver.disable_cover_traffic(identity_id))
+                    })
+                    .unwrap();
+                if let Err(e) = result {
+                    c.add_layer(error_dialog(e));
+                }
+            })
+            .button("Enable", |c| {
+                let interval: ViewRef<EditView> = c.find_name(INTERVAL_EDIT).unwrap();
+                let budget: ViewRef<EditView> = c.find_name(BUDGET_EDIT).unwrap();
+                let (interval_secs, daily_budget) = match (
+                    interval.get_content().trim().parse::<i32>(),
+                    budget.get_content().trim().parse::<i32>(),
+                ) {
+                    (Ok(interval_secs), Ok(daily_budget)) => (interval_secs, daily_budget),
+                    _ => {
+                        c.add_layer(Dialog::info("Please enter numbers").title("Error"));
+                        return;
+                    }
+                };
+                c.pop_layer();
+
+                let result = c
+                    .with_user_data(|data: &mut CursiveData| {
+                        let identity_id = data.current_identity_id.unwrap();
+                        data.current_driver().map_or(Ok(()), |driver| {
+                            driver.enable_cover_traffic(identity_id, interval_secs, daily_budget)
+                        })
+                    })
+                    .unwrap();
+                if let Err(e) = result {
+                    c.add_layer(error_dialog(e));
+                }
+            })
+            .h_align(HAlign::Center),
+    );
+}
+
+/// Formats an identity key in groups of 4 hex digits.
+fn fingerprint(key: &[u8]) -> String {
+    key.chunks(2)
+        .map(|chunk| chunk.iter().map(|byte| format!("{:02x}", byte)).collect())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn show_key_history(c: &mut Cursive, contact_id: i32) {
+    let result = c
+        .with_user_data(|data: &mut CursiveData| {
+            let identity_id = data.current_identity_id?;
+            let contact = data.user_db.find_contact(contact_id);
+            let key_changes = data
+                .current_driver()?
+                .list_key_changes(identity_id, contact_id);
+            Some(
+                contact
+                    .map_err(DynamicError::from)
+                    .and_then(|contact| Ok((contact, key_changes.map_err(DynamicError::from)?))),
+            )
+        })
+        .flatten();
+    let (contact, key_changes) = match result {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => {
+            c.add_layer(error_dialog(e));
+            return;
+        }
+        None => {
+            c.add_layer(Dialog::info("Please select an identity").title("Error"));
+            return;
+        }
+    };
+
+    // pinned key: xxxx xxxx ...
+    //
+    // detected_at
+    //   old: xxxx xxxx ...
+    //   new: xxxx xxxx ...
+    //   accepted at accepted_at / pending
+    let mut styled = StyledString::plain("pinned key: ");
+    match &contact.pinned_identity_key {
+        Some(key) => styled.append_styled(fingerprint(key), Effect::Bold),
+        None => styled.append("(not seen yet)"),
+    }
+    if key_changes.is_empty() {
+        styled.append("\n\nThe identity key has never changed.");
+    }
+    let mut pending = false;
+    for key_change in key_changes.iter() {
+        styled.append(format!(
+            "\n\n{}\n  old: {}\n  new: {}\n  ",
+            key_change.detected_at.format("%Y-%m-%d %H:%M:%S"),
+            fingerprint(&key_change.old_identity_key),
+            fingerprint(&key_change.new_identity_key),
+        ));
+        match key_change.accepted_at {
+            Some(accepted_at) => styled.append(format!(
+                "accepted at {}",
+                accepted_at.format("%Y-%m-%d %H:%M:%S")
+            )),
+            None => {
+                pending = true;
+                styled.append_styled("pending", Effect::Reverse)
+            }
+        }
+    }
+
+    let mut dialog = Dialog::around(TextView::new(styled).scrollable())
+        .title(format!("Identity keys of {}", contact.name))
+        .dismiss_button("Close");
+    if pending {
+        dialog.add_button("Accept new key", move |c| {
+            c.pop_layer();
+            let result = c
+                .with_user_data(|data: &mut CursiveData| {
+                    let identity_id = data.current_identity_id.unwrap();
+                    data.current_driver().map_or(Ok(()), |driver| {
+                        driver.accept_identity_key(identity_id, contact_id)
+                    })
+                })
+                .unwrap();
+            if let Err(e) = result {
+                c.add_layer(error_dialog(e));
+            }
+        });
+    }
+    c.add_layer(dialog);
+}
+
+/// Forwards events of `driver` to the UI thread.
+fn subscribe(driver: &DynamicDriver, cb_sink: CbSink) {
+    driver.on_event(move |event| {
+        let event = event.clone();
+        // Unsubscribe once the UI is closed.
+        cb_sink
+            .send(Box::new(move |s| handle_event(s, event)))
+            .is_ok()
+    });
+}
+
+fn handle_event(siv: &mut Cursive, event: DriverEvent) {
+    match event {
+        DriverEvent::MessageReceived {
+            identity_id,
+            contact_id,
+            content,
+            ..
+        } => {
+            let new_message = matches!(content, Content::Text(_) | Content::Reply { .. });
+            let shown = siv
+                .with_user_data(|data: &mut CursiveData| {
+                    if data.current_identity_id != Some(identity_id) {
+                        return false;
+                    }
+                    if new_message && data.current_contact_id != Some(contact_id) {
+                        *data.unread.entry(contact_id).or_insert(0) += 1;
+                    }
+                    true
+                })
+                .unwrap();
+            if shown {
+                render_conversation(siv);
+            }
+        }
+        // Messages may be sent in a cover traffic slot, long after send_message.
+        DriverEvent::MessageQueued { identity_id, .. }
+        | DriverEvent::MessageSent { identity_id, .. }
+        | DriverEvent::DeliveryStatusChanged { identity_id, .. } => {
+            let shown = siv
+                .with_user_data(|data: &mut CursiveData| {
+                    data.current_identity_id == Some(identity_id)
+                })
+                .unwrap();
+            if shown {
+                render_conversation(siv);
+            }
+        }
+        // TODO: show pokes
+        DriverEvent::ContactRequestReceived { .. } => {}
+        DriverEvent::KeyChanged { contact_id, .. } => siv.add_layer(
+            Dialog::text(format!(
+                "The identity key of contact {} has changed. \
+                 Make sure you are still talking to the same person \
+                 before accepting the new key. \
+                 Until then, messages to them are not sent.",
+                contact_id
+            ))
+            .title("Warning")
+            .dismiss_button("Later")
+            .button("Show key history", move |c| {
+                c.pop_layer();
+                show_key_history(c, contact_id);
+            }),
+        ),
+        DriverEvent::KeyRestored { contact_id } => siv.add_layer(
+            Dialog::text(format!(
+                "Contact {} is using the pinned identity key again. \
+                 Messages to them are sent again.",
+                contact_id
+            ))
+            .title("Key change resolved")
+            .dismiss_button("Ok"),
+        ),
+        DriverEvent::SyncFailed {
+            identity_id,
+            contact_id,
+            error,
+        } => eprintln!(
+            "failed to retrieve messages from Tezos: identity = {}, contact = {}, {}",
+            identity_id, contact_id, error
+        ),
+        DriverEvent::SendFailed {
+            identity_id,
+            contact_id,
+            error,
+        } => eprintln!(
+            "failed to send a held message: identity = {}, contact = {}, {}",
+            identity_id, contact_id, error
+        ),
+        DriverEvent::TrackingFailed { identity_id, error } => eprintln!(
+            "failed to track deliveries: identity = {}, {}",
+            identity_id, error
+        ),
+    }
+}
+
+/// Loads the current conversation, whose messages are no longer unread once shown.
+fn current_messages(
+    data: &mut CursiveData,
+) -> (
+    Vec<mizu_sqlite::message::Message>,
+    Vec<mizu_sqlite::reaction::Reaction>,
+    Vec<mizu_sqlite::outbox::OutboxMessage>,
+) {
+    match (data.current_identity_id, data.current_contact_id) {
+        (Some(current_identity_id), Some(current_contact_id)) => {
+            data.unread.remove(&current_contact_id);
+            let messages = data.user_db.find_messages(current_identity_id, current_contact_id)
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to retrieve messages from local DB: identity = {}, contact = {}, {:?}", current_identity_id, current_contact_id, e);
+                    vec![]
+                });
+            let held_messages = data
+                .user_db
+                .list_outbox_messages(current_identity_id)
+                .unwrap_or_else(|e| {
+                    eprintln!(
+                        "failed to retrieve held messages from local DB: identity = {}, {:?}",
+                        current_identity_id, e
+                    );
+                    vec![]
+                })
+                .into_iter()
+                .filter(|message| message.contact_id == current_contact_id)
+                .collect();
+            let reactions = data.user_db.list_reactions(current_identity_id, current_contact_id)
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to retrieve reactions from local DB: identity = {}, contact = {}, {:?}", current_identity_id, current_contact_id, e);
+                    vec![]
+                });
+            (messages, reactions, held_messages)
+        }
+        _ => (vec![], vec![], vec![]),
+    }
+}
+
+/// Updates the messages and unread counts shown by `render_world` for what the poller found.
+///
+/// Unlike `render_world`, the message being typed is kept. Nothing is updated while a dialog is
+/// open on top of the world, as the world can't be found under it.
+fn render_conversation(siv: &mut Cursive) {
+    if siv.screen().len() != 1 {
+        return;
+    }
+    let (messages, contacts, unread) = siv
+        .with_user_data(|data: &mut CursiveData| {
+            let (messages, reactions, held_messages) = current_messages(data);
+            let messages = render_messages(messages.into_iter(), reactions, held_messages);
+            let contacts = match data.current_identity_id {
+                Some(identity_id) => data.user_db.list_contacts(identity_id).unwrap_or_else(|e| {
+                    eprintln!("failed to retrieve contacts from local DB: {:?}", e);
+                    vec![]
+                }),
+                None => vec![],
+            };
+            (messages, contacts, data.unread.clone())
+        })
+        .unwrap();
+
+    siv.call_on_name(MESSAGE_LIST, |list: &mut LinearLayout| {
+        list.remove_child(0);
+        list.add_child(messages);
+    });
+    siv.call_on_name(CONTACT_LIST, |list: &mut SelectView<i32>| {
+        for (label, contact_id) in list.iter_mut() {
+            if let Some(contact) = contacts.iter().find(|contact| contact.id == *contact_id) {
+                *label = render_contact(contact, &unread).0;
+            }
+        }
+    });
+}
+
 fn render_world(siv: &mut Cursive) {
     let world = siv
         .with_user_data(|data: &mut CursiveData| {
@@ -409,37 +1294,37 @@ fn render_world(siv: &mut Cursive) {
                     }
                     None => None,
                 };
-            // TODO: contacts are shared among identities
             // list_talking_clients searches for `Client`s, so contacts are not listed if no conversation happened
-            let contacts = data.user_db.list_contacts().unwrap_or_else(|e| {
-                eprintln!("failed to retrieve contacts from local DB: {:?}", e);
-                vec![]
-            });
-            let messages = match (data.current_identity_id, data.current_contact_id) {
-                (Some(current_identity_id), Some(current_contact_id)) => {
-                    // update messages
-                    data.current_driver().unwrap().get_messages(&mut OsRng, current_identity_id, current_contact_id)
-                        .unwrap_or_else(|e| {
-                            eprintln!("failed to retrieve messages from Tezos: identity = {}, contact = {}, {:?}", current_identity_id, current_contact_id, e);
-                            vec![]
-                        });
-                    data.user_db.find_messages(current_identity_id, current_contact_id)
-                        .unwrap_or_else(|e| {
-                            eprintln!("failed to retrieve messages from local DB: identity = {}, contact = {}, {:?}", current_identity_id, current_contact_id, e);
-                            vec![]
-                        })
-                }
-                _ => vec![],
+            let contacts = match data.current_identity_id {
+                Some(current_identity_id) => data.user_db.list_contacts(current_identity_id).unwrap_or_else(|e| {
+                    eprintln!("failed to retrieve contacts from local DB: {:?}", e);
+                    vec![]
+                }),
+                None => vec![],
             };
+            let (messages, reactions, held_messages) = current_messages(data);
 
             let identity = render_identity(&identity);
-            let contacts = render_contacts(contacts);
+            let contacts = render_contacts(contacts, &data.unread);
             let left = LinearLayout::vertical().child(identity).child(contacts);
 
-            let refresh = Panel::new(Button::new("Refresh", render_world))
-                .fixed_height(3);
+            let refresh = Panel::new(
+                LinearLayout::horizontal()
+                    .child(Button::new("Refresh", refresh))
+                    .child(DummyView)
+                    .child(Button::new("Key history", |c| {
+                        let contact_id = c
+                            .with_user_data(|data: &mut CursiveData| data.current_contact_id)
+                            .flatten();
+                        match contact_id {
+                            Some(contact_id) => show_key_history(c, contact_id),
+                            None => c.add_layer(Dialog::info("Please select a contact").title("Error")),
+                        }
+                    })),
+            )
+            .fixed_height(3);
 
-            let messages = render_messages(messages.into_iter());
+            let messages = render_messages(messages.into_iter(), reactions, held_messages);
             let input_view = render_input_view();
             let messages_title = match data.current_contact_id.map(|id| data.user_db.find_contact(id)) {
                 Some(Ok(contact)) => format!("Conversation with {}", contact.name),
@@ -452,7 +1337,12 @@ fn render_world(siv: &mut Cursive) {
             };
             let messages = Panel::new(
                 LinearLayout::vertical()
-                    .child(messages.full_height())
+                    .child(
+                        LinearLayout::vertical()
+                            .child(messages)
+                            .with_name(MESSAGE_LIST)
+                            .full_height(),
+                    )
                     .child(input_view),
             )
             .title(messages_title);
@@ -488,6 +1378,12 @@ struct Opt {
     /// Path to theme TOML file (see
     /// https://docs.rs/cursive/0.15.0/cursive/theme/index.html#themes)
     theme: Option<PathBuf>,
+    /// Seconds between background syncs of all conversations
+    #[structopt(long, default_value = "30")]
+    poll_interval: u64,
+    /// Maximum random delay in seconds added to each poll interval
+    #[structopt(long, default_value = "10")]
+    poll_jitter: u64,
     #[structopt(subcommand)]
     rpc_opt: Option<Command>,
 }
@@ -532,9 +1428,10 @@ fn default_theme() -> theme::Theme {
 
 fn main() -> Result<(), DynamicError> {
     let opt = Opt::from_args();
-    let user_db = Rc::new(MizuConnection::connect(
+    let user_db = Arc::new(MizuConnection::connect(
         &opt.db.unwrap_or_else(|| ":memory:".to_string()),
     )?);
+    let passphrases: Passphrases = Arc::new(Mutex::new(HashMap::new()));
     let mock_factory: TezosFactory = match opt.rpc_opt {
         Some(Command::Rpc {
             debug,
@@ -545,31 +1442,34 @@ fn main() -> Result<(), DynamicError> {
                 host.unwrap_or_else(|| Url::parse("https://carthagenet.smartpy.io").unwrap());
             let contract_address = contract_address
                 .unwrap_or_else(|| "KT1UnS3wvwcUnj3dFAikmM773byGjY5Ci2Lk".to_string());
-            Rc::new(move |pkh, secret_key| {
-                TezosRpc::new(
+            let passphrases = Arc::clone(&passphrases);
+            Arc::new(move |pkh, signer, activation_secret| {
+                let signer = open_signer(pkh, signer, &passphrases)?;
+                let rpc = TezosRpc::new(
                     debug,
                     host.clone(),
                     pkh.into(),
-                    secret_key.into(),
+                    signer,
                     contract_address.clone(),
-                )
-                .boxed()
+                );
+                Ok(match activation_secret {
+                    Some(secret) => rpc.with_activation_secret(secret.to_string()).boxed(),
+                    None => rpc.boxed(),
+                })
             })
         }
         None => {
             let database_url = opt.tezos_mock.as_deref().unwrap_or(":memory:").to_string();
-            let run_migration =
-                database_url == ":memory:" || std::fs::metadata(&database_url).is_err();
-            let mock_db = Rc::new(SqliteConnection::establish(&database_url)?);
-            // Ideally, we want to perform this check in TezosMock like
+            let mock_db = SqliteConnection::establish(&database_url)?;
+            // Ideally, we want to run migrations in TezosMock like
             // MizuConnection::connect does, but since we use the connection
             // across multiple instances, we need to do this here.
-            if run_migration {
-                mizu_tezos_mock::run_migrations(&mock_db);
-            }
+            // Only pending migrations are run, so this is fine for existing databases.
+            mizu_tezos_mock::run_migrations(&mock_db);
+            let mock_db = Arc::new(Mutex::new(mock_db));
 
-            Rc::new(move |pkh, secret_key| {
-                TezosMock::new(pkh.into(), secret_key.into(), Rc::clone(&mock_db)).boxed()
+            Arc::new(move |pkh, _signer, _activation_secret| {
+                Ok(TezosMock::new(pkh.into(), Arc::clone(&mock_db)).boxed())
             })
         }
     };
@@ -593,17 +1493,23 @@ fn main() -> Result<(), DynamicError> {
         .list_identities()?
         .first()
         .map(|identity| identity.id);
-    let current_contact_id = user_db.list_contacts()?.first().map(|contact| contact.id);
 
     let mut siv = cursive::default();
     siv.set_user_data(CursiveData {
-        current_identity_id,
-        current_contact_id,
+        current_identity_id: None,
+        current_contact_id: None,
+        unread: HashMap::new(),
         drivers: HashMap::new(),
-        user_db: Rc::clone(&user_db),
-        factory: Rc::clone(&mock_factory),
+        user_db: Arc::clone(&user_db),
+        factory: Arc::clone(&mock_factory),
+        passphrases,
+        cb_sink: siv.cb_sink().clone(),
+        worker: Worker::spawn(),
     });
     siv.set_theme(theme);
+    if let Some(identity_id) = current_identity_id {
+        siv.with_user_data(|data: &mut CursiveData| data.select_identity(identity_id));
+    }
 
     siv.menubar()
         .add_subtree(
@@ -621,20 +1527,61 @@ fn main() -> Result<(), DynamicError> {
                 })
                 .leaf("Exit", |c| c.quit()),
         )
-        .add_subtree("Identity", MenuTree::new());
+        .add_subtree("Identity", MenuTree::new())
+        .add_subtree(
+            "Settings",
+            MenuTree::new().leaf("Cover traffic...", cover_traffic_dialog),
+        );
 
     render_identity_menu(
         // 1st subtree corresponds to "Identity" menu
         siv.menubar().get_subtree(IDENTITY_MENU_INDEX).unwrap(),
-        Rc::clone(&user_db),
-        Rc::clone(&mock_factory),
+        Arc::clone(&user_db),
+        Arc::clone(&mock_factory),
     )?;
 
     siv.set_autohide_menu(false);
     //siv.add_fullscreen_layer(view);
-    render_world(&mut siv);
+    refresh(&mut siv);
+    for identity in user_db.list_identities()? {
+        if crypto::is_encrypted_secret_key(&identity.signer) {
+            siv.add_layer(unlock_dialog(
+                identity.name,
+                identity.address,
+                identity.signer,
+            ));
+        } else if crypto::derive_public_key(&identity.signer).is_ok() {
+            siv.add_layer(protect_dialog(
+                identity.id,
+                identity.name,
+                identity.address,
+                identity.signer,
+            ));
+        }
+    }
     siv.add_global_callback(Key::Esc, |c| c.select_menubar());
+
+    let poller = Poller::spawn(
+        PollerConfig::new(
+            Duration::from_secs(opt.poll_interval),
+            Duration::from_secs(opt.poll_jitter),
+        ),
+        {
+            let cb_sink = siv.cb_sink().clone();
+            move || cb_sink.send(Box::new(sync_all)).is_ok()
+        },
+    );
+    // Slots are randomized by the driver, so this only needs to tick often enough.
+    let cover_traffic_poller = Poller::spawn(
+        PollerConfig::new(COVER_SLOT_CHECK_INTERVAL, Duration::from_secs(0)),
+        {
+            let cb_sink = siv.cb_sink().clone();
+            move || cb_sink.send(Box::new(run_cover_slot)).is_ok()
+        },
+    );
     siv.run();
+    poller.stop();
+    cover_traffic_poller.stop();
 
     Ok(())
 }